@@ -0,0 +1,67 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+
+use file_system::block_device::BlockDevice;
+use file_system::layout::EFS_VERSION;
+use file_system::migrate::migrate;
+use file_system::BLOCK_SZ;
+
+#[derive(Debug)]
+/// 块文件
+struct BlockFile(Mutex<File>);
+
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start(block_id * BLOCK_SZ as u64))
+            .expect("Error when seeking!");
+        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+    }
+
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
+        let mut file = self.0.lock().unwrap();
+        file.seek(SeekFrom::Start(block_id * BLOCK_SZ as u64))
+            .expect("Error when seeking!");
+        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
+    }
+
+    fn flush(&self) {
+        let file = self.0.lock().unwrap();
+        file.sync_data().expect("Error when flushing!");
+    }
+
+    fn num_blocks(&self) -> Option<u64> {
+        let file = self.0.lock().unwrap();
+        Some(file.metadata().ok()?.len() / BLOCK_SZ as u64)
+    }
+}
+
+/// 将旧版本的镜像原地迁移为当前的磁盘格式，迁移失败时镜像保持原样
+/// 用法：efs-migrate <镜像文件>
+fn main() -> ExitCode {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: efs-migrate <image>");
+        return ExitCode::FAILURE;
+    };
+    let file = match OpenOptions::new().read(true).write(true).open(&path) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    };
+    match migrate(Arc::new(BlockFile(Mutex::new(file)))) {
+        Ok(EFS_VERSION) => println!("{} is already at version {}", path, EFS_VERSION),
+        Ok(version) => println!(
+            "{} migrated from version {} to version {}",
+            path, version, EFS_VERSION
+        ),
+        Err(error) => {
+            eprintln!("{}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    }
+    ExitCode::SUCCESS
+}
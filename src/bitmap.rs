@@ -1,11 +1,17 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
+use spin::{Mutex, MutexGuard};
+
 use crate::block_cache::get_block_cache;
 use crate::block_device::BlockDevice;
+use crate::error::BitmapError;
 use crate::BLOCK_SZ;
 
 /// 位图块
-type BitmapBlock = [u64; 64];
+type BitmapBlock = [u64; BLOCK_SZ / 8];
 
 /// 一个块中的比特数
 const BLOCK_BITS: usize = BLOCK_SZ * 8;
@@ -23,14 +29,89 @@ fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     (block_pos, bit / 64, bit % 64)
 }
 
+/// 清除一个字中由掩码给出的比特
+///
+/// # Arguments
+///
+/// * `bits64`: 字
+/// * `mask`: 需要清除的比特
+///
+/// returns: (usize, u64) (清除的比特数, 掩码中原本就没有分配的比特)
+fn clear_bits(bits64: &mut u64, mask: u64) -> (usize, u64) {
+    let missing = mask & !*bits64;
+    *bits64 &= !mask;
+    ((mask & !missing).count_ones() as usize, missing)
+}
+
+/// 位图中空闲比特的计数
+/// 分为两级：每个位图块的空闲比特数，以及还有空闲比特的位图块的集合，
+/// 分配时通过后者直接找到下一个有空闲比特的位图块，不用逐个检查已经分配满的位图块
+#[derive(Debug)]
+struct FreeCounts {
+    /// 每个位图块中空闲的比特数
+    blocks: Vec<usize>,
+
+    /// 还有空闲比特的位图块的序号
+    available: BTreeSet<usize>,
+
+    /// 空闲的比特总数
+    total: usize,
+}
+
+impl FreeCounts {
+    /// 查找给定范围内第一个还有空闲比特的位图块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_range`: 位图块序号的范围
+    ///
+    /// returns: Option<usize> 位图块在位图中的序号，范围内的位图块都已经分配满时为 None
+    fn next_available(&self, block_range: Range<usize>) -> Option<usize> {
+        if block_range.is_empty() {
+            return None;
+        }
+        self.available.range(block_range).next().copied()
+    }
+
+    /// 分配或者释放比特之后更新一个位图块的空闲计数
+    ///
+    /// # Arguments
+    ///
+    /// * `block_pos`: 位图块在位图中的序号
+    /// * `delta`: 空闲比特数的变化
+    fn update(&mut self, block_pos: usize, delta: isize) {
+        self.blocks[block_pos] = self.blocks[block_pos].wrapping_add_signed(delta);
+        self.total = self.total.wrapping_add_signed(delta);
+        if self.blocks[block_pos] > 0 {
+            self.available.insert(block_pos);
+        } else {
+            self.available.remove(&block_pos);
+        }
+    }
+}
+
 #[derive(Debug)]
 /// 位图
 pub struct Bitmap {
     /// 起始块ID
-    start_block_id: usize,
+    start_block_id: u64,
 
     /// 块数
     blocks: usize,
+
+    /// 可分配的比特数，不超过块数对应的比特数
+    bits: usize,
+
+    /// 空闲比特的计数，第一次用到时扫描位图建立，之后随分配和释放更新
+    /// 绕过位图修改了位图块之后需要调用 [`reset_counts`](Self::reset_counts)
+    ///
+    /// 它同时是这个位图自己的锁：分配和释放在整个查找和修改过程中持有它，
+    /// 同一个位图上的操作不依赖文件系统的锁也不会相互干扰，不同的位图之间互不阻塞；
+    /// 锁的顺序在文件系统的锁之后，在块缓存的锁之前
+    free: Mutex<Option<FreeCounts>>,
+
+    /// 下一次 [`alloc`](Self::alloc) 开始查找的比特，即上一次分配的比特之后
+    cursor: AtomicUsize,
 }
 
 impl Bitmap {
@@ -42,70 +123,879 @@ impl Bitmap {
     /// * `blocks`: 块数
     ///
     /// returns: Bitmap 位图
-    pub fn new(start_block_id: usize, blocks: usize) -> Self {
+    pub fn new(start_block_id: u64, blocks: usize) -> Self {
+        Self::with_bits(start_block_id, blocks, blocks * BLOCK_BITS)
+    }
+
+    /// 创建一个只使用前 `bits` 个比特的位图，用于位图比对应区域大的情况
+    ///
+    /// # Arguments
+    ///
+    /// * `start_block_id`: 起始块ID
+    /// * `blocks`: 块数
+    /// * `bits`: 可分配的比特数
+    ///
+    /// returns: Bitmap 位图
+    pub fn with_bits(start_block_id: u64, blocks: usize, bits: usize) -> Self {
+        assert!(bits <= blocks * BLOCK_BITS);
         Self {
             start_block_id,
             blocks,
+            bits,
+            free: Mutex::new(None),
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    /// 扫描位图，重新建立空闲比特的计数
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    pub fn load_counts(&self, block_device: &Arc<dyn BlockDevice>) {
+        let mut free = self.free.lock();
+        *free = Some(self.scan_counts(block_device));
+    }
+
+    /// 扫描位图，统计每个位图块中空闲的比特数
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: FreeCounts 空闲比特的计数
+    fn scan_counts(&self, block_device: &Arc<dyn BlockDevice>) -> FreeCounts {
+        let blocks: Vec<usize> = (0..self.blocks)
+            .map(|block_pos| {
+                let id = block_pos as u64 + self.start_block_id;
+                let cache = get_block_cache(id, block_device.clone());
+                let allocated = cache.lock().read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block
+                        .iter()
+                        .map(|bits64| bits64.count_ones() as usize)
+                        .sum::<usize>()
+                });
+                self.block_bits(block_pos).saturating_sub(allocated)
+            })
+            .collect();
+        let available = (0..self.blocks)
+            .filter(|&block_pos| blocks[block_pos] > 0)
+            .collect();
+        let total = blocks.iter().sum();
+        FreeCounts {
+            blocks,
+            available,
+            total,
+        }
+    }
+
+    /// 丢弃空闲比特的计数，下次用到时重新扫描位图
+    /// 用于位图块被绕过位图修改之后，例如事务回滚重新读取了块缓存
+    pub fn reset_counts(&self) {
+        *self.free.lock() = None;
+    }
+
+    /// 获取空闲的比特数，计数还没有建立时先扫描位图
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: usize 空闲的比特数
+    pub fn count_free(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        self.with_counts(block_device, |counts| counts.total)
+    }
+
+    /// 获取一个位图块中空闲的比特数，计数还没有建立时先扫描位图
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `block_pos`: 位图块在位图中的序号
+    ///
+    /// returns: usize 空闲的比特数
+    pub fn count_free_in_block(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        block_pos: usize,
+    ) -> usize {
+        self.with_counts(block_device, |counts| counts.blocks[block_pos])
+    }
+
+    /// 一个位图块中可分配的比特数，只有最后一个位图块可能不满
+    ///
+    /// # Arguments
+    ///
+    /// * `block_pos`: 位图块在位图中的序号
+    ///
+    /// returns: usize 比特数
+    fn block_bits(&self, block_pos: usize) -> usize {
+        self.bits
+            .saturating_sub(block_pos * BLOCK_BITS)
+            .min(BLOCK_BITS)
+    }
+
+    /// 持有位图的锁，在空闲比特的计数上调用一个函数，计数还没有建立时先扫描位图
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `f`: 回调函数
+    ///
+    /// returns: V 回调函数的返回值
+    fn with_counts<V>(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        f: impl FnOnce(&mut FreeCounts) -> V,
+    ) -> V {
+        f(self.lock_counts(block_device).as_mut().unwrap())
+    }
+
+    /// 获取位图的锁，计数还没有建立时先扫描位图，返回时计数总是存在
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: MutexGuard<Option<FreeCounts>> 空闲比特的计数
+    fn lock_counts(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> MutexGuard<'_, Option<FreeCounts>> {
+        let mut free = self.free.lock();
+        if free.is_none() {
+            *free = Some(self.scan_counts(block_device));
         }
+        free
     }
 
     /// 从块设备中分配一个新的块
+    /// 给出了目标时先在目标所在的位图块中查找，从目标开始向后，再从位图块开头到目标；
+    /// 没有给出目标或者这个位图块已经分配满时，从上一次分配的比特之后开始查找，
+    /// 到末尾仍没有空闲的比特时从头开始查找，不再每次都重新扫描前面已经分配满的位图块
     ///
     /// # Arguments
     ///
     /// * `block_device`: 块设备
+    /// * `near`: 希望靠近的比特，超出范围时忽略
     ///
     /// returns: Option<usize> 块ID
-    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>) -> Option<usize> {
-        for block_id in 0..self.blocks {
-            let id = block_id + self.start_block_id;
+    pub fn alloc(&self, block_device: &Arc<dyn BlockDevice>, near: Option<usize>) -> Option<usize> {
+        self.with_counts(block_device, |counts| {
+            if let Some(near) = near.filter(|&near| near < self.bits) {
+                let base = near / BLOCK_BITS * BLOCK_BITS;
+                let end = self.bits.min(base + BLOCK_BITS);
+                let bit = self
+                    .alloc_in(block_device, counts, near..end)
+                    .or_else(|| self.alloc_in(block_device, counts, base..near));
+                if bit.is_some() {
+                    return bit;
+                }
+            }
+            let start = self.cursor.load(Ordering::Relaxed);
+            let bit = self.alloc_wrapping(block_device, counts, start)?;
+            self.cursor.store(bit + 1, Ordering::Relaxed);
+            Some(bit)
+        })
+    }
+
+    /// 从给定的比特开始分配一个新的块，到末尾仍没有空闲的比特时从头开始查找
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `start`: 首先尝试的比特，超出范围时从头开始
+    ///
+    /// returns: Option<usize> 块ID
+    pub fn alloc_from(&self, block_device: &Arc<dyn BlockDevice>, start: usize) -> Option<usize> {
+        self.with_counts(block_device, |counts| {
+            self.alloc_wrapping(block_device, counts, start)
+        })
+    }
+
+    /// 从给定的比特开始分配一个新的块，到末尾仍没有空闲的比特时从头开始查找，调用者需持有位图的锁
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `counts`: 空闲比特的计数
+    /// * `start`: 首先尝试的比特，超出范围时从头开始
+    ///
+    /// returns: Option<usize> 块ID
+    fn alloc_wrapping(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        counts: &mut FreeCounts,
+        start: usize,
+    ) -> Option<usize> {
+        let start = if start < self.bits { start } else { 0 };
+        self.alloc_in(block_device, counts, start..self.bits)
+            .or_else(|| self.alloc_in(block_device, counts, 0..start))
+    }
+
+    /// 从给定的比特开始按顺序分配多个块，不要求连续，到末尾仍不够时从头开始查找
+    /// 每个位图块只获取一次块缓存，在其中一次设置所有能分配的比特
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `start`: 首先尝试的比特，超出范围时从头开始
+    /// * `count`: 块数
+    ///
+    /// returns: Vec<usize, Global> 分配的块ID，按查找顺序排列，空闲的比特不够时少于 `count` 个
+    pub fn alloc_many(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        start: usize,
+        count: usize,
+    ) -> Vec<usize> {
+        let start = if start < self.bits { start } else { 0 };
+        let mut bits = Vec::with_capacity(count);
+        self.with_counts(block_device, |counts| {
+            self.alloc_many_in(block_device, counts, start..self.bits, count, &mut bits);
+            self.alloc_many_in(block_device, counts, 0..start, count, &mut bits);
+        });
+        bits
+    }
+
+    /// 在给定范围内按顺序分配多个空闲的比特，调用者需持有位图的锁
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `counts`: 空闲比特的计数
+    /// * `range`: 比特范围
+    /// * `count`: 总共需要的块数
+    /// * `bits`: 已经分配的块ID，新分配的追加在后面，达到 `count` 个时停止
+    fn alloc_many_in(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        counts: &mut FreeCounts,
+        range: Range<usize>,
+        count: usize,
+        bits: &mut Vec<usize>,
+    ) {
+        let end_block = range.end.div_ceil(BLOCK_BITS);
+        let mut next = range.start / BLOCK_BITS;
+        while bits.len() < count {
+            let Some(block_pos) = counts.next_available(next..end_block) else {
+                return;
+            };
+            next = block_pos + 1;
+            let base = block_pos * BLOCK_BITS;
+            let end = range.end.min(base + BLOCK_BITS);
+            let id = block_pos as u64 + self.start_block_id;
+            let cache = get_block_cache(id, block_device.clone());
+            let allocated = cache.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+                let before = bits.len();
+                let mut bit = range.start.max(base);
+                while bit < end && bits.len() < count {
+                    let (_, bits64_pos, inner_pos) = decomposition(bit);
+                    // 把这个比特之前的比特视为已分配
+                    let value = bitmap_block[bits64_pos] | ((1u64 << inner_pos) - 1);
+                    if value == u64::MAX {
+                        bit = base + (bits64_pos + 1) * 64;
+                        continue;
+                    }
+                    bit = base + bits64_pos * 64 + value.trailing_ones() as usize;
+                    if bit >= end {
+                        break;
+                    }
+                    bitmap_block[bits64_pos] |= 1u64 << (bit % 64);
+                    bits.push(bit);
+                    bit += 1;
+                }
+                bits.len() - before
+            });
+            counts.update(block_pos, -(allocated as isize));
+        }
+    }
+
+    /// 分配连续的多个块，从给定的比特开始查找足够长的空闲比特段，到末尾仍没有时从头开始查找
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `start`: 首先尝试的比特，超出范围时从头开始
+    /// * `len`: 块数
+    ///
+    /// returns: Option<usize> 第一个块ID，没有足够长的空闲比特段时返回 None，此时不做任何修改
+    pub fn alloc_contiguous(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        start: usize,
+        len: usize,
+    ) -> Option<usize> {
+        let mut free = self.lock_counts(block_device);
+        let counts = free.as_mut().unwrap();
+        if len == 0 || len > counts.total {
+            return None;
+        }
+        let start = if start < self.bits { start } else { 0 };
+        let words = self.read_words(block_device);
+        let first = Self::find_run(&words, start..self.bits, len)
+            .or_else(|| Self::find_run(&words, 0..(start + len - 1).min(self.bits), len))?;
+        for block_pos in first / BLOCK_BITS..(first + len).div_ceil(BLOCK_BITS) {
+            let id = block_pos as u64 + self.start_block_id;
+            let cache = get_block_cache(id, block_device.clone());
+            cache.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+                let base = block_pos * BLOCK_BITS;
+                for bit in first.max(base)..(first + len).min(base + BLOCK_BITS) {
+                    let (_, bits64_pos, inner_pos) = decomposition(bit);
+                    assert_eq!(bitmap_block[bits64_pos] & (1u64 << inner_pos), 0);
+                    bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+                }
+            });
+            let base = block_pos * BLOCK_BITS;
+            let allocated = (first + len).min(base + BLOCK_BITS) - first.max(base);
+            counts.update(block_pos, -(allocated as isize));
+        }
+        Some(first)
+    }
+
+    /// 读出位图中的所有字
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 按比特顺序排列的字
+    fn read_words(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u64> {
+        let mut words = Vec::with_capacity(self.blocks * BLOCK_BITS / 64);
+        for block_pos in 0..self.blocks {
+            let id = block_pos as u64 + self.start_block_id;
+            let cache = get_block_cache(id, block_device.clone());
+            cache.lock().read(0, |bitmap_block: &BitmapBlock| {
+                words.extend_from_slice(bitmap_block)
+            });
+        }
+        words
+    }
+
+    /// 在给定范围内查找第一段足够长的空闲比特
+    /// 按字扫描：全空的字整体计入当前的空闲段，其余的字用前导零和尾随零得到两端的空闲比特，
+    /// 再用 [`find_run_in_word`](Self::find_run_in_word) 查找完全位于字中的空闲段
+    ///
+    /// # Arguments
+    ///
+    /// * `words`: 位图中的字
+    /// * `range`: 比特段必须完全位于其中的范围
+    /// * `len`: 比特数
+    ///
+    /// returns: Option<usize> 比特段的起点
+    fn find_run(words: &[u64], range: Range<usize>, len: usize) -> Option<usize> {
+        let mut run_start = range.start;
+        let mut run_len = 0;
+        for (word_pos, &word) in words
+            .iter()
+            .enumerate()
+            .take(range.end.div_ceil(64))
+            .skip(range.start / 64)
+        {
+            // 把范围之外的比特视为已分配
+            let base = word_pos * 64;
+            let mut word = word;
+            if range.start > base {
+                word |= (1u64 << (range.start - base)) - 1;
+            }
+            if range.end < base + 64 {
+                word |= u64::MAX << (range.end - base);
+            }
+            if word == 0 {
+                run_len += 64;
+                if run_len >= len {
+                    return Some(run_start);
+                }
+                continue;
+            }
+            // 从前面的字延续过来的空闲段加上这个字低位的空闲比特
+            if run_len + word.trailing_zeros() as usize >= len {
+                return Some(run_start);
+            }
+            if len < 64 {
+                if let Some(start) = Self::find_run_in_word(!word, len) {
+                    return Some(base + start);
+                }
+            }
+            // 这个字高位的空闲比特，可能延续到后面的字
+            let high = word.leading_zeros() as usize;
+            run_start = base + 64 - high;
+            run_len = high;
+        }
+        None
+    }
+
+    /// 在一个字中查找第一段连续置位的比特
+    /// 反复将字和它右移之后的结果相与，第 i 位仍然置位说明从 i 开始的已覆盖长度的比特都置位，
+    /// 移位量按倍增递增，只需要对数次运算
+    ///
+    /// # Arguments
+    ///
+    /// * `bits64`: 字
+    /// * `len`: 比特数，小于 64
+    ///
+    /// returns: Option<usize> 比特段在字中的起点
+    fn find_run_in_word(bits64: u64, len: usize) -> Option<usize> {
+        let mut value = bits64;
+        let mut covered = 1;
+        while covered < len {
+            let shift = covered.min(len - covered);
+            value &= value >> shift;
+            covered += shift;
+        }
+        (value != 0).then_some(value.trailing_zeros() as usize)
+    }
+
+    /// 在给定范围内分配第一个空闲的比特，调用者需持有位图的锁
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `counts`: 空闲比特的计数
+    /// * `range`: 比特范围
+    ///
+    /// returns: Option<usize> 块ID
+    fn alloc_in(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        counts: &mut FreeCounts,
+        range: Range<usize>,
+    ) -> Option<usize> {
+        if range.is_empty() || counts.total == 0 {
+            return None;
+        }
+        let end_block = range.end.div_ceil(BLOCK_BITS);
+        let mut next = range.start / BLOCK_BITS;
+        // 跳过没有空闲比特的位图块
+        while let Some(block_pos) = counts.next_available(next..end_block) {
+            next = block_pos + 1;
+            let id = block_pos as u64 + self.start_block_id;
             let cache = get_block_cache(id, block_device.clone());
             let pos = cache.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-                if let Some((bits64_pos, inner_pos)) = bitmap_block
-                    .iter()
-                    .enumerate()
-                    .find(|(_, bits64)| {
-                        let value = **bits64;
-                        value != u64::MAX
-                    })
-                    .map(|(bits64_pos, bits64)| {
-                        let inner_pos = bits64.trailing_ones() as usize;
-                        (bits64_pos, inner_pos)
-                    })
-                {
-                    // 修改缓存
-                    let value = 1u64 << inner_pos;
-                    bitmap_block[bits64_pos] |= value;
-                    let result = block_id * BLOCK_BITS + bits64_pos * 64 + inner_pos;
-                    Some(result)
-                } else {
-                    None
+                let base = block_pos * BLOCK_BITS;
+                let first = range.start.saturating_sub(base) / 64;
+                for (bits64_pos, bits64) in bitmap_block.iter_mut().enumerate().skip(first) {
+                    // 把范围起点之前的比特视为已分配，只有第一个字中有这样的比特
+                    let offset = base + bits64_pos * 64;
+                    let skipped = range.start.saturating_sub(offset);
+                    let value = *bits64 | ((1u64 << skipped) - 1);
+                    if value == u64::MAX {
+                        continue;
+                    }
+                    let result = offset + value.trailing_ones() as usize;
+                    // 按顺序查找，第一个空闲的比特超出范围时说明范围内已经没有可分配的比特
+                    if result >= range.end {
+                        return Some(None);
+                    }
+                    *bits64 |= 1u64 << (result - offset);
+                    return Some(Some(result));
                 }
+                None
             });
-            if pos.is_some() {
-                return pos;
+            match pos {
+                Some(Some(pos)) => {
+                    counts.update(block_pos, -1);
+                    return Some(pos);
+                }
+                Some(None) => return None,
+                None => continue,
             }
         }
         None
     }
 
-    /// 释放一个块
+    /// 将给定的块标记为已分配，用于保留固定位置的块
     ///
     /// # Arguments
     ///
     /// * `block_device`: 块设备
     /// * `bit`: 块ID
-    pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+    pub fn reserve(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let mut free = self.lock_counts(block_device);
+        let counts = free.as_mut().unwrap();
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
-        let cache = get_block_cache(block_pos + self.start_block_id, block_device.clone());
+        let cache = get_block_cache(block_pos as u64 + self.start_block_id, block_device.clone());
         cache.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
-            assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) > 0);
-            bitmap_block[bits64_pos] -= 1u64 << inner_pos;
+            assert_eq!(bitmap_block[bits64_pos] & (1u64 << inner_pos), 0);
+            bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+        });
+        counts.update(block_pos, -1);
+    }
+
+    /// 释放一个块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `bit`: 块ID
+    ///
+    /// returns: Result<(), BitmapError> 比特没有分配或者超出范围时返回错误，此时不做任何修改
+    pub fn dealloc(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        bit: usize,
+    ) -> Result<(), BitmapError> {
+        if bit >= self.bits {
+            return Err(BitmapError::OutOfRange(bit));
+        }
+        let mut free = self.lock_counts(block_device);
+        let counts = free.as_mut().unwrap();
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        let cache = get_block_cache(block_pos as u64 + self.start_block_id, block_device.clone());
+        let allocated = cache.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+            let mask = 1u64 << inner_pos;
+            let allocated = bitmap_block[bits64_pos] & mask != 0;
+            bitmap_block[bits64_pos] &= !mask;
+            allocated
         });
+        if !allocated {
+            return Err(BitmapError::NotAllocated(bit));
+        }
+        counts.update(block_pos, 1);
+        Ok(())
+    }
+
+    /// 释放多个块，每个位图块只获取一次块缓存，排序之后同一个字中的比特一次清除
+    /// 没有分配、重复或者超出范围的比特被跳过，其余的比特照常释放
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `bits`: 块ID，不要求有序
+    ///
+    /// returns: Result<(), BitmapError> 有被跳过的比特时返回按块ID顺序的第一个错误
+    pub fn dealloc_many(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        bits: &[usize],
+    ) -> Result<(), BitmapError> {
+        let mut bits = bits.to_vec();
+        bits.sort_unstable();
+        let out_of_range = bits.iter().find(|&&bit| bit >= self.bits).copied();
+        bits.retain(|&bit| bit < self.bits);
+        let mut free = self.lock_counts(block_device);
+        let counts = free.as_mut().unwrap();
+        let mut first_not_allocated = None;
+        for chunk in bits.chunk_by(|a, b| a / BLOCK_BITS == b / BLOCK_BITS) {
+            let block_pos = chunk[0] / BLOCK_BITS;
+            let id = block_pos as u64 + self.start_block_id;
+            let cache = get_block_cache(id, block_device.clone());
+            let (count, not_allocated) =
+                cache.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+                    let mut count = 0;
+                    let mut not_allocated = None;
+                    for word in chunk.chunk_by(|a, b| a / 64 == b / 64) {
+                        let (_, bits64_pos, _) = decomposition(word[0]);
+                        let base = word[0] - word[0] % 64;
+                        // 重复的比特只有第一次能够释放
+                        let mut duplicate = u64::MAX;
+                        let mut mask = 0;
+                        for &bit in word {
+                            if mask & (1u64 << (bit % 64)) != 0 {
+                                duplicate = duplicate.min((bit % 64) as u64);
+                            }
+                            mask |= 1u64 << (bit % 64);
+                        }
+                        let (cleared, missing) = clear_bits(&mut bitmap_block[bits64_pos], mask);
+                        count += cleared;
+                        let first = duplicate.min(missing.trailing_zeros() as u64);
+                        if first < 64 {
+                            not_allocated.get_or_insert(base + first as usize);
+                        }
+                    }
+                    (count, not_allocated)
+                });
+            counts.update(block_pos, count as isize);
+            first_not_allocated = first_not_allocated.or(not_allocated);
+        }
+        Self::dealloc_result(first_not_allocated, out_of_range)
+    }
+
+    /// 释放一段连续的块，每个位图块只获取一次块缓存，整个字在范围内时一次清除
+    /// 没有分配或者超出范围的比特被跳过，其余的比特照常释放
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `start`: 第一个块ID
+    /// * `len`: 块数
+    ///
+    /// returns: Result<(), BitmapError> 有被跳过的比特时返回第一个错误
+    pub fn dealloc_range(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        start: usize,
+        len: usize,
+    ) -> Result<(), BitmapError> {
+        let end = start.saturating_add(len);
+        let out_of_range = (len > 0 && end > self.bits).then_some(start.max(self.bits));
+        let end = end.min(self.bits);
+        let mut free = self.lock_counts(block_device);
+        let counts = free.as_mut().unwrap();
+        let mut first_not_allocated = None;
+        let mut block_start = start;
+        while block_start < end {
+            let block_pos = block_start / BLOCK_BITS;
+            let block_end = end.min((block_pos + 1) * BLOCK_BITS);
+            let id = block_pos as u64 + self.start_block_id;
+            let cache = get_block_cache(id, block_device.clone());
+            let (count, not_allocated) =
+                cache.lock().modify(0, |bitmap_block: &mut BitmapBlock| {
+                    let mut count = 0;
+                    let mut not_allocated = None;
+                    let mut bit = block_start;
+                    while bit < block_end {
+                        let (_, bits64_pos, inner_pos) = decomposition(bit);
+                        let base = bit - inner_pos;
+                        let width = block_end.min(base + 64) - bit;
+                        let mask = if width == 64 {
+                            u64::MAX
+                        } else {
+                            ((1u64 << width) - 1) << inner_pos
+                        };
+                        let (cleared, missing) = clear_bits(&mut bitmap_block[bits64_pos], mask);
+                        count += cleared;
+                        if missing != 0 {
+                            not_allocated.get_or_insert(base + missing.trailing_zeros() as usize);
+                        }
+                        bit += width;
+                    }
+                    (count, not_allocated)
+                });
+            counts.update(block_pos, count as isize);
+            first_not_allocated = first_not_allocated.or(not_allocated);
+            block_start = block_end;
+        }
+        Self::dealloc_result(first_not_allocated, out_of_range)
+    }
+
+    /// 批量释放的结果，超出范围的比特总是排在其它比特之后
+    ///
+    /// # Arguments
+    ///
+    /// * `not_allocated`: 第一个没有分配的比特
+    /// * `out_of_range`: 第一个超出范围的比特
+    ///
+    /// returns: Result<(), BitmapError> 有被跳过的比特时返回第一个错误
+    fn dealloc_result(
+        not_allocated: Option<usize>,
+        out_of_range: Option<usize>,
+    ) -> Result<(), BitmapError> {
+        match (not_allocated, out_of_range) {
+            (Some(bit), _) => Err(BitmapError::NotAllocated(bit)),
+            (None, Some(bit)) => Err(BitmapError::OutOfRange(bit)),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// 按重新计算出的使用情况重写整个位图，用于修复与元数据不一致的位图
+    /// 每个位图块只获取一次块缓存，没有变化的位图块不会被标记为脏，之后重新建立空闲比特的计数
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `is_used`: 给定的比特是否应当标记为已分配
+    ///
+    /// returns: Vec<usize, Global> 改变了的比特，按顺序排列
+    pub fn rebuild(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        is_used: impl Fn(usize) -> bool,
+    ) -> Vec<usize> {
+        let mut free = self.free.lock();
+        let mut changed = Vec::new();
+        for block_pos in 0..self.blocks {
+            let base = block_pos * BLOCK_BITS;
+            let mut expected: BitmapBlock = [0; BLOCK_SZ / 8];
+            for bit in base..base + self.block_bits(block_pos) {
+                if is_used(bit) {
+                    let (_, bits64_pos, inner_pos) = decomposition(bit);
+                    expected[bits64_pos] |= 1u64 << inner_pos;
+                }
+            }
+            let id = block_pos as u64 + self.start_block_id;
+            let cache = get_block_cache(id, block_device.clone());
+            let mut cache = cache.lock();
+            let first = changed.len();
+            cache.read(0, |bitmap_block: &BitmapBlock| {
+                for (bits64_pos, (&actual, &wanted)) in
+                    bitmap_block.iter().zip(expected.iter()).enumerate()
+                {
+                    // 可分配范围之外的比特保持原样
+                    let mut diff = actual ^ wanted;
+                    let offset = base + bits64_pos * 64;
+                    while diff != 0 {
+                        let bit = offset + diff.trailing_zeros() as usize;
+                        diff &= diff - 1;
+                        if bit < self.bits {
+                            changed.push(bit);
+                        }
+                    }
+                }
+            });
+            if changed.len() > first {
+                cache.modify(0, |bitmap_block: &mut BitmapBlock| {
+                    for &bit in &changed[first..] {
+                        let (_, bits64_pos, inner_pos) = decomposition(bit);
+                        bitmap_block[bits64_pos] ^= 1u64 << inner_pos;
+                    }
+                });
+            }
+        }
+        *free = Some(self.scan_counts(block_device));
+        changed
+    }
+
+    /// 给定的块是否已分配
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `bit`: 块ID
+    ///
+    /// returns: bool 是否已分配
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        let cache = get_block_cache(block_pos as u64 + self.start_block_id, block_device.clone());
+        let allocated = cache.lock().read(0, |bitmap_block: &BitmapBlock| {
+            bitmap_block[bits64_pos] & (1u64 << inner_pos) != 0
+        });
+        allocated
+    }
+
+    /// 扫描位图，统计已分配的块数
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: usize 已分配的块数
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|block_id| {
+                let cache =
+                    get_block_cache(block_id as u64 + self.start_block_id, block_device.clone());
+                let count = cache.lock().read(0, |bitmap_block: &BitmapBlock| {
+                    bitmap_block
+                        .iter()
+                        .map(|bits64| bits64.count_ones() as usize)
+                        .sum::<usize>()
+                });
+                count
+            })
+            .sum()
     }
 
     /// 获取可分配块的最大数量
     pub fn maximum(&self) -> usize {
-        self.blocks * BLOCK_BITS
+        self.bits
+    }
+
+    /// 按顺序遍历已分配的比特
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: BitIter 比特的迭代器，见 [`BitIter`]
+    pub fn iter_allocated<'a>(&'a self, block_device: &'a Arc<dyn BlockDevice>) -> BitIter<'a> {
+        BitIter::new(self, block_device, true)
+    }
+
+    /// 按顺序遍历空闲的比特
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: BitIter 比特的迭代器，见 [`BitIter`]
+    pub fn iter_free<'a>(&'a self, block_device: &'a Arc<dyn BlockDevice>) -> BitIter<'a> {
+        BitIter::new(self, block_device, false)
+    }
+}
+
+/// 位图中已分配或者空闲的比特的迭代器，由 [`Bitmap::iter_allocated`] 和 [`Bitmap::iter_free`] 创建
+/// 每次读出一个位图块中的所有字，不持有块缓存，在字中用尾随零找到下一个比特
+/// 遍历过程中修改位图时，已经读出的位图块中的变化不可见
+pub struct BitIter<'a> {
+    /// 位图
+    bitmap: &'a Bitmap,
+
+    /// 块设备
+    block_device: &'a Arc<dyn BlockDevice>,
+
+    /// 遍历已分配的比特还是空闲的比特
+    allocated: bool,
+
+    /// 下一个读取的位图块在位图中的序号
+    block_pos: usize,
+
+    /// 当前位图块中的字
+    words: Vec<u64>,
+
+    /// 当前位图块中下一个处理的字的序号
+    word_pos: usize,
+
+    /// 当前的字中还没有返回的比特
+    current: u64,
+
+    /// 当前的字中第一个比特的序号
+    current_base: usize,
+}
+
+impl<'a> BitIter<'a> {
+    /// 创建一个比特的迭代器
+    ///
+    /// # Arguments
+    ///
+    /// * `bitmap`: 位图
+    /// * `block_device`: 块设备
+    /// * `allocated`: 遍历已分配的比特还是空闲的比特
+    ///
+    /// returns: BitIter 比特的迭代器
+    fn new(bitmap: &'a Bitmap, block_device: &'a Arc<dyn BlockDevice>, allocated: bool) -> Self {
+        Self {
+            bitmap,
+            block_device,
+            allocated,
+            block_pos: 0,
+            words: Vec::new(),
+            word_pos: 0,
+            current: 0,
+            current_base: 0,
+        }
+    }
+}
+
+impl Iterator for BitIter<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            if self.current != 0 {
+                let bit = self.current_base + self.current.trailing_zeros() as usize;
+                self.current &= self.current - 1;
+                // 比特按顺序返回，之后的比特都超出可分配的范围
+                return (bit < self.bitmap.bits).then_some(bit);
+            }
+            if self.word_pos < self.words.len() {
+                let bits64 = self.words[self.word_pos];
+                self.current = if self.allocated { bits64 } else { !bits64 };
+                self.current_base = (self.block_pos - 1) * BLOCK_BITS + self.word_pos * 64;
+                self.word_pos += 1;
+                continue;
+            }
+            if self.block_pos * BLOCK_BITS >= self.bitmap.bits {
+                return None;
+            }
+            let id = self.block_pos as u64 + self.bitmap.start_block_id;
+            let cache = get_block_cache(id, self.block_device.clone());
+            self.words.clear();
+            cache.lock().read(0, |bitmap_block: &BitmapBlock| {
+                self.words.extend_from_slice(bitmap_block)
+            });
+            self.block_pos += 1;
+            self.word_pos = 0;
+        }
     }
 }
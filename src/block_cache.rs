@@ -1,26 +1,40 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::mem::size_of;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
 use spin::Mutex;
 
 use crate::block_device::BlockDevice;
+use crate::error::CacheError;
+use crate::eviction::{EvictionPolicy, FifoPolicy};
+use crate::pod::{from_bytes, from_bytes_mut, Pod};
 use crate::{nop, BLOCK_SZ};
 
+/// 按 8 字节对齐的块数据，保证块中按自然对齐存放的磁盘布局类型可以被直接引用
+#[repr(C, align(8))]
+struct AlignedBlock([u8; BLOCK_SZ]);
+
 /// 内存中的缓存块
 pub struct BlockCache {
     /// 缓存块数据
-    cache: [u8; BLOCK_SZ],
+    cache: AlignedBlock,
 
     /// 底层块ID
-    block_id: usize,
+    block_id: u64,
 
     /// 底层块设备
     block_device: Arc<dyn BlockDevice>,
 
     /// 该块是否为脏块
     modified: bool,
+
+    /// 该块最近一次从干净变脏的时间，干净时为 None
+    dirty_since: Option<Instant>,
+
+    /// 写回该块之前必须先写回的同一块设备上的块，写回该块之后清空
+    dependencies: BTreeSet<u64>,
 }
 
 impl BlockCache {
@@ -32,67 +46,134 @@ impl BlockCache {
     /// * `block_device`: 块设备
     ///
     /// returns: BlockCache 块缓存
-    pub fn new(block_id: usize, block_device: Arc<dyn BlockDevice>) -> Self {
-        let mut cache = [0u8; BLOCK_SZ];
-        block_device.read_block(block_id, &mut cache);
+    pub fn new(block_id: u64, block_device: Arc<dyn BlockDevice>) -> Self {
+        let mut cache = AlignedBlock([0u8; BLOCK_SZ]);
+        if !read_transaction_block(&block_device, block_id, &mut cache.0) {
+            block_device.read_block(block_id, &mut cache.0);
+        }
+        Self::with_data(block_id, block_device, cache)
+    }
+
+    /// 用已经从块设备读出的数据创建块缓存，块设备正在进行事务时优先使用事务中的内容
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    /// * `block_device`: 块设备
+    /// * `data`: 块设备中的块数据
+    ///
+    /// returns: BlockCache 块缓存
+    fn from_device_data(block_id: u64, block_device: Arc<dyn BlockDevice>, data: &[u8]) -> Self {
+        let mut cache = AlignedBlock([0u8; BLOCK_SZ]);
+        if !read_transaction_block(&block_device, block_id, &mut cache.0) {
+            cache.0.copy_from_slice(data);
+        }
+        Self::with_data(block_id, block_device, cache)
+    }
+
+    /// 用给定的数据创建干净的块缓存
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    /// * `block_device`: 块设备
+    /// * `cache`: 块数据
+    ///
+    /// returns: BlockCache 块缓存
+    fn with_data(block_id: u64, block_device: Arc<dyn BlockDevice>, cache: AlignedBlock) -> Self {
         Self {
             cache,
             block_id,
             block_device,
             modified: false,
+            dirty_since: None,
+            dependencies: BTreeSet::new(),
         }
     }
 
-    /// 获取缓存块数据内的偏移地址
+    /// 以磁盘布局类型引用缓存块中给定偏移处的数据
+    /// 偏移必须满足该类型的对齐要求
     ///
     /// # Arguments
     ///
     /// * `offset`: 偏移量
     ///
-    /// returns: usize 偏移地址
-    fn addr_of_offset(&self, offset: usize) -> usize {
-        &self.cache[offset] as *const _ as usize
-    }
-
-    pub fn get_ref<T>(&self, offset: usize) -> &T
-    where
-        T: Sized,
-    {
+    /// returns: &T 数据的引用
+    pub fn get_ref<T: Pod>(&self, offset: usize) -> &T {
         let type_size = size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
-        let addr = self.addr_of_offset(offset);
+        let value = from_bytes(&self.cache.0[offset..offset + type_size]);
         nop();
-        unsafe { &*(addr as *const T) }
+        value
     }
 
-    pub fn get_mut<T>(&mut self, offset: usize) -> &mut T
-    where
-        T: Sized,
-    {
+    /// 以磁盘布局类型可变地引用缓存块中给定偏移处的数据，并将该块标记为脏块
+    /// 偏移必须满足该类型的对齐要求
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移量
+    ///
+    /// returns: &mut T 数据的可变引用
+    pub fn get_mut<T: Pod>(&mut self, offset: usize) -> &mut T {
         let type_size = size_of::<T>();
         assert!(offset + type_size <= BLOCK_SZ);
+        if !self.modified {
+            self.dirty_since = Some(Instant::now());
+        }
         self.modified = true;
-        let addr = self.addr_of_offset(offset);
+        let value = from_bytes_mut(&mut self.cache.0[offset..offset + type_size]);
         nop();
-        unsafe { &mut *(addr as *mut T) }
+        value
     }
 
-    pub fn read<T, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
+    pub fn read<T: Pod, V>(&self, offset: usize, f: impl FnOnce(&T) -> V) -> V {
         let value = self.get_ref(offset);
         f(value)
     }
 
-    pub fn modify<T, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
+    pub fn modify<T: Pod, V>(&mut self, offset: usize, f: impl FnOnce(&mut T) -> V) -> V {
         let value = self.get_mut(offset);
         f(value)
     }
 
+    /// 记录该块依赖同一块设备上的另一个块，块缓存按依赖顺序写回时先写回被依赖的块
+    /// 依赖只约束块缓存写回块设备的顺序，直接调用 [`sync`](Self::sync) 时不检查
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 被依赖的块ID，是该块自己时忽略
+    pub fn depend_on(&mut self, block_id: u64) {
+        if block_id != self.block_id {
+            self.dependencies.insert(block_id);
+        }
+    }
+
+    /// 将脏块写回块设备，块设备正在进行事务时只保存到事务中
     pub fn sync(&mut self) {
+        self.dependencies.clear();
         if self.modified {
             self.modified = false;
-            self.block_device.write_block(self.block_id, &self.cache);
+            self.dirty_since = None;
+            if !write_transaction_block(&self.block_device, self.block_id, &self.cache.0) {
+                self.block_device.write_block(self.block_id, &self.cache.0);
+            }
         }
     }
+
+    /// 丢弃缓存中的修改，之后不再写回
+    fn discard(&mut self) {
+        self.modified = false;
+        self.dirty_since = None;
+        self.dependencies.clear();
+    }
+
+    /// 丢弃缓存中的修改，从块设备重新读取
+    fn reload(&mut self) {
+        self.discard();
+        self.block_device
+            .read_block(self.block_id, &mut self.cache.0);
+    }
 }
 
 impl Drop for BlockCache {
@@ -101,20 +182,367 @@ impl Drop for BlockCache {
     }
 }
 
-/// 块缓存的大小
-const BLOCK_CACHE_SIZE: usize = 16;
+/// 块缓存的默认大小
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 16;
+
+/// 块设备的标识，取块设备对象的地址
+/// 块缓存持有块设备，因此缓存中还有某个块设备的块时，它的地址不会被另一个块设备复用
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+///
+/// returns: usize 块设备标识
+fn device_id(block_device: &Arc<dyn BlockDevice>) -> usize {
+    Arc::as_ptr(block_device) as *const () as usize
+}
+
+/// 块缓存的键：（块设备标识，块ID）
+pub type CacheKey = (usize, u64);
+
+/// 块缓存的写回策略，决定文件系统在每次修改之后是否将所有块缓存写回块设备
+/// 只对 [`WriteMode::Sync`](crate::efs::WriteMode::Sync) 的文件系统生效
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WritebackPolicy {
+    /// 每次修改之后立即写回所有块缓存，崩溃时不丢失已经返回的修改
+    #[default]
+    WriteThrough,
+
+    /// 修改之后只在距上次写回所有块缓存超过给定间隔，或者脏块数超过给定数量时才写回，
+    /// 崩溃时可能丢失最近一个间隔内的修改
+    WriteBack {
+        /// 两次写回之间最长的间隔
+        interval: Duration,
+
+        /// 最多允许的脏块数
+        max_dirty: usize,
+    },
+}
+
+/// 一个事务中写回过的脏块，按块ID索引
+type TransactionBlocks = BTreeMap<u64, Box<[u8; BLOCK_SZ]>>;
 
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    queue: VecDeque<(CacheKey, Arc<Mutex<BlockCache>>)>,
+
+    /// 最多缓存的块数
+    capacity: usize,
+
+    /// 替换策略
+    policy: Box<dyn EvictionPolicy>,
+
+    /// 被固定的块及其固定次数，被固定的块不会被替换出去，也不会在替换其它块时被提前写回
+    pins: BTreeMap<CacheKey, usize>,
+
+    /// 写回策略
+    writeback: WritebackPolicy,
+
+    /// 上次写回所有块缓存的时间
+    last_flush: Instant,
+}
+
+impl Default for BlockCacheManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BlockCacheManager {
     pub fn new() -> Self {
+        Self::with_policy(Box::<FifoPolicy>::default())
+    }
+
+    /// 使用给定的替换策略创建块缓存管理器
+    ///
+    /// # Arguments
+    ///
+    /// * `policy`: 替换策略
+    ///
+    /// returns: BlockCacheManager 块缓存管理器
+    pub fn with_policy(mut policy: Box<dyn EvictionPolicy>) -> Self {
+        policy.resize(DEFAULT_BLOCK_CACHE_SIZE);
         Self {
             queue: VecDeque::new(),
+            capacity: DEFAULT_BLOCK_CACHE_SIZE,
+            policy,
+            pins: BTreeMap::new(),
+            writeback: WritebackPolicy::default(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// 更换替换策略，已经缓存的块按加入缓存的顺序告知新的策略
+    ///
+    /// # Arguments
+    ///
+    /// * `policy`: 替换策略
+    pub fn set_policy(&mut self, mut policy: Box<dyn EvictionPolicy>) {
+        policy.resize(self.capacity);
+        for (key, _) in self.queue.iter() {
+            policy.insert(*key);
+        }
+        self.policy = policy;
+    }
+
+    /// 获取写回策略
+    pub fn writeback_policy(&self) -> WritebackPolicy {
+        self.writeback
+    }
+
+    /// 设置写回策略
+    ///
+    /// # Arguments
+    ///
+    /// * `writeback`: 写回策略
+    pub fn set_writeback_policy(&mut self, writeback: WritebackPolicy) {
+        self.writeback = writeback;
+    }
+
+    /// 按写回策略判断一次修改之后是否需要写回所有块缓存
+    /// 持有管理器锁时只能尝试获取块缓存锁，正被锁定的块缓存不计入脏块数
+    ///
+    /// returns: bool 是否需要写回
+    fn commit_due(&self) -> bool {
+        match self.writeback {
+            WritebackPolicy::WriteThrough => true,
+            WritebackPolicy::WriteBack {
+                interval,
+                max_dirty,
+            } => {
+                self.last_flush.elapsed() >= interval
+                    || self
+                        .queue
+                        .iter()
+                        .filter(|(_, cache)| cache.try_lock().is_some_and(|cache| cache.modified))
+                        .count()
+                        > max_dirty
+            }
+        }
+    }
+
+    /// 获取最多缓存的块数
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// 设置最多缓存的块数，缩小时立即替换出多余的未使用的块缓存
+    /// 仍在使用中的块缓存会保留到之后的替换
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity`: 最多缓存的块数，至少为 1
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0);
+        self.capacity = capacity;
+        self.policy.resize(capacity);
+        while self.queue.len() > self.capacity && self.evict_one() {
+            nop();
         }
     }
 
+    /// 按替换策略给出的顺序找到第一个没有被使用也没有被固定的块缓存并替换出去
+    /// 优先替换依赖的块都能先写回的块缓存，替换前按依赖顺序写回它依赖的块，
+    /// 所有候选的依赖都正被其它线程锁定时才退回到第一个没有被使用的块缓存
+    ///
+    /// returns: bool 是否替换出了一个块缓存
+    fn evict_one(&mut self) -> bool {
+        let candidates: Vec<usize> = self
+            .policy
+            .candidates()
+            .into_iter()
+            .filter_map(|key| self.queue.iter().position(|pair| pair.0 == key))
+            .filter(|&idx| {
+                let (key, cache) = &self.queue[idx];
+                let free = Arc::strong_count(cache) == 1 && !self.pins.contains_key(key);
+                nop();
+                free
+            })
+            .collect();
+        let Some(&first) = candidates.first() else {
+            return false;
+        };
+        let idx = candidates
+            .into_iter()
+            .find(|&idx| self.flush_dependencies(idx, &mut BTreeSet::new()))
+            .unwrap_or(first);
+        let range = idx..=idx;
+        for (key, _) in self.queue.drain(range) {
+            self.policy.remove(key);
+        }
+        true
+    }
+
+    /// 持有管理器锁时按依赖顺序写回给定块缓存依赖的块，不包括它自己
+    /// 这时只能尝试获取块缓存锁，遇到正被其它线程锁定或者被固定的块缓存时放弃，已经写回的块保持写回
+    ///
+    /// # Arguments
+    ///
+    /// * `idx`: 块缓存在队列中的位置
+    /// * `visited`: 已经访问过的块缓存，用于打破依赖之间的环
+    ///
+    /// returns: bool 是否写回了所有依赖的块
+    fn flush_dependencies(&self, idx: usize, visited: &mut BTreeSet<CacheKey>) -> bool {
+        let (key, cache) = &self.queue[idx];
+        visited.insert(*key);
+        let Some(dependencies) = cache.try_lock().map(|cache| cache.dependencies.clone()) else {
+            return false;
+        };
+        for block_id in dependencies {
+            let dependency = (key.0, block_id);
+            if visited.contains(&dependency) {
+                continue;
+            }
+            // 不在缓存中的块已经写回了
+            let Some(dependency_idx) = self.queue.iter().position(|(key, _)| *key == dependency)
+            else {
+                continue;
+            };
+            if self.pins.contains_key(&dependency)
+                || !self.flush_dependencies(dependency_idx, visited)
+            {
+                return false;
+            }
+            match self.queue[dependency_idx].1.try_lock() {
+                Some(mut cache) => cache.sync(),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// 获取块缓存并将它固定在缓存中，直到调用相同次数的 [`unpin`](Self::unpin)
+    /// 固定次数与块缓存的引用计数分开计算，被固定的块缓存即使没有被使用也不会被替换出去，
+    /// 替换其它块缓存时也不会把它作为依赖提前写回，写回所有块缓存时也跳过它，
+    /// 只有持有者显式同步它或者块设备被释放时才会写回
+    /// 至少留下一个可以替换的位置，固定的块数不能达到缓存容量
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Result<Arc<Mutex<BlockCache, Spin>>, CacheError> 块缓存，
+    /// 固定它会占用最后一个可以替换的位置，或者块不在缓存中而所有块缓存都在使用中或者被固定时返回错误
+    pub fn pin(
+        &mut self,
+        block_id: u64,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Result<Arc<Mutex<BlockCache>>, CacheError> {
+        let key = (device_id(&block_device), block_id);
+        if !self.pins.contains_key(&key) && self.pins.len() + 1 >= self.capacity {
+            return Err(CacheError::Exhausted);
+        }
+        let cache = self.try_get_block_cache(block_id, block_device)?;
+        *self.pins.entry(key).or_insert(0) += 1;
+        Ok(cache)
+    }
+
+    /// 撤销一次 [`pin`](Self::pin)，固定次数减为零时块缓存可以再被替换出去
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: bool 该块是否被固定过
+    pub fn unpin(&mut self, block_id: u64, block_device: &Arc<dyn BlockDevice>) -> bool {
+        let key = (device_id(block_device), block_id);
+        match self.pins.get_mut(&key) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    self.pins.remove(&key);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 获取块的固定次数
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: usize 固定次数，没有被固定时为 0
+    pub fn pin_count(&self, block_id: u64, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let key = (device_id(block_device), block_id);
+        self.pins.get(&key).copied().unwrap_or(0)
+    }
+
+    /// 将一个块从缓存中移除，同时撤销它的固定
+    /// 持有管理器锁时不等待块缓存锁，调用者释放管理器锁之后再丢弃返回的块缓存中的修改，
+    /// 否则最后一个引用被释放时仍会写回
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Option<Arc<Mutex<BlockCache, Spin>>> 被移除的块缓存，不在缓存中时为 None
+    fn remove(
+        &mut self,
+        block_id: u64,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<Arc<Mutex<BlockCache>>> {
+        let key = (device_id(block_device), block_id);
+        let idx = self.queue.iter().position(|(current, _)| *current == key)?;
+        let (_, cache) = self.queue.remove(idx)?;
+        self.policy.remove(key);
+        self.pins.remove(&key);
+        Some(cache)
+    }
+
+    /// 预读：将给定的块中不在缓存里的块成批加载到缓存，之后获取它们时不必再等待块设备
+    /// 连续的块通过一次 [`BlockDevice::read_blocks`] 读取；预读是尽力而为的，
+    /// 最多加载缓存容量一半的块，腾不出空间时少加载一些，不会返回错误
+    ///
+    /// # Arguments
+    ///
+    /// * `block_ids`: 块ID，按预计访问的顺序排列
+    /// * `block_device`: 块设备
+    ///
+    /// returns: usize 新加载的块数
+    pub fn prefetch(&mut self, block_ids: &[u64], block_device: &Arc<dyn BlockDevice>) -> usize {
+        let device = device_id(block_device);
+        let mut missing: Vec<u64> = Vec::new();
+        for &block_id in block_ids {
+            if !missing.contains(&block_id)
+                && !self.queue.iter().any(|(key, _)| *key == (device, block_id))
+            {
+                missing.push(block_id);
+            }
+        }
+        missing.truncate((self.capacity / 2).max(1));
+        while self.queue.len() + missing.len() > self.capacity && self.evict_one() {
+            nop();
+        }
+        missing.truncate(self.capacity.saturating_sub(self.queue.len()));
+
+        let mut loaded = 0;
+        while loaded < missing.len() {
+            // 找到一段连续的块
+            let start = missing[loaded];
+            let len = missing[loaded..]
+                .iter()
+                .zip(start..)
+                .take_while(|&(&block_id, expected)| block_id == expected)
+                .count();
+            let mut buf = vec![0u8; len * BLOCK_SZ];
+            block_device.read_blocks(start, &mut buf);
+            for (block_id, data) in (start..).zip(buf.chunks(BLOCK_SZ)) {
+                let key = (device, block_id);
+                let cache = BlockCache::from_device_data(block_id, block_device.clone(), data);
+                self.queue.push_back((key, Arc::new(Mutex::new(cache))));
+                self.policy.insert(key);
+            }
+            loaded += len;
+        }
+        loaded
+    }
+
     /// 获取块缓存
     ///
     /// # Arguments
@@ -125,39 +553,50 @@ impl BlockCacheManager {
     /// returns: Arc<Mutex<BlockCache, Spin>> 块缓存
     pub fn get_block_cache(
         &mut self,
-        block_id: usize,
+        block_id: u64,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
+        self.try_get_block_cache(block_id, block_device)
+            .expect("Run out of BlockCache!")
+    }
+
+    /// 获取块缓存，所有块缓存都在使用中时返回错误
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Result<Arc<Mutex<BlockCache, Spin>>, CacheError> 块缓存
+    pub fn try_get_block_cache(
+        &mut self,
+        block_id: u64,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> Result<Arc<Mutex<BlockCache>>, CacheError> {
+        let key = (device_id(&block_device), block_id);
         if let Some(pair) = self.queue.iter().find(|pair| {
-            let current_id = pair.0;
-            let same = current_id == block_id;
+            let current_key = pair.0;
+            let same = current_key == key;
             nop();
             same
         }) {
             let old = pair.1.clone();
+            self.policy.access(key);
             nop();
-            old
+            Ok(old)
         } else {
-            // 替换
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // 从头到尾
-                if let Some((idx, _)) = self.queue.iter().enumerate().find(|(_, pair)| {
-                    let count = Arc::strong_count(&pair.1);
-                    let free = count == 1;
-                    nop();
-                    free
-                }) {
-                    let range = idx..=idx;
-                    self.queue.drain(range);
-                } else {
-                    panic!("Run out of BlockCache!");
+            // 替换，缩小容量之后可能需要替换出不止一个
+            while self.queue.len() >= self.capacity {
+                if !self.evict_one() {
+                    return Err(CacheError::Exhausted);
                 }
             }
             // 将块加载到内存，并推入队列
             let cache = BlockCache::new(block_id, block_device.clone());
             let new = Arc::new(Mutex::new(cache));
-            self.queue.push_back((block_id, new.clone()));
-            new
+            self.queue.push_back((key, new.clone()));
+            self.policy.insert(key);
+            Ok(new)
         }
     }
 }
@@ -166,6 +605,184 @@ lazy_static! {
     /// 全局块缓存管理器
     pub static ref BLOCK_CACHE_MANAGER: Mutex<BlockCacheManager> =
         Mutex::new(BlockCacheManager::new());
+
+    /// 正在进行事务的块设备上写回过的脏块，按块设备标识和块ID索引
+    /// 事务结束前这些块不写回块设备，重新加载时从这里读取
+    /// 它的锁总是最后获取，持有它时不再获取块缓存管理器和块缓存的锁
+    static ref TRANSACTIONS: Mutex<BTreeMap<usize, TransactionBlocks>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// 块设备正在进行事务时从事务中读取一个块
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+/// * `block_id`: 块ID
+/// * `buf`: 缓冲区
+///
+/// returns: bool 事务中是否有这个块
+fn read_transaction_block(
+    block_device: &Arc<dyn BlockDevice>,
+    block_id: u64,
+    buf: &mut [u8; BLOCK_SZ],
+) -> bool {
+    let transactions = TRANSACTIONS.lock();
+    match transactions
+        .get(&device_id(block_device))
+        .and_then(|blocks| blocks.get(&block_id))
+    {
+        Some(data) => {
+            buf.copy_from_slice(data.as_slice());
+            true
+        }
+        None => false,
+    }
+}
+
+/// 块设备正在进行事务时将一个块写入事务
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+/// * `block_id`: 块ID
+/// * `buf`: 块内容
+///
+/// returns: bool 块设备是否正在进行事务
+fn write_transaction_block(
+    block_device: &Arc<dyn BlockDevice>,
+    block_id: u64,
+    buf: &[u8; BLOCK_SZ],
+) -> bool {
+    let mut transactions = TRANSACTIONS.lock();
+    match transactions.get_mut(&device_id(block_device)) {
+        Some(blocks) => {
+            blocks.insert(block_id, Box::new(*buf));
+            true
+        }
+        None => false,
+    }
+}
+
+/// 获取块设备上所有的块缓存
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+///
+/// returns: Vec<Arc<Mutex<BlockCache, Spin>>> 块缓存
+fn device_caches(block_device: &Arc<dyn BlockDevice>) -> Vec<Arc<Mutex<BlockCache>>> {
+    let device = device_id(block_device);
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .filter(|(key, _)| key.0 == device)
+        .map(|(_, cache)| cache.clone())
+        .collect()
+}
+
+/// 在块设备上开始一个事务
+/// 之后写回这个块设备的脏块都只保存在内存中，直到 [`end_transaction`] 结束事务；
+/// 开始之前先写回这个块设备已有的脏块，回滚时重新读取块设备不会丢失事务之前的修改
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+///
+/// returns: bool 是否开始了事务，块设备已经在进行事务时返回 false
+pub(crate) fn begin_transaction(block_device: &Arc<dyn BlockDevice>) -> bool {
+    let device = device_id(block_device);
+    if TRANSACTIONS.lock().contains_key(&device) {
+        return false;
+    }
+    let caches: Vec<_> = BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .filter(|(key, _)| key.0 == device)
+        .map(|(key, cache)| (*key, cache.clone()))
+        .collect();
+    let mut visited = BTreeSet::new();
+    for (key, cache) in &caches {
+        sync_ordered(*key, cache, &mut visited);
+    }
+    let mut transactions = TRANSACTIONS.lock();
+    if transactions.contains_key(&device) {
+        return false;
+    }
+    transactions.insert(device, BTreeMap::new());
+    true
+}
+
+/// 获取块设备上正在进行的事务修改过的所有块
+/// 先将这个块设备的所有块缓存写回事务，返回的块按块ID排列
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+///
+/// returns: Vec<(u64, [u8; BLOCK_SZ])> 块ID及其新内容，没有在进行事务时为空
+pub(crate) fn transaction_blocks(
+    block_device: &Arc<dyn BlockDevice>,
+) -> Vec<(u64, [u8; BLOCK_SZ])> {
+    for cache in device_caches(block_device) {
+        cache.lock().sync();
+    }
+    let transactions = TRANSACTIONS.lock();
+    transactions
+        .get(&device_id(block_device))
+        .map(|blocks| {
+            blocks
+                .iter()
+                .map(|(&block_id, data)| (block_id, **data))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 结束块设备上的事务
+/// 提交时调用者必须已经将 [`transaction_blocks`] 返回的块写入块设备；
+/// 回滚时丢弃事务中的块，并从块设备重新读取这个块设备的所有块缓存
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+/// * `rollback`: 是否回滚
+pub(crate) fn end_transaction(block_device: &Arc<dyn BlockDevice>, rollback: bool) {
+    TRANSACTIONS.lock().remove(&device_id(block_device));
+    if rollback {
+        for cache in device_caches(block_device) {
+            cache.lock().reload();
+        }
+    }
+}
+
+/// 设置全局块缓存最多缓存的块数，块缓存在所有文件系统之间共享
+///
+/// # Arguments
+///
+/// * `capacity`: 最多缓存的块数，至少为 1
+pub fn set_block_cache_capacity(capacity: usize) {
+    BLOCK_CACHE_MANAGER.lock().set_capacity(capacity);
+}
+
+/// 设置全局块缓存的写回策略
+///
+/// # Arguments
+///
+/// * `writeback`: 写回策略
+pub fn set_block_cache_writeback_policy(writeback: WritebackPolicy) {
+    BLOCK_CACHE_MANAGER.lock().set_writeback_policy(writeback);
+}
+
+/// 更换全局块缓存的替换策略
+///
+/// # Arguments
+///
+/// * `policy`: 替换策略
+pub fn set_block_cache_policy(policy: Box<dyn EvictionPolicy>) {
+    BLOCK_CACHE_MANAGER.lock().set_policy(policy);
 }
 
 /// 获取块缓存
@@ -177,7 +794,7 @@ lazy_static! {
 ///
 /// returns: Arc<Mutex<BlockCache, Spin>> 块缓存
 pub fn get_block_cache(
-    block_id: usize,
+    block_id: u64,
     block_device: Arc<dyn BlockDevice>,
 ) -> Arc<Mutex<BlockCache>> {
     BLOCK_CACHE_MANAGER
@@ -185,10 +802,431 @@ pub fn get_block_cache(
         .get_block_cache(block_id, block_device)
 }
 
-/// 将所有块缓存同步到块设备
-pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+/// 获取块缓存，所有块缓存都在使用中时返回错误
+///
+/// # Arguments
+///
+/// * `block_id`: 块ID
+/// * `block_device`: 块设备
+///
+/// returns: Result<Arc<Mutex<BlockCache, Spin>>, CacheError> 块缓存
+pub fn try_get_block_cache(
+    block_id: u64,
+    block_device: Arc<dyn BlockDevice>,
+) -> Result<Arc<Mutex<BlockCache>>, CacheError> {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .try_get_block_cache(block_id, block_device)
+}
+
+/// 获取全局块缓存中的块缓存并将它固定在缓存中，见 [`BlockCacheManager::pin`]
+///
+/// # Arguments
+///
+/// * `block_id`: 块ID
+/// * `block_device`: 块设备
+///
+/// returns: Result<Arc<Mutex<BlockCache, Spin>>, CacheError> 块缓存
+pub fn pin_block_cache(
+    block_id: u64,
+    block_device: Arc<dyn BlockDevice>,
+) -> Result<Arc<Mutex<BlockCache>>, CacheError> {
+    BLOCK_CACHE_MANAGER.lock().pin(block_id, block_device)
+}
+
+/// 撤销一次 [`pin_block_cache`]
+///
+/// # Arguments
+///
+/// * `block_id`: 块ID
+/// * `block_device`: 块设备
+///
+/// returns: bool 该块是否被固定过
+pub fn unpin_block_cache(block_id: u64, block_device: &Arc<dyn BlockDevice>) -> bool {
+    BLOCK_CACHE_MANAGER.lock().unpin(block_id, block_device)
+}
+
+/// 使一个块的缓存失效：丢弃其中的修改并将它从缓存中移除，之后再获取这个块时从块设备重新读取
+/// 块被释放之后调用，避免其中过时的内容在块被重新分配之后写回，覆盖新的内容
+///
+/// # Arguments
+///
+/// * `block_id`: 块ID
+/// * `block_device`: 块设备
+///
+/// returns: bool 该块是否在缓存中
+pub fn invalidate_block_cache(block_id: u64, block_device: &Arc<dyn BlockDevice>) -> bool {
+    // 先从缓存中移除，释放管理器锁之后再丢弃修改
+    let cache = BLOCK_CACHE_MANAGER.lock().remove(block_id, block_device);
+    match cache {
+        Some(cache) => {
+            cache.lock().discard();
+            true
+        }
+        None => false,
+    }
+}
+
+/// 将块设备上所有的块缓存写回并从缓存中移除，块设备不再使用时调用，之后缓存不再持有这个块设备
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+///
+/// returns: usize 被移除的块缓存数
+pub fn block_cache_release(block_device: &Arc<dyn BlockDevice>) -> usize {
+    // 先从缓存中移除，释放管理器锁之后再写回
+    let caches: Vec<_> = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        let device = device_id(block_device);
+        let block_ids: Vec<u64> = manager
+            .queue
+            .iter()
+            .filter(|(key, _)| key.0 == device)
+            .map(|(key, _)| key.1)
+            .collect();
+        block_ids
+            .into_iter()
+            .filter_map(|block_id| manager.remove(block_id, block_device))
+            .collect()
+    };
+    for cache in &caches {
         cache.lock().sync();
     }
+    caches.len()
+}
+
+/// 将块设备上给定的块预读到全局块缓存，见 [`BlockCacheManager::prefetch`]
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+/// * `block_ids`: 块ID，按预计访问的顺序排列
+///
+/// returns: usize 新加载的块数
+pub fn block_cache_prefetch(block_device: &Arc<dyn BlockDevice>, block_ids: &[u64]) -> usize {
+    BLOCK_CACHE_MANAGER.lock().prefetch(block_ids, block_device)
+}
+
+/// 在块缓存中查找一个块
+///
+/// # Arguments
+///
+/// * `key`: 块缓存的键
+///
+/// returns: Option<Arc<Mutex<BlockCache, Spin>>> 块缓存，不在缓存中时为 None
+fn find_cache(key: CacheKey) -> Option<Arc<Mutex<BlockCache>>> {
+    BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .find(|(current_key, _)| *current_key == key)
+        .map(|(_, cache)| cache.clone())
+}
+
+/// 按依赖顺序写回一个块缓存依赖的块，不包括它自己
+/// 每次只持有一个锁，不在持有管理器锁时等待块缓存锁
+///
+/// # Arguments
+///
+/// * `key`: 块缓存的键
+/// * `cache`: 块缓存
+/// * `visited`: 已经访问过的块缓存，用于打破依赖之间的环
+fn sync_dependencies(
+    key: CacheKey,
+    cache: &Arc<Mutex<BlockCache>>,
+    visited: &mut BTreeSet<CacheKey>,
+) {
+    loop {
+        let pending: Vec<CacheKey> = cache
+            .lock()
+            .dependencies
+            .iter()
+            .map(|&block_id| (key.0, block_id))
+            .filter(|dependency| !visited.contains(dependency))
+            .collect();
+        if pending.is_empty() {
+            break;
+        }
+        for dependency in pending {
+            // 不在缓存中的块已经写回了
+            match find_cache(dependency) {
+                Some(dependency_cache) => sync_ordered(dependency, &dependency_cache, visited),
+                None => {
+                    visited.insert(dependency);
+                }
+            }
+        }
+    }
+}
+
+/// 先按依赖顺序写回一个块缓存依赖的块，再写回它自己
+///
+/// # Arguments
+///
+/// * `key`: 块缓存的键
+/// * `cache`: 块缓存
+/// * `visited`: 已经访问过的块缓存，用于打破依赖之间的环
+fn sync_ordered(key: CacheKey, cache: &Arc<Mutex<BlockCache>>, visited: &mut BTreeSet<CacheKey>) {
+    if !visited.insert(key) {
+        return;
+    }
+    sync_dependencies(key, cache, visited);
+    cache.lock().sync();
+}
+
+/// 块设备是否正在进行事务
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+///
+/// returns: bool 是否正在进行事务
+fn in_transaction(block_device: &Arc<dyn BlockDevice>) -> bool {
+    TRANSACTIONS.lock().contains_key(&device_id(block_device))
+}
+
+/// 从给定的块开始，将一段连续的脏块通过一次 [`BlockDevice::write_blocks`] 写回
+/// 只等待第一个块的锁，之后的块只尝试获取锁，遇到被锁定、已经干净或者依赖还没写回的块时结束这一段；
+/// 第一个块自己的依赖还没写回时退回到按依赖顺序逐块写回
+///
+/// # Arguments
+///
+/// * `keys`: 依赖已经写回的脏块，按块设备标识和块ID排列，从第一个开始写回
+/// * `pending`: 还没有写回的脏块
+///
+/// returns: usize 处理过的块数，至少为 1
+fn write_run(keys: &[CacheKey], pending: &BTreeMap<CacheKey, Arc<Mutex<BlockCache>>>) -> usize {
+    let (device, first_id) = keys[0];
+    let blocked = |cache: &BlockCache| {
+        cache
+            .dependencies
+            .iter()
+            .any(|&block_id| pending.contains_key(&(device, block_id)))
+    };
+    let first = pending[&keys[0]].lock();
+    if !first.modified {
+        return 1;
+    }
+    if blocked(&first) {
+        drop(first);
+        sync_ordered(keys[0], &pending[&keys[0]], &mut BTreeSet::new());
+        return 1;
+    }
+    let mut guards = vec![first];
+    for (key, block_id) in keys[1..].iter().zip(first_id + 1..) {
+        if *key != (device, block_id) {
+            break;
+        }
+        match pending[key].try_lock() {
+            Some(cache) if cache.modified && !blocked(&cache) => guards.push(cache),
+            _ => break,
+        }
+    }
+
+    // 事务中的块只写入内存，不必合并
+    let block_device = guards[0].block_device.clone();
+    if in_transaction(&block_device) {
+        for cache in guards.iter_mut() {
+            cache.sync();
+        }
+        return guards.len();
+    }
+    let mut buf = Vec::with_capacity(guards.len() * BLOCK_SZ);
+    for cache in guards.iter() {
+        buf.extend_from_slice(&cache.cache.0);
+    }
+    block_device.write_blocks(first_id, &buf);
+    for cache in guards.iter_mut() {
+        cache.discard();
+    }
+    guards.len()
+}
+
+/// 将所有块缓存同步到块设备，每个块都在它依赖的块之后写回
+/// 先复制出所有块缓存再逐个加锁，不在持有管理器锁时等待块缓存锁，
+/// 因为持有块缓存锁的线程可能正在等待管理器锁
+///
+/// 脏块分轮写回，每一轮写回依赖的块都已经写回的脏块，按块ID排列，
+/// 连续的块合并成一次 [`BlockDevice::write_blocks`]；依赖之间有环时剩下的块按依赖顺序逐块写回
+///
+/// 被固定的脏块不写回，直接或间接依赖它们的脏块也留到下一次写回
+pub fn block_cache_sync_all() {
+    let (caches, pinned): (Vec<_>, BTreeSet<CacheKey>) = {
+        let mut manager = BLOCK_CACHE_MANAGER.lock();
+        manager.last_flush = Instant::now();
+        (
+            manager
+                .queue
+                .iter()
+                .map(|(key, cache)| (*key, cache.clone()))
+                .collect(),
+            manager.pins.keys().copied().collect(),
+        )
+    };
+    let mut pending: BTreeMap<CacheKey, Arc<Mutex<BlockCache>>> = BTreeMap::new();
+    let mut dependencies: BTreeMap<CacheKey, Vec<CacheKey>> = BTreeMap::new();
+    for (key, cache) in caches {
+        let guard = cache.lock();
+        if guard.modified {
+            let keys = guard
+                .dependencies
+                .iter()
+                .map(|&block_id| (key.0, block_id))
+                .collect();
+            drop(guard);
+            dependencies.insert(key, keys);
+            pending.insert(key, cache);
+        }
+    }
+
+    // 跳过被固定的脏块，以及依赖被跳过的块的脏块
+    let mut held: BTreeSet<CacheKey> = pending
+        .keys()
+        .filter(|key| pinned.contains(key))
+        .copied()
+        .collect();
+    loop {
+        let blocked: Vec<CacheKey> = pending
+            .keys()
+            .filter(|key| !held.contains(key))
+            .filter(|key| {
+                dependencies[key]
+                    .iter()
+                    .any(|dependency| held.contains(dependency))
+            })
+            .copied()
+            .collect();
+        if blocked.is_empty() {
+            break;
+        }
+        held.extend(blocked);
+    }
+    pending.retain(|key, _| !held.contains(key));
+
+    while !pending.is_empty() {
+        // 依赖的块都已经写回的脏块，按块设备标识和块ID排列
+        let ready: Vec<CacheKey> = pending
+            .keys()
+            .filter(|key| {
+                dependencies[key]
+                    .iter()
+                    .all(|dependency| !pending.contains_key(dependency))
+            })
+            .copied()
+            .collect();
+        if ready.is_empty() {
+            // 剩下的块的依赖之间有环
+            let mut visited = BTreeSet::new();
+            for (key, cache) in pending.iter() {
+                sync_ordered(*key, cache, &mut visited);
+            }
+            break;
+        }
+        let mut idx = 0;
+        while idx < ready.len() {
+            let written = write_run(&ready[idx..], &pending);
+            for key in &ready[idx..idx + written] {
+                pending.remove(key);
+            }
+            idx += written;
+        }
+    }
+}
+
+/// 写回变脏超过给定时长的块缓存，之后脏块数仍超过缓存容量的给定百分比时，
+/// 再从最早变脏的块缓存开始写回，直到不超过为止；每个块都在它依赖的块之后写回
+/// 被固定的块缓存不会被选中写回，但仍可能作为其它块依赖的块被写回
+///
+/// # Arguments
+///
+/// * `max_age`: 脏块在缓存中最多停留的时长
+/// * `dirty_percent`: 脏块最多占缓存容量的百分比
+///
+/// returns: usize 被选中写回的块缓存数，不包括作为依赖写回的块
+pub fn block_cache_writeback(max_age: Duration, dirty_percent: usize) -> usize {
+    // 只记下脏块的键，不持有块缓存，以免写回期间其它线程无法替换；正被锁定的块缓存这次跳过
+    let (mut dirty, capacity): (Vec<(Instant, CacheKey)>, usize) = {
+        let manager = BLOCK_CACHE_MANAGER.lock();
+        let dirty = manager
+            .queue
+            .iter()
+            .filter(|(key, _)| !manager.pins.contains_key(key))
+            .filter_map(|(key, cache)| {
+                let since = cache.try_lock()?.dirty_since;
+                since.map(|since| (since, *key))
+            })
+            .collect();
+        (dirty, manager.capacity)
+    };
+    dirty.sort();
+
+    let limit = capacity * dirty_percent / 100;
+    let now = Instant::now();
+    let total = dirty.len();
+    let mut written = 0;
+    let mut visited = BTreeSet::new();
+    for (since, key) in dirty {
+        // 按变脏的时间排列，之后的块都既不够旧，数量也没有超过阈值
+        if now.duration_since(since) < max_age && total - written <= limit {
+            break;
+        }
+        // 已经被替换出去的块在替换时写回了
+        if let Some(cache) = find_cache(key) {
+            sync_ordered(key, &cache, &mut visited);
+        }
+        written += 1;
+    }
+    written
+}
+
+/// 文件系统完成一次修改时调用，按写回策略决定是否写回所有块缓存
+/// 写回策略为 [`WritebackPolicy::WriteThrough`] 时等同于 [`block_cache_sync_all`]
+pub fn block_cache_commit() {
+    let due = BLOCK_CACHE_MANAGER.lock().commit_due();
+    if due {
+        block_cache_sync_all();
+    }
+}
+
+/// 按依赖顺序写回块设备上给定块依赖的块，给定的块自己不写回，它们之间的依赖也被忽略
+/// 用于在一组块通过日志原子地提交之前先写回它们依赖的块
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+/// * `block_ids`: 块ID
+pub(crate) fn block_cache_sync_dependencies(
+    block_device: &Arc<dyn BlockDevice>,
+    block_ids: &[u64],
+) {
+    let device = device_id(block_device);
+    let mut visited: BTreeSet<CacheKey> = block_ids
+        .iter()
+        .map(|&block_id| (device, block_id))
+        .collect();
+    for &block_id in block_ids {
+        let key = (device, block_id);
+        if let Some(cache) = find_cache(key) {
+            sync_dependencies(key, &cache, &mut visited);
+        }
+    }
+}
+
+/// 按顺序将块设备上给定块的缓存同步到块设备，不在缓存中的块会被跳过
+/// 每个块都在它依赖的块之后写回
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+/// * `block_ids`: 块ID
+pub fn block_cache_sync(block_device: &Arc<dyn BlockDevice>, block_ids: &[u64]) {
+    let device = device_id(block_device);
+    let mut visited = BTreeSet::new();
+    for &block_id in block_ids {
+        let key = (device, block_id);
+        if let Some(cache) = find_cache(key) {
+            sync_ordered(key, &cache, &mut visited);
+        }
+    }
 }
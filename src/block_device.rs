@@ -1,8 +1,10 @@
 use std::any::Any;
 use std::fmt::Debug;
 
+use crate::BLOCK_SZ;
+
 /// 块设备的特征
-/// 以块为单位读写数据
+/// 以块为单位读写数据，块ID为 64 位，文件系统最多使用其中的低 48 位
 pub trait BlockDevice: Debug + Send + Sync + Any {
     /// 将数据从块读取到缓冲区
     ///
@@ -10,7 +12,20 @@ pub trait BlockDevice: Debug + Send + Sync + Any {
     ///
     /// * `block_id`: 块ID
     /// * `buf`: 缓冲区
-    fn read_block(&self, block_id: usize, buf: &mut [u8]);
+    fn read_block(&self, block_id: u64, buf: &mut [u8]);
+
+    /// 将数据从一组连续的块读取到缓冲区，预读时用来成批加载块
+    /// 默认实现逐块调用 [`read_block`](Self::read_block)，支持批量读取的设备可以覆盖它
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 第一个块的块ID
+    /// * `buf`: 缓冲区，长度是块大小的整数倍
+    fn read_blocks(&self, block_id: u64, buf: &mut [u8]) {
+        for (i, chunk) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.read_block(block_id + i as u64, chunk);
+        }
+    }
 
     /// 将数据从缓冲区写入到块
     ///
@@ -18,5 +33,28 @@ pub trait BlockDevice: Debug + Send + Sync + Any {
     ///
     /// * `block_id`: 块ID
     /// * `buf`: 缓冲区
-    fn write_block(&self, block_id: usize, buf: &[u8]);
+    fn write_block(&self, block_id: u64, buf: &[u8]);
+
+    /// 将数据从缓冲区写入到一组连续的块，写回所有块缓存时用来合并写入
+    /// 默认实现逐块调用 [`write_block`](Self::write_block)，支持批量写入的设备可以覆盖它
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 第一个块的块ID
+    /// * `buf`: 缓冲区，长度是块大小的整数倍
+    fn write_blocks(&self, block_id: u64, buf: &[u8]) {
+        for (i, chunk) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.write_block(block_id + i as u64, chunk);
+        }
+    }
+
+    /// 将之前写入的数据持久化到存储介质，作为写屏障使用
+    /// 默认实现不做任何事
+    fn flush(&self) {}
+
+    /// 获取设备的总块数，打开文件系统时用来检查超级块是否超出设备范围
+    /// 默认实现返回 None，表示容量未知，不做检查
+    fn num_blocks(&self) -> Option<u64> {
+        None
+    }
 }
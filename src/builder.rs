@@ -0,0 +1,342 @@
+use std::mem::size_of;
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use crate::block_device::BlockDevice;
+use crate::efs::{EasyFileSystem, JOURNAL_BLOCKS};
+use crate::error::{FsError, FsResult, SuperBlockError};
+use crate::journal::Journal;
+use crate::layout::{
+    DiskInode, Geometry, IncompatFeatures, RoCompatFeatures, SuperBlock, DEFAULT_RESERVED_PERCENT,
+    LABEL_LENGTH_LIMIT, MAX_RESERVED_PERCENT,
+};
+use crate::BLOCK_SZ;
+
+/// 默认每个索引节点对应的字节数
+pub const DEFAULT_BYTES_PER_INODE: u64 = 8192;
+
+/// 实现总是使用的只读兼容特性，格式化时不能关闭
+const REQUIRED_RO_COMPAT: RoCompatFeatures = RoCompatFeatures::from_bits_truncate(
+    RoCompatFeatures::SPARSE_FILES.bits() | RoCompatFeatures::FREE_COUNTERS.bits(),
+);
+
+/// 实现总是使用的不兼容特性，格式化时不能关闭
+const REQUIRED_INCOMPAT: IncompatFeatures = IncompatFeatures::from_bits_truncate(
+    IncompatFeatures::EXTENTS.bits()
+        | IncompatFeatures::INLINE_DATA.bits()
+        | IncompatFeatures::BLOCK_GROUPS.bits(),
+);
+
+/// 由块组布局决定的不兼容特性，格式化时按需要开启
+const GEOMETRY_INCOMPAT: IncompatFeatures = IncompatFeatures::from_bits_truncate(
+    IncompatFeatures::LARGE_BLOCKS.bits() | IncompatFeatures::ADDRESS_48BIT.bits(),
+);
+
+/// 外部日志
+struct ExternalJournal {
+    /// 日志设备
+    block_device: Arc<dyn BlockDevice>,
+
+    /// 日志区域块数
+    journal_blocks: u32,
+}
+
+/// 文件系统的创建参数
+/// 格式化之前先计算块组布局并检查所有参数，参数无效时不会写入块设备
+///
+/// 可以关闭的特性只有 [`RoCompatFeatures::SUPERBLOCK_CHECKSUM`]、
+/// [`RoCompatFeatures::METADATA_CHECKSUM`]、[`RoCompatFeatures::USER_QUOTA`]、
+/// [`RoCompatFeatures::GROUP_DESCRIPTORS`]、
+/// [`IncompatFeatures::TAIL_PACKING`] 和 [`IncompatFeatures::DIR_FREE_SLOTS`]，
+/// [`IncompatFeatures::LARGE_BLOCKS`] 和 [`IncompatFeatures::ADDRESS_48BIT`] 由块大小和总块数决定
+pub struct FilesystemBuilder {
+    /// 总块数
+    total_blocks: u64,
+
+    /// 每个索引节点对应的字节数
+    bytes_per_inode: u64,
+
+    /// 索引节点数，为 None 时按每个索引节点对应的字节数计算
+    inodes: Option<u64>,
+
+    /// 块大小
+    block_size: usize,
+
+    /// 保留给特权写入者的数据块百分比
+    reserved_percent: u32,
+
+    /// 卷标
+    label: String,
+
+    /// UUID，为 None 时随机生成
+    uuid: Option<[u8; 16]>,
+
+    /// 只读兼容特性
+    ro_compat: RoCompatFeatures,
+
+    /// 不兼容特性
+    incompat: IncompatFeatures,
+
+    /// 外部日志，为 None 时在设备末尾创建内部日志
+    journal: Option<ExternalJournal>,
+}
+
+impl FilesystemBuilder {
+    /// 使用默认参数创建指定总块数的文件系统的创建参数
+    ///
+    /// # Arguments
+    ///
+    /// * `total_blocks`: 总块数
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn new(total_blocks: u64) -> Self {
+        Self {
+            total_blocks,
+            bytes_per_inode: DEFAULT_BYTES_PER_INODE,
+            inodes: None,
+            block_size: BLOCK_SZ,
+            reserved_percent: DEFAULT_RESERVED_PERCENT,
+            label: String::new(),
+            uuid: None,
+            ro_compat: RoCompatFeatures::all(),
+            incompat: IncompatFeatures::all() - GEOMETRY_INCOMPAT,
+            journal: None,
+        }
+    }
+
+    /// 设置每个索引节点对应的字节数，索引节点数为总字节数除以该值
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes_per_inode`: 每个索引节点对应的字节数，不能小于块大小
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn bytes_per_inode(mut self, bytes_per_inode: u64) -> Self {
+        self.bytes_per_inode = bytes_per_inode;
+        self.inodes = None;
+        self
+    }
+
+    /// 直接设置索引节点数，代替按每个索引节点对应的字节数计算
+    ///
+    /// # Arguments
+    ///
+    /// * `inodes`: 索引节点数，平均分配到各个块组中，每个块组向上取整
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn inodes(mut self, inodes: u64) -> Self {
+        self.inodes = Some(inodes);
+        self
+    }
+
+    /// 设置块大小，只能是编译时选择的 [`BLOCK_SZ`]
+    ///
+    /// # Arguments
+    ///
+    /// * `block_size`: 块大小
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// 设置保留给特权写入者的数据块百分比
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved_percent`: 百分比，不能超过 [`MAX_RESERVED_PERCENT`]
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn reserved_percent(mut self, reserved_percent: u32) -> Self {
+        self.reserved_percent = reserved_percent;
+        self
+    }
+
+    /// 设置卷标
+    ///
+    /// # Arguments
+    ///
+    /// * `label`: 卷标，不能超过 [`LABEL_LENGTH_LIMIT`] 字节，也不能包含零字节
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+
+    /// 设置 UUID，不设置时随机生成
+    ///
+    /// # Arguments
+    ///
+    /// * `uuid`: UUID
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// 设置开启的只读兼容特性
+    ///
+    /// # Arguments
+    ///
+    /// * `ro_compat`: 只读兼容特性，必须包含实现总是使用的特性
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn ro_compat_features(mut self, ro_compat: RoCompatFeatures) -> Self {
+        self.ro_compat = ro_compat;
+        self
+    }
+
+    /// 设置开启的不兼容特性
+    ///
+    /// # Arguments
+    ///
+    /// * `incompat`: 不兼容特性，必须包含实现总是使用的特性，由块组布局决定的特性会被忽略
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn incompat_features(mut self, incompat: IncompatFeatures) -> Self {
+        self.incompat = incompat - GEOMETRY_INCOMPAT;
+        self
+    }
+
+    /// 将日志放在另一个块设备上
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 日志设备
+    /// * `journal_blocks`: 日志区域块数，至少为 2
+    ///
+    /// returns: FilesystemBuilder 创建参数
+    pub fn journal_device(
+        mut self,
+        block_device: Arc<dyn BlockDevice>,
+        journal_blocks: u32,
+    ) -> Self {
+        self.journal = Some(ExternalJournal {
+            block_device,
+            journal_blocks,
+        });
+        self
+    }
+
+    /// 索引节点数，没有直接设置时为总字节数除以每个索引节点对应的字节数
+    fn inode_count(&self) -> u64 {
+        self.inodes.unwrap_or_else(|| {
+            self.total_blocks.saturating_mul(BLOCK_SZ as u64) / self.bytes_per_inode.max(1)
+        })
+    }
+
+    /// 检查创建参数并计算块组布局
+    ///
+    /// returns: Result<Geometry, FsError> 块组布局，块大小不是 [`BLOCK_SZ`] 时返回
+    /// [`FsError::SuperBlock`]，参数超出范围或者关闭了必需的特性时返回 [`FsError::InvalidArgument`]，
+    /// 放不下块组布局时返回 [`FsError::SuperBlock`]
+    pub fn geometry(&self) -> FsResult<Geometry> {
+        if self.block_size != BLOCK_SZ {
+            return Err(SuperBlockError::BlockSizeMismatch {
+                stored: self.block_size,
+                supported: BLOCK_SZ,
+            }
+            .into());
+        }
+        if self.inodes.is_none() && self.bytes_per_inode < BLOCK_SZ as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+        if self.reserved_percent > MAX_RESERVED_PERCENT
+            || self.label.len() > LABEL_LENGTH_LIMIT
+            || self.label.contains('\0')
+            || !self.ro_compat.contains(REQUIRED_RO_COMPAT)
+            || !self.incompat.contains(REQUIRED_INCOMPAT)
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        if let Some(journal) = &self.journal {
+            if journal.journal_blocks < 2 {
+                return Err(FsError::InvalidArgument);
+            }
+        }
+
+        // 根目录和用户配额文件各占用一个索引节点，每个块组至少有一个索引节点区域块
+        let inodes_per_block = (BLOCK_SZ / size_of::<DiskInode>()) as u64;
+        let inodes = self.inode_count().max(inodes_per_block);
+        if inodes > u32::MAX as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+        let journal_blocks = if self.journal.is_some() {
+            0
+        } else {
+            JOURNAL_BLOCKS
+        };
+        Ok(Geometry::new(self.total_blocks, inodes, journal_blocks)?)
+    }
+
+    /// 检查创建参数和块设备的大小，然后在块设备上格式化文件系统
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Result<Arc<Mutex<EasyFileSystem, Spin>>, FsError> 简易文件系统，
+    /// 参数无效时返回的错误见 [`geometry`](Self::geometry)，
+    /// 块设备或日志设备太小时返回 [`FsError::SuperBlock`]
+    pub fn format(
+        &self,
+        block_device: Arc<dyn BlockDevice>,
+    ) -> FsResult<Arc<Mutex<EasyFileSystem>>> {
+        let geometry = self.geometry()?;
+        if let Some(device) = block_device.num_blocks() {
+            if device < self.total_blocks {
+                return Err(SuperBlockError::DeviceTooSmall {
+                    total: self.total_blocks,
+                    device,
+                }
+                .into());
+            }
+        }
+        let journal = match &self.journal {
+            Some(journal) => {
+                // 日志设备超级块之后是日志区域
+                let total = journal.journal_blocks as u64 + 1;
+                if let Some(device) = journal.block_device.num_blocks() {
+                    if device < total {
+                        return Err(SuperBlockError::DeviceTooSmall { total, device }.into());
+                    }
+                }
+                Some(Journal::create_external(
+                    journal.block_device.clone(),
+                    journal.journal_blocks,
+                ))
+            }
+            None => None,
+        };
+        Ok(EasyFileSystem::format(
+            block_device,
+            geometry,
+            journal,
+            self,
+        ))
+    }
+
+    /// 是否创建用户配额文件
+    pub(crate) fn user_quota(&self) -> bool {
+        self.ro_compat.contains(RoCompatFeatures::USER_QUOTA)
+    }
+
+    /// 将卷标、UUID、保留百分比和特性写入刚初始化的超级块
+    ///
+    /// # Arguments
+    ///
+    /// * `super_block`: 超级块
+    pub(crate) fn apply(&self, super_block: &mut SuperBlock) {
+        super_block.set_label(self.label.as_bytes());
+        if let Some(uuid) = self.uuid {
+            super_block.uuid = uuid;
+        }
+        super_block.reserved_percent = self.reserved_percent;
+        let incompat = super_block.incompat_features() & GEOMETRY_INCOMPAT | self.incompat;
+        super_block.set_features(self.ro_compat, incompat);
+    }
+}
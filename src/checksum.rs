@@ -0,0 +1,51 @@
+/// CRC32（IEEE 802.3）使用的反射多项式
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// 按字节查表计算 CRC32 使用的表，在编译期生成
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// 计算数据的 CRC32
+///
+/// # Arguments
+///
+/// * `data`: 数据
+///
+/// returns: u32 校验和
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// 在已有的 CRC32 之后继续计算，用于分段计算不连续的数据
+/// `crc32_update(crc32(a), b)` 等于 `a` 和 `b` 拼接后的 CRC32
+///
+/// # Arguments
+///
+/// * `crc`: 之前的数据的 CRC32
+/// * `data`: 之后的数据
+///
+/// returns: u32 校验和
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
@@ -0,0 +1,24 @@
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 时钟的特征
+/// 为索引节点的时间戳提供当前时间，没有标准库的内核可以提供自己的实现
+pub trait Clock: Debug + Send + Sync {
+    /// 获取当前时间
+    ///
+    /// returns: u64 自 UNIX 纪元以来的纳秒数
+    fn now(&self) -> u64;
+}
+
+/// 使用系统时间的时钟
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0)
+    }
+}
@@ -1,14 +1,34 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
 use std::mem::size_of;
-use std::sync::Arc;
+use std::ops::Range;
+use std::sync::{Arc, Weak};
 
 use spin::Mutex;
 
 use crate::bitmap::Bitmap;
-use crate::block_cache::{block_cache_sync_all, get_block_cache};
+use crate::block_cache::{
+    begin_transaction, block_cache_commit, block_cache_sync_all, block_cache_sync_dependencies,
+    end_transaction, get_block_cache, invalidate_block_cache, pin_block_cache,
+    set_block_cache_capacity, transaction_blocks, unpin_block_cache,
+};
 use crate::block_device::BlockDevice;
-use crate::layout::{DiskInode, DiskInodeType, SuperBlock};
-use crate::vfs::Inode;
-use crate::{nop, BLOCK_SZ};
+use crate::builder::FilesystemBuilder;
+use crate::clock::{Clock, SystemClock};
+use crate::error::{ErrorContext, FsError, FsResult, ResultExt, SuperBlockError};
+use crate::fsck::{self, AllocationReport};
+use crate::journal::{Journal, JournalRecovery};
+use crate::layout::{
+    CompatFeatures, DirEntry, DirEntryType, DiskInode, DiskInodeType, Geometry, GroupDescriptor,
+    IncompatFeatures, Quota, QuotaTarget, RoCompatFeatures, SuperBlock, SuperBlockState, Tail,
+    TailBlockHeader, UserQuota, BACKUP_SUPER_BLOCK_INTERVAL, DIRENT_SZ, GROUP_DESCS_PER_BLOCK,
+    LABEL_LENGTH_LIMIT, MAX_BLOCK_ID, MAX_RESERVED_PERCENT, NAME_LENGTH_LIMIT, TAIL_FRAGMENT_SZ,
+    TAIL_PACK_LIMIT, USER_QUOTA_SZ,
+};
+use crate::name::FileName;
+use crate::permission::PermissionCheck;
+use crate::vfs::{self, CopyOptions, Inode};
+use crate::BLOCK_SZ;
 
 #[derive(Debug)]
 /// 简易块式文件系统
@@ -16,168 +36,738 @@ pub struct EasyFileSystem {
     /// 真实块设备
     pub block_device: Arc<dyn BlockDevice>,
 
-    /// 索引节点位图
-    pub inode_bitmap: Bitmap,
+    /// 块组布局
+    geometry: Geometry,
 
-    /// 数据位图
-    pub data_bitmap: Bitmap,
+    /// 块组
+    pub groups: Vec<BlockGroup>,
+
+    /// 日志
+    journal: Journal,
+
+    /// 路径解析时允许的最大深度
+    max_path_depth: usize,
+
+    /// 文件名编码
+    name_encoding: NameEncoding,
+
+    /// 新建的文件是否将内容直接存放在索引节点中
+    inline_data: bool,
+
+    /// 写入后是否将文件的尾部打包到共享的尾部块中
+    tail_packing: bool,
+
+    /// 读取时是否不更新访问时间
+    noatime: bool,
+
+    /// 写入模式
+    write_mode: WriteMode,
+
+    /// 写入文件时是否连同数据块一起通过日志提交
+    data_journaling: bool,
+
+    /// 还有空闲片段的尾部块，只记录本次打开之后用到的尾部块
+    tail_blocks: BTreeSet<u64>,
+
+    /// 为时间戳提供当前时间的时钟
+    clock: Arc<dyn Clock>,
+
+    /// 打开、读和写时的权限检查，为 None 时不检查
+    permission_check: Option<Arc<dyn PermissionCheck>>,
+
+    /// 是否以只读方式打开
+    read_only: bool,
+
+    /// 是否允许分配保留的数据块
+    use_reserved: bool,
+
+    /// 是否强制执行用户配额，不强制执行时仍然记录每个用户的使用量
+    user_quota: bool,
 
-    /// 索引节点区域起始块ID
-    inode_area_start_block: u32,
+    /// 自上次同步以来是否修改过文件系统，即超级块中是否设置了脏标志
+    dirty: bool,
 
-    /// 数据区域起始块ID
-    data_area_start_block: u32,
+    /// 打开时超级块中是否仍然设置着脏标志，即上次没有正常卸载
+    unclean: bool,
+
+    /// 索引节点表，保证同一个索引节点ID只对应一个索引节点对象
+    inode_table: BTreeMap<u32, Weak<Inode>>,
+
+    /// 已经删除了目录条目、但还有索引节点对象的索引节点，到曾经指向它的目录条目所在的块，
+    /// 最后一个索引节点对象被释放时才释放索引节点，此前索引节点ID不会被重新分配
+    orphans: BTreeMap<u32, u64>,
+
+    /// 写入空洞时是否延迟到落盘时才分配数据块
+    delayed_allocation: bool,
+
+    /// 各个文件还没有分配数据块的数据
+    delayed: BTreeMap<u32, DelayedData>,
+
+    /// 为延迟分配的数据预留的数据块数，预留的数据块不能再被分配
+    delayed_reserved: u64,
+
+    /// 是否记录元数据块之间的依赖，让块缓存按依赖顺序写回
+    soft_updates: bool,
+
+    /// 打开时日志恢复的结果
+    recovery: JournalRecovery,
+
+    /// 正在进行的事务开始时的内存状态，没有在进行事务时为 None
+    transaction: Option<TransactionState>,
 }
 
-/// 数据块
-type DataBlock = [u8; BLOCK_SZ];
+/// 事务开始时的内存状态，回滚时恢复
+#[derive(Debug)]
+struct TransactionState {
+    /// 是否修改过文件系统
+    dirty: bool,
 
-impl EasyFileSystem {
-    /// 创建指定块大小的简易文件系统
+    /// 还有空闲片段的尾部块
+    tail_blocks: BTreeSet<u64>,
+
+    /// 等待释放的索引节点，事务中删除的目录条目回滚后又指向它们，不能再释放
+    orphans: BTreeMap<u32, u64>,
+}
+
+/// 把多个操作组合成一个原子提交的事务，由 [`EasyFileSystem::transaction`] 传给回调函数
+pub struct Transaction {
+    /// 简易文件系统
+    efs: Arc<Mutex<EasyFileSystem>>,
+
+    /// 是否已经提交或者回滚
+    finished: bool,
+}
+
+impl Transaction {
+    /// 获取进行事务的文件系统
+    pub fn fs(&self) -> &Arc<Mutex<EasyFileSystem>> {
+        &self.efs
+    }
+
+    /// 获取文件系统的根节点
+    pub fn root_inode(&self) -> Arc<Inode> {
+        EasyFileSystem::root_inode(&self.efs)
+    }
+
+    /// 通过索引节点ID获取索引节点
     ///
     /// # Arguments
     ///
-    /// * `block_device`: 块设备
-    /// * `total_blocks`: 总块数
-    /// * `inode_bitmap_blocks`: 索引节点位图块数
+    /// * `inode_id`: 索引节点ID
     ///
-    /// returns: Arc<Mutex<EasyFileSystem, Spin>> 简易文件系统
-    pub fn create(
-        block_device: Arc<dyn BlockDevice>,
-        total_blocks: u32,
-        inode_bitmap_blocks: u32,
-    ) -> Arc<Mutex<Self>> {
-        //region 计算区域的块大小并创建位图
+    /// returns: Arc<Inode> 索引节点
+    pub fn get_inode(&self, inode_id: u32) -> Arc<Inode> {
+        EasyFileSystem::get_inode(&self.efs, inode_id)
+    }
+}
+
+impl Drop for Transaction {
+    /// 回调函数发生恐慌时回滚事务
+    fn drop(&mut self) {
+        if !self.finished {
+            self.efs.lock().rollback_transaction();
+        }
+    }
+}
 
-        // 索引节点位图
-        let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
+/// 一个文件还没有分配数据块的数据
+#[derive(Debug)]
+pub(crate) struct DelayedData {
+    /// 按内部 ID 排列的整块数据，每一块都对应磁盘上的一个空洞
+    pub(crate) blocks: BTreeMap<u32, Box<DataBlock>>,
 
-        // 索引节点比特数
-        let inode_num = inode_bitmap.maximum();
+    /// 为这些数据预留的数据块数，包括可能需要的索引块
+    pub(crate) reserved: usize,
 
-        // 128 字节
-        let size_of_disk_inode = size_of::<DiskInode>();
+    /// 预留时记账的配额
+    pub(crate) quota_target: QuotaTarget,
+}
 
-        // 索引节点区域块数
-        let inode_area_blocks = ((inode_num * size_of_disk_inode + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+#[derive(Debug)]
+/// 块组，包含自己的索引节点位图和数据位图
+pub struct BlockGroup {
+    /// 索引节点位图，比特序号是块组内的索引节点序号
+    pub inode_bitmap: Bitmap,
 
-        // 索引节点总块数
-        let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
+    /// 数据位图，比特序号是块组数据区域内的块序号
+    pub data_bitmap: Bitmap,
+}
 
-        // 数据总块数
-        let data_total_blocks = total_blocks - 1 - inode_total_blocks;
+impl BlockGroup {
+    /// 按块组布局创建块组的位图
+    ///
+    /// # Arguments
+    ///
+    /// * `geometry`: 块组布局
+    ///
+    /// returns: Vec<BlockGroup> 所有块组
+    fn from_geometry(geometry: &Geometry) -> Vec<Self> {
+        (0..geometry.group_count)
+            .map(|group| Self {
+                inode_bitmap: Bitmap::with_bits(
+                    geometry.group_start(group),
+                    geometry.inode_bitmap_blocks as usize,
+                    geometry.inodes_per_group as usize,
+                ),
+                data_bitmap: Bitmap::with_bits(
+                    geometry.data_bitmap_start(group),
+                    geometry.data_bitmap_blocks as usize,
+                    geometry.data_area_blocks(group) as usize,
+                ),
+            })
+            .collect()
+    }
+}
 
-        // 数据位图块数
-        let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
+/// 文件名编码
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameEncoding {
+    /// 文件名必须是合法的 UTF-8
+    #[default]
+    Utf8,
 
-        // 数据区域块数
-        let data_area_blocks = data_total_blocks - data_bitmap_blocks;
+    /// 文件名是任意的字节串，显示时有损地转换为 UTF-8
+    Raw,
+}
 
-        // 数据位图
-        let data_bitmap = Bitmap::new(
-            (1 + inode_total_blocks) as usize,
-            data_bitmap_blocks as usize,
-        );
+/// 写入模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WriteMode {
+    /// 每次修改之后按块缓存的写回策略将所有块缓存写回块设备，
+    /// 默认的 [`WritebackPolicy::WriteThrough`](crate::block_cache::WritebackPolicy::WriteThrough) 立即写回
+    #[default]
+    Sync,
+
+    /// 修改只留在块缓存中，直到被替换出去、被后台写回或者调用 [`EasyFileSystem::sync`]，
+    /// 通过日志提交的元数据仍然立即持久化
+    Writeback,
+}
+
+/// 数据块的分配目标
+/// 分配器从目标位置开始查找空闲的数据块，找不到时再依次尝试后面的块组
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocGoal {
+    /// 没有目标，从空闲的数据块最多的块组开始，没有块组描述符时从第一个块组开始
+    #[default]
+    Any,
+
+    /// 靠近索引节点，从它所在的块组开始
+    Inode(u32),
+
+    /// 紧跟在给定的数据块之后，通常是文件中位于写入位置之前的最后一个数据块，使文件保持连续
+    After(u64),
+}
+
+/// 挂载选项，打开文件系统时传给 [`EasyFileSystem::open_with_options`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MountOptions {
+    /// 是否以只读方式打开，有不认识的只读兼容特性时总是以只读方式打开
+    pub read_only: bool,
+
+    /// 读取时是否不更新访问时间
+    pub noatime: bool,
+
+    /// 写入模式
+    pub write_mode: WriteMode,
+
+    /// 写入文件时是否连同数据块一起通过日志提交
+    pub data_journaling: bool,
+
+    /// 块缓存最多缓存的块数，为 None 时保持当前的容量，块缓存在所有文件系统之间共享
+    pub cache_size: Option<usize>,
+
+    /// 上次没有正常卸载时是否拒绝打开
+    pub fail_on_dirty: bool,
+
+    /// 是否强制执行用户配额
+    pub user_quota: bool,
+
+    /// 新建的文件是否将内容直接存放在索引节点中
+    pub inline_data: bool,
+
+    /// 写入后是否将文件的尾部打包到共享的尾部块中
+    pub tail_packing: bool,
+
+    /// 写入空洞时是否延迟到落盘时才分配数据块
+    pub delayed_allocation: bool,
+
+    /// 是否按依赖顺序写回元数据块，见 [`EasyFileSystem::set_soft_updates`]
+    pub soft_updates: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            noatime: false,
+            write_mode: WriteMode::default(),
+            data_journaling: false,
+            cache_size: None,
+            fail_on_dirty: false,
+            user_quota: false,
+            inline_data: true,
+            tail_packing: false,
+            delayed_allocation: false,
+            soft_updates: false,
+        }
+    }
+}
+
+/// 文件系统的容量和使用情况，由 [`EasyFileSystem::statfs`] 返回
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    /// 块大小，单位为字节
+    pub block_size: usize,
+
+    /// 总块数，包括超级块、各块组的元数据和日志区域
+    pub total_blocks: u64,
+
+    /// 数据块总数
+    pub total_data_blocks: u64,
+
+    /// 空闲的数据块数
+    pub free_data_blocks: u64,
+
+    /// 非特权写入者可以分配的数据块数，不包括保留的数据块
+    pub available_data_blocks: u64,
+
+    /// 已使用的数据块数，包括保留给备份超级块的块
+    pub used_data_blocks: u64,
+
+    /// 索引节点总数
+    pub total_inodes: u64,
+
+    /// 空闲的索引节点数
+    pub free_inodes: u64,
+
+    /// 文件名的最大字节数
+    pub name_max: usize,
+}
+
+/// 数据块
+pub(crate) type DataBlock = [u8; BLOCK_SZ];
+
+/// 日志区域块数
+pub(crate) const JOURNAL_BLOCKS: u32 = 32;
+
+/// 按索引节点分配数据块时，一个块组被分成的起点数
+const INODE_GOAL_SLOTS: u32 = 16;
+
+/// 路径解析时默认允许的最大深度
+pub const DEFAULT_MAX_PATH_DEPTH: usize = 64;
+
+impl EasyFileSystem {
+    /// 按已经检查过的块组布局和创建参数在块设备上格式化简易文件系统
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `geometry`: 块组布局
+    /// * `external_journal`: 外部日志，为 None 时在设备末尾创建内部日志
+    /// * `builder`: 创建参数
+    ///
+    /// returns: Arc<Mutex<EasyFileSystem, Spin>> 简易文件系统
+    pub(crate) fn format(
+        block_device: Arc<dyn BlockDevice>,
+        geometry: Geometry,
+        external_journal: Option<Journal>,
+        builder: &FilesystemBuilder,
+    ) -> Arc<Mutex<Self>> {
+        //region 创建位图
+
+        // 放不下一个块组的剩余块并入内部日志区域
+        let journal_blocks = geometry.journal_blocks;
+        let total_blocks = geometry.total_blocks;
 
         let mut efs = Self {
             block_device: block_device.clone(),
-            inode_bitmap,
-            data_bitmap,
-            inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            geometry,
+            groups: BlockGroup::from_geometry(&geometry),
+            journal: external_journal.unwrap_or_else(|| {
+                Journal::new(
+                    block_device.clone(),
+                    total_blocks - journal_blocks as u64,
+                    journal_blocks as usize,
+                )
+            }),
+            max_path_depth: DEFAULT_MAX_PATH_DEPTH,
+            name_encoding: NameEncoding::default(),
+            inline_data: true,
+            tail_packing: false,
+            noatime: false,
+            write_mode: WriteMode::default(),
+            data_journaling: false,
+            tail_blocks: BTreeSet::new(),
+            clock: Arc::new(SystemClock),
+            permission_check: None,
+            read_only: false,
+            use_reserved: false,
+            user_quota: false,
+            dirty: false,
+            unclean: false,
+            inode_table: BTreeMap::new(),
+            orphans: BTreeMap::new(),
+            delayed_allocation: false,
+            delayed: BTreeMap::new(),
+            delayed_reserved: 0,
+            soft_updates: false,
+            recovery: JournalRecovery::default(),
+            transaction: None,
         };
         //endregion
 
-        //region 清空所有块
-        for i in 0..total_blocks {
-            let cache = get_block_cache(i as usize, block_device.clone());
-            cache.lock().modify(0, |data_block: &mut DataBlock| {
-                for byte in data_block.iter_mut() {
-                    *byte = 0;
-                }
-            });
-        }
+        //region 清空位图和日志头
+        // 索引节点和数据块在分配时才清零，格式化的耗时与设备大小基本无关
+        efs.zero_metadata(0..geometry.group_count);
         //endregion
 
         //region 初始化超级块
+        let journal_uuid = efs.journal.uuid();
+        efs.modify_super_block(|super_block| {
+            super_block.initialize(&geometry);
+            super_block.journal_uuid = journal_uuid;
+            builder.apply(super_block);
+        });
+        //endregion
+
+        //region 在数据位图中保留备份超级块和块组描述符所在的块，然后写入块组描述符
         let cache = get_block_cache(0, block_device.clone());
-        cache.lock().modify(0, |super_block: &mut SuperBlock| {
-            super_block.initialize(
-                total_blocks,
-                inode_bitmap_blocks,
-                inode_area_blocks,
-                data_bitmap_blocks,
-                data_area_blocks,
-            );
-            nop();
+        let reserved_block_ids = cache.lock().read(0, |super_block: &SuperBlock| {
+            let mut block_ids = super_block.backup_block_ids();
+            block_ids.extend(super_block.group_descriptor_block_ids());
+            block_ids
         });
+        for block_id in reserved_block_ids {
+            let (group, bit) = geometry.data_block_position(block_id).unwrap();
+            efs.groups[group as usize]
+                .data_bitmap
+                .reserve(&block_device, bit as usize);
+            efs.modify_primary_super_block(|super_block| super_block.free_data_blocks -= 1);
+        }
+        efs.store_group_descriptors();
         //endregion
 
         //region 为根节点创建索引节点
-        assert_eq!(efs.alloc_inode(), 0);
-        let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
-        let block_cache = get_block_cache(root_inode_block_id as usize, block_device.clone());
-        block_cache
-            .lock()
-            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-                disk_inode.initialize(DiskInodeType::Directory);
+        assert_eq!(efs.alloc_inode(), Ok(0));
+        // 根目录的父目录是它自己
+        efs.initialize_dir(0, 0)
+            .expect("No space for the root directory!");
+        //endregion
+
+        //region 创建用户配额文件，并为根目录记账
+        if builder.user_quota() {
+            let quota_inode_id = efs
+                .alloc_inode()
+                .expect("No space for the user quota file!");
+            let (block_id, block_offset) = efs.get_disk_inode_pos(quota_inode_id);
+            let now = efs.now();
+            let address_48bit = efs.address_48bit();
+            let cache = get_block_cache(block_id, block_device.clone());
+            cache
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.initialize(DiskInodeType::File);
+                    disk_inode.atime = now;
+                    disk_inode.mtime = now;
+                    disk_inode.ctime = now;
+                    disk_inode.btime = now;
+                    if address_48bit {
+                        disk_inode.enable_extents();
+                    }
+                    disk_inode.update_checksum(quota_inode_id);
+                });
+            efs.modify_super_block(|super_block| super_block.user_quota_inode = quota_inode_id);
+            let (block_id, block_offset) = efs.get_disk_inode_pos(0);
+            let cache = get_block_cache(block_id, block_device.clone());
+            let root_blocks = cache.lock().read(block_offset, |disk_inode: &DiskInode| {
+                disk_inode.block_ids(&block_device).len()
             });
+            // 根目录属于 uid 0，用户配额文件本身不计入任何配额
+            efs.charge_user_quota(0, (root_blocks * BLOCK_SZ) as i64, 1);
+            //endregion
+        }
         //endregion
 
-        //region 立即写回
-        block_cache_sync_all();
+        //region 立即写回，格式化完成的文件系统是干净的
+        efs.sync();
         //endregion
 
         Arc::new(Mutex::new(efs))
     }
 
     /// 将一个块设备作为文件系统打开
+    /// 打开之前检查超级块中各区域的大小是否一致、互不重叠且没有超出块设备的范围
     ///
     /// # Arguments
     ///
     /// * `block_device`: 块设备
     ///
-    /// returns: Arc<Mutex<EasyFileSystem, Spin>> 简易文件系统
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
-        // 读取超级块
+    /// returns: Result<Arc<Mutex<EasyFileSystem, Spin>>, FsError> 简易文件系统，
+    /// 主超级块和备份超级块都无效时返回 [`FsError::SuperBlock`]，
+    /// 有不认识的不兼容特性时返回 [`FsError::Unsupported`]，
+    /// 需要外部日志设备时返回 [`FsError::InvalidArgument`]
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> FsResult<Arc<Mutex<Self>>> {
+        Self::load(block_device, None, MountOptions::default())
+    }
+
+    /// 将一个块设备作为使用外部日志设备的文件系统打开
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `journal_device`: 日志设备
+    ///
+    /// returns: Result<Arc<Mutex<EasyFileSystem, Spin>>, FsError> 简易文件系统，错误见 [`EasyFileSystem::open`]，
+    /// 日志设备无效或者不属于该文件系统时返回 [`FsError::Corrupted`] 或 [`FsError::InvalidArgument`]
+    pub fn open_with_journal(
+        block_device: Arc<dyn BlockDevice>,
+        journal_device: Arc<dyn BlockDevice>,
+    ) -> FsResult<Arc<Mutex<Self>>> {
+        Self::load(block_device, Some(journal_device), MountOptions::default())
+    }
+
+    /// 按挂载选项打开文件系统
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `journal_device`: 外部日志设备，使用内部日志时为 None
+    /// * `options`: 挂载选项
+    ///
+    /// returns: Result<Arc<Mutex<EasyFileSystem, Spin>>, FsError> 简易文件系统，错误见 [`EasyFileSystem::open`]，
+    /// 上次没有正常卸载且设置了 [`MountOptions::fail_on_dirty`] 时返回 [`FsError::Unclean`]
+    pub fn open_with_options(
+        block_device: Arc<dyn BlockDevice>,
+        journal_device: Option<Arc<dyn BlockDevice>>,
+        options: MountOptions,
+    ) -> FsResult<Arc<Mutex<Self>>> {
+        Self::load(block_device, journal_device, options)
+    }
+
+    /// 找到一个有效的超级块
+    /// 主超级块无效时尝试第一个备份超级块，两者都无效时报告主超级块的错误
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Result<u64, FsError> 超级块所在的块ID
+    fn locate_super_block(block_device: &Arc<dyn BlockDevice>) -> FsResult<u64> {
+        let device_blocks = block_device.num_blocks();
         let cache = get_block_cache(0, block_device.clone());
+        let primary = cache.lock().read(0, |super_block: &SuperBlock| {
+            Self::validate_super_block(super_block, device_blocks)
+        });
+        let Err(error) = primary else {
+            return Ok(0);
+        };
 
-        let ret = cache.lock().read(0, |super_block: &SuperBlock| {
-            // 检查超级块
-            assert!(super_block.is_valid(), "Error loading EFS!");
+        // 只有镜像足够大时才会有备份超级块，并且备份超级块必须认为自己在这个位置
+        let backup = BACKUP_SUPER_BLOCK_INTERVAL;
+        if device_blocks.is_some_and(|device_blocks| device_blocks <= backup) {
+            return Err(error);
+        }
+        let cache = get_block_cache(backup, block_device.clone());
+        let valid = cache.lock().read(0, |super_block: &SuperBlock| {
+            Self::validate_super_block(super_block, device_blocks).is_ok()
+                && super_block.backup_block_ids().first() == Some(&backup)
+        });
+        if !valid {
+            return Err(error);
+        }
+        Ok(backup)
+    }
 
-            // 索引节点总块数
-            let inode_total_blocks =
-                super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
+    /// 检查超级块，并检查它描述的文件系统是否超出块设备的范围
+    ///
+    /// # Arguments
+    ///
+    /// * `super_block`: 超级块
+    /// * `device_blocks`: 块设备的块数，为 None 时不检查设备范围
+    ///
+    /// returns: Result<(), FsError> 第一个发现的问题
+    fn validate_super_block(super_block: &SuperBlock, device_blocks: Option<u64>) -> FsResult<()> {
+        super_block.validate()?;
+        if let Some(device) = device_blocks {
+            if super_block.total_blocks > device {
+                return Err(SuperBlockError::DeviceTooSmall {
+                    total: super_block.total_blocks,
+                    device,
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
 
-            // 索引节点位图
-            let inode_bitmap = Bitmap::new(1, super_block.inode_bitmap_blocks as usize);
+    /// 从块设备中加载文件系统
+    /// 打开日志之后先进行日志恢复，见 [`recovery`](Self::recovery)；
+    /// 主超级块损坏时使用备份超级块，并在可写时用它修复主超级块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `journal_device`: 外部日志设备
+    /// * `options`: 挂载选项
+    ///
+    /// returns: Result<Arc<Mutex<EasyFileSystem, Spin>>, FsError> 简易文件系统
+    fn load(
+        block_device: Arc<dyn BlockDevice>,
+        journal_device: Option<Arc<dyn BlockDevice>>,
+        options: MountOptions,
+    ) -> FsResult<Arc<Mutex<Self>>> {
+        if let Some(cache_size) = options.cache_size {
+            set_block_cache_capacity(cache_size);
+        }
 
-            // 数据位图
-            let data_bitmap = Bitmap::new(
-                (1 + inode_total_blocks) as usize,
-                super_block.data_bitmap_blocks as usize,
-            );
+        // 读取超级块
+        let super_block_id = Self::locate_super_block(&block_device)?;
+        let cache = get_block_cache(super_block_id, block_device.clone());
 
-            // 索引节点区域起始块ID
-            let inode_area_start_block = 1 + super_block.inode_bitmap_blocks;
+        let (mut journal, groups_end) = cache.lock().read(0, |super_block: &SuperBlock| {
+            // 有不认识的不兼容特性时不能打开
+            if super_block.unknown_incompat() != 0 {
+                return Err(FsError::Unsupported);
+            }
 
-            // 数据区域起始块ID
-            let data_area_start_block = 1 + inode_total_blocks + super_block.data_bitmap_blocks;
+            // 上次没有正常卸载时脏标志仍然设置着，直到下次同步才会清除
+            let unclean = super_block.state().contains(SuperBlockState::DIRTY);
+            if unclean && options.fail_on_dirty {
+                return Err(FsError::Unclean);
+            }
+            if super_block.has_external_journal() != journal_device.is_some() {
+                return Err(FsError::InvalidArgument);
+            }
+
+            // 日志
+            let groups_end = super_block.total_blocks - super_block.journal_blocks as u64;
+            let journal = match journal_device {
+                Some(journal_device) => {
+                    Journal::open_external(journal_device, super_block.journal_uuid)?
+                }
+                None => Journal::new(
+                    block_device.clone(),
+                    groups_end,
+                    super_block.journal_blocks as usize,
+                ),
+            };
+            Ok((journal, groups_end))
+        })?;
+
+        // 重放上次崩溃时已经提交但没有写回原位置的事务，超级块可能因此改变，需要重新读取
+        let recovery = journal.recover(&block_device, groups_end);
+        let super_block_id = if recovery.replayed() {
+            Self::locate_super_block(&block_device)?
+        } else {
+            super_block_id
+        };
+        let cache = get_block_cache(super_block_id, block_device.clone());
+
+        let ret = cache.lock().read(0, |super_block: &SuperBlock| {
+            // 有不认识的只读兼容特性时以只读方式打开
+            let read_only = options.read_only || super_block.unknown_ro_compat() != 0;
+            let unclean = super_block.state().contains(SuperBlockState::DIRTY);
+
+            // 块组布局，旧镜像被视为只有一个块组
+            let geometry = super_block.geometry();
 
             // 简易文件系统
             let efs = Self {
                 block_device,
-                inode_bitmap,
-                data_bitmap,
-                inode_area_start_block,
-                data_area_start_block,
+                geometry,
+                groups: BlockGroup::from_geometry(&geometry),
+                journal,
+                max_path_depth: DEFAULT_MAX_PATH_DEPTH,
+                name_encoding: NameEncoding::default(),
+                inline_data: options.inline_data,
+                tail_packing: options.tail_packing,
+                noatime: options.noatime,
+                write_mode: options.write_mode,
+                data_journaling: options.data_journaling,
+                tail_blocks: BTreeSet::new(),
+                clock: Arc::new(SystemClock),
+                permission_check: None,
+                read_only,
+                use_reserved: false,
+                user_quota: options.user_quota,
+                dirty: unclean,
+                unclean,
+                inode_table: BTreeMap::new(),
+                orphans: BTreeMap::new(),
+                delayed_allocation: options.delayed_allocation,
+                delayed: BTreeMap::new(),
+                delayed_reserved: 0,
+                soft_updates: options.soft_updates,
+                recovery,
+                transaction: None,
             };
 
             Arc::new(Mutex::new(efs))
         });
 
-        ret
+        // 用备份超级块修复主超级块
+        if super_block_id != 0 {
+            let mut efs = ret.lock();
+            if !efs.read_only {
+                let cache = get_block_cache(super_block_id, efs.block_device.clone());
+                let backup = cache.lock().read(0, |data_block: &DataBlock| *data_block);
+                let cache = get_block_cache(0, efs.block_device.clone());
+                cache
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| *data_block = backup);
+                efs.write_through_journal(&[0]);
+            }
+        }
+
+        // 扫描位图，建立各个位图在内存中的空闲计数；
+        // 有块组描述符时分配器按描述符挑选块组，各个位图在第一次用到时才扫描
+        {
+            let efs = ret.lock();
+            if !efs.group_descriptors() {
+                for group in &efs.groups {
+                    group.inode_bitmap.load_counts(&efs.block_device);
+                    group.data_bitmap.load_counts(&efs.block_device);
+                }
+            }
+        }
+
+        // 备份超级块中的空闲计数可能已经过时，旧镜像中则没有空闲计数，
+        // 上次没有正常卸载时空闲计数也可能没有和位图一起写回，都需要扫描位图重新统计，
+        // 块组描述符中的空闲计数也一样
+        {
+            let mut efs = ret.lock();
+            let cache = get_block_cache(0, efs.block_device.clone());
+            let counted = cache.lock().read(0, |super_block: &SuperBlock| {
+                super_block
+                    .ro_compat_features()
+                    .contains(RoCompatFeatures::FREE_COUNTERS)
+            });
+            if !efs.read_only && (super_block_id != 0 || !counted || efs.unclean) {
+                let (free_data_blocks, free_inodes) = efs.count_free();
+                let mut block_ids = efs.store_group_descriptors();
+                block_ids.extend(efs.modify_super_block(|super_block| {
+                    super_block.free_data_blocks = free_data_blocks;
+                    super_block.free_inodes = free_inodes;
+                    super_block.enable_ro_compat_features(RoCompatFeatures::FREE_COUNTERS);
+                }));
+                efs.write_through_journal(&block_ids);
+            }
+        }
+
+        // 记录挂载次数和挂载时间
+        {
+            let mut efs = ret.lock();
+            if !efs.read_only {
+                let now = efs.now();
+                efs.modify_primary_super_block(|super_block| {
+                    super_block.mount_count = super_block.mount_count.wrapping_add(1);
+                    super_block.last_mount_time = now;
+                });
+                efs.write_through_journal(&[0]);
+            }
+        }
+
+        Ok(ret)
     }
 
     /// 获取文件系统的根节点
@@ -186,65 +776,2544 @@ impl EasyFileSystem {
     ///
     /// * `efs`: 简易文件系统
     ///
-    /// returns: Inode 索引节点
-    pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
-        let block_device = efs.lock().block_device.clone();
-        // 暂时获得简易文件系统锁
-        let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
-        // 释放简易文件系统锁
-        Inode::new(block_id, block_offset, efs.clone(), block_device)
+    /// returns: Arc<Inode> 索引节点
+    pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Arc<Inode> {
+        Self::get_inode(efs, 0)
     }
 
-    /// 按ID获取索引节点
+    /// 从索引节点表中按ID获取索引节点
+    /// 同一个索引节点ID总是得到同一个索引节点对象，它们共享同一把索引节点锁
     ///
     /// # Arguments
     ///
+    /// * `efs`: 简易文件系统
     /// * `inode_id`: 索引节点ID
     ///
-    /// returns: (u32, usize) 索引节点所在块ID和偏移
-    pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
-        // 磁盘索引节点字节数
-        // 128 字节
-        let inode_size = size_of::<DiskInode>();
-
-        // 每个块中的索引节点数
-        // 4
-        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+    /// returns: Arc<Inode> 索引节点
+    pub fn get_inode(efs: &Arc<Mutex<Self>>, inode_id: u32) -> Arc<Inode> {
+        let mut fs = efs.lock();
+        if let Some(inode) = fs.inode_table.get(&inode_id).and_then(Weak::upgrade) {
+            return inode;
+        }
+        let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+        let inode = Arc::new(Inode::new(
+            inode_id,
+            block_id,
+            block_offset,
+            efs.clone(),
+            fs.block_device.clone(),
+        ));
 
-        // 索引节点所在块ID
-        let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
+        // 顺便清理已经没有引用的索引节点
+        fs.inode_table.retain(|_, inode| inode.strong_count() > 0);
+        fs.inode_table.insert(inode_id, Arc::downgrade(&inode));
+        inode
+    }
 
-        // 索引节点在块中的偏移
-        let offset = (inode_id % inodes_per_block) as usize * inode_size;
+    /// 为所有还有延迟分配的数据的文件分配数据块，并将数据写入块缓存
+    ///
+    /// # Arguments
+    ///
+    /// * `efs`: 简易文件系统
+    ///
+    /// returns: Result<(), FsError> 可以分配的数据块不够时返回 [`FsError::NoSpace`]，
+    /// 此时没能落盘的数据仍然留在内存中
+    pub fn flush_delayed(efs: &Arc<Mutex<Self>>) -> FsResult<()> {
+        let inode_ids: Vec<u32> = efs.lock().delayed.keys().copied().collect();
+        for inode_id in inode_ids {
+            Self::get_inode(efs, inode_id).flush_delayed()?;
+        }
+        Ok(())
+    }
 
-        (block_id, offset)
+    /// 在子卷目录中创建一个子卷，子卷是一棵独立的目录树，可以作为单独的命名空间使用
+    /// 子卷的根目录是它自己的父目录，从子卷中按绝对路径查找时从子卷的根目录开始；
+    /// 子卷的内容不计入文件系统根目录的配额，可以为子卷单独设置配额
+    /// 第一次创建子卷时创建子卷目录，并开启 [`CompatFeatures::SUBVOLUMES`]
+    ///
+    /// # Arguments
+    ///
+    /// * `efs`: 简易文件系统
+    /// * `name`: 子卷名
+    /// * `quota`: 子卷的配额，即最多占用的字节数和最多包含的索引节点数，为零时不限制，为 None 时不设置配额
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 子卷的根目录，子卷名为 `.` 或 `..` 时返回 [`FsError::InvalidArgument`]，
+    /// 其余错误与 [`Inode::create_dir`] 相同
+    pub fn create_subvolume(
+        efs: &Arc<Mutex<Self>>,
+        name: &str,
+        quota: Option<(u64, u64)>,
+    ) -> FsResult<Arc<Inode>> {
+        Self::check_subvolume_name(name)?;
+        let subvolumes = match Self::subvolumes_inode(efs) {
+            Some(subvolumes) => subvolumes,
+            None => Self::create_subvolumes_inode(efs)?,
+        };
+        let root = subvolumes.create_dir(name)?;
+        root.set_parent(root.inode_id());
+        if let Some((max_bytes, max_inodes)) = quota {
+            root.set_quota(max_bytes, max_inodes)?;
+        }
+        Ok(root)
     }
 
-    /// 分配一个新索引节点
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    /// 打开一个子卷
+    ///
+    /// # Arguments
+    ///
+    /// * `efs`: 简易文件系统
+    /// * `name`: 子卷名
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 子卷的根目录，子卷不存在时返回 [`FsError::NotFound`]
+    pub fn open_subvolume(efs: &Arc<Mutex<Self>>, name: &str) -> FsResult<Arc<Inode>> {
+        Self::check_subvolume_name(name)?;
+        Self::subvolumes_inode(efs)
+            .ok_or(FsError::NotFound)?
+            .find(name)
     }
 
-    /// 分配一个数据块
-    pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+    /// 列出所有子卷
+    ///
+    /// # Arguments
+    ///
+    /// * `efs`: 简易文件系统
+    ///
+    /// returns: Result<Vec<String>, FsError> 子卷名，还没有创建过子卷时为空
+    pub fn subvolumes(efs: &Arc<Mutex<Self>>) -> FsResult<Vec<String>> {
+        let Some(subvolumes) = Self::subvolumes_inode(efs) else {
+            return Ok(Vec::new());
+        };
+        let mut names = subvolumes.ls()?;
+        names.retain(|name| name != "." && name != "..");
+        Ok(names)
     }
 
-    /// 释放一个数据块
+    /// 删除一个子卷及其中的所有内容
     ///
     /// # Arguments
     ///
-    /// * `block_id`: 数据块ID
-    pub fn dealloc_data(&mut self, block_id: u32) {
-        let cache = get_block_cache(block_id as usize, self.block_device.clone());
-        cache.lock().modify(0, |data_block: &mut DataBlock| {
-            data_block.iter_mut().for_each(|p| {
-                *p = 0;
-            })
+    /// * `efs`: 简易文件系统
+    /// * `name`: 子卷名
+    ///
+    /// returns: Result<(), FsError> 子卷不存在时返回 [`FsError::NotFound`]
+    pub fn remove_subvolume(efs: &Arc<Mutex<Self>>, name: &str) -> FsResult<()> {
+        Self::check_subvolume_name(name)?;
+        Self::subvolumes_inode(efs)
+            .ok_or(FsError::NotFound)?
+            .remove_dir_all(name)
+    }
+
+    /// 检查子卷名，`.` 和 `..` 指向子卷目录本身，不能作为子卷名
+    fn check_subvolume_name(name: &str) -> FsResult<()> {
+        let name = FileName::new(name)?;
+        if name == FileName::DOT || name == FileName::DOT_DOT {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// 获取子卷目录的索引节点ID，还没有创建过子卷时为 None
+    pub(crate) fn subvolume_dir(&self) -> Option<u32> {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let subvolume_dir = cache.lock().read(0, |super_block: &SuperBlock| {
+            super_block
+                .compat_features()
+                .contains(CompatFeatures::SUBVOLUMES)
+                .then_some(super_block.subvolume_dir)
         });
-        self.data_bitmap.dealloc(
-            &self.block_device,
-            (block_id - self.data_area_start_block) as usize,
-        )
+        subvolume_dir
+    }
+
+    /// 获取子卷目录，还没有创建过子卷时为 None
+    fn subvolumes_inode(efs: &Arc<Mutex<Self>>) -> Option<Arc<Inode>> {
+        let subvolume_dir = efs.lock().subvolume_dir()?;
+        Some(Self::get_inode(efs, subvolume_dir))
+    }
+
+    /// 创建子卷目录并记录在超级块中
+    /// 子卷目录和根目录一样是自己的父目录，它也是自己的配额目录，但不设置配额
+    fn create_subvolumes_inode(efs: &Arc<Mutex<Self>>) -> FsResult<Arc<Inode>> {
+        let inode_id = {
+            let mut fs = efs.lock();
+            if fs.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            let inode_id = fs.alloc_inode()?;
+            if let Err(err) = fs.initialize_dir(inode_id, inode_id) {
+                fs.dealloc_inode(inode_id).ok();
+                return Err(err);
+            }
+            let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+            let cache = get_block_cache(block_id, fs.block_device.clone());
+            let blocks = cache
+                .lock()
+                .modify(block_offset, |disk_inode: &mut DiskInode| {
+                    disk_inode.set_quota_root(inode_id);
+                    disk_inode.update_checksum(inode_id);
+                    disk_inode.block_ids(&fs.block_device).len()
+                });
+            // 和根目录一样属于 uid 0
+            fs.charge_user_quota(0, (blocks * BLOCK_SZ) as i64, 1);
+            let mut block_ids = fs.modify_super_block(|super_block| {
+                super_block.subvolume_dir = inode_id;
+                super_block.enable_compat_features(CompatFeatures::SUBVOLUMES);
+            });
+            block_ids.push(block_id);
+            fs.commit(&block_ids);
+            inode_id
+        };
+        Ok(Self::get_inode(efs, inode_id))
+    }
+
+    /// 在一个事务中进行多个操作，例如创建临时文件、写入并重命名覆盖目标文件
+    /// 回调函数返回 Ok 时所有修改通过日志作为一个事务提交，崩溃后要么全部生效要么全部没有生效；
+    /// 返回 Err 或者发生恐慌时所有修改都被回滚，块设备上的内容和调用之前相同
+    ///
+    /// 事务期间这个文件系统上的所有修改都属于该事务，包括其它线程的修改，
+    /// 修改过的块在提交之前只保存在内存中，期间的同步操作也推迟到提交时进行；
+    /// 开始事务之前先将延迟分配的数据落盘，事务中的写入不再延迟分配
+    ///
+    /// # Arguments
+    ///
+    /// * `efs`: 简易文件系统
+    /// * `f`: 回调函数
+    ///
+    /// returns: Result<T, FsError> 回调函数的返回值，文件系统只读时返回 [`FsError::ReadOnly`]，
+    /// 已经在进行事务时返回 [`FsError::InvalidArgument`]，
+    /// 修改的块超过一个日志事务的容量时回滚并返回 [`FsError::NoSpace`]
+    pub fn transaction<T>(
+        efs: &Arc<Mutex<Self>>,
+        f: impl FnOnce(&Transaction) -> FsResult<T>,
+    ) -> FsResult<T> {
+        {
+            let fs = efs.lock();
+            if fs.read_only {
+                return Err(FsError::ReadOnly);
+            }
+            if fs.transaction.is_some() {
+                return Err(FsError::InvalidArgument);
+            }
+        }
+        Self::flush_delayed(efs)?;
+        {
+            let mut fs = efs.lock();
+            fs.mark_dirty();
+            if !begin_transaction(&fs.block_device) {
+                return Err(FsError::InvalidArgument);
+            }
+            fs.transaction = Some(TransactionState {
+                dirty: fs.dirty,
+                tail_blocks: fs.tail_blocks.clone(),
+                orphans: fs.orphans.clone(),
+            });
+        }
+
+        let mut txn = Transaction {
+            efs: efs.clone(),
+            finished: false,
+        };
+        let result = f(&txn);
+        txn.finished = true;
+        let mut fs = efs.lock();
+        match result {
+            Ok(value) => {
+                fs.commit_transaction()?;
+                Ok(value)
+            }
+            Err(err) => {
+                fs.rollback_transaction();
+                Err(err)
+            }
+        }
+    }
+
+    /// 是否正在进行事务
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// 通过日志把事务修改过的所有块作为一个日志事务提交，超过日志事务的容量时回滚
+    fn commit_transaction(&mut self) -> FsResult<()> {
+        let blocks = transaction_blocks(&self.block_device);
+        if blocks.len() > self.journal.capacity() {
+            self.rollback_transaction();
+            return Err(FsError::NoSpace);
+        }
+        self.transaction = None;
+        if !blocks.is_empty() {
+            self.journal.log(&blocks);
+            // 检查点，块缓存中已经是新内容，直接写回原位置
+            for (block_id, data) in &blocks {
+                self.block_device.write_block(*block_id, data);
+            }
+            self.journal.checkpoint();
+        }
+        end_transaction(&self.block_device, false);
+        Ok(())
+    }
+
+    /// 丢弃事务修改过的所有块，并恢复事务开始时的内存状态
+    fn rollback_transaction(&mut self) {
+        if let Some(state) = self.transaction.take() {
+            end_transaction(&self.block_device, true);
+            self.dirty = state.dirty;
+            self.tail_blocks = state.tail_blocks;
+            self.orphans = state.orphans;
+            // 位图块已经从块设备重新读取
+            for group in &self.groups {
+                group.inode_bitmap.reset_counts();
+                group.data_bitmap.reset_counts();
+            }
+        }
+    }
+
+    /// 获取路径解析时允许的最大深度
+    pub fn max_path_depth(&self) -> usize {
+        self.max_path_depth
+    }
+
+    /// 设置路径解析时允许的最大深度
+    ///
+    /// # Arguments
+    ///
+    /// * `max_path_depth`: 最大深度
+    pub fn set_max_path_depth(&mut self, max_path_depth: usize) {
+        self.max_path_depth = max_path_depth;
+    }
+
+    /// 获取文件名编码
+    pub fn name_encoding(&self) -> NameEncoding {
+        self.name_encoding
+    }
+
+    /// 设置文件名编码
+    /// 挂载由外部工具写入了非 UTF-8 文件名的镜像时应使用 [`NameEncoding::Raw`]
+    ///
+    /// # Arguments
+    ///
+    /// * `name_encoding`: 文件名编码
+    pub fn set_name_encoding(&mut self, name_encoding: NameEncoding) {
+        self.name_encoding = name_encoding;
+    }
+
+    /// 新建的文件是否将内容直接存放在索引节点中
+    pub fn inline_data(&self) -> bool {
+        self.inline_data
+    }
+
+    /// 设置新建的文件是否将内容直接存放在索引节点中
+    /// 内联数据最多 [`INLINE_DATA_CAPACITY`](crate::layout::INLINE_DATA_CAPACITY) 字节，文件变大时自动迁出到数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `inline_data`: 是否使用内联数据
+    pub fn set_inline_data(&mut self, inline_data: bool) {
+        self.inline_data = inline_data;
+    }
+
+    /// 写入后是否将文件的尾部打包到共享的尾部块中
+    /// 只有超级块中开启了 [`IncompatFeatures::TAIL_PACKING`] 时才会打包
+    pub fn tail_packing(&self) -> bool {
+        if !self.tail_packing {
+            return false;
+        }
+        let cache = get_block_cache(0, self.block_device.clone());
+        let tail_packing = cache.lock().read(0, |super_block: &SuperBlock| {
+            super_block
+                .incompat_features()
+                .contains(IncompatFeatures::TAIL_PACKING)
+        });
+        tail_packing
+    }
+
+    /// 设置写入后是否将文件的尾部打包到共享的尾部块中
+    /// 不超过 [`TAIL_PACK_LIMIT`] 字节的尾部按 [`TAIL_FRAGMENT_SZ`] 字节的片段分配，
+    /// 适合包含大量小文件的镜像；再次写入尾部时会先将它迁回到独占的数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `tail_packing`: 是否打包文件尾部
+    pub fn set_tail_packing(&mut self, tail_packing: bool) {
+        self.tail_packing = tail_packing;
+    }
+
+    /// 写入空洞时是否延迟到落盘时才分配数据块
+    pub fn delayed_allocation(&self) -> bool {
+        self.delayed_allocation
+    }
+
+    /// 设置写入空洞时是否延迟到落盘时才分配数据块
+    /// 延迟分配的数据在 [`Inode::sync`] 或者 [`EasyFileSystem::flush_delayed`] 时落盘，
+    /// 同一个文件中连续的数据一起分配，得到连续的数据块；关闭后已经延迟的数据仍然要这样落盘
+    ///
+    /// # Arguments
+    ///
+    /// * `delayed_allocation`: 是否延迟分配
+    pub fn set_delayed_allocation(&mut self, delayed_allocation: bool) {
+        self.delayed_allocation = delayed_allocation;
+    }
+
+    /// 是否按依赖顺序写回元数据块
+    pub fn soft_updates(&self) -> bool {
+        self.soft_updates
+    }
+
+    /// 设置是否按依赖顺序写回元数据块
+    /// 开启后修改元数据时记录块之间的依赖，块缓存替换和同步时先写回被依赖的块：
+    /// 目录条目在它指向的索引节点之后写回，指针在它指向的块及其数据位图之后写回，
+    /// 释放的块和索引节点在不再指向它们的指针和目录条目之后才在位图中清除，
+    /// 这样即使没有通过日志提交的元数据块被替换出去，崩溃后也不会留下悬空的引用；
+    /// 关闭后已经记录的依赖仍然有效，直到相应的块写回
+    ///
+    /// # Arguments
+    ///
+    /// * `soft_updates`: 是否按依赖顺序写回
+    pub fn set_soft_updates(&mut self, soft_updates: bool) {
+        self.soft_updates = soft_updates;
+    }
+
+    /// 开启了软更新时记录一个块必须在另一个块之后写回
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 后写回的块ID
+    /// * `dependency`: 先写回的块ID
+    pub(crate) fn order_after(&self, block_id: u64, dependency: u64) {
+        if self.soft_updates {
+            let cache = get_block_cache(block_id, self.block_device.clone());
+            cache.lock().depend_on(dependency);
+        }
+    }
+
+    /// 开启了软更新时记录指针块在新分配的块之后写回
+    /// 指针块要等新块的内容和记录它们已分配的数据位图块写回之后才能写回，
+    /// 否则崩溃后指针可能指向未初始化或者空闲的块
+    ///
+    /// # Arguments
+    ///
+    /// * `pointer_blocks`: 指向新块的索引节点所在的块和间接索引块
+    /// * `block_ids`: 新分配的块ID
+    pub(crate) fn order_pointers(&self, pointer_blocks: &[u64], block_ids: &[u64]) {
+        if !self.soft_updates || block_ids.is_empty() {
+            return;
+        }
+        let mut dependencies: BTreeSet<u64> = block_ids.iter().copied().collect();
+        dependencies.extend(
+            block_ids
+                .iter()
+                .filter_map(|&block_id| self.data_bitmap_block(block_id)),
+        );
+        for &pointer_block in pointer_blocks {
+            let cache = get_block_cache(pointer_block, self.block_device.clone());
+            let mut cache = cache.lock();
+            for &dependency in &dependencies {
+                cache.depend_on(dependency);
+            }
+        }
+    }
+
+    /// 开启了软更新时记录释放的块在数据位图中清除的时机
+    /// 记录它们空闲的数据位图块要等清除了指针的块写回之后才能写回，
+    /// 否则崩溃后块可能被重新分配给另一个文件，同时仍被原来的文件指向
+    ///
+    /// # Arguments
+    ///
+    /// * `block_ids`: 释放的块ID
+    /// * `pointer_blocks`: 原来指向它们的索引节点所在的块和间接索引块
+    pub(crate) fn order_frees(&self, block_ids: &[u64], pointer_blocks: &[u64]) {
+        if !self.soft_updates {
+            return;
+        }
+        let bitmap_blocks: BTreeSet<u64> = block_ids
+            .iter()
+            .filter_map(|&block_id| self.data_bitmap_block(block_id))
+            .collect();
+        for bitmap_block in bitmap_blocks {
+            for &pointer_block in pointer_blocks {
+                self.order_after(bitmap_block, pointer_block);
+            }
+        }
+    }
+
+    /// 开启了软更新时记录释放的尾部在尾部块和数据位图中清除的时机，见 [`order_frees`](Self::order_frees)
+    ///
+    /// # Arguments
+    ///
+    /// * `tail`: 释放的尾部
+    /// * `pointer_block`: 原来指向它的索引节点所在的块
+    pub(crate) fn order_tail_free(&self, tail: Tail, pointer_block: u64) {
+        self.order_after(tail.block_id, pointer_block);
+        self.order_frees(&[tail.block_id], &[pointer_block]);
+    }
+
+    /// 读取时是否不更新访问时间
+    pub fn noatime(&self) -> bool {
+        self.noatime
+    }
+
+    /// 设置读取时是否不更新访问时间
+    ///
+    /// # Arguments
+    ///
+    /// * `noatime`: 是否不更新访问时间
+    pub fn set_noatime(&mut self, noatime: bool) {
+        self.noatime = noatime;
+    }
+
+    /// 获取写入模式
+    pub fn write_mode(&self) -> WriteMode {
+        self.write_mode
+    }
+
+    /// 设置写入模式，从 [`WriteMode::Writeback`] 切换到 [`WriteMode::Sync`] 时不会立即写回，
+    /// 需要时调用 [`EasyFileSystem::sync`]
+    ///
+    /// # Arguments
+    ///
+    /// * `write_mode`: 写入模式
+    pub fn set_write_mode(&mut self, write_mode: WriteMode) {
+        self.write_mode = write_mode;
+    }
+
+    /// 写入文件时是否连同数据块一起通过日志提交
+    pub fn data_journaling(&self) -> bool {
+        self.data_journaling
+    }
+
+    /// 设置写入文件时是否连同数据块一起通过日志提交
+    /// 开启后每次 [`Inode::write_at`] 和 [`Inode::write_append`] 返回前，
+    /// 写入的数据块、索引块和磁盘索引节点都已经通过日志持久化，崩溃后这次写入要么完整可见，要么完全不可见；
+    /// 数据要写两遍，吞吐量大约减半，写入期间也不再延迟分配数据块。
+    /// 涉及的块超过一个事务的容量时拆成多个事务，磁盘索引节点在最后一个事务中提交，
+    /// 此时追加写入仍然是原子的，覆盖写入只保证每个事务是原子的
+    ///
+    /// # Arguments
+    ///
+    /// * `data_journaling`: 是否通过日志提交数据块
+    pub fn set_data_journaling(&mut self, data_journaling: bool) {
+        self.data_journaling = data_journaling;
+    }
+
+    /// 修改之后按写入模式和块缓存的写回策略决定是否将所有块缓存写回块设备
+    pub(crate) fn sync_on_write(&self) {
+        if self.write_mode == WriteMode::Sync {
+            block_cache_commit();
+        }
+    }
+
+    /// 获取当前时间
+    ///
+    /// returns: u64 自 UNIX 纪元以来的纳秒数
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// 设置为时间戳提供当前时间的时钟
+    ///
+    /// # Arguments
+    ///
+    /// * `clock`: 时钟
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// 获取权限检查
+    pub fn permission_check(&self) -> Option<Arc<dyn PermissionCheck>> {
+        self.permission_check.clone()
+    }
+
+    /// 设置打开、读和写时的权限检查，为 None 时不检查
+    ///
+    /// # Arguments
+    ///
+    /// * `permission_check`: 权限检查
+    pub fn set_permission_check(&mut self, permission_check: Option<Arc<dyn PermissionCheck>>) {
+        self.permission_check = permission_check;
+    }
+
+    /// 是否以只读方式打开
+    /// 镜像中有不认识的只读兼容特性时，文件系统以只读方式打开，
+    /// 所有修改操作都返回 [`FsError::ReadOnly`] 或者不做任何修改
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// 获取文件系统的 UUID
+    pub fn uuid(&self) -> [u8; 16] {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let uuid = cache
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.uuid);
+        uuid
+    }
+
+    /// 设置文件系统的 UUID，例如在复制镜像之后让两个镜像可以区分
+    ///
+    /// # Arguments
+    ///
+    /// * `uuid`: UUID
+    ///
+    /// returns: Result<(), FsError>
+    pub fn set_uuid(&mut self, uuid: [u8; 16]) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let block_ids = self.modify_super_block(|super_block| super_block.uuid = uuid);
+        self.commit(&block_ids);
+        Ok(())
+    }
+
+    /// 获取卷标
+    pub fn label(&self) -> String {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let label = cache.lock().read(0, |super_block: &SuperBlock| {
+            String::from_utf8_lossy(super_block.label()).into_owned()
+        });
+        label
+    }
+
+    /// 设置卷标
+    ///
+    /// # Arguments
+    ///
+    /// * `label`: 卷标，不能超过 [`LABEL_LENGTH_LIMIT`] 字节，也不能包含零字节
+    ///
+    /// returns: Result<(), FsError>
+    pub fn set_label(&mut self, label: &str) -> FsResult<()> {
+        if label.len() > LABEL_LENGTH_LIMIT || label.contains('\0') {
+            return Err(FsError::InvalidArgument);
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let block_ids =
+            self.modify_super_block(|super_block| super_block.set_label(label.as_bytes()));
+        self.commit(&block_ids);
+        Ok(())
+    }
+
+    /// 按ID获取索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: (u64, usize) 索引节点所在块ID和偏移
+    pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u64, usize) {
+        // 磁盘索引节点字节数
+        // 256 字节
+        let inode_size = size_of::<DiskInode>();
+
+        // 每个块中的索引节点数
+        // 2
+        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
+
+        // 索引节点所在块组及其在块组中的序号
+        let group = self.geometry.group_of_inode(inode_id);
+        let index = inode_id % self.geometry.inodes_per_group;
+
+        // 索引节点所在块ID
+        let block_id = self.geometry.inode_area_start(group) + (index / inodes_per_block) as u64;
+
+        // 索引节点在块中的偏移
+        let offset = (inode_id % inodes_per_block) as usize * inode_size;
+
+        (block_id, offset)
+    }
+
+    /// 将索引节点初始化为目录，并写入 `.` 和 `..` 目录条目
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 目录的索引节点ID
+    /// * `parent_inode_id`: 父目录的索引节点ID
+    ///
+    /// returns: Result<(), FsError> 分配不到数据块时返回 [`FsError::NoSpace`]，此时不做任何修改
+    pub fn initialize_dir(&mut self, inode_id: u32, parent_inode_id: u32) -> FsResult<()> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let now = self.now();
+        let address_48bit = self.address_48bit();
+        let cache = get_block_cache(block_id, self.block_device.clone());
+
+        // 先分配扩容需要的数据块，分配不到时不修改索引节点
+        let new_size = (2 * DIRENT_SZ) as u64;
+        let blocks_needed = DiskInode::total_blocks(new_size) as usize;
+        let new_blocks = self.alloc_data_blocks_near(inode_id, blocks_needed)?;
+
+        cache
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.initialize(DiskInodeType::Directory);
+                disk_inode.atime = now;
+                disk_inode.mtime = now;
+                disk_inode.ctime = now;
+                disk_inode.btime = now;
+                if address_48bit {
+                    disk_inode.enable_extents();
+                }
+
+                // 扩容
+                let unused =
+                    disk_inode.increase_size(new_size, new_blocks.clone(), &self.block_device);
+                assert!(unused.is_empty());
+
+                // 写入指向自己和父目录的目录条目
+                let dot = DirEntry::new(FileName::DOT, inode_id, DirEntryType::Directory);
+                let dot_dot =
+                    DirEntry::new(FileName::DOT_DOT, parent_inode_id, DirEntryType::Directory);
+                disk_inode.write_at(0, dot.as_bytes(), &self.block_device);
+                disk_inode.write_at(DIRENT_SZ, dot_dot.as_bytes(), &self.block_device);
+                disk_inode.set_dir_entries(2);
+                disk_inode.update_dir_checksum(&self.block_device);
+                disk_inode.update_checksum(inode_id);
+            });
+        self.order_pointers(&[block_id], &new_blocks);
+        Ok(())
+    }
+
+    /// 在超级块上调用一个函数来修改它，重新计算超级块的校验和，并更新所有备份超级块
+    ///
+    /// # Arguments
+    ///
+    /// * `f`: 回调函数
+    ///
+    /// returns: Vec<u64> 主超级块和备份超级块的块ID，需要由调用者提交
+    fn modify_super_block(&self, f: impl FnOnce(&mut SuperBlock)) -> Vec<u64> {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let (backup_block_ids, super_block_data) = {
+            let mut cache = cache.lock();
+            let backup_block_ids = cache.modify(0, |super_block: &mut SuperBlock| {
+                f(super_block);
+                super_block.update_checksum();
+                super_block.backup_block_ids()
+            });
+            (
+                backup_block_ids,
+                cache.read(0, |data_block: &DataBlock| *data_block),
+            )
+        };
+
+        let mut block_ids = vec![0];
+        for block_id in backup_block_ids {
+            let cache = get_block_cache(block_id, self.block_device.clone());
+            cache.lock().modify(0, |data_block: &mut DataBlock| {
+                *data_block = super_block_data
+            });
+            block_ids.push(block_id);
+        }
+        block_ids
+    }
+
+    /// 通过日志将给定块在缓存中的当前内容持久化
+    /// 返回时这些块已经写回原位置，可以安全地进行依赖于它们的后续修改
+    ///
+    /// # Arguments
+    ///
+    /// * `block_ids`: 块ID
+    pub fn commit(&mut self, block_ids: &[u64]) {
+        self.mark_dirty();
+        self.write_through_journal(block_ids);
+    }
+
+    /// 在第一次修改文件系统之前在超级块中设置脏标志，并立即持久化
+    fn mark_dirty(&mut self) {
+        if self.dirty || self.read_only {
+            return;
+        }
+        self.dirty = true;
+        self.modify_primary_super_block(|super_block| {
+            super_block.set_state(super_block.state() | SuperBlockState::DIRTY)
+        });
+        self.write_through_journal(&[0]);
+    }
+
+    /// 将所有块缓存写回块设备，然后清除超级块中的脏标志
+    /// 卸载文件系统之前调用它，下次打开时 [`unclean`](Self::unclean) 返回 false
+    pub fn sync(&mut self) {
+        block_cache_sync_all();
+        if !self.dirty || self.read_only {
+            self.block_device.flush();
+            return;
+        }
+        self.modify_primary_super_block(|super_block| {
+            super_block.set_state(super_block.state() - SuperBlockState::DIRTY)
+        });
+        self.write_through_journal(&[0]);
+        self.dirty = false;
+    }
+
+    /// 将文件系统的全部内容持久化到块设备
+    /// 先为所有延迟分配的数据分配数据块，然后按 [`sync`](Self::sync) 写回所有块缓存，
+    /// 包括超级块、位图和其它元数据，清除超级块中的脏标志并刷新块设备
+    /// 文件系统被释放时也会同步，但那时无法报告错误，需要确认结果时应在释放前调用它
+    ///
+    /// # Arguments
+    ///
+    /// * `efs`: 简易文件系统
+    ///
+    /// returns: Result<(), FsError> 可以分配的数据块不够时返回 [`FsError::NoSpace`]，
+    /// 此时没能落盘的数据仍然留在内存中，其余内容照常同步
+    pub fn sync_all(efs: &Arc<Mutex<Self>>) -> FsResult<()> {
+        let delayed = Self::flush_delayed(efs);
+        efs.lock().sync();
+        delayed
+    }
+
+    /// 获取打开时日志恢复的结果
+    /// 打开时总是先重放日志中已提交的事务、丢弃不完整的事务，然后才读取其余的元数据，以只读方式打开时也是如此
+    pub fn recovery(&self) -> JournalRecovery {
+        self.recovery
+    }
+
+    /// 上次是否没有正常卸载，即打开时超级块中仍然设置着脏标志
+    /// 这种情况下打开时已经扫描位图重新统计了空闲计数，调用者还可以进一步检查文件系统
+    pub fn unclean(&self) -> bool {
+        self.unclean
+    }
+
+    /// 获取挂载次数，包括本次挂载
+    pub fn mount_count(&self) -> u32 {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let mount_count = cache
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.mount_count);
+        mount_count
+    }
+
+    /// 获取上次挂载的时间，即本次挂载的时间
+    ///
+    /// returns: u64 自 UNIX 纪元以来的纳秒数
+    pub fn last_mount_time(&self) -> u64 {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let last_mount_time = cache
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.last_mount_time);
+        last_mount_time
+    }
+
+    /// 通过日志持久化给定的块，不设置脏标志，正在进行事务时推迟到提交事务时
+    /// 一个日志事务中的块从读出内容到完成检查点一直被固定，不会在事务提交之前被替换出去写回原位置；
+    /// 能固定的块不够一个日志事务时分成多个日志事务
+    ///
+    /// # Arguments
+    ///
+    /// * `block_ids`: 块ID
+    fn write_through_journal(&mut self, block_ids: &[u64]) {
+        // 事务中修改过的块在提交时一起记录
+        if self.transaction.is_some() {
+            return;
+        }
+        let mut remaining = block_ids;
+        while !remaining.is_empty() {
+            let mut pinned = 0;
+            for &block_id in remaining.iter().take(self.journal.capacity()) {
+                if pin_block_cache(block_id, self.block_device.clone()).is_err() {
+                    break;
+                }
+                pinned += 1;
+            }
+            // 一个块也固定不了时单独提交一个块，单个块写回原位置本身是原子的
+            let (chunk, rest) = remaining.split_at(pinned.max(1));
+            remaining = rest;
+
+            // 逐个读出块的内容
+            let blocks: Vec<(u64, DataBlock)> = chunk
+                .iter()
+                .map(|&block_id| {
+                    let cache = get_block_cache(block_id, self.block_device.clone());
+                    let data = cache.lock().read(0, |data: &DataBlock| *data);
+                    (block_id, data)
+                })
+                .collect();
+
+            // 提交事务，这些块依赖的块要先写回
+            block_cache_sync_dependencies(&self.block_device, chunk);
+            self.journal.log(&blocks);
+
+            // 检查点，没有被固定的块缓存可能在替换时就写回了原位置
+            for &block_id in chunk {
+                get_block_cache(block_id, self.block_device.clone())
+                    .lock()
+                    .sync();
+            }
+            for &block_id in &chunk[..pinned] {
+                unpin_block_cache(block_id, &self.block_device);
+            }
+            self.journal.checkpoint();
+        }
+    }
+
+    /// 是否检查索引节点和目录内容的校验和
+    /// 旧镜像中的元数据没有校验和，只有设置了 [`RoCompatFeatures::METADATA_CHECKSUM`] 时才检查
+    pub fn metadata_checksum(&self) -> bool {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let metadata_checksum = cache.lock().read(0, |super_block: &SuperBlock| {
+            super_block
+                .ro_compat_features()
+                .contains(RoCompatFeatures::METADATA_CHECKSUM)
+        });
+        metadata_checksum
+    }
+
+    /// 删除目录条目时是否在目录中留下空位，不开启时用最后一个目录条目填补空位
+    pub fn dir_free_slots(&self) -> bool {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let dir_free_slots = cache.lock().read(0, |super_block: &SuperBlock| {
+            super_block
+                .incompat_features()
+                .contains(IncompatFeatures::DIR_FREE_SLOTS)
+        });
+        dir_free_slots
+    }
+
+    /// 是否开启了 48 位寻址，开启时新建的索引节点都使用区段树映射数据块
+    /// 只有块数超出 32 位寻址范围的文件系统才会开启
+    pub fn address_48bit(&self) -> bool {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let address_48bit = cache.lock().read(0, |super_block: &SuperBlock| {
+            super_block
+                .incompat_features()
+                .contains(IncompatFeatures::ADDRESS_48BIT)
+        });
+        address_48bit
+    }
+
+    /// 获取空闲的数据块数，直接读取超级块中的计数
+    pub fn free_data_blocks(&self) -> u64 {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let free_data_blocks = cache
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.free_data_blocks);
+        free_data_blocks
+    }
+
+    /// 获取空闲的索引节点数，直接读取超级块中的计数
+    pub fn free_inodes(&self) -> u64 {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let free_inodes = cache
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.free_inodes);
+        free_inodes
+    }
+
+    /// 获取文件系统的容量和使用情况，空闲计数直接读取超级块，不扫描位图
+    pub fn statfs(&self) -> StatFs {
+        let total_data_blocks = self.geometry.total_data_blocks();
+        let free_data_blocks = self.free_data_blocks();
+        StatFs {
+            block_size: BLOCK_SZ,
+            total_blocks: self.geometry.total_blocks,
+            total_data_blocks,
+            free_data_blocks,
+            available_data_blocks: free_data_blocks.saturating_sub(self.reserved_data_blocks()),
+            used_data_blocks: total_data_blocks - free_data_blocks,
+            total_inodes: self.geometry.total_inodes(),
+            free_inodes: self.free_inodes(),
+            name_max: NAME_LENGTH_LIMIT,
+        }
+    }
+
+    /// 扫描位图统计空闲的数据块数和索引节点数，可以用来核对超级块中的计数
+    ///
+    /// returns: (u64, u64) (空闲的数据块数, 空闲的索引节点数)
+    pub fn count_free(&self) -> (u64, u64) {
+        self.groups
+            .iter()
+            .fold((0, 0), |(free_data_blocks, free_inodes), group| {
+                let data_bitmap = &group.data_bitmap;
+                let inode_bitmap = &group.inode_bitmap;
+                (
+                    free_data_blocks
+                        + (data_bitmap.maximum() - data_bitmap.count_allocated(&self.block_device))
+                            as u64,
+                    free_inodes
+                        + (inode_bitmap.maximum()
+                            - inode_bitmap.count_allocated(&self.block_device))
+                            as u64,
+                )
+            })
+    }
+
+    /// 是否在块组描述符中维护每个块组的空闲计数，见 [`RoCompatFeatures::GROUP_DESCRIPTORS`]
+    pub fn group_descriptors(&self) -> bool {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let group_descriptors = cache.lock().read(0, |super_block: &SuperBlock| {
+            super_block
+                .ro_compat_features()
+                .contains(RoCompatFeatures::GROUP_DESCRIPTORS)
+        });
+        group_descriptors
+    }
+
+    /// 获取块组描述符块的块ID，没有块组描述符时为空
+    pub(crate) fn group_descriptor_block_ids(&self) -> Vec<u64> {
+        if !self.group_descriptors() {
+            return Vec::new();
+        }
+        self.geometry.group_descriptor_block_ids()
+    }
+
+    /// 读取所有块组的描述符，没有块组描述符时为 None
+    ///
+    /// returns: Option<Vec<GroupDescriptor, Global>> 按块组序号排列的描述符
+    pub fn read_group_descriptors(&self) -> Option<Vec<GroupDescriptor>> {
+        if !self.group_descriptors() {
+            return None;
+        }
+        let mut descriptors = Vec::with_capacity(self.groups.len());
+        for block_id in self.geometry.group_descriptor_block_ids() {
+            let cache = get_block_cache(block_id, self.block_device.clone());
+            let count = (self.groups.len() - descriptors.len()).min(GROUP_DESCS_PER_BLOCK);
+            cache
+                .lock()
+                .read(0, |block: &[GroupDescriptor; GROUP_DESCS_PER_BLOCK]| {
+                    descriptors.extend_from_slice(&block[..count])
+                });
+        }
+        Some(descriptors)
+    }
+
+    /// 按位图统计一个块组的空闲计数，使用位图在内存中的计数
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    ///
+    /// returns: GroupDescriptor 块组描述符
+    fn count_group_free(&self, group: u32) -> GroupDescriptor {
+        let block_group = &self.groups[group as usize];
+        GroupDescriptor::new(
+            block_group.data_bitmap.count_free(&self.block_device) as u32,
+            block_group.inode_bitmap.count_free(&self.block_device) as u32,
+        )
+    }
+
+    /// 按位图重新统计所有块组的空闲计数并写入块组描述符，没有块组描述符时什么也不做
+    ///
+    /// returns: Vec<u64> 块组描述符块的块ID，需要由调用者提交
+    fn store_group_descriptors(&self) -> Vec<u64> {
+        let block_ids = self.group_descriptor_block_ids();
+        for (i, &block_id) in block_ids.iter().enumerate() {
+            let first = (i * GROUP_DESCS_PER_BLOCK) as u32;
+            let last = (first + GROUP_DESCS_PER_BLOCK as u32).min(self.geometry.group_count);
+            let descriptors: Vec<GroupDescriptor> = (first..last)
+                .map(|group| self.count_group_free(group))
+                .collect();
+            let cache = get_block_cache(block_id, self.block_device.clone());
+            cache
+                .lock()
+                .modify(0, |block: &mut [GroupDescriptor; GROUP_DESCS_PER_BLOCK]| {
+                    *block = [GroupDescriptor::default(); GROUP_DESCS_PER_BLOCK];
+                    block[..descriptors.len()].copy_from_slice(&descriptors);
+                });
+        }
+        block_ids
+    }
+
+    /// 修改一个块组的描述符，没有块组描述符时什么也不做
+    /// 与超级块中的空闲计数一样不立即提交，由提交位图的调用者一起提交
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    /// * `f`: 回调函数
+    fn modify_group_descriptor(&self, group: u32, f: impl FnOnce(&mut GroupDescriptor)) {
+        if !self.group_descriptors() {
+            return;
+        }
+        let (block_id, block_offset) = self.geometry.group_descriptor_position(group);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        cache.lock().modify(block_offset, f);
+    }
+
+    /// 按给定的占用情况重建索引节点位图和数据位图，并重新统计超级块和块组描述符中的空闲计数
+    /// 被释放的索引节点不清零，再次分配时才清零；调用者需保证期间没有其他操作
+    ///
+    /// # Arguments
+    ///
+    /// * `inodes`: 应当标记为已分配的索引节点ID
+    /// * `data_blocks`: 应当标记为已分配的数据块ID，包括备份超级块和块组描述符所在的块
+    ///
+    /// returns: usize 改变了的比特数
+    pub(crate) fn rebuild_bitmaps(
+        &mut self,
+        inodes: &BTreeSet<u32>,
+        data_blocks: &BTreeSet<u64>,
+    ) -> usize {
+        self.mark_dirty();
+        let mut changed = 0;
+        let mut freed_inodes = Vec::new();
+        for (group, block_group) in self.groups.iter().enumerate() {
+            let first_inode = group as u32 * self.geometry.inodes_per_group;
+            let changed_inodes = block_group.inode_bitmap.rebuild(&self.block_device, |bit| {
+                inodes.contains(&(first_inode + bit as u32))
+            });
+            changed += changed_inodes.len();
+            freed_inodes.extend(
+                changed_inodes
+                    .into_iter()
+                    .map(|bit| first_inode + bit as u32)
+                    .filter(|inode_id| !inodes.contains(inode_id)),
+            );
+            let data_area_start = self.geometry.data_area_start(group as u32);
+            changed += block_group
+                .data_bitmap
+                .rebuild(&self.block_device, |bit| {
+                    data_blocks.contains(&(data_area_start + bit as u64))
+                })
+                .len();
+        }
+        for inode_id in freed_inodes {
+            self.inode_table.remove(&inode_id);
+            // 预留计数不一致时也继续重建位图
+            let _ = self.discard_delayed(inode_id);
+        }
+        // 记录的尾部块可能已经被释放，只是分配时的提示，清空是安全的
+        self.tail_blocks.clear();
+        let (free_data_blocks, free_inodes) = self.count_free();
+        self.modify_primary_super_block(|super_block| {
+            super_block.free_data_blocks = free_data_blocks;
+            super_block.free_inodes = free_inodes;
+        });
+        self.store_group_descriptors();
+        changed
+    }
+
+    /// 从根目录开始遍历所有可以到达的索引节点，收集它们引用的数据块，与数据位图对照，
+    /// 列出泄漏的、没有在位图中标记的和被重复引用的数据块，不做任何修改
+    /// 调用者需保证检查期间没有其他操作，延迟分配的数据还没有落盘，不在检查范围内
+    ///
+    /// returns: AllocationReport 对照的结果
+    pub fn verify_allocation(&self) -> AllocationReport {
+        fsck::verify_allocation(self)
+    }
+
+    /// 获取记录给定数据块分配情况的数据位图块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 数据块ID
+    ///
+    /// returns: Option<u64> 数据位图块的块ID，不是数据块时为 None
+    pub(crate) fn data_bitmap_block(&self, block_id: u64) -> Option<u64> {
+        let (group, bit) = self.geometry.data_block_position(block_id)?;
+        Some(self.geometry.data_bitmap_start(group) + bit as u64 / (BLOCK_SZ as u64 * 8))
+    }
+
+    /// 获取记录给定索引节点分配情况的索引节点位图块
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: u64 索引节点位图块的块ID
+    pub(crate) fn inode_bitmap_block(&self, inode_id: u32) -> u64 {
+        let group = self.geometry.group_of_inode(inode_id);
+        let index = inode_id % self.geometry.inodes_per_group;
+        self.geometry.group_start(group) + index as u64 / (BLOCK_SZ as u64 * 8)
+    }
+
+    /// 获取块组布局
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// 清空给定块组的索引节点位图和数据位图，以及内部日志的日志头
+    /// 索引节点区域和数据区域不清零，分配时再清零
+    ///
+    /// # Arguments
+    ///
+    /// * `groups`: 块组序号的范围
+    fn zero_metadata(&self, groups: Range<u32>) {
+        let geometry = self.geometry;
+        let mut block_ids = Vec::new();
+        for group in groups {
+            let inode_bitmap_start = geometry.group_start(group);
+            block_ids.extend(inode_bitmap_start..geometry.inode_area_start(group));
+            let data_bitmap_start = geometry.data_bitmap_start(group);
+            block_ids.extend(data_bitmap_start..geometry.data_area_start(group));
+        }
+        if self.journal.uuid() == [0u8; 16] {
+            block_ids.push(geometry.total_blocks - geometry.journal_blocks as u64);
+        }
+        for block_id in block_ids {
+            let cache = get_block_cache(block_id, self.block_device.clone());
+            cache
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
+        }
+    }
+
+    /// 在挂载状态下将文件系统扩大到给定的总块数，用于底层文件或分区变大之后
+    /// 最后一个块组先被补足到完整块组的大小，之后追加新的块组，新增的索引节点和数据块都是空闲的，
+    /// 只清零新块组的位图；
+    /// 内部日志区域移动到设备末尾，落在新增区域中的备份超级块和块组描述符块被保留并写入
+    /// 调用期间不应有其他对文件系统的修改
+    ///
+    /// # Arguments
+    ///
+    /// * `new_total_blocks`: 新的总块数
+    ///
+    /// returns: Result<(), FsError> 文件系统只读时返回 [`FsError::ReadOnly`]，
+    /// 新的总块数不大于当前的总块数、超出寻址范围或者正在进行事务时返回 [`FsError::InvalidArgument`]，
+    /// 超出块设备的容量时返回 [`FsError::SuperBlock`]
+    pub fn grow(&mut self, new_total_blocks: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let old = self.geometry;
+        if new_total_blocks <= old.total_blocks
+            || new_total_blocks - 1 > MAX_BLOCK_ID
+            || self.transaction.is_some()
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        if let Some(device) = self.block_device.num_blocks() {
+            if new_total_blocks > device {
+                return Err(SuperBlockError::DeviceTooSmall {
+                    total: new_total_blocks,
+                    device,
+                }
+                .into());
+            }
+        }
+        let cache = get_block_cache(0, self.block_device.clone());
+        let (addressable, external_journal) = cache.lock().read(0, |super_block: &SuperBlock| {
+            (
+                super_block.addressable(new_total_blocks),
+                super_block.has_external_journal(),
+            )
+        });
+        if !addressable {
+            return Err(FsError::InvalidArgument);
+        }
+
+        //region 计算新的块组布局
+        // 内部日志恢复到默认大小，之前并入日志区域的剩余块可能组成新的块组
+        let journal_blocks = if external_journal {
+            0
+        } else {
+            old.journal_blocks.min(JOURNAL_BLOCKS)
+        };
+        let geometry = old.grow(new_total_blocks, journal_blocks);
+        if geometry.total_inodes() > u32::MAX as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+        //endregion
+
+        //region 切换到新的块组布局，清空新块组的位图，并将内部日志移动到设备末尾
+        // 日志在每次提交之后都已经检查点，旧的日志区域中没有需要重放的事务，
+        // 它和新增的块一样，成为数据区域后在分配时清零
+        block_cache_sync_all();
+        self.geometry = geometry;
+        self.groups = BlockGroup::from_geometry(&geometry);
+        self.zero_metadata(old.group_count..geometry.group_count);
+        block_cache_sync_all();
+        if !external_journal {
+            self.journal = Journal::new(
+                self.block_device.clone(),
+                new_total_blocks - geometry.journal_blocks as u64,
+                geometry.journal_blocks as usize,
+            );
+        }
+        //endregion
+
+        //region 保留新增的备份超级块和块组描述符块，然后更新超级块和块组描述符
+        let mut old_reserved_block_ids = old.backup_block_ids();
+        let mut reserved_block_ids = geometry.backup_block_ids();
+        if self.group_descriptors() {
+            old_reserved_block_ids.extend(old.group_descriptor_block_ids());
+            reserved_block_ids.extend(geometry.group_descriptor_block_ids());
+        }
+        let mut block_ids = Vec::new();
+        let mut reserved_blocks = 0;
+        for block_id in reserved_block_ids {
+            if old_reserved_block_ids.contains(&block_id) {
+                continue;
+            }
+            let (group, bit) = geometry.data_block_position(block_id).unwrap();
+            let data_bitmap = &self.groups[group as usize].data_bitmap;
+            data_bitmap.reserve(&self.block_device, bit as usize);
+            block_ids
+                .push(geometry.data_bitmap_start(group) + (bit as usize / (BLOCK_SZ * 8)) as u64);
+            reserved_blocks += 1;
+        }
+        block_ids.extend(
+            self.modify_super_block(|super_block| super_block.grow(&geometry, reserved_blocks)),
+        );
+        block_ids.extend(self.store_group_descriptors());
+        block_ids.sort_unstable();
+        block_ids.dedup();
+        self.commit(&block_ids);
+        self.sync_on_write();
+        //endregion
+        Ok(())
+    }
+
+    /// 从给定块组开始依次尝试每个块组，返回第一个成功的结果
+    /// 有块组描述符时跳过其中记录为没有空闲的块组，不必扫描它们的位图
+    ///
+    /// # Arguments
+    ///
+    /// * `first`: 首先尝试的块组
+    /// * `free`: 从块组描述符中取出要分配的资源的空闲计数
+    /// * `f`: 在块组中尝试分配的函数
+    ///
+    /// returns: Option<T> 分配结果
+    fn find_in_groups<T>(
+        &self,
+        first: u32,
+        free: impl Fn(&GroupDescriptor) -> u32,
+        mut f: impl FnMut(u32, &BlockGroup) -> Option<T>,
+    ) -> Option<T> {
+        let descriptors = self.read_group_descriptors();
+        let group_count = self.groups.len() as u32;
+        (0..group_count)
+            .map(|i| (first + i) % group_count)
+            .filter(|&group| {
+                descriptors
+                    .as_ref()
+                    .is_none_or(|descriptors| free(&descriptors[group as usize]) > 0)
+            })
+            .find_map(|group| f(group, &self.groups[group as usize]))
+    }
+
+    /// 按块组描述符挑选空闲计数最多的块组，计数相同时选择序号最小的块组
+    /// 只需要读取描述符块，没有块组描述符时返回第一个块组，不为此扫描位图
+    ///
+    /// # Arguments
+    ///
+    /// * `free`: 从块组描述符中取出要分配的资源的空闲计数
+    ///
+    /// returns: u32 块组序号
+    fn emptiest_group(&self, free: impl Fn(&GroupDescriptor) -> u32) -> u32 {
+        self.read_group_descriptors()
+            .and_then(|descriptors| {
+                descriptors
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(group, descriptor)| (free(descriptor), Reverse(group)))
+                    .map(|(group, _)| group as u32)
+            })
+            .unwrap_or(0)
+    }
+
+    /// 在主超级块上调用一个函数来修改它，并重新计算超级块的校验和
+    /// 用于空闲计数和挂载状态这类频繁变化的字段，不更新备份超级块，也不立即提交
+    ///
+    /// # Arguments
+    ///
+    /// * `f`: 回调函数
+    fn modify_primary_super_block(&self, f: impl FnOnce(&mut SuperBlock)) {
+        let cache = get_block_cache(0, self.block_device.clone());
+        cache.lock().modify(0, |super_block: &mut SuperBlock| {
+            f(super_block);
+            super_block.update_checksum();
+        });
+    }
+
+    /// 获取保留给特权写入者的数据块百分比
+    pub fn reserved_percent(&self) -> u32 {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let reserved_percent = cache
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.reserved_percent);
+        reserved_percent
+    }
+
+    /// 设置保留给特权写入者的数据块百分比
+    /// 只剩下保留的数据块时，普通的写入不再分配数据块，
+    /// 这样文件系统写满时仍然可以通过 [`set_use_reserved`](Self::set_use_reserved) 进行清理
+    ///
+    /// # Arguments
+    ///
+    /// * `reserved_percent`: 百分比，不能超过 [`MAX_RESERVED_PERCENT`]
+    ///
+    /// returns: Result<(), FsError>
+    pub fn set_reserved_percent(&mut self, reserved_percent: u32) -> FsResult<()> {
+        if reserved_percent > MAX_RESERVED_PERCENT {
+            return Err(FsError::InvalidArgument);
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let block_ids =
+            self.modify_super_block(|super_block| super_block.reserved_percent = reserved_percent);
+        self.commit(&block_ids);
+        Ok(())
+    }
+
+    /// 是否允许分配保留的数据块
+    pub fn use_reserved(&self) -> bool {
+        self.use_reserved
+    }
+
+    /// 设置是否允许分配保留的数据块，相当于以特权写入者的身份写入
+    ///
+    /// # Arguments
+    ///
+    /// * `use_reserved`: 是否允许
+    pub fn set_use_reserved(&mut self, use_reserved: bool) {
+        self.use_reserved = use_reserved;
+    }
+
+    /// 获取保留的数据块数
+    pub fn reserved_data_blocks(&self) -> u64 {
+        self.geometry.total_data_blocks() * self.reserved_percent() as u64 / 100
+    }
+
+    /// 获取当前可以分配的数据块数，不包括为延迟分配预留的数据块，
+    /// 不允许分配保留的数据块时也不包括它们
+    pub fn available_data_blocks(&self) -> u64 {
+        let free = self
+            .free_data_blocks()
+            .saturating_sub(self.delayed_reserved);
+        if self.use_reserved {
+            free
+        } else {
+            free.saturating_sub(self.reserved_data_blocks())
+        }
+    }
+
+    /// 为延迟分配的数据预留数据块，并在目录配额和用户配额中记账
+    /// 预留的数据块不计入 [`available_data_blocks`](Self::available_data_blocks)，落盘时再真正分配
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: 配额目录和属主
+    /// * `count`: 块数
+    ///
+    /// returns: Result<(), FsError> 可以分配的数据块不够时返回 [`FsError::NoSpace`]，
+    /// 超出配额时返回 [`FsError::QuotaExceeded`]，失败时不做任何修改
+    pub fn reserve_data(&mut self, target: QuotaTarget, count: usize) -> FsResult<()> {
+        if self.available_data_blocks() < count as u64 {
+            return Err(FsError::NoSpace);
+        }
+        if !self.charge_usage(target, (count * BLOCK_SZ) as i64, 0) {
+            return Err(FsError::QuotaExceeded);
+        }
+        self.delayed_reserved += count as u64;
+        Ok(())
+    }
+
+    /// 释放为延迟分配预留的数据块，并减少配额中的使用量
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: 预留时记账的配额目录和属主
+    /// * `count`: 块数
+    ///
+    /// returns: Result<(), FsError> 块数超出预留的总块数时返回 [`FsError::Corrupted`]，此时不做任何修改
+    pub fn release_reservation(&mut self, target: QuotaTarget, count: usize) -> FsResult<()> {
+        if count as u64 > self.delayed_reserved {
+            return Err(FsError::Corrupted.context(ErrorContext::new("release_reservation")));
+        }
+        self.delayed_reserved -= count as u64;
+        self.charge_usage(target, -((count * BLOCK_SZ) as i64), 0);
+        Ok(())
+    }
+
+    /// 获取为延迟分配预留的数据块数
+    pub fn delayed_reserved_blocks(&self) -> u64 {
+        self.delayed_reserved
+    }
+
+    /// 获取一个文件还没有分配数据块的数据
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: Option<&DelayedData> 没有这样的数据时返回 None
+    pub(crate) fn delayed(&self, inode_id: u32) -> Option<&DelayedData> {
+        self.delayed.get(&inode_id)
+    }
+
+    /// 获取一个文件还没有分配数据块的数据，没有时按给定的配额创建
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    /// * `quota_target`: 预留时记账的配额
+    ///
+    /// returns: &mut DelayedData 延迟分配的数据
+    pub(crate) fn delayed_mut(
+        &mut self,
+        inode_id: u32,
+        quota_target: QuotaTarget,
+    ) -> &mut DelayedData {
+        self.delayed.entry(inode_id).or_insert_with(|| DelayedData {
+            blocks: BTreeMap::new(),
+            reserved: 0,
+            quota_target,
+        })
+    }
+
+    /// 取出一个文件还没有分配数据块的数据，预留的数据块仍然保留，由调用者负责释放
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: Option<DelayedData> 没有这样的数据时返回 None
+    pub(crate) fn take_delayed(&mut self, inode_id: u32) -> Option<DelayedData> {
+        self.delayed.remove(&inode_id)
+    }
+
+    /// 放回取出后没能落盘的延迟分配的数据
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    /// * `delayed`: 延迟分配的数据
+    pub(crate) fn restore_delayed(&mut self, inode_id: u32, delayed: DelayedData) {
+        if !delayed.blocks.is_empty() || delayed.reserved > 0 {
+            assert!(self.delayed.insert(inode_id, delayed).is_none());
+        }
+    }
+
+    /// 丢弃一个文件还没有分配数据块的数据，并释放为它预留的数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: Result<(), FsError> 释放预留出错时返回的错误见 [`release_reservation`](Self::release_reservation)
+    pub(crate) fn discard_delayed(&mut self, inode_id: u32) -> FsResult<()> {
+        match self.delayed.remove(&inode_id) {
+            Some(delayed) => self.release_reservation(delayed.quota_target, delayed.reserved),
+            None => Ok(()),
+        }
+    }
+
+    /// 在配额目录的磁盘索引节点上调用一个函数来修改它的配额
+    /// 配额目录没有设置配额时不调用
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_inode_id`: 配额目录的索引节点ID
+    /// * `f`: 回调函数
+    ///
+    /// returns: Option<V> 回调函数的返回值
+    pub(crate) fn modify_quota<V>(
+        &self,
+        quota_inode_id: u32,
+        f: impl FnOnce(&mut Quota) -> V,
+    ) -> Option<V> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(quota_inode_id);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        let ret = cache
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                let mut quota = disk_inode.quota()?;
+                let ret = f(&mut quota);
+                disk_inode.set_quota(Some(quota));
+                disk_inode.update_checksum(quota_inode_id);
+                Some(ret)
+            });
+        ret
+    }
+
+    /// 配额目录是否还允许再占用给定的字节数和索引节点数
+    /// 配额目录没有设置配额时总是允许
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_inode_id`: 配额目录的索引节点ID
+    /// * `bytes`: 增加的字节数
+    /// * `inodes`: 增加的索引节点数
+    ///
+    /// returns: bool 是否允许
+    pub fn quota_allows(&self, quota_inode_id: u32, bytes: u64, inodes: u64) -> bool {
+        let (block_id, block_offset) = self.get_disk_inode_pos(quota_inode_id);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        let quota = cache
+            .lock()
+            .read(block_offset, |disk_inode: &DiskInode| disk_inode.quota());
+        quota.is_none_or(|quota| quota.allows(bytes, inodes))
+    }
+
+    /// 在配额目录上记录使用量的变化
+    /// 增加使用量会超出配额时不做任何修改，减少使用量时不会低于零
+    /// 配额目录没有设置配额时不做任何修改
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_inode_id`: 配额目录的索引节点ID
+    /// * `bytes`: 字节数的变化
+    /// * `inodes`: 索引节点数的变化
+    ///
+    /// returns: bool 是否在配额之内
+    pub fn charge_quota(&self, quota_inode_id: u32, bytes: i64, inodes: i64) -> bool {
+        self.modify_quota(quota_inode_id, |quota| {
+            if !quota.allows(bytes.max(0) as u64, inodes.max(0) as u64) {
+                return false;
+            }
+            quota.used_bytes = quota.used_bytes.saturating_add_signed(bytes);
+            quota.used_inodes = quota.used_inodes.saturating_add_signed(inodes);
+            true
+        })
+        .unwrap_or(true)
+    }
+
+    /// 为占用或者释放的空间同时在目录配额和用户配额中记账
+    /// 两者都允许时才一起修改，否则都不修改，见 [`charge_quota`](Self::charge_quota)
+    ///
+    /// # Arguments
+    ///
+    /// * `target`: 配额目录和属主
+    /// * `bytes`: 字节数的变化
+    /// * `inodes`: 索引节点数的变化
+    ///
+    /// returns: bool 是否在配额之内
+    pub fn charge_usage(&mut self, target: QuotaTarget, bytes: i64, inodes: i64) -> bool {
+        if !self.quota_allows(target.quota_root, bytes.max(0) as u64, inodes.max(0) as u64)
+            || !self.charge_user_quota(target.uid, bytes, inodes)
+        {
+            return false;
+        }
+        self.charge_quota(target.quota_root, bytes, inodes)
+    }
+
+    /// 为一个索引节点分配多个数据块，并在目录配额和用户配额中记账
+    /// 尽量分配连续的数据块，可以分配的数据块不够或者超出配额时不做任何修改
+    ///
+    /// # Arguments
+    ///
+    /// * `goal`: 第一个块的分配目标
+    /// * `count`: 块数
+    /// * `target`: 配额目录和属主
+    /// * `bytes`: 记账的字节数，可以与块数不对应，例如迁回打包的尾部时
+    ///
+    /// returns: Result<Vec<u64>, FsError> 块ID，可以分配的数据块不够时返回 [`FsError::NoSpace`]，
+    /// 超出配额时返回 [`FsError::QuotaExceeded`]
+    pub fn alloc_charged(
+        &mut self,
+        goal: AllocGoal,
+        count: usize,
+        target: QuotaTarget,
+        bytes: i64,
+    ) -> FsResult<Vec<u64>> {
+        if self.available_data_blocks() < count as u64 {
+            return Err(FsError::NoSpace);
+        }
+        if !self.charge_usage(target, bytes, 0) {
+            return Err(FsError::QuotaExceeded);
+        }
+        match self.alloc_data_contiguous(goal, count) {
+            Ok(block_ids) => Ok(block_ids),
+            Err(err) => {
+                self.charge_usage(target, -bytes, 0);
+                Err(err)
+            }
+        }
+    }
+
+    /// 是否强制执行用户配额
+    pub fn user_quota(&self) -> bool {
+        self.user_quota
+    }
+
+    /// 设置是否强制执行用户配额，相当于挂载选项
+    /// 不强制执行时仍然记录每个用户的使用量，只是不检查限制
+    ///
+    /// # Arguments
+    ///
+    /// * `user_quota`: 是否强制执行
+    pub fn set_user_quota(&mut self, user_quota: bool) {
+        self.user_quota = user_quota;
+    }
+
+    /// 获取用户配额文件的索引节点ID
+    ///
+    /// returns: Option<u32> 索引节点ID，旧镜像中没有用户配额文件时为 None
+    pub(crate) fn user_quota_inode(&self) -> Option<u32> {
+        let cache = get_block_cache(0, self.block_device.clone());
+        let quota_inode_id = cache.lock().read(0, |super_block: &SuperBlock| {
+            super_block
+                .ro_compat_features()
+                .contains(RoCompatFeatures::USER_QUOTA)
+                .then_some(super_block.user_quota_inode)
+        });
+        quota_inode_id
+    }
+
+    /// 在用户配额文件中查找一个用户的记录
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_inode_id`: 用户配额文件的索引节点ID
+    /// * `uid`: 用户ID
+    ///
+    /// returns: Option<(usize, UserQuota)> 记录在文件中的偏移和记录的内容，没有记录时为 None
+    fn find_user_quota(&self, quota_inode_id: u32, uid: u32) -> Option<(usize, UserQuota)> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(quota_inode_id);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        let ret = cache.lock().read(block_offset, |disk_inode: &DiskInode| {
+            let mut record = UserQuota::default();
+            (0..disk_inode.size as usize)
+                .step_by(USER_QUOTA_SZ)
+                .find(|&offset| {
+                    disk_inode.read_at(offset, record.as_bytes_mut(), &self.block_device);
+                    record.uid == uid
+                })
+                .map(|offset| (offset, record))
+        });
+        ret
+    }
+
+    /// 在用户配额文件的末尾为一个用户追加一条不限制也没有占用的记录
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_inode_id`: 用户配额文件的索引节点ID
+    /// * `uid`: 用户ID
+    ///
+    /// returns: Option<usize> 新记录在文件中的偏移，没有空闲的数据块用于扩容时为 None
+    fn append_user_quota(&mut self, quota_inode_id: u32, uid: u32) -> Option<usize> {
+        let (block_id, block_offset) = self.get_disk_inode_pos(quota_inode_id);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        let (size, blocks_needed) = cache.lock().read(block_offset, |disk_inode: &DiskInode| {
+            let new_size = disk_inode.size + USER_QUOTA_SZ as u64;
+            (disk_inode.size, disk_inode.blocks_num_needed(new_size))
+        });
+        if self.available_data_blocks() < blocks_needed as u64 {
+            return None;
+        }
+        let new_blocks = self
+            .alloc_data_blocks_near(quota_inode_id, blocks_needed as usize)
+            .ok()?;
+        let record = UserQuota::new(uid);
+        cache
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                let new_size = size + USER_QUOTA_SZ as u64;
+                let unused =
+                    disk_inode.increase_size(new_size, new_blocks.clone(), &self.block_device);
+                assert!(unused.is_empty());
+                disk_inode.write_at(size as usize, record.as_bytes(), &self.block_device);
+                disk_inode.update_checksum(quota_inode_id);
+            });
+        let mut pointer_blocks = cache.lock().read(block_offset, |disk_inode: &DiskInode| {
+            disk_inode.index_block_ids(&self.block_device)
+        });
+        pointer_blocks.push(block_id);
+        self.order_pointers(&pointer_blocks, &new_blocks);
+        Some(size as usize)
+    }
+
+    /// 在用户配额文件中的一条记录上调用一个函数来修改它的配额
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_inode_id`: 用户配额文件的索引节点ID
+    /// * `offset`: 记录在文件中的偏移
+    /// * `f`: 回调函数
+    ///
+    /// returns: V 回调函数的返回值
+    fn modify_user_quota<V>(
+        &self,
+        quota_inode_id: u32,
+        offset: usize,
+        f: impl FnOnce(&mut Quota) -> V,
+    ) -> V {
+        let (block_id, block_offset) = self.get_disk_inode_pos(quota_inode_id);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        let ret = cache
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                let mut record = UserQuota::default();
+                disk_inode.read_at(offset, record.as_bytes_mut(), &self.block_device);
+                let ret = f(&mut record.quota);
+                disk_inode.write_at(offset, record.as_bytes(), &self.block_device);
+                ret
+            });
+        ret
+    }
+
+    /// 用户是否还允许再占用给定的字节数和索引节点数
+    /// 没有强制执行用户配额、没有用户配额文件或者用户还没有记录时总是允许
+    ///
+    /// # Arguments
+    ///
+    /// * `uid`: 用户ID
+    /// * `bytes`: 增加的字节数
+    /// * `inodes`: 增加的索引节点数
+    ///
+    /// returns: bool 是否允许
+    pub fn user_quota_allows(&self, uid: u32, bytes: u64, inodes: u64) -> bool {
+        if !self.user_quota {
+            return true;
+        }
+        let Some(quota_inode_id) = self.user_quota_inode() else {
+            return true;
+        };
+        self.find_user_quota(quota_inode_id, uid)
+            .is_none_or(|(_, record)| record.quota.allows(bytes, inodes))
+    }
+
+    /// 在用户配额中记录一个用户使用量的变化，用户还没有记录时先追加一条记录
+    /// 强制执行用户配额时，增加使用量会超出配额则不做任何修改；减少使用量时不会低于零
+    ///
+    /// # Arguments
+    ///
+    /// * `uid`: 用户ID
+    /// * `bytes`: 字节数的变化
+    /// * `inodes`: 索引节点数的变化
+    ///
+    /// returns: bool 是否已经记录，没有用户配额文件时总是为 true
+    fn charge_user_quota(&mut self, uid: u32, bytes: i64, inodes: i64) -> bool {
+        let Some(quota_inode_id) = self.user_quota_inode() else {
+            return true;
+        };
+        let offset = match self.find_user_quota(quota_inode_id, uid) {
+            Some((offset, _)) => offset,
+            None => match self.append_user_quota(quota_inode_id, uid) {
+                Some(offset) => offset,
+                // 记录不下的用户只能减少使用量
+                None => return bytes <= 0 && inodes <= 0,
+            },
+        };
+        let enforce = self.user_quota;
+        self.modify_user_quota(quota_inode_id, offset, |quota| {
+            if enforce && !quota.allows(bytes.max(0) as u64, inodes.max(0) as u64) {
+                return false;
+            }
+            quota.used_bytes = quota.used_bytes.saturating_add_signed(bytes);
+            quota.used_inodes = quota.used_inodes.saturating_add_signed(inodes);
+            true
+        })
+    }
+
+    /// 将一个索引节点的使用量从原来的属主转移到新的属主
+    ///
+    /// # Arguments
+    ///
+    /// * `from`: 原来的属主的用户ID
+    /// * `to`: 新的属主的用户ID
+    /// * `bytes`: 转移的字节数
+    /// * `inodes`: 转移的索引节点数
+    ///
+    /// returns: bool 新的属主是否在配额之内，不在时不做任何修改
+    pub(crate) fn transfer_user_quota(
+        &mut self,
+        from: u32,
+        to: u32,
+        bytes: u64,
+        inodes: u64,
+    ) -> bool {
+        if from == to {
+            return true;
+        }
+        if !self.charge_user_quota(to, bytes as i64, inodes as i64) {
+            return false;
+        }
+        self.charge_user_quota(from, -(bytes as i64), -(inodes as i64));
+        true
+    }
+
+    /// 获取一个用户的配额
+    ///
+    /// # Arguments
+    ///
+    /// * `uid`: 用户ID
+    ///
+    /// returns: Result<Quota, FsError> 用户的限制和使用量，还没有记录的用户不受限制也没有占用；
+    /// 旧镜像中没有用户配额文件时返回 [`FsError::Unsupported`]
+    pub fn quota_get(&self, uid: u32) -> FsResult<Quota> {
+        let quota_inode_id = self.user_quota_inode().ok_or(FsError::Unsupported)?;
+        Ok(self
+            .find_user_quota(quota_inode_id, uid)
+            .map(|(_, record)| record.quota)
+            .unwrap_or_default())
+    }
+
+    /// 设置一个用户的限制，不改变它的使用量
+    /// 新的限制可以低于当前的使用量，此后强制执行用户配额时该用户只能释放空间
+    ///
+    /// # Arguments
+    ///
+    /// * `uid`: 用户ID
+    /// * `max_bytes`: 最多占用的字节数，为零时不限制
+    /// * `max_inodes`: 最多拥有的索引节点数，为零时不限制
+    ///
+    /// returns: Result<(), FsError> 旧镜像中没有用户配额文件时返回 [`FsError::Unsupported`]，
+    /// 没有空闲的数据块用于记录新用户时返回 [`FsError::NoSpace`]
+    pub fn quota_set(&mut self, uid: u32, max_bytes: u64, max_inodes: u64) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let quota_inode_id = self.user_quota_inode().ok_or(FsError::Unsupported)?;
+        self.mark_dirty();
+        let offset = match self.find_user_quota(quota_inode_id, uid) {
+            Some((offset, _)) => offset,
+            None => self
+                .append_user_quota(quota_inode_id, uid)
+                .ok_or(FsError::NoSpace)?,
+        };
+        self.modify_user_quota(quota_inode_id, offset, |quota| {
+            quota.max_bytes = max_bytes;
+            quota.max_inodes = max_inodes;
+        });
+        Ok(())
+    }
+
+    /// 分配一个新索引节点，从空闲的索引节点最多的块组开始
+    ///
+    /// returns: Result<u32, FsError> 索引节点ID，没有空闲的索引节点时返回 [`FsError::NoSpace`]
+    pub fn alloc_inode(&mut self) -> FsResult<u32> {
+        let first = self.emptiest_group(|descriptor| descriptor.free_inodes);
+        self.alloc_inode_in(first, None)
+    }
+
+    /// 分配一个新索引节点，优先使用父目录所在的块组，并尽量靠近父目录的索引节点，
+    /// 使同一目录下的文件相互靠近
+    ///
+    /// # Arguments
+    ///
+    /// * `parent_inode_id`: 父目录的索引节点ID
+    ///
+    /// returns: Result<u32, FsError> 索引节点ID，没有空闲的索引节点时返回 [`FsError::NoSpace`]
+    pub fn alloc_inode_near(&mut self, parent_inode_id: u32) -> FsResult<u32> {
+        let near = parent_inode_id % self.geometry.inodes_per_group;
+        self.alloc_inode_in(
+            self.geometry.group_of_inode(parent_inode_id),
+            Some(near as usize),
+        )
+    }
+
+    /// 从给定块组开始分配一个新索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `first`: 首先尝试的块组
+    /// * `near`: 在首先尝试的块组中希望靠近的索引节点序号，见 [`Bitmap::alloc`]
+    ///
+    /// returns: Result<u32, FsError> 索引节点ID，没有空闲的索引节点时返回 [`FsError::NoSpace`]
+    fn alloc_inode_in(&mut self, first: u32, near: Option<usize>) -> FsResult<u32> {
+        let inodes_per_group = self.geometry.inodes_per_group;
+        let inode_id = self
+            .find_in_groups(
+                first,
+                |descriptor| descriptor.free_inodes,
+                |group, block_group| {
+                    let near = if group == first { near } else { None };
+                    let index = block_group.inode_bitmap.alloc(&self.block_device, near)? as u32;
+                    self.modify_group_descriptor(group, |descriptor| descriptor.free_inodes -= 1);
+                    Some(group * inodes_per_group + index)
+                },
+            )
+            .ok_or(FsError::NoSpace)?;
+        self.mark_dirty();
+        self.modify_primary_super_block(|super_block| super_block.free_inodes -= 1);
+
+        // 快速格式化不清零索引节点区域，分配时清零，世代号从原有的值开始
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        cache
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| disk_inode.zero());
+        Ok(inode_id)
+    }
+
+    /// 推迟释放一个已经删除了目录条目的索引节点，直到它的最后一个索引节点对象被释放
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    /// * `dirent_block`: 曾经指向它的目录条目所在的块，释放索引节点时排在它之后
+    pub(crate) fn add_orphan(&mut self, inode_id: u32, dirent_block: u64) {
+        self.orphans.insert(inode_id, dirent_block);
+    }
+
+    /// 索引节点是否已经删除了目录条目、等待释放
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: bool 是否等待释放
+    pub fn is_orphan(&self, inode_id: u32) -> bool {
+        self.orphans.contains_key(&inode_id)
+    }
+
+    /// 取出一个等待释放的索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: Option<u64> 曾经指向它的目录条目所在的块，没有等待释放时为 None
+    pub(crate) fn take_orphan(&mut self, inode_id: u32) -> Option<u64> {
+        self.orphans.remove(&inode_id)
+    }
+
+    /// 释放一个索引节点，清除索引节点位图中的比特，并清零磁盘上的索引节点
+    /// 世代号被保留，见 [`DiskInode::zero`]
+    /// 调用者需要先释放它的数据块，并且已经持久化了删除指向它的目录条目
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: Result<(), FsError> 索引节点没有分配时返回附加了索引节点ID的 [`FsError::Bitmap`]，
+    /// 磁盘上的索引节点仍会被清零，释放延迟分配的预留出错时返回的错误见 [`release_reservation`](Self::release_reservation)
+    pub fn dealloc_inode(&mut self, inode_id: u32) -> FsResult<()> {
+        self.mark_dirty();
+        self.inode_table.remove(&inode_id);
+        let discarded = self.discard_delayed(inode_id);
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        cache
+            .lock()
+            .modify(block_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.zero();
+                disk_inode.update_checksum(inode_id);
+            });
+        // 清零的索引节点写回之后再在位图中清除
+        self.order_after(self.inode_bitmap_block(inode_id), block_id);
+        let group = self.geometry.group_of_inode(inode_id);
+        let index = inode_id % self.geometry.inodes_per_group;
+        self.groups[group as usize]
+            .inode_bitmap
+            .dealloc(&self.block_device, index as usize)
+            .context(ErrorContext::new("dealloc_inode").inode(inode_id))?;
+        self.modify_group_descriptor(group, |descriptor| descriptor.free_inodes += 1);
+        self.modify_primary_super_block(|super_block| super_block.free_inodes += 1);
+        discarded
+    }
+
+    /// 分配一个数据块
+    ///
+    /// returns: Result<u64, FsError> 块ID，没有可以分配的数据块时返回 [`FsError::NoSpace`]
+    pub fn alloc_data(&mut self) -> FsResult<u64> {
+        self.alloc_data_goal(AllocGoal::Any)
+    }
+
+    /// 分配一个数据块，优先使用索引节点所在的块组，使文件的数据靠近它的索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 数据块所属的索引节点ID
+    ///
+    /// returns: Result<u64, FsError> 块ID，没有可以分配的数据块时返回 [`FsError::NoSpace`]
+    pub fn alloc_data_near(&mut self, inode_id: u32) -> FsResult<u64> {
+        self.alloc_data_goal(AllocGoal::Inode(inode_id))
+    }
+
+    /// 为一个索引节点分配多个数据块，任何一个分配不到时释放已经分配的数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 数据块所属的索引节点ID
+    /// * `count`: 块数
+    ///
+    /// returns: Result<Vec<u64>, FsError> 块ID，没有足够的可以分配的数据块时返回 [`FsError::NoSpace`]
+    pub fn alloc_data_blocks_near(&mut self, inode_id: u32, count: usize) -> FsResult<Vec<u64>> {
+        self.alloc_data_blocks(AllocGoal::Inode(inode_id), count)
+    }
+
+    /// 从分配目标开始分配多个数据块，每个块都紧跟在上一个块之后分配，
+    /// 目标所在的块组分配完之后依次使用后面的块组，每个块组中的数据位图一次批量分配，
+    /// 分配不到足够的数据块时释放已经分配的数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `goal`: 第一个块的分配目标
+    /// * `count`: 块数
+    ///
+    /// returns: Result<Vec<u64>, FsError> 块ID，没有足够的可以分配的数据块时返回 [`FsError::NoSpace`]
+    pub fn alloc_data_blocks(&mut self, goal: AllocGoal, count: usize) -> FsResult<Vec<u64>> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if self.available_data_blocks() < count as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let geometry = self.geometry;
+        let (first, start) = self.goal_position(goal);
+        let mut block_ids: Vec<u64> = Vec::with_capacity(count);
+        self.find_in_groups(
+            first,
+            |descriptor| descriptor.free_data_blocks,
+            |group, block_group| {
+                let start = if group == first { start } else { 0 };
+                let bits = block_group.data_bitmap.alloc_many(
+                    &self.block_device,
+                    start,
+                    count - block_ids.len(),
+                );
+                self.modify_group_descriptor(group, |descriptor| {
+                    descriptor.free_data_blocks -= bits.len() as u32;
+                });
+                let data_area_start = geometry.data_area_start(group);
+                block_ids.extend(bits.into_iter().map(|bit| data_area_start + bit as u64));
+                (block_ids.len() == count).then_some(())
+            },
+        );
+        self.mark_dirty();
+        self.modify_primary_super_block(|super_block| {
+            super_block.free_data_blocks -= block_ids.len() as u64;
+        });
+        // 位图与超级块中的空闲计数不一致时可能分配不到足够的数据块
+        if block_ids.len() < count {
+            self.dealloc_data_blocks(&block_ids).ok();
+            return Err(FsError::NoSpace);
+        }
+        for &block_id in &block_ids {
+            self.zero_data_block(block_id);
+        }
+        Ok(block_ids)
+    }
+
+    /// 从分配目标开始分配一个数据块
+    /// 在目标所在的块组中先查找目标附近的数据块，见 [`Bitmap::alloc`]
+    /// 不允许分配保留的数据块时，只剩下保留的数据块也视为没有空间
+    ///
+    /// # Arguments
+    ///
+    /// * `goal`: 分配目标
+    ///
+    /// returns: Result<u64, FsError> 块ID，没有可以分配的数据块时返回 [`FsError::NoSpace`]
+    pub fn alloc_data_goal(&mut self, goal: AllocGoal) -> FsResult<u64> {
+        if self.available_data_blocks() == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let geometry = self.geometry;
+        let (first, start) = self.goal_position(goal);
+        let block_id = self
+            .find_in_groups(
+                first,
+                |descriptor| descriptor.free_data_blocks,
+                |group, block_group| {
+                    let near = (group == first).then_some(start);
+                    let bit = block_group.data_bitmap.alloc(&self.block_device, near)? as u64;
+                    self.modify_group_descriptor(group, |descriptor| {
+                        descriptor.free_data_blocks -= 1;
+                    });
+                    Some(geometry.data_area_start(group) + bit)
+                },
+            )
+            .ok_or(FsError::NoSpace)?;
+        self.mark_dirty();
+        self.modify_primary_super_block(|super_block| super_block.free_data_blocks -= 1);
+
+        self.zero_data_block(block_id);
+        Ok(block_id)
+    }
+
+    /// 从分配目标开始分配连续的多个数据块，使大的写入得到一个区段而不是分散的单个块
+    /// 所有块组中都没有足够长的空闲区域时退回到逐个分配，见 [`alloc_data_blocks`](Self::alloc_data_blocks)
+    ///
+    /// # Arguments
+    ///
+    /// * `goal`: 分配目标
+    /// * `count`: 块数
+    ///
+    /// returns: Result<Vec<u64>, FsError> 块ID，没有足够的可以分配的数据块时返回 [`FsError::NoSpace`]
+    pub fn alloc_data_contiguous(&mut self, goal: AllocGoal, count: usize) -> FsResult<Vec<u64>> {
+        if count <= 1 {
+            return self.alloc_data_blocks(goal, count);
+        }
+        if self.available_data_blocks() < count as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let geometry = self.geometry;
+        let (first, start) = self.goal_position(goal);
+        let found = self.find_in_groups(
+            first,
+            |descriptor| descriptor.free_data_blocks,
+            |group, block_group| {
+                let start = if group == first { start } else { 0 };
+                let bit =
+                    block_group
+                        .data_bitmap
+                        .alloc_contiguous(&self.block_device, start, count)?
+                        as u64;
+                self.modify_group_descriptor(group, |descriptor| {
+                    descriptor.free_data_blocks -= count as u32;
+                });
+                Some(geometry.data_area_start(group) + bit)
+            },
+        );
+        let Some(first_block_id) = found else {
+            return self.alloc_data_blocks(goal, count);
+        };
+        self.mark_dirty();
+        self.modify_primary_super_block(|super_block| {
+            super_block.free_data_blocks -= count as u64;
+        });
+        let block_ids: Vec<u64> = (first_block_id..first_block_id + count as u64).collect();
+        for &block_id in &block_ids {
+            self.zero_data_block(block_id);
+        }
+        Ok(block_ids)
+    }
+
+    /// 获取分配目标对应的块组和其中首先尝试的比特
+    ///
+    /// # Arguments
+    ///
+    /// * `goal`: 分配目标
+    ///
+    /// returns: (u32, usize) (块组, 比特)
+    fn goal_position(&self, goal: AllocGoal) -> (u32, usize) {
+        match goal {
+            AllocGoal::Any => (
+                self.emptiest_group(|descriptor| descriptor.free_data_blocks),
+                0,
+            ),
+            AllocGoal::Inode(inode_id) => {
+                // 按索引节点ID把各个文件的起点分散到块组中的不同位置，
+                // 这样同时写入的文件各自紧跟在自己的最后一个数据块之后增长，不会相互交错
+                let group = self.geometry.group_of_inode(inode_id);
+                let bits = self.groups[group as usize].data_bitmap.maximum();
+                let slot = (inode_id % INODE_GOAL_SLOTS) as usize;
+                (group, bits / INODE_GOAL_SLOTS as usize * slot)
+            }
+            AllocGoal::After(block_id) => self
+                .geometry
+                .data_block_position(block_id)
+                .map_or((0, 0), |(group, bit)| (group, bit as usize + 1)),
+        }
+    }
+
+    /// 清零一个刚分配的数据块，空闲的数据块中可能是格式化之前的内容
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 数据块ID
+    fn zero_data_block(&self, block_id: u64) {
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        cache
+            .lock()
+            .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
+    }
+
+    /// 释放一个数据块，块中的内容不清零，再次分配时才清零
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 数据块ID
+    ///
+    /// returns: Result<(), FsError> 不是数据块时返回 [`FsError::Corrupted`]，
+    /// 数据块没有分配时返回 [`FsError::Bitmap`]，都附加了块ID
+    pub fn dealloc_data(&mut self, block_id: u64) -> FsResult<()> {
+        self.mark_dirty();
+        let context = ErrorContext::new("dealloc_data").block(block_id);
+        let (group, bit) = self
+            .geometry
+            .data_block_position(block_id)
+            .ok_or(FsError::Corrupted)
+            .context(context)?;
+        self.groups[group as usize]
+            .data_bitmap
+            .dealloc(&self.block_device, bit as usize)
+            .context(context)?;
+        self.modify_group_descriptor(group, |descriptor| descriptor.free_data_blocks += 1);
+        invalidate_block_cache(block_id, &self.block_device);
+        self.modify_primary_super_block(|super_block| super_block.free_data_blocks += 1);
+        Ok(())
+    }
+
+    /// 释放多个数据块，同一个数据位图块中的比特一起清除，块中的内容不清零
+    ///
+    /// # Arguments
+    ///
+    /// * `block_ids`: 数据块ID
+    ///
+    /// returns: Result<(), FsError> 有不是数据块或者没有分配的块时返回第一个错误，其余的块照常释放
+    pub fn dealloc_data_blocks(&mut self, block_ids: &[u64]) -> FsResult<()> {
+        if block_ids.is_empty() {
+            return Ok(());
+        }
+        self.mark_dirty();
+        let mut result = Ok(());
+        let mut bits: BTreeMap<u32, (Vec<usize>, Vec<u64>)> = BTreeMap::new();
+        for &block_id in block_ids {
+            match self.geometry.data_block_position(block_id) {
+                Some((group, bit)) => {
+                    let (group_bits, group_blocks) = bits.entry(group).or_default();
+                    group_bits.push(bit as usize);
+                    group_blocks.push(block_id);
+                }
+                None => {
+                    result = result.and(Err(FsError::Corrupted
+                        .context(ErrorContext::new("dealloc_data_blocks").block(block_id))))
+                }
+            }
+        }
+        let mut freed = 0;
+        for (group, (bits, group_blocks)) in bits {
+            // 出错时其余的比特仍然被释放，按空闲计数的变化更新超级块
+            let bitmap = &self.groups[group as usize].data_bitmap;
+            let before = bitmap.count_free(&self.block_device);
+            let dealloc = bitmap.dealloc_many(&self.block_device, &bits);
+            let group_freed = bitmap.count_free(&self.block_device) - before;
+            self.modify_group_descriptor(group, |descriptor| {
+                descriptor.free_data_blocks += group_freed as u32;
+            });
+            freed += group_freed;
+            for block_id in group_blocks {
+                invalidate_block_cache(block_id, &self.block_device);
+            }
+            let data_area_start = self.geometry.data_area_start(group);
+            result = result.and(dealloc.map_err(|error| {
+                let block_id = data_area_start + error.bit() as u64;
+                FsError::from(error)
+                    .context(ErrorContext::new("dealloc_data_blocks").block(block_id))
+            }));
+        }
+        self.modify_primary_super_block(|super_block| {
+            super_block.free_data_blocks += freed as u64;
+        });
+        result
+    }
+
+    /// 为一个文件尾部分配尾部块中的片段
+    /// 优先使用已知还有空闲片段的尾部块，都放不下时分配一个新的尾部块
+    ///
+    /// # Arguments
+    ///
+    /// * `len`: 尾部的字节数
+    /// * `inode_id`: 尾部所属的索引节点ID，新的尾部块靠近它分配
+    ///
+    /// returns: Result<Option<Tail>, FsError> 分配的尾部，没有可以分配的数据块时为 None，
+    /// 分配数据块出错时返回的其他错误见 [`alloc_data_near`](Self::alloc_data_near)
+    pub fn alloc_tail(&mut self, len: usize, inode_id: u32) -> FsResult<Option<Tail>> {
+        assert!(len > 0 && len <= TAIL_PACK_LIMIT);
+        let fragments = len.div_ceil(TAIL_FRAGMENT_SZ);
+        let candidates: Vec<u64> = self.tail_blocks.iter().copied().collect();
+        for block_id in candidates {
+            let cache = get_block_cache(block_id, self.block_device.clone());
+            let allocated = cache.lock().modify(0, |header: &mut TailBlockHeader| {
+                let first = header.alloc(fragments)?;
+                Some((first, header.free_fragments()))
+            });
+            if let Some((first, free_fragments)) = allocated {
+                self.mark_dirty();
+                if free_fragments == 0 {
+                    self.tail_blocks.remove(&block_id);
+                }
+                return Ok(Some(Tail {
+                    block_id,
+                    offset: first * TAIL_FRAGMENT_SZ,
+                    len,
+                }));
+            }
+        }
+        let block_id = match self.alloc_data_near(inode_id) {
+            Ok(block_id) => block_id,
+            Err(FsError::NoSpace) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        let first = cache.lock().modify(0, |header: &mut TailBlockHeader| {
+            header.initialize();
+            header.alloc(fragments).unwrap()
+        });
+        self.tail_blocks.insert(block_id);
+        Ok(Some(Tail {
+            block_id,
+            offset: first * TAIL_FRAGMENT_SZ,
+            len,
+        }))
+    }
+
+    /// 释放一个文件尾部占用的片段，尾部块中不再有尾部时释放整个尾部块
+    ///
+    /// # Arguments
+    ///
+    /// * `tail`: 文件尾部
+    ///
+    /// returns: Result<(), FsError> 不是尾部块或者片段没有被占用时返回附加了块ID的 [`FsError::Corrupted`]，
+    /// 此时不做任何修改，释放尾部块出错时返回的错误见 [`dealloc_data`](Self::dealloc_data)
+    pub fn dealloc_tail(&mut self, tail: Tail) -> FsResult<()> {
+        self.mark_dirty();
+        let cache = get_block_cache(tail.block_id, self.block_device.clone());
+        let empty = cache.lock().modify(0, |header: &mut TailBlockHeader| {
+            (header.is_valid()
+                && tail.offset.is_multiple_of(TAIL_FRAGMENT_SZ)
+                && header.dealloc(tail.offset / TAIL_FRAGMENT_SZ, tail.fragments()))
+            .then(|| header.is_empty())
+        });
+        let Some(empty) = empty else {
+            return Err(
+                FsError::Corrupted.context(ErrorContext::new("dealloc_tail").block(tail.block_id))
+            );
+        };
+        if empty {
+            self.tail_blocks.remove(&tail.block_id);
+            self.dealloc_data(tail.block_id)
+        } else {
+            self.tail_blocks.insert(tail.block_id);
+            Ok(())
+        }
+    }
+}
+
+impl Drop for EasyFileSystem {
+    /// 释放时同步整个文件系统
+    /// 索引节点都持有文件系统，此时它们都已经被释放，各自延迟分配的数据也已经落盘
+    fn drop(&mut self) {
+        self.sync();
+    }
+}
+
+/// 将一个文件系统镜像紧凑地重写到另一个块设备上
+/// 新镜像的大小刚好容纳所有文件和目录，目录条目被紧密地重建，
+/// 每个文件的数据块被连续地分配，子卷连同它们的配额限制一起复制；
+/// 源文件系统以只读方式打开，文件和目录的权限位、属主、属组和时间戳都保持不变
+///
+/// # Arguments
+///
+/// * `src_device`: 源文件系统所在的块设备
+/// * `dst_device`: 目标块设备，容量不能小于返回的总块数
+///
+/// returns: Result<u64, FsError> 新镜像的总块数，源文件系统无法打开、有损坏的目录或者不是 UTF-8 的文件名时返回错误
+pub fn compact(
+    src_device: Arc<dyn BlockDevice>,
+    dst_device: Arc<dyn BlockDevice>,
+) -> FsResult<u64> {
+    // 以只读方式打开源文件系统，读取时不更新访问时间，复制出的访问时间与源文件系统相同
+    let src_efs = EasyFileSystem::open_with_options(
+        src_device,
+        None,
+        MountOptions {
+            read_only: true,
+            noatime: true,
+            ..Default::default()
+        },
+    )?;
+    let src_root = EasyFileSystem::root_inode(&src_efs);
+    let src_subvolumes = EasyFileSystem::subvolumes_inode(&src_efs);
+
+    //region 计算新镜像的大小
+    // 根目录在格式化时记在 uid 0 名下，用户配额文件中总有它的记录
+    let mut uids = BTreeSet::from([0]);
+    let (mut inodes, mut data_blocks) = measure_tree(&src_root, &mut uids)?;
+    if let Some(src_subvolumes) = &src_subvolumes {
+        let (subvolume_inodes, subvolume_data_blocks) = measure_tree(src_subvolumes, &mut uids)?;
+        inodes += subvolume_inodes;
+        data_blocks += subvolume_data_blocks;
+    }
+
+    // 复制后保留原来的属主，用户配额文件为每个属主记录一条，占用一个索引节点
+    let quota_blocks = DiskInode::total_blocks((uids.len() * USER_QUOTA_SZ) as u64) as usize;
+    let (inodes, data_blocks) = (inodes + 1, data_blocks + quota_blocks);
+
+    // 索引节点位图块数
+    let inode_bitmap_blocks = inodes.div_ceil(BLOCK_SZ * 8) as u32;
+
+    // 索引节点数，与索引节点位图块数对应
+    let inode_num = inode_bitmap_blocks as u64 * BLOCK_SZ as u64 * 8;
+
+    // 索引节点区域块数
+    let inode_area_blocks = (inode_num as usize * size_of::<DiskInode>()).div_ceil(BLOCK_SZ) as u64;
+
+    // 块组元数据、备份超级块和块组描述符块都会随总块数变化，从一个块组的估计值开始逐步增加总块数，
+    // 直到数据区域足够容纳所有数据块以及备份超级块和块组描述符块
+    let mut total_blocks = 1
+        + inode_bitmap_blocks as u64
+        + inode_area_blocks
+        + 1
+        + data_blocks as u64
+        + JOURNAL_BLOCKS as u64;
+    loop {
+        let geometry = Geometry::new(total_blocks, inode_num, JOURNAL_BLOCKS)?;
+        let needed = data_blocks as u64
+            + geometry.backup_block_ids().len() as u64
+            + geometry.group_descriptor_block_ids().len() as u64;
+        let available = geometry.total_data_blocks();
+        if available >= needed {
+            break;
+        }
+        total_blocks += needed - available;
+    }
+    //endregion
+
+    let dst_efs = FilesystemBuilder::new(total_blocks)
+        .inodes(inode_num)
+        .format(dst_device)?;
+    // 新镜像刚好放下所有内容，复制时需要使用保留的数据块
+    dst_efs.lock().set_use_reserved(true);
+    let dst_root = EasyFileSystem::root_inode(&dst_efs);
+    copy_tree(&src_root, &dst_root)?;
+    for name in EasyFileSystem::subvolumes(&src_efs)? {
+        let src_subvolume = EasyFileSystem::open_subvolume(&src_efs, &name)?;
+        let quota = src_subvolume
+            .quota()
+            .map(|quota| (quota.max_bytes, quota.max_inodes));
+        let dst_subvolume = EasyFileSystem::create_subvolume(&dst_efs, &name, quota)?;
+        copy_tree(&src_subvolume, &dst_subvolume)?;
+    }
+    block_cache_sync_all();
+    Ok(total_blocks)
+}
+
+/// 统计一个目录树占用的索引节点数和数据块数（包括间接索引块），并收集其中的属主
+///
+/// # Arguments
+///
+/// * `dir`: 目录
+/// * `uids`: 收集到的属主的用户ID
+///
+/// returns: Result<(usize, usize), FsError> (索引节点数, 数据块数)
+fn measure_tree(dir: &Inode, uids: &mut BTreeSet<u32>) -> FsResult<(usize, usize)> {
+    uids.insert(dir.metadata().uid);
+    let mut inodes = 1;
+    let mut entries = 0;
+    let mut data_blocks = 0;
+    for dirent in dir.read_dir() {
+        let dirent = dirent?;
+        entries += 1;
+        if dirent.name_bytes() == b"." || dirent.name_bytes() == b".." {
+            continue;
+        }
+        let child = dir.find_bytes(dirent.name_bytes())?;
+        if child.is_dir() {
+            let (child_inodes, child_data_blocks) = measure_tree(&child, uids)?;
+            inodes += child_inodes;
+            data_blocks += child_data_blocks;
+        } else {
+            uids.insert(child.metadata().uid);
+            inodes += 1;
+            data_blocks += DiskInode::total_blocks(child.size() as u64) as usize;
+        }
+    }
+    data_blocks += DiskInode::total_blocks((entries * DIRENT_SZ) as u64) as usize;
+    Ok((inodes, data_blocks))
+}
+
+/// 将一个目录树复制到另一个目录中，保留每个文件和目录的权限位、属主、属组和时间戳
+/// 先复制当前目录下的文件并创建子目录，然后递归进入子目录，
+/// 最后恢复目标目录自己的元数据，复制其中的内容时更新的修改时间不会留下来
+///
+/// # Arguments
+///
+/// * `src`: 源目录
+/// * `dst`: 目标目录
+///
+/// returns: Result<(), FsError> 文件名不是 UTF-8 时返回 [`FsError::Unsupported`]
+fn copy_tree(src: &Inode, dst: &Inode) -> FsResult<()> {
+    let mut dirs = Vec::new();
+    for dirent in src.read_dir() {
+        let dirent = dirent?;
+        let name = dirent.name().map_err(|_| FsError::Unsupported)?;
+        if name == "." || name == ".." {
+            continue;
+        }
+        let child = src.find(name)?;
+        if child.is_dir() {
+            dirs.push((child, dst.create_dir(name)?));
+        } else {
+            vfs::copy(&child, dst, name, CopyOptions::default())?
+                .restore_metadata(&child.statx())?;
+        }
+    }
+
+    for (src_dir, dst_dir) in dirs {
+        copy_tree(&src_dir, &dst_dir)?;
     }
+    dst.restore_metadata(&src.statx())
 }
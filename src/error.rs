@@ -0,0 +1,486 @@
+use core::fmt;
+
+/// 块缓存错误
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheError {
+    /// 所有块缓存都在使用中，无法替换
+    Exhausted,
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Exhausted => f.write_str("run out of block cache"),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+/// 位图错误，由 [`crate::bitmap::Bitmap`] 的释放操作返回，说明位图与其它元数据不一致，
+/// 通常是损坏的镜像导致的重复释放
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitmapError {
+    /// 释放的比特没有被分配
+    NotAllocated(usize),
+
+    /// 比特超出了位图中可分配的范围
+    OutOfRange(usize),
+}
+
+impl BitmapError {
+    /// 出错的比特
+    pub fn bit(&self) -> usize {
+        match *self {
+            BitmapError::NotAllocated(bit) | BitmapError::OutOfRange(bit) => bit,
+        }
+    }
+}
+
+impl fmt::Display for BitmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitmapError::NotAllocated(bit) => write!(f, "bit {} is not allocated", bit),
+            BitmapError::OutOfRange(bit) => write!(f, "bit {} is out of bitmap range", bit),
+        }
+    }
+}
+
+impl std::error::Error for BitmapError {}
+
+/// 超级块错误，打开文件系统时由 [`crate::layout::SuperBlock::validate`] 返回，
+/// 包装为 [`FsError::SuperBlock`] 后由 [`crate::efs::EasyFileSystem::open`] 返回
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuperBlockError {
+    /// 魔数不匹配，不是简易文件系统或者格式版本不同
+    BadMagic(u32),
+
+    /// 校验和不匹配
+    ChecksumMismatch {
+        /// 超级块中记录的校验和
+        stored: u32,
+
+        /// 重新计算的校验和
+        computed: u32,
+    },
+
+    /// 各区域的块数之和与总块数不一致
+    SizeMismatch {
+        /// 各区域的块数之和，包括超级块
+        regions: u64,
+
+        /// 总块数
+        total: u64,
+    },
+
+    /// 区域为空
+    EmptyRegion(&'static str),
+
+    /// 区域太小，其中的内容会覆盖到下一个区域
+    RegionOverlap(&'static str),
+
+    /// 磁盘格式版本不是当前版本，旧版本的镜像需要先升级
+    UnsupportedVersion(u32),
+
+    /// 镜像的块大小与编译时选择的块大小不同
+    BlockSizeMismatch {
+        /// 超级块中记录的块大小
+        stored: usize,
+
+        /// 当前支持的块大小
+        supported: usize,
+    },
+
+    /// 块ID超出 32 位，但没有开启 48 位寻址
+    AddressOverflow(u64),
+
+    /// 位图太小，不能覆盖对应的区域
+    BitmapTooSmall(&'static str),
+
+    /// 字段的值超出了文件系统的范围
+    FieldOutOfRange(&'static str),
+
+    /// 总块数超出了块设备的容量
+    DeviceTooSmall {
+        /// 超级块中记录的总块数
+        total: u64,
+
+        /// 块设备的块数
+        device: u64,
+    },
+}
+
+impl fmt::Display for SuperBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuperBlockError::BadMagic(magic) => write!(f, "bad magic number {:#x}", magic),
+            SuperBlockError::ChecksumMismatch { stored, computed } => write!(
+                f,
+                "superblock checksum mismatch: stored {:#010x}, computed {:#010x}",
+                stored, computed
+            ),
+            SuperBlockError::SizeMismatch { regions, total } => write!(
+                f,
+                "regions span {} blocks but total is {} blocks",
+                regions, total
+            ),
+            SuperBlockError::EmptyRegion(region) => write!(f, "{} is empty", region),
+            SuperBlockError::RegionOverlap(region) => {
+                write!(f, "{} overlaps the next region", region)
+            }
+            SuperBlockError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported format version {}, migrate the image first",
+                version
+            ),
+            SuperBlockError::BlockSizeMismatch { stored, supported } => write!(
+                f,
+                "block size is {} bytes but only {} bytes is supported",
+                stored, supported
+            ),
+            SuperBlockError::AddressOverflow(total) => write!(
+                f,
+                "{} blocks need 48-bit addressing but the feature is not enabled",
+                total
+            ),
+            SuperBlockError::BitmapTooSmall(bitmap) => {
+                write!(f, "{} is too small for its region", bitmap)
+            }
+            SuperBlockError::FieldOutOfRange(field) => write!(f, "{} is out of range", field),
+            SuperBlockError::DeviceTooSmall { total, device } => write!(
+                f,
+                "filesystem spans {} blocks but the device has only {} blocks",
+                total, device
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SuperBlockError {}
+
+/// 迁移错误，由 [`crate::migrate::migrate`] 返回，返回错误时镜像仍是原来的版本，内容没有被改动
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrateError {
+    /// 不是简易文件系统
+    BadMagic(u32),
+
+    /// 镜像的版本比当前版本新
+    NewerVersion(u32),
+
+    /// 不支持迁移的镜像
+    Unsupported(&'static str),
+
+    /// 旧版本的镜像已经损坏，例如索引节点被多个目录条目引用或者块指针超出了数据区域
+    Corrupted(&'static str),
+
+    /// 当前格式的镜像放不下所有文件和目录
+    NoSpace {
+        /// 需要的数据块数
+        needed: u64,
+
+        /// 可用的数据块数
+        available: u64,
+    },
+
+    /// 按当前格式重建目录树时出错，旧版本仍在使用的块都没有被覆盖
+    Write(FsError),
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrateError::BadMagic(magic) => write!(f, "bad magic number {:#x}", magic),
+            MigrateError::NewerVersion(version) => {
+                write!(f, "format version {} is newer than supported", version)
+            }
+            MigrateError::Unsupported(reason) => write!(f, "cannot migrate: {}", reason),
+            MigrateError::Corrupted(reason) => write!(f, "corrupted image: {}", reason),
+            MigrateError::NoSpace { needed, available } => write!(
+                f,
+                "need {} data blocks but only {} are available after migration",
+                needed, available
+            ),
+            MigrateError::Write(error) => write!(f, "cannot write the migrated image: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for MigrateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MigrateError::Write(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<FsError> for MigrateError {
+    fn from(error: FsError) -> Self {
+        MigrateError::Write(error)
+    }
+}
+
+/// 错误发生时的上下文
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// 正在进行的操作
+    pub operation: &'static str,
+
+    /// 相关的块ID
+    pub block_id: Option<u64>,
+
+    /// 相关的索引节点ID
+    pub inode_id: Option<u32>,
+}
+
+impl ErrorContext {
+    /// 只包含操作名称的上下文
+    ///
+    /// # Arguments
+    ///
+    /// * `operation`: 操作名称
+    ///
+    /// returns: ErrorContext 上下文
+    pub fn new(operation: &'static str) -> Self {
+        Self {
+            operation,
+            block_id: None,
+            inode_id: None,
+        }
+    }
+
+    /// 附加块ID
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    ///
+    /// returns: ErrorContext 上下文
+    pub fn block(mut self, block_id: u64) -> Self {
+        self.block_id = Some(block_id);
+        self
+    }
+
+    /// 附加索引节点ID
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: ErrorContext 上下文
+    pub fn inode(mut self, inode_id: u32) -> Self {
+        self.inode_id = Some(inode_id);
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.operation)?;
+        if let Some(block_id) = self.block_id {
+            write!(f, " (block {})", block_id)?;
+        }
+        if let Some(inode_id) = self.inode_id {
+            write!(f, " (inode {})", inode_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// 文件系统错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsError {
+    /// 找不到文件或目录
+    NotFound,
+
+    /// 不是一个目录
+    NotADirectory,
+
+    /// 是一个目录
+    IsADirectory,
+
+    /// 文件已存在
+    AlreadyExists,
+
+    /// 目录不为空
+    DirectoryNotEmpty,
+
+    /// 无效的参数
+    InvalidArgument,
+
+    /// 文件名过长
+    NameTooLong,
+
+    /// 路径过深或者存在目录环
+    LoopDetected,
+
+    /// 没有访问权限
+    PermissionDenied,
+
+    /// 文件系统以只读方式打开
+    ReadOnly,
+
+    /// 没有可以分配的空间
+    NoSpace,
+
+    /// 超出了配额
+    QuotaExceeded,
+
+    /// 文件超出了映射格式能够寻址的最大大小
+    FileTooLarge,
+
+    /// 文件系统不支持该操作
+    Unsupported,
+
+    /// 磁盘上的数据已损坏
+    Corrupted,
+
+    /// 超级块无效，不能打开文件系统
+    SuperBlock(SuperBlockError),
+
+    /// 上次没有正常卸载，需要先检查文件系统
+    Unclean,
+
+    /// 块缓存错误
+    Cache(CacheError),
+
+    /// 位图与其它元数据不一致
+    Bitmap(BitmapError),
+
+    /// 附加了上下文的错误
+    WithContext {
+        /// 上下文
+        context: ErrorContext,
+
+        /// 原始错误
+        source: Box<FsError>,
+    },
+}
+
+impl FsError {
+    /// 附加上下文
+    ///
+    /// # Arguments
+    ///
+    /// * `context`: 上下文
+    ///
+    /// returns: FsError 附加了上下文的错误
+    pub fn context(self, context: ErrorContext) -> Self {
+        FsError::WithContext {
+            context,
+            source: Box::new(self),
+        }
+    }
+
+    /// 去掉所有上下文，获取最初的错误
+    pub fn kind(&self) -> &FsError {
+        match self {
+            FsError::WithContext { source, .. } => source.kind(),
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotADirectory => "not a directory",
+            FsError::IsADirectory => "is a directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::DirectoryNotEmpty => "directory not empty",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::NameTooLong => "file name too long",
+            FsError::LoopDetected => "too many levels of directories",
+            FsError::PermissionDenied => "permission denied",
+            FsError::ReadOnly => "read-only file system",
+            FsError::NoSpace => "no space left on device",
+            FsError::QuotaExceeded => "disk quota exceeded",
+            FsError::FileTooLarge => "file too large",
+            FsError::Unsupported => "operation not supported",
+            FsError::Corrupted => "filesystem corrupted",
+            FsError::Unclean => "filesystem was not cleanly unmounted",
+            FsError::Cache(error) => return write!(f, "{}", error),
+            FsError::Bitmap(error) => return write!(f, "corrupted bitmap: {}", error),
+            FsError::SuperBlock(error) => return write!(f, "invalid superblock: {}", error),
+            FsError::WithContext { context, source } => {
+                return write!(f, "{}: {}", context, source)
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for FsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FsError::Cache(error) => Some(error),
+            FsError::Bitmap(error) => Some(error),
+            FsError::SuperBlock(error) => Some(error),
+            FsError::WithContext { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<CacheError> for FsError {
+    fn from(error: CacheError) -> Self {
+        FsError::Cache(error)
+    }
+}
+
+impl From<BitmapError> for FsError {
+    fn from(error: BitmapError) -> Self {
+        FsError::Bitmap(error)
+    }
+}
+
+impl From<SuperBlockError> for FsError {
+    fn from(error: SuperBlockError) -> Self {
+        FsError::SuperBlock(error)
+    }
+}
+
+impl From<FsError> for std::io::Error {
+    fn from(error: FsError) -> Self {
+        use std::io::ErrorKind;
+        let kind = match error.kind() {
+            FsError::NotFound => ErrorKind::NotFound,
+            FsError::AlreadyExists => ErrorKind::AlreadyExists,
+            FsError::InvalidArgument => ErrorKind::InvalidInput,
+            FsError::NameTooLong => ErrorKind::InvalidFilename,
+            FsError::PermissionDenied => ErrorKind::PermissionDenied,
+            FsError::ReadOnly => ErrorKind::ReadOnlyFilesystem,
+            FsError::NoSpace => ErrorKind::StorageFull,
+            FsError::QuotaExceeded => ErrorKind::QuotaExceeded,
+            FsError::FileTooLarge => ErrorKind::FileTooLarge,
+            FsError::Unsupported => ErrorKind::Unsupported,
+            FsError::Corrupted | FsError::Bitmap(_) | FsError::SuperBlock(_) => {
+                ErrorKind::InvalidData
+            }
+            _ => ErrorKind::Other,
+        };
+        std::io::Error::new(kind, error)
+    }
+}
+
+/// 文件系统操作的结果
+pub type FsResult<T> = Result<T, FsError>;
+
+/// 为各种错误结果附加上下文，并转换为 [`FsResult`]
+pub trait ResultExt<T> {
+    /// 附加上下文
+    ///
+    /// # Arguments
+    ///
+    /// * `context`: 上下文
+    ///
+    /// returns: Result<T, FsError> 附加了上下文的结果
+    fn context(self, context: ErrorContext) -> FsResult<T>;
+}
+
+impl<T, E: Into<FsError>> ResultExt<T> for Result<T, E> {
+    fn context(self, context: ErrorContext) -> FsResult<T> {
+        self.map_err(|error| error.into().context(context))
+    }
+}
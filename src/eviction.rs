@@ -0,0 +1,269 @@
+use std::collections::VecDeque;
+
+use crate::block_cache::CacheKey;
+
+/// 块缓存的替换策略，由 [`BlockCacheManager`](crate::block_cache::BlockCacheManager) 在块缓存
+/// 加入、命中和被替换出去时通知，需要替换时按策略给出的顺序选择第一个没有被使用的块缓存
+///
+/// 管理器还会优先替换依赖的块都能先写回的块缓存，因此实际被替换的块不一定是策略给出的第一个，
+/// 被替换的块总是通过 [`remove`](Self::remove) 告知策略
+pub trait EvictionPolicy: Send {
+    /// 一个块被加入缓存
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: 块缓存的键
+    fn insert(&mut self, key: CacheKey);
+
+    /// 缓存中的一个块被命中
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: 块缓存的键
+    fn access(&mut self, key: CacheKey);
+
+    /// 一个块被替换出缓存
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: 块缓存的键
+    fn remove(&mut self, key: CacheKey);
+
+    /// 按替换的先后顺序列出缓存中所有的块
+    ///
+    /// returns: Vec<CacheKey, Global> 块缓存的键，越靠前越先被替换
+    fn candidates(&mut self) -> Vec<CacheKey>;
+
+    /// 缓存最多缓存的块数改变了，需要按容量调整的策略在这里调整
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity`: 最多缓存的块数
+    fn resize(&mut self, capacity: usize) {
+        let _ = capacity;
+    }
+}
+
+/// 从双端队列中移除一个键
+///
+/// # Arguments
+///
+/// * `queue`: 队列
+/// * `key`: 块缓存的键
+///
+/// returns: bool 队列中是否有这个键
+fn remove_key(queue: &mut VecDeque<CacheKey>, key: CacheKey) -> bool {
+    match queue.iter().position(|&current| current == key) {
+        Some(idx) => {
+            queue.remove(idx);
+            true
+        }
+        None => false,
+    }
+}
+
+/// 先进先出，按加入缓存的顺序替换，命中不改变顺序，是块缓存默认的策略
+#[derive(Debug, Default)]
+pub struct FifoPolicy {
+    /// 按加入顺序排列的块
+    queue: VecDeque<CacheKey>,
+}
+
+impl EvictionPolicy for FifoPolicy {
+    fn insert(&mut self, key: CacheKey) {
+        self.queue.push_back(key);
+    }
+
+    fn access(&mut self, _key: CacheKey) {}
+
+    fn remove(&mut self, key: CacheKey) {
+        remove_key(&mut self.queue, key);
+    }
+
+    fn candidates(&mut self) -> Vec<CacheKey> {
+        self.queue.iter().copied().collect()
+    }
+}
+
+/// 最近最少使用，先替换最久没有被命中的块
+#[derive(Debug, Default)]
+pub struct LruPolicy {
+    /// 从最久没有使用到最近使用排列的块
+    queue: VecDeque<CacheKey>,
+}
+
+impl EvictionPolicy for LruPolicy {
+    fn insert(&mut self, key: CacheKey) {
+        self.queue.push_back(key);
+    }
+
+    fn access(&mut self, key: CacheKey) {
+        if remove_key(&mut self.queue, key) {
+            self.queue.push_back(key);
+        }
+    }
+
+    fn remove(&mut self, key: CacheKey) {
+        remove_key(&mut self.queue, key);
+    }
+
+    fn candidates(&mut self) -> Vec<CacheKey> {
+        self.queue.iter().copied().collect()
+    }
+}
+
+/// 时钟算法，LRU 的近似：命中只设置引用位，指针扫过时给设置了引用位的块第二次机会
+#[derive(Debug, Default)]
+pub struct ClockPolicy {
+    /// 环形排列的块及其引用位
+    ring: Vec<(CacheKey, bool)>,
+
+    /// 指针，下一次从这里开始扫描
+    hand: usize,
+}
+
+impl EvictionPolicy for ClockPolicy {
+    fn insert(&mut self, key: CacheKey) {
+        // 新块放在指针之前，指针绕一圈之后才会扫到它
+        self.ring.insert(self.hand, (key, false));
+        self.hand = (self.hand + 1) % self.ring.len();
+    }
+
+    fn access(&mut self, key: CacheKey) {
+        if let Some(entry) = self.ring.iter_mut().find(|(current, _)| *current == key) {
+            entry.1 = true;
+        }
+    }
+
+    fn remove(&mut self, key: CacheKey) {
+        let Some(idx) = self.ring.iter().position(|(current, _)| *current == key) else {
+            return;
+        };
+        self.ring.remove(idx);
+        // 指针仍然指向原来的块，被替换的就是它时指向它之后的块
+        if idx < self.hand {
+            self.hand -= 1;
+        }
+        if self.hand >= self.ring.len() {
+            self.hand = 0;
+        }
+    }
+
+    fn candidates(&mut self) -> Vec<CacheKey> {
+        // 从指针开始扫描，清除扫过的引用位，直到遇到第一个没有设置引用位的块
+        let len = self.ring.len();
+        let mut unreferenced = Vec::with_capacity(len);
+        let mut referenced = Vec::new();
+        for i in 0..len {
+            let (key, bit) = &mut self.ring[(self.hand + i) % len];
+            if *bit {
+                if unreferenced.is_empty() {
+                    *bit = false;
+                }
+                referenced.push(*key);
+            } else {
+                unreferenced.push(*key);
+            }
+        }
+        unreferenced.extend(referenced);
+        unreferenced
+    }
+}
+
+/// 自适应替换缓存（ARC）
+/// 缓存中的块分为只被访问过一次的 T1 和被访问过多次的 T2，两者都按 LRU 排列；
+/// 另外记住最近从 T1 和 T2 中替换出去的块（B1 和 B2），它们再次加入缓存时说明对应的一侧太小，
+/// 据此调整 T1 的目标大小，在偏向最近访问和偏向访问频率之间自适应
+#[derive(Debug, Default)]
+pub struct ArcPolicy {
+    /// 最多缓存的块数
+    capacity: usize,
+
+    /// T1 的目标大小
+    target: usize,
+
+    /// 只被访问过一次的块，从最久没有使用到最近使用排列
+    t1: VecDeque<CacheKey>,
+
+    /// 被访问过多次的块，从最久没有使用到最近使用排列
+    t2: VecDeque<CacheKey>,
+
+    /// 最近从 T1 中替换出去的块
+    b1: VecDeque<CacheKey>,
+
+    /// 最近从 T2 中替换出去的块
+    b2: VecDeque<CacheKey>,
+}
+
+impl ArcPolicy {
+    /// 创建给定容量的自适应替换策略，容量之后由块缓存管理器通过 [`resize`](EvictionPolicy::resize) 调整
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity`: 最多缓存的块数
+    ///
+    /// returns: ArcPolicy 替换策略
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    /// 限制记住的被替换的块的数量，T1 和 B1 合计不超过容量，四个列表合计不超过两倍容量
+    fn trim_ghosts(&mut self) {
+        while self.t1.len() + self.b1.len() > self.capacity && self.b1.pop_front().is_some() {}
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * self.capacity
+            && self.b2.pop_front().is_some()
+        {}
+    }
+}
+
+impl EvictionPolicy for ArcPolicy {
+    fn insert(&mut self, key: CacheKey) {
+        if remove_key(&mut self.b1, key) {
+            // 最近从 T1 替换出去的块又被访问，增大 T1 的目标大小
+            let delta = (self.b2.len() / (self.b1.len() + 1)).max(1);
+            self.target = (self.target + delta).min(self.capacity);
+            self.t2.push_back(key);
+        } else if remove_key(&mut self.b2, key) {
+            let delta = (self.b1.len() / (self.b2.len() + 1)).max(1);
+            self.target = self.target.saturating_sub(delta);
+            self.t2.push_back(key);
+        } else {
+            self.t1.push_back(key);
+        }
+        self.trim_ghosts();
+    }
+
+    fn access(&mut self, key: CacheKey) {
+        if remove_key(&mut self.t1, key) || remove_key(&mut self.t2, key) {
+            self.t2.push_back(key);
+        }
+    }
+
+    fn remove(&mut self, key: CacheKey) {
+        if remove_key(&mut self.t1, key) {
+            self.b1.push_back(key);
+        } else if remove_key(&mut self.t2, key) {
+            self.b2.push_back(key);
+        }
+        self.trim_ghosts();
+    }
+
+    fn candidates(&mut self) -> Vec<CacheKey> {
+        // T1 超过目标大小时先从 T1 替换，否则先从 T2 替换
+        let (first, second) = if self.t1.len() > self.target {
+            (&self.t1, &self.t2)
+        } else {
+            (&self.t2, &self.t1)
+        };
+        first.iter().chain(second.iter()).copied().collect()
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.target = self.target.min(capacity);
+        self.trim_ghosts();
+    }
+}
@@ -0,0 +1,167 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use bitflags::bitflags;
+
+use crate::permission::Access;
+use crate::vfs::Inode;
+
+bitflags! {
+    /// 打开文件时的标志
+    pub struct OpenFlags: u32 {
+        /// 只读
+        const RDONLY = 0;
+
+        /// 只写
+        const WRONLY = 1 << 0;
+
+        /// 读写
+        const RDWR = 1 << 1;
+
+        /// 文件不存在时创建
+        const CREATE = 1 << 9;
+
+        /// 与 `CREATE` 一起使用，文件已存在时失败
+        const EXCL = 1 << 10;
+
+        /// 以可写方式打开时清空文件
+        const TRUNC = 1 << 11;
+
+        /// 每次写入都追加到文件末尾
+        const APPEND = 1 << 12;
+    }
+}
+
+impl OpenFlags {
+    /// 是否可读
+    pub fn readable(&self) -> bool {
+        !self.contains(Self::WRONLY)
+    }
+
+    /// 是否可写
+    pub fn writable(&self) -> bool {
+        self.intersects(Self::WRONLY | Self::RDWR)
+    }
+}
+
+/// 文件句柄
+/// 在索引节点之上维护读写位置，实现标准库的 [`Read`]、[`Write`] 和 [`Seek`]
+pub struct FileHandle {
+    /// 索引节点
+    inode: Arc<Inode>,
+
+    /// 当前读写位置
+    offset: usize,
+
+    /// 打开标志
+    flags: OpenFlags,
+}
+
+impl FileHandle {
+    /// 创建一个可读写、读写位置在文件开头的文件句柄
+    ///
+    /// # Arguments
+    ///
+    /// * `inode`: 索引节点
+    ///
+    /// returns: FileHandle 文件句柄
+    pub fn new(inode: Arc<Inode>) -> Self {
+        Self::with_flags(inode, OpenFlags::RDWR)
+    }
+
+    /// 按打开标志创建一个读写位置在文件开头的文件句柄
+    ///
+    /// # Arguments
+    ///
+    /// * `inode`: 索引节点
+    /// * `flags`: 打开标志
+    ///
+    /// returns: FileHandle 文件句柄
+    pub fn with_flags(inode: Arc<Inode>, flags: OpenFlags) -> Self {
+        Self {
+            inode,
+            offset: 0,
+            flags,
+        }
+    }
+
+    /// 获取打开标志
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    /// 获取文件句柄对应的索引节点
+    pub fn inode(&self) -> &Arc<Inode> {
+        &self.inode
+    }
+
+    /// 获取当前读写位置
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// 取出文件句柄对应的索引节点
+    pub fn into_inner(self) -> Arc<Inode> {
+        self.inode
+    }
+}
+
+impl Read for FileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.flags.readable() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not opened for reading",
+            ));
+        }
+        self.inode.check_access(Access::READ)?;
+        let len = self.inode.read_at(self.offset, buf);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+impl Write for FileHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.flags.writable() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not opened for writing",
+            ));
+        }
+        self.inode.check_access(Access::WRITE)?;
+        if self.flags.contains(OpenFlags::APPEND) {
+            let len = self.inode.write_append(buf)?;
+            self.offset = self.inode.size();
+            return Ok(len);
+        }
+        let len = self.inode.write_at(self.offset, buf)?;
+        self.offset += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inode.sync()?;
+        Ok(())
+    }
+}
+
+impl Seek for FileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::End(delta) => (self.inode.size() as i64, delta),
+            SeekFrom::Current(delta) => (self.offset as i64, delta),
+        };
+        match base.checked_add(delta) {
+            Some(offset) if offset >= 0 => {
+                self.offset = offset as usize;
+                Ok(self.offset as u64)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
@@ -0,0 +1,938 @@
+use core::fmt;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use crate::block_cache::{get_block_cache, pin_block_cache, unpin_block_cache};
+use crate::efs::EasyFileSystem;
+use crate::error::{FsError, FsResult, SuperBlockError};
+use crate::layout::{
+    DirEntry, DirEntryType, DiskInode, GroupDescriptor, SuperBlock, Tail, TailBlockHeader,
+    DIRENT_SZ,
+};
+use crate::name::FileName;
+use crate::BLOCK_SZ;
+
+/// 目录条目的问题
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryProblem {
+    /// 名称不是合法的文件名，或者在 `.` 和 `..` 之外又出现了这两个名称
+    BadName,
+
+    /// 与同一目录下前面的目录条目重名
+    DuplicateName,
+
+    /// 指向超出范围或者没有分配的索引节点
+    Dangling(u32),
+
+    /// 记录的索引节点类型与索引节点不一致
+    TypeMismatch(u32),
+
+    /// 指向的索引节点已经被另一个目录条目引用，或者是根目录
+    MultiplyLinked(u32),
+}
+
+/// 检查中发现的一个问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// 超级块无效
+    SuperBlock(SuperBlockError),
+
+    /// 超级块中的空闲数据块计数与数据位图不一致
+    FreeBlocksMismatch {
+        /// 超级块中记录的空闲数据块数
+        stored: u64,
+
+        /// 扫描数据位图统计的空闲数据块数
+        counted: u64,
+    },
+
+    /// 超级块中的空闲索引节点计数与索引节点位图不一致
+    FreeInodesMismatch {
+        /// 超级块中记录的空闲索引节点数
+        stored: u64,
+
+        /// 扫描索引节点位图统计的空闲索引节点数
+        counted: u64,
+    },
+
+    /// 块组描述符中的空闲计数与该块组的位图不一致
+    GroupSummaryMismatch {
+        /// 块组序号
+        group: u32,
+
+        /// 块组描述符中记录的空闲计数
+        stored: GroupDescriptor,
+
+        /// 扫描该块组的位图统计的空闲计数
+        counted: GroupDescriptor,
+    },
+
+    /// 根目录没有分配或者不是目录，此时不检查目录树
+    BadRoot,
+
+    /// 索引节点的校验和不匹配，此时不检查它占用的块
+    InodeChecksum(u32),
+
+    /// 目录内容的校验和不匹配
+    DirChecksum(u32),
+
+    /// 索引节点占用了不能分配给文件的块，即数据区域之外的块、备份超级块或者块组描述符所在的块
+    BadBlock {
+        /// 索引节点ID
+        inode_id: u32,
+
+        /// 块ID
+        block_id: u64,
+    },
+
+    /// 同一个数据块被多个索引节点占用
+    DoubleAllocated {
+        /// 块ID
+        block_id: u64,
+
+        /// 先遇到的占用者
+        first: u32,
+
+        /// 后遇到的占用者
+        second: u32,
+    },
+
+    /// 文件尾部所在的尾部块无效，或者与同一尾部块中的其他尾部重叠
+    BadTail(u32),
+
+    /// 尾部块头部记录的片段使用情况与其中的文件尾部不一致
+    TailFragments(u64),
+
+    /// 在数据位图中已分配，但没有被任何索引节点占用的数据块
+    LeakedBlock(u64),
+
+    /// 被索引节点占用，但在数据位图中空闲的数据块
+    UnmarkedBlock(u64),
+
+    /// 目录的 `.` 或 `..` 不存在，或者没有指向目录自身和父目录
+    BadDotEntries(u32),
+
+    /// 有问题的目录条目
+    BadEntry {
+        /// 目录的索引节点ID
+        dir: u32,
+
+        /// 目录条目的序号
+        index: usize,
+
+        /// 用于显示的条目名称
+        name: String,
+
+        /// 具体的问题
+        problem: EntryProblem,
+    },
+
+    /// 目录中记录的条目数与实际的目录条目数不一致
+    DirEntryCount {
+        /// 目录的索引节点ID
+        dir: u32,
+
+        /// 记录的条目数
+        stored: usize,
+
+        /// 实际的条目数，包括有问题的目录条目
+        counted: usize,
+    },
+
+    /// 已分配但从根目录无法到达的索引节点
+    OrphanInode(u32),
+}
+
+impl fmt::Display for EntryProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryProblem::BadName => f.write_str("invalid name"),
+            EntryProblem::DuplicateName => f.write_str("duplicate name"),
+            EntryProblem::Dangling(inode_id) => {
+                write!(f, "points to unallocated inode {}", inode_id)
+            }
+            EntryProblem::TypeMismatch(inode_id) => {
+                write!(f, "type does not match inode {}", inode_id)
+            }
+            EntryProblem::MultiplyLinked(inode_id) => {
+                write!(f, "inode {} is already linked", inode_id)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::SuperBlock(err) => write!(f, "invalid superblock: {}", err),
+            Problem::FreeBlocksMismatch { stored, counted } => write!(
+                f,
+                "free data block count is {} but bitmap has {}",
+                stored, counted
+            ),
+            Problem::FreeInodesMismatch { stored, counted } => write!(
+                f,
+                "free inode count is {} but bitmap has {}",
+                stored, counted
+            ),
+            Problem::GroupSummaryMismatch {
+                group,
+                stored,
+                counted,
+            } => write!(
+                f,
+                "group {} descriptor has {} free data blocks and {} free inodes but bitmaps have {} and {}",
+                group,
+                stored.free_data_blocks,
+                stored.free_inodes,
+                counted.free_data_blocks,
+                counted.free_inodes
+            ),
+            Problem::BadRoot => f.write_str("root directory is missing"),
+            Problem::InodeChecksum(inode_id) => {
+                write!(f, "inode {} checksum mismatch", inode_id)
+            }
+            Problem::DirChecksum(inode_id) => {
+                write!(f, "directory {} content checksum mismatch", inode_id)
+            }
+            Problem::BadBlock { inode_id, block_id } => write!(
+                f,
+                "inode {} uses block {} outside the data area",
+                inode_id, block_id
+            ),
+            Problem::DoubleAllocated {
+                block_id,
+                first,
+                second,
+            } => write!(
+                f,
+                "block {} is used by both inode {} and inode {}",
+                block_id, first, second
+            ),
+            Problem::BadTail(inode_id) => write!(f, "inode {} has a bad tail", inode_id),
+            Problem::TailFragments(block_id) => {
+                write!(f, "tail block {} fragment map is wrong", block_id)
+            }
+            Problem::LeakedBlock(block_id) => {
+                write!(f, "block {} is allocated but unused", block_id)
+            }
+            Problem::UnmarkedBlock(block_id) => {
+                write!(f, "block {} is used but not allocated", block_id)
+            }
+            Problem::BadDotEntries(dir) => {
+                write!(f, "directory {} has bad `.` or `..` entries", dir)
+            }
+            Problem::BadEntry {
+                dir,
+                index,
+                name,
+                problem,
+            } => write!(
+                f,
+                "entry {} ({:?}) in directory {}: {}",
+                index, name, dir, problem
+            ),
+            Problem::DirEntryCount {
+                dir,
+                stored,
+                counted,
+            } => write!(
+                f,
+                "directory {} records {} entries but has {}",
+                dir, stored, counted
+            ),
+            Problem::OrphanInode(inode_id) => write!(f, "inode {} is unreachable", inode_id),
+        }
+    }
+}
+
+/// 检查的结果
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// 检查过的已分配的索引节点数
+    pub inodes: u64,
+
+    /// 其中的目录数
+    pub directories: u64,
+
+    /// 被占用的数据块数，包括索引块、尾部块、备份超级块和块组描述符所在的块
+    pub used_blocks: u64,
+
+    /// 发现的问题
+    pub problems: Vec<Problem>,
+
+    /// 是否进行了修复
+    pub repaired: bool,
+
+    /// 修复之后再次检查仍然存在的问题，没有修复时与发现的问题相同
+    pub remaining: Vec<Problem>,
+}
+
+impl FsckReport {
+    /// 是否没有发现问题
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// 数据块的引用与数据位图的对照结果，由 [`EasyFileSystem::verify_allocation`] 生成
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllocationReport {
+    /// 被可以到达的索引节点引用的数据块数，包括索引块、尾部块、备份超级块和块组描述符所在的块
+    pub referenced: u64,
+
+    /// 在数据位图中已分配，但没有被可以到达的索引节点引用的数据块，按块ID排列
+    pub leaked: Vec<u64>,
+
+    /// 被可以到达的索引节点引用，但在数据位图中空闲的数据块，按块ID排列
+    pub unmarked: Vec<u64>,
+
+    /// 被多个可以到达的索引节点引用的数据块及其引用者，按块ID排列，不包括共享的尾部块
+    pub doubly_referenced: Vec<(u64, Vec<u32>)>,
+
+    /// 是否收集到了所有的引用，有索引节点损坏或者根目录损坏时为 false，
+    /// 此时泄漏的数据块中可能有实际被引用的块
+    pub complete: bool,
+}
+
+impl AllocationReport {
+    /// 数据位图是否与引用完全一致
+    pub fn is_consistent(&self) -> bool {
+        self.leaked.is_empty() && self.unmarked.is_empty() && self.doubly_referenced.is_empty()
+    }
+}
+
+/// 从磁盘索引节点中读出的检查需要的信息
+struct InodeInfo {
+    /// 是否是目录
+    is_dir: bool,
+
+    /// 是否可以读取其中的内容，校验和不匹配或者占用了无效的块时不读取
+    readable: bool,
+}
+
+/// 一次扫描的结果
+#[derive(Default)]
+struct Scan {
+    /// 发现的问题
+    problems: Vec<Problem>,
+
+    /// 已分配的索引节点
+    inodes: BTreeMap<u32, InodeInfo>,
+
+    /// 每个数据块的占用者，不包括尾部块
+    owners: BTreeMap<u64, Vec<u32>>,
+
+    /// 每个尾部块中的文件尾部及其所属的索引节点
+    tails: BTreeMap<u64, Vec<(u32, Tail)>>,
+
+    /// 备份超级块和块组描述符所在的块
+    reserved: BTreeSet<u64>,
+
+    /// 从根目录可以到达的索引节点，包括根目录和用户配额文件
+    reachable: BTreeSet<u32>,
+
+    /// 需要修复的目录及其中需要清除的目录条目的序号
+    bad_entries: BTreeMap<u32, Vec<usize>>,
+
+    /// 是否有索引节点占用的块没能全部收集，此时重建位图不释放任何数据块
+    incomplete: bool,
+}
+
+/// 检查文件系统的一致性，不做任何修改
+/// 检查超级块、所有已分配的索引节点占用的块与数据位图是否一致，以及从根目录开始的整个目录树；
+/// 调用者需保证检查期间没有其他操作，延迟分配的数据还没有落盘，不在检查范围内
+///
+/// # Arguments
+///
+/// * `efs`: 简易文件系统
+///
+/// returns: FsckReport 检查的结果
+pub fn check(efs: &Arc<Mutex<EasyFileSystem>>) -> FsckReport {
+    let scan = scan(&efs.lock());
+    let mut report = scan.report();
+    report.remaining = report.problems.clone();
+    report
+}
+
+/// 检查文件系统的一致性，并尽可能修复发现的问题
+/// 清除有问题的目录条目，重新统计目录的条目数，按索引节点实际占用的块重建位图、尾部块的片段使用情况
+/// 和超级块、块组描述符中的空闲计数；从根目录无法到达的索引节点及其数据块被释放，
+/// 重复占用的数据块、损坏的索引节点和超级块无法修复，保留在报告中
+/// 调用者需保证修复期间没有其他操作
+///
+/// # Arguments
+///
+/// * `efs`: 简易文件系统
+///
+/// returns: Result<FsckReport, FsError> 修复前后的检查结果，以只读方式打开时返回 [`FsError::ReadOnly`]
+pub fn repair(efs: &Arc<Mutex<EasyFileSystem>>) -> FsResult<FsckReport> {
+    if efs.lock().read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    let first = scan(&efs.lock());
+    let mut report = first.report();
+    // 根目录损坏时所有索引节点都无法到达，不能按目录树重建位图
+    if first.problems.is_empty() || first.problems.contains(&Problem::BadRoot) {
+        report.remaining = report.problems.clone();
+        return Ok(report);
+    }
+
+    // 先重建位图，清除目录条目时释放的目录块在位图中必须是已分配的
+    first.rebuild(&mut efs.lock());
+    for (&dir, indices) in &first.bad_entries {
+        EasyFileSystem::get_inode(efs, dir).repair_entries(indices);
+    }
+
+    // 清除目录条目之后可能有新的无法到达的索引节点
+    let second = scan(&efs.lock());
+    second.rebuild(&mut efs.lock());
+    efs.lock().sync();
+
+    report.repaired = true;
+    report.remaining = scan(&efs.lock()).problems;
+    Ok(report)
+}
+
+/// 对照可以到达的索引节点引用的数据块与数据位图，见 [`EasyFileSystem::verify_allocation`]
+///
+/// # Arguments
+///
+/// * `fs`: 简易文件系统
+///
+/// returns: AllocationReport 对照的结果
+pub(crate) fn verify_allocation(fs: &EasyFileSystem) -> AllocationReport {
+    let scan = scan(fs);
+    let mut referenced = scan.owned_blocks();
+    referenced.extend(
+        scan.tails
+            .iter()
+            .filter(|(_, users)| {
+                users
+                    .iter()
+                    .any(|(inode_id, _)| scan.reachable.contains(inode_id))
+            })
+            .map(|(&block_id, _)| block_id),
+    );
+    let geometry = fs.geometry();
+    let mut allocated = BTreeSet::new();
+    for (group, block_group) in fs.groups.iter().enumerate() {
+        let data_area_start = geometry.data_area_start(group as u32);
+        allocated.extend(
+            block_group
+                .data_bitmap
+                .iter_allocated(&fs.block_device)
+                .map(|bit| data_area_start + bit as u64),
+        );
+    }
+    let doubly_referenced = scan
+        .owners
+        .iter()
+        .filter_map(|(&block_id, owners)| {
+            let owners: Vec<u32> = owners
+                .iter()
+                .copied()
+                .filter(|owner| scan.reachable.contains(owner))
+                .collect();
+            (owners.len() > 1).then_some((block_id, owners))
+        })
+        .collect();
+    AllocationReport {
+        referenced: referenced.len() as u64,
+        leaked: allocated.difference(&referenced).copied().collect(),
+        // 数据区域之外的块不在数据位图中，由 `Problem::BadBlock` 报告
+        unmarked: referenced
+            .difference(&allocated)
+            .copied()
+            .filter(|&block_id| geometry.data_block_position(block_id).is_some())
+            .collect(),
+        doubly_referenced,
+        complete: !scan.incomplete && !scan.problems.contains(&Problem::BadRoot),
+    }
+}
+
+/// 扫描整个文件系统
+///
+/// # Arguments
+///
+/// * `fs`: 简易文件系统
+///
+/// returns: Scan 扫描的结果
+fn scan(fs: &EasyFileSystem) -> Scan {
+    let mut scan = Scan {
+        reserved: fs
+            .geometry()
+            .backup_block_ids()
+            .into_iter()
+            .chain(fs.group_descriptor_block_ids())
+            .collect(),
+        ..Scan::default()
+    };
+    scan.check_super_block(fs);
+    scan.check_group_descriptors(fs);
+    scan.check_inodes(fs);
+    scan.check_tails(fs);
+    scan.check_bitmaps(fs);
+    scan.check_tree(fs);
+    scan
+}
+
+/// 读取一个磁盘索引节点
+///
+/// # Arguments
+///
+/// * `fs`: 简易文件系统
+/// * `inode_id`: 索引节点ID
+/// * `f`: 回调函数
+///
+/// returns: V 回调函数的返回值
+fn read_disk_inode<V>(fs: &EasyFileSystem, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> V {
+    let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
+    let cache = get_block_cache(block_id, fs.block_device.clone());
+    let ret = cache.lock().read(block_offset, f);
+    ret
+}
+
+impl Scan {
+    /// 生成检查的结果
+    fn report(&self) -> FsckReport {
+        let used_blocks = self
+            .owners
+            .keys()
+            .chain(self.tails.keys())
+            .chain(self.reserved.iter())
+            .collect::<BTreeSet<_>>()
+            .len() as u64;
+        FsckReport {
+            inodes: self.inodes.len() as u64,
+            directories: self.inodes.values().filter(|info| info.is_dir).count() as u64,
+            used_blocks,
+            problems: self.problems.clone(),
+            repaired: false,
+            remaining: Vec::new(),
+        }
+    }
+
+    /// 检查超级块和其中的空闲计数
+    fn check_super_block(&mut self, fs: &EasyFileSystem) {
+        let cache = get_block_cache(0, fs.block_device.clone());
+        let (valid, free_data_blocks, free_inodes) =
+            cache.lock().read(0, |super_block: &SuperBlock| {
+                (
+                    super_block.validate(),
+                    super_block.free_data_blocks,
+                    super_block.free_inodes,
+                )
+            });
+        if let Err(err) = valid {
+            self.problems.push(Problem::SuperBlock(err));
+        }
+        let (counted_data_blocks, counted_inodes) = fs.count_free();
+        if free_data_blocks != counted_data_blocks {
+            self.problems.push(Problem::FreeBlocksMismatch {
+                stored: free_data_blocks,
+                counted: counted_data_blocks,
+            });
+        }
+        if free_inodes != counted_inodes {
+            self.problems.push(Problem::FreeInodesMismatch {
+                stored: free_inodes,
+                counted: counted_inodes,
+            });
+        }
+    }
+
+    /// 将块组描述符中的空闲计数与各个块组的位图对照，没有块组描述符时不检查
+    fn check_group_descriptors(&mut self, fs: &EasyFileSystem) {
+        let Some(descriptors) = fs.read_group_descriptors() else {
+            return;
+        };
+        for (group, (block_group, &stored)) in fs.groups.iter().zip(&descriptors).enumerate() {
+            let data_bitmap = &block_group.data_bitmap;
+            let inode_bitmap = &block_group.inode_bitmap;
+            let counted = GroupDescriptor::new(
+                (data_bitmap.maximum() - data_bitmap.count_allocated(&fs.block_device)) as u32,
+                (inode_bitmap.maximum() - inode_bitmap.count_allocated(&fs.block_device)) as u32,
+            );
+            if stored != counted {
+                self.problems.push(Problem::GroupSummaryMismatch {
+                    group: group as u32,
+                    stored,
+                    counted,
+                });
+            }
+        }
+    }
+
+    /// 检查所有已分配的索引节点，收集它们占用的块
+    fn check_inodes(&mut self, fs: &EasyFileSystem) {
+        let geometry = fs.geometry();
+        let metadata_checksum = fs.metadata_checksum();
+        let block_device = &fs.block_device;
+        let is_data_block = |block_id: u64| {
+            geometry.data_block_position(block_id).is_some() && !self.reserved.contains(&block_id)
+        };
+        let mut inodes = BTreeMap::new();
+        let mut problems = Vec::new();
+        let mut owners: BTreeMap<u64, Vec<u32>> = BTreeMap::new();
+        let mut tails: BTreeMap<u64, Vec<(u32, Tail)>> = BTreeMap::new();
+        let mut incomplete = false;
+        for (group, block_group) in fs.groups.iter().enumerate() {
+            for bit in block_group.inode_bitmap.iter_allocated(block_device) {
+                let inode_id = group as u32 * geometry.inodes_per_group + bit as u32;
+                let info = read_disk_inode(fs, inode_id, |disk_inode| {
+                    let is_dir = disk_inode.is_dir();
+                    if metadata_checksum && !disk_inode.verify_checksum(inode_id) {
+                        problems.push(Problem::InodeChecksum(inode_id));
+                        incomplete = true;
+                        return InodeInfo {
+                            is_dir,
+                            readable: false,
+                        };
+                    }
+
+                    // 间接索引树的根超出范围时不能继续读取，
+                    // 区段和内联数据与间接索引共用同一块空间，不能按间接索引解释
+                    let bad_roots: Vec<u64> =
+                        if disk_inode.uses_extents() || disk_inode.has_inline_data() {
+                            Vec::new()
+                        } else {
+                            [
+                                disk_inode.indirect1,
+                                disk_inode.indirect2,
+                                disk_inode.indirect3,
+                            ]
+                            .into_iter()
+                            .map(u64::from)
+                            .filter(|&root| root != 0 && !is_data_block(root))
+                            .collect()
+                        };
+                    let block_ids = if bad_roots.is_empty() {
+                        disk_inode.block_ids(block_device)
+                    } else {
+                        incomplete = true;
+                        bad_roots
+                    };
+                    let mut readable = true;
+                    for block_id in block_ids {
+                        if !is_data_block(block_id) {
+                            problems.push(Problem::BadBlock { inode_id, block_id });
+                            readable = false;
+                            continue;
+                        }
+                        let block_owners = owners.entry(block_id).or_default();
+                        if let Some(&first) = block_owners.first() {
+                            problems.push(Problem::DoubleAllocated {
+                                block_id,
+                                first,
+                                second: inode_id,
+                            });
+                        }
+                        block_owners.push(inode_id);
+                    }
+                    if let Some(tail) = disk_inode.tail() {
+                        if is_data_block(tail.block_id)
+                            && tail.len == disk_inode.size as usize % BLOCK_SZ
+                            && tail.fragment_mask().is_some()
+                        {
+                            tails
+                                .entry(tail.block_id)
+                                .or_default()
+                                .push((inode_id, tail));
+                        } else {
+                            problems.push(Problem::BadTail(inode_id));
+                            readable = false;
+                        }
+                    }
+                    if readable
+                        && metadata_checksum
+                        && !disk_inode.verify_dir_checksum(block_device)
+                    {
+                        problems.push(Problem::DirChecksum(inode_id));
+                    }
+                    InodeInfo { is_dir, readable }
+                });
+                inodes.insert(inode_id, info);
+            }
+        }
+        self.inodes = inodes;
+        self.problems.extend(problems);
+        self.owners = owners;
+        self.tails = tails;
+        self.incomplete = incomplete;
+    }
+
+    /// 检查尾部块的头部及其中的文件尾部
+    fn check_tails(&mut self, fs: &EasyFileSystem) {
+        for (&block_id, users) in &self.tails {
+            if let Some(&first) = self.owners.get(&block_id).and_then(|owners| owners.first()) {
+                self.problems.push(Problem::DoubleAllocated {
+                    block_id,
+                    first,
+                    second: users[0].0,
+                });
+            }
+            let cache = get_block_cache(block_id, fs.block_device.clone());
+            let header = cache.lock().read(0, |header: &TailBlockHeader| {
+                header.is_valid().then(|| header.used())
+            });
+            let Some(used) = header else {
+                self.problems.extend(
+                    users
+                        .iter()
+                        .map(|&(inode_id, _)| Problem::BadTail(inode_id)),
+                );
+                continue;
+            };
+            let mut expected = 1u64;
+            for &(inode_id, tail) in users {
+                let mask = tail.fragment_mask().unwrap();
+                if expected & mask != 0 {
+                    self.problems.push(Problem::BadTail(inode_id));
+                }
+                expected |= mask;
+            }
+            if used != expected {
+                self.problems.push(Problem::TailFragments(block_id));
+            }
+        }
+    }
+
+    /// 将索引节点占用的块与数据位图对照
+    fn check_bitmaps(&mut self, fs: &EasyFileSystem) {
+        let geometry = fs.geometry();
+        for (group, block_group) in fs.groups.iter().enumerate() {
+            let data_bitmap = &block_group.data_bitmap;
+            let data_area_start = geometry.data_area_start(group as u32);
+            let mut allocated = data_bitmap.iter_allocated(&fs.block_device).peekable();
+            for bit in 0..data_bitmap.maximum() {
+                let block_id = data_area_start + bit as u64;
+                let used = self.owners.contains_key(&block_id)
+                    || self.tails.contains_key(&block_id)
+                    || self.reserved.contains(&block_id);
+                match (used, allocated.next_if_eq(&bit).is_some()) {
+                    (true, false) => self.problems.push(Problem::UnmarkedBlock(block_id)),
+                    (false, true) => self.problems.push(Problem::LeakedBlock(block_id)),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// 从根目录开始检查整个目录树，并找出无法到达的索引节点
+    fn check_tree(&mut self, fs: &EasyFileSystem) {
+        if !self.inodes.get(&0).is_some_and(|info| info.is_dir) {
+            self.problems.push(Problem::BadRoot);
+            return;
+        }
+        self.reachable.insert(0);
+        if let Some(quota_inode_id) = fs.user_quota_inode() {
+            if self.inodes.contains_key(&quota_inode_id) {
+                self.reachable.insert(quota_inode_id);
+            }
+        }
+        // 删除了目录条目、但仍被打开的索引节点在最后一个索引节点对象释放时才释放
+        let open_orphans: Vec<u32> = self
+            .inodes
+            .keys()
+            .copied()
+            .filter(|&inode_id| fs.is_orphan(inode_id))
+            .collect();
+        self.reachable.extend(open_orphans);
+        let mut queue = VecDeque::from([(0, 0)]);
+        // 子卷目录和根目录一样是自己的父目录
+        if let Some(subvolume_dir) = fs.subvolume_dir() {
+            if self
+                .inodes
+                .get(&subvolume_dir)
+                .is_some_and(|info| info.is_dir)
+            {
+                self.reachable.insert(subvolume_dir);
+                queue.push_back((subvolume_dir, subvolume_dir));
+            }
+        }
+        while let Some((dir, parent)) = queue.pop_front() {
+            if self.inodes[&dir].readable {
+                self.check_dir(fs, dir, parent, &mut queue);
+            }
+        }
+        let orphans: Vec<u32> = self
+            .inodes
+            .keys()
+            .copied()
+            .filter(|inode_id| !self.reachable.contains(inode_id))
+            .collect();
+        self.problems
+            .extend(orphans.into_iter().map(Problem::OrphanInode));
+    }
+
+    /// 检查一个目录中的所有目录条目，把其中的子目录加入队列
+    ///
+    /// # Arguments
+    ///
+    /// * `fs`: 简易文件系统
+    /// * `dir`: 目录的索引节点ID
+    /// * `parent`: 父目录的索引节点ID
+    /// * `queue`: 待检查的目录及其父目录
+    fn check_dir(
+        &mut self,
+        fs: &EasyFileSystem,
+        dir: u32,
+        parent: u32,
+        queue: &mut VecDeque<(u32, u32)>,
+    ) {
+        let free_slots = fs.dir_free_slots();
+        let subvolumes = fs.subvolume_dir() == Some(dir);
+        let (stored, dirents) = read_disk_inode(fs, dir, |disk_inode| {
+            let dirents: Vec<DirEntry> = (0..disk_inode.dir_slots())
+                .map(|slot| {
+                    let mut dirent = DirEntry::empty();
+                    disk_inode.read_at(slot * DIRENT_SZ, dirent.as_bytes_mut(), &fs.block_device);
+                    dirent
+                })
+                .collect();
+            (disk_inode.dir_entries(), dirents)
+        });
+
+        let dot_entries = [(FileName::DOT, dir), (FileName::DOT_DOT, parent)];
+        let dots_valid = dot_entries
+            .iter()
+            .enumerate()
+            .all(|(index, (name, inode_id))| {
+                dirents.get(index).is_some_and(|dirent| {
+                    dirent.name_bytes() == name.as_bytes() && dirent.inode_number() == *inode_id
+                })
+            });
+        if !dots_valid {
+            self.problems.push(Problem::BadDotEntries(dir));
+        }
+
+        let mut counted = 0;
+        let mut names = BTreeSet::new();
+        let mut bad = Vec::new();
+        for (index, dirent) in dirents.iter().enumerate() {
+            if free_slots && dirent.is_free() {
+                continue;
+            }
+            counted += 1;
+            if dots_valid && index < dot_entries.len() {
+                continue;
+            }
+            let inode_id = dirent.inode_number();
+            let problem = if FileName::from_bytes(dirent.name_bytes()).is_err()
+                || dot_entries
+                    .iter()
+                    .any(|(name, _)| dirent.name_bytes() == name.as_bytes())
+            {
+                Some(EntryProblem::BadName)
+            } else if !names.insert(dirent.name_bytes().to_vec()) {
+                Some(EntryProblem::DuplicateName)
+            } else if let Some(info) = self.inodes.get(&inode_id) {
+                let expected = if info.is_dir {
+                    DirEntryType::Directory
+                } else {
+                    DirEntryType::File
+                };
+                let entry_type = dirent.entry_type();
+                if entry_type != DirEntryType::Unknown && entry_type != expected {
+                    Some(EntryProblem::TypeMismatch(inode_id))
+                } else if !self.reachable.insert(inode_id) {
+                    Some(EntryProblem::MultiplyLinked(inode_id))
+                } else {
+                    if info.is_dir {
+                        // 子卷的根目录是它自己的父目录
+                        let parent = if subvolumes { inode_id } else { dir };
+                        queue.push_back((inode_id, parent));
+                    }
+                    None
+                }
+            } else {
+                Some(EntryProblem::Dangling(inode_id))
+            };
+            if let Some(problem) = problem {
+                self.problems.push(Problem::BadEntry {
+                    dir,
+                    index,
+                    name: dirent.name_lossy().into_owned(),
+                    problem,
+                });
+                bad.push(index);
+            }
+        }
+
+        if counted != stored {
+            self.problems.push(Problem::DirEntryCount {
+                dir,
+                stored,
+                counted,
+            });
+        }
+        if !bad.is_empty() || counted != stored {
+            self.bad_entries.insert(dir, bad);
+        }
+    }
+
+    /// 可以到达的索引节点占用的块、备份超级块和块组描述符所在的块，不包括尾部块
+    fn owned_blocks(&self) -> BTreeSet<u64> {
+        let mut blocks = self.reserved.clone();
+        blocks.extend(
+            self.owners
+                .iter()
+                .filter(|(_, owners)| owners.iter().any(|owner| self.reachable.contains(owner)))
+                .map(|(&block_id, _)| block_id),
+        );
+        blocks
+    }
+
+    /// 按可以到达的索引节点实际占用的块重建位图和尾部块的片段使用情况
+    /// 修改过的尾部块在位图重建完成之前被固定，不会先于位图写回
+    ///
+    /// # Arguments
+    ///
+    /// * `fs`: 简易文件系统
+    fn rebuild(&self, fs: &mut EasyFileSystem) {
+        let mut pinned = Vec::new();
+        let mut data_blocks = self.owned_blocks();
+        if self.incomplete {
+            let geometry = fs.geometry();
+            for (group, block_group) in fs.groups.iter().enumerate() {
+                let data_area_start = geometry.data_area_start(group as u32);
+                data_blocks.extend(
+                    block_group
+                        .data_bitmap
+                        .iter_allocated(&fs.block_device)
+                        .map(|bit| data_area_start + bit as u64),
+                );
+            }
+        }
+        for (&block_id, users) in &self.tails {
+            let used = users
+                .iter()
+                .filter(|(inode_id, _)| self.reachable.contains(inode_id))
+                .fold(0, |used, (_, tail)| used | tail.fragment_mask().unwrap());
+            if used == 0 || data_blocks.contains(&block_id) {
+                continue;
+            }
+            data_blocks.insert(block_id);
+            // 缓存中固定不了更多的块时只是不再保证写回的顺序
+            let cache = match pin_block_cache(block_id, fs.block_device.clone()) {
+                Ok(cache) => {
+                    pinned.push(block_id);
+                    cache
+                }
+                Err(_) => get_block_cache(block_id, fs.block_device.clone()),
+            };
+            cache.lock().modify(0, |header: &mut TailBlockHeader| {
+                if header.is_valid() && header.used() != used | 1 {
+                    header.set_used(used);
+                }
+            });
+        }
+        fs.rebuild_bitmaps(&self.reachable, &data_blocks);
+        for block_id in pinned {
+            unpin_block_cache(block_id, &fs.block_device);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::sync::Arc;
+
+use crate::block_cache::get_block_cache;
+use crate::block_device::BlockDevice;
+use crate::error::{FsError, FsResult, SuperBlockError};
+use crate::layout::{JournalDeviceSuperBlock, JournalHeader, JOURNAL_MAX_BLOCKS};
+use crate::BLOCK_SZ;
+
+/// 数据块
+type DataBlock = [u8; BLOCK_SZ];
+
+/// 打开文件系统时日志恢复的结果，由 [`crate::efs::EasyFileSystem::recovery`] 返回
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JournalRecovery {
+    /// 日志中留下的事务的序号，上次正常完成了所有检查点时为 None
+    pub sequence: Option<u32>,
+
+    /// 重放的块数
+    pub replayed_blocks: usize,
+
+    /// 事务不完整而被丢弃时为 true，此时原位置的块没有被修改过
+    pub discarded: bool,
+}
+
+impl JournalRecovery {
+    /// 是否重放了一个已提交的事务
+    pub fn replayed(&self) -> bool {
+        self.sequence.is_some() && !self.discarded
+    }
+}
+
+#[derive(Debug)]
+/// 预写日志
+///
+/// 一个事务按以下顺序落盘：
+/// 1. 将块的新内容写入日志区域
+/// 2. 写入记录了原位置块ID的日志头，此时事务提交
+/// 3. 由调用者将块写回原位置（检查点）
+/// 4. 清空日志头
+///
+/// 每一步之间都会刷新块设备作为写屏障
+pub struct Journal {
+    /// 块设备
+    block_device: Arc<dyn BlockDevice>,
+
+    /// 日志区域起始块ID
+    start_block: u64,
+
+    /// 日志区域块数
+    blocks: usize,
+
+    /// 下一个事务的序号
+    sequence: u32,
+
+    /// 外部日志设备的 UUID，内部日志为全零
+    uuid: [u8; 16],
+}
+
+impl Journal {
+    /// 打开块设备上的日志区域
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    /// * `start_block`: 日志区域起始块ID
+    /// * `blocks`: 日志区域块数
+    ///
+    /// returns: Journal 日志
+    pub fn new(block_device: Arc<dyn BlockDevice>, start_block: u64, blocks: usize) -> Self {
+        assert!(blocks >= 2, "Journal is too small!");
+        let mut journal = Self {
+            block_device,
+            start_block,
+            blocks,
+            sequence: 0,
+            uuid: [0u8; 16],
+        };
+        let header = journal.read_header();
+        if header.is_valid() {
+            journal.sequence = header.sequence.wrapping_add(1);
+        }
+        journal
+    }
+
+    /// 将块设备格式化为外部日志设备
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 日志设备
+    /// * `journal_blocks`: 日志区域块数
+    ///
+    /// returns: Journal 日志
+    pub fn create_external(block_device: Arc<dyn BlockDevice>, journal_blocks: u32) -> Self {
+        let uuid: [u8; 16] = rand::random();
+        let super_block = JournalDeviceSuperBlock::new(uuid, journal_blocks);
+        block_device.write_block(0, super_block.as_bytes());
+        block_device.write_block(1, &[0u8; BLOCK_SZ]);
+        block_device.flush();
+        let mut journal = Self::new(block_device, 1, journal_blocks as usize);
+        journal.uuid = uuid;
+        journal
+    }
+
+    /// 打开外部日志设备，并检查它是否属于给定的文件系统
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 日志设备
+    /// * `uuid`: 文件系统超级块中记录的日志设备 UUID
+    ///
+    /// returns: Result<Journal, FsError> 日志，日志设备无效时返回 [`FsError::Corrupted`]，
+    /// 不属于该文件系统时返回 [`FsError::InvalidArgument`]
+    pub fn open_external(block_device: Arc<dyn BlockDevice>, uuid: [u8; 16]) -> FsResult<Self> {
+        let mut super_block = JournalDeviceSuperBlock::new([0u8; 16], 0);
+        block_device.read_block(0, super_block.as_bytes_mut());
+        if !super_block.is_valid() {
+            return Err(FsError::Corrupted);
+        }
+        if super_block.uuid != uuid {
+            return Err(FsError::InvalidArgument);
+        }
+        if let Some(device_blocks) = block_device.num_blocks() {
+            let total = 1 + super_block.journal_blocks as u64;
+            if total > device_blocks {
+                return Err(SuperBlockError::DeviceTooSmall {
+                    total,
+                    device: device_blocks,
+                }
+                .into());
+            }
+        }
+        let mut journal = Self::new(block_device, 1, super_block.journal_blocks as usize);
+        journal.uuid = uuid;
+        Ok(journal)
+    }
+
+    /// 获取外部日志设备的 UUID，内部日志为全零
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    /// 一个事务最多能记录的块数
+    pub fn capacity(&self) -> usize {
+        (self.blocks - 1).min(JOURNAL_MAX_BLOCKS)
+    }
+
+    /// 读取日志头
+    fn read_header(&self) -> JournalHeader {
+        let mut header = JournalHeader::new(0, &[]);
+        self.block_device
+            .read_block(self.start_block, header.as_bytes_mut());
+        header
+    }
+
+    /// 将块的新内容写入日志并提交事务
+    /// 返回后事务已经持久化，调用者可以开始将这些块写回原位置
+    ///
+    /// # Arguments
+    ///
+    /// * `blocks`: 块ID及其新内容
+    pub fn log(&mut self, blocks: &[(u64, DataBlock)]) {
+        assert!(
+            blocks.len() <= self.capacity(),
+            "Journal transaction is too large!"
+        );
+
+        // 写入块内容
+        for (i, (_, data)) in blocks.iter().enumerate() {
+            self.block_device
+                .write_block(self.start_block + 1 + i as u64, data);
+        }
+        self.block_device.flush();
+
+        // 写入日志头，提交事务
+        let block_ids: Vec<u64> = blocks.iter().map(|(id, _)| *id).collect();
+        let mut header = JournalHeader::new(self.sequence, &block_ids);
+        header.update_checksum(blocks.iter().map(|(_, data)| data));
+        self.block_device
+            .write_block(self.start_block, header.as_bytes());
+        self.block_device.flush();
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+
+    /// 检查日志中是否留下了已提交但没有完成检查点的事务
+    /// 完整的事务通过块缓存重放到原位置，日志头或者块内容与校验和不一致的事务是提交时被打断的，直接丢弃；
+    /// 两种情况最后都清空日志头
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 文件系统所在的块设备
+    /// * `limit`: 事务中的块ID必须小于它，否则视为不完整的事务
+    ///
+    /// returns: JournalRecovery 恢复的结果
+    pub fn recover(&mut self, block_device: &Arc<dyn BlockDevice>, limit: u64) -> JournalRecovery {
+        let header = self.read_header();
+        if !header.is_valid() || header.count == 0 {
+            return JournalRecovery::default();
+        }
+        let block_ids = header.block_ids();
+        let contents: Vec<DataBlock> = (0..block_ids.len().min(self.blocks - 1))
+            .map(|i| {
+                let mut data = [0u8; BLOCK_SZ];
+                self.block_device
+                    .read_block(self.start_block + 1 + i as u64, &mut data);
+                data
+            })
+            .collect();
+        let intact = header.count as usize <= self.capacity()
+            && block_ids.iter().all(|&block_id| block_id < limit)
+            && header.verify_checksum(contents.iter());
+        if intact {
+            for (&block_id, data) in block_ids.iter().zip(contents.iter()) {
+                let cache = get_block_cache(block_id, block_device.clone());
+                let mut cache = cache.lock();
+                cache.modify(0, |block: &mut DataBlock| *block = *data);
+                cache.sync();
+            }
+            block_device.flush();
+        }
+        self.sequence = header.sequence.wrapping_add(1);
+        self.checkpoint();
+        JournalRecovery {
+            sequence: Some(header.sequence),
+            replayed_blocks: if intact { block_ids.len() } else { 0 },
+            discarded: !intact,
+        }
+    }
+
+    /// 在事务中的块都写回原位置后清空日志头
+    pub fn checkpoint(&mut self) {
+        self.block_device.flush();
+        let header = JournalHeader::new(self.sequence.wrapping_sub(1), &[]);
+        self.block_device
+            .write_block(self.start_block, header.as_bytes());
+        self.block_device.flush();
+    }
+}
@@ -1,17 +1,72 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::str::Utf8Error;
 use std::sync::Arc;
 
-use crate::block_cache::get_block_cache;
+use bitflags::bitflags;
+
+use crate::block_cache::{block_cache_prefetch, get_block_cache};
 use crate::block_device::BlockDevice;
+use crate::checksum::{crc32, crc32_update};
+use crate::error::SuperBlockError;
+use crate::name::FileName;
+use crate::pod::{bytes_of, bytes_of_mut};
 use crate::{nop, BLOCK_SZ};
 
-/// 简易文件系统的魔数
-const EFS_MAGIC: u32 = 0x3b800001;
+/// 当前的磁盘格式版本
+/// 版本 2 加入三级间接索引，直接索引减少到 26 个，文件名最长 26 字节；
+/// 版本 3 将文件大小和总块数扩展为 64 位，版本 4 将磁盘索引节点扩大到 256 字节并加入时间戳，
+/// 旧版本的镜像不能直接打开，需要先用 [`crate::migrate::migrate`] 迁移
+pub const EFS_VERSION: u32 = 4;
+
+/// 简易文件系统魔数中与版本无关的部分
+pub const EFS_MAGIC_BASE: u32 = 0x3b800000;
+
+/// 简易文件系统的魔数，最低字节为磁盘格式版本
+const EFS_MAGIC: u32 = EFS_MAGIC_BASE | EFS_VERSION;
+
+/// 最小的块大小，也是旧镜像的块大小
+const MIN_BLOCK_SZ: usize = 512;
+
+/// 卷标的最大字节数
+pub const LABEL_LENGTH_LIMIT: usize = 32;
+
+/// 新建文件系统时默认保留的数据块百分比
+pub const DEFAULT_RESERVED_PERCENT: u32 = 5;
+
+/// 保留的数据块百分比的上限
+pub const MAX_RESERVED_PERCENT: u32 = 50;
+
+/// 一个位图块中的比特数
+const BLOCK_BITS: u64 = BLOCK_SZ as u64 * 8;
+
+/// 完整块组的块数，使一个数据位图块基本可以覆盖一个块组
+const BLOCKS_PER_GROUP: u64 = BLOCK_BITS;
+
+/// 块ID的最大值，块地址最多 48 位
+pub const MAX_BLOCK_ID: u64 = (1 << 48) - 1;
+
+/// 直接索引和间接索引只能记录 32 位的块ID，超出时需要开启 [`IncompatFeatures::ADDRESS_48BIT`]
+const MAX_BLOCK_MAP_ID: u64 = u32::MAX as u64;
+
+/// 备份超级块的间隔，块ID为该值整数倍且落在数据区域中的块存放超级块的备份
+pub const BACKUP_SUPER_BLOCK_INTERVAL: u64 = 8192;
+
+/// 日志头的魔数
+const JOURNAL_MAGIC: u32 = 0x6a726e6c;
+
+/// 外部日志设备超级块的魔数
+const JOURNAL_DEVICE_MAGIC: u32 = 0x6a646576;
+
+/// 一个日志事务最多记录的块数
+pub const JOURNAL_MAX_BLOCKS: usize = (BLOCK_SZ - 16) / 6;
 
 /// 直接索引节点的最大数量
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 25;
 
 /// 索引节点名称的最大长度
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 26;
 
 /// 一级间接索引节点的最大数量
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
@@ -19,6 +74,9 @@ const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// 二级间接索引节点的最大数量
 const INODE_INDIRECT2_COUNT: usize = INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
 
+/// 三级间接索引节点的最大数量
+const INODE_INDIRECT3_COUNT: usize = INODE_INDIRECT2_COUNT * INODE_INDIRECT1_COUNT;
+
 /// 直接索引节点的上界
 const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 
@@ -26,291 +84,2877 @@ const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 
 /// 二级间接索引节点的上界
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
 
+/// 三级间接索引节点的上界
+const INDIRECT3_BOUND: usize = INDIRECT2_BOUND + INODE_INDIRECT3_COUNT;
+
+/// 索引节点中用于映射数据块的字数，即直接索引节点和三个间接索引节点
+const INODE_MAP_WORDS: usize = INODE_DIRECT_COUNT + 3;
+
+/// 新建文件的默认权限位
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// 新建目录的默认权限位
+pub const DEFAULT_DIR_MODE: u32 = 0o755;
+
+/// 内联数据的最大字节数，内联数据存放在用于映射数据块的字中
+pub const INLINE_DATA_CAPACITY: usize = INODE_MAP_WORDS * 4;
+
+/// 尾部块中一个片段的字节数，打包的文件尾部按片段分配
+pub const TAIL_FRAGMENT_SZ: usize = 64;
+
+/// 一个尾部块中的片段数，第一个片段存放 [`TailBlockHeader`]
+pub const TAIL_FRAGMENTS: usize = BLOCK_SZ / TAIL_FRAGMENT_SZ;
+
+/// 不超过这个字节数的文件尾部才会被打包，更大的尾部打包后节省的空间不多
+pub const TAIL_PACK_LIMIT: usize = BLOCK_SZ / 2;
+
+/// 读取数据时一次成批加载的块数，也是顺序读取时在读取范围之后预读的块数
+pub const READ_AHEAD_BLOCKS: usize = 8;
+
+/// 尾部块的魔数
+const TAIL_BLOCK_MAGIC: u32 = 0x7461696c;
+
+/// 区段树节点头部的字数：条目数和节点深度
+const EXTENT_HEADER_WORDS: usize = 2;
+
+/// 一个区段树条目的字数
+const EXTENT_WORDS: usize = 3;
+
+/// 索引节点中的区段树根最多容纳的条目数
+const EXTENT_ROOT_CAPACITY: usize = (INODE_MAP_WORDS - EXTENT_HEADER_WORDS) / EXTENT_WORDS;
+
+/// 一个区段树节点块最多容纳的条目数
+const EXTENT_NODE_CAPACITY: usize = (BLOCK_SZ / 4 - EXTENT_HEADER_WORDS) / EXTENT_WORDS;
+
+/// 一个区段最多包含的块数，区段条目中块数字段的高 16 位用来存放起始块ID的高 16 位
+/// 块组之间隔着元数据，一个块组的数据区域不超过这个值，因此旧镜像中的区段不会超出
+const MAX_EXTENT_LEN: u32 = u16::MAX as u32;
+
 /// 文件系统超级块
 #[repr(C)]
 #[derive(Debug)]
 pub struct SuperBlock {
-    /// 魔数
+    /// 魔数，最低字节为磁盘格式版本，见 [`SuperBlock::version`]
     magic: u32,
 
-    /// 总块数
-    pub total_blocks: u32,
-
-    /// 索引节点位图块数
+    /// 每个块组的索引节点位图块数
     pub inode_bitmap_blocks: u32,
 
-    /// 索引节点区域块数
+    /// 每个块组的索引节点区域块数
     pub inode_area_blocks: u32,
 
-    /// 数据位图块数
+    /// 每个块组的数据位图块数
     pub data_bitmap_blocks: u32,
 
-    /// 数据区域块数
+    /// 每个完整块组的数据区域块数，最后一个块组可能更少
     pub data_area_blocks: u32,
+
+    /// 日志区域块数，日志区域位于设备末尾
+    /// 使用外部日志设备时为零
+    pub journal_blocks: u32,
+
+    /// 总块数
+    pub total_blocks: u64,
+
+    /// 外部日志设备的 UUID，全零表示使用内部日志
+    pub journal_uuid: [u8; 16],
+
+    /// 文件系统的 UUID，创建时随机生成
+    pub uuid: [u8; 16],
+
+    /// 卷标，不足的部分以零填充
+    label: [u8; LABEL_LENGTH_LIMIT],
+
+    /// 兼容特性，见 [`CompatFeatures`]
+    feature_compat: u32,
+
+    /// 只读兼容特性，见 [`RoCompatFeatures`]
+    feature_ro_compat: u32,
+
+    /// 不兼容特性，见 [`IncompatFeatures`]
+    feature_incompat: u32,
+
+    /// 超级块的 CRC32，计算时该字段视为零，
+    /// 只在设置了 [`RoCompatFeatures::SUPERBLOCK_CHECKSUM`] 时有效
+    checksum: u32,
+
+    /// 空闲的数据块数，只在设置了 [`RoCompatFeatures::FREE_COUNTERS`] 时有效
+    pub free_data_blocks: u64,
+
+    /// 空闲的索引节点数，只在设置了 [`RoCompatFeatures::FREE_COUNTERS`] 时有效
+    pub free_inodes: u64,
+
+    /// 文件系统状态，见 [`SuperBlockState`]
+    state: u32,
+
+    /// 挂载次数
+    pub mount_count: u32,
+
+    /// 上次挂载的时间，自 UNIX 纪元以来的纳秒数
+    pub last_mount_time: u64,
+
+    /// 块组数，旧镜像中为零，此时整个文件系统是一个块组
+    group_count: u32,
+
+    /// 每个完整块组的块数
+    blocks_per_group: u32,
+
+    /// 每个块组的索引节点数
+    inodes_per_group: u32,
+
+    /// 块大小为 512 字节左移该值，旧镜像中为零，即 512 字节
+    log_block_size: u32,
+
+    /// 保留给特权写入者的数据块百分比，旧镜像中为零，即不保留
+    pub reserved_percent: u32,
+
+    /// 用户配额文件的索引节点ID，只在设置了 [`RoCompatFeatures::USER_QUOTA`] 时有效
+    pub user_quota_inode: u32,
+
+    /// 子卷目录的索引节点ID，只在设置了 [`CompatFeatures::SUBVOLUMES`] 时有效
+    pub subvolume_dir: u32,
+
+    /// 保留，使超级块的大小是 8 的倍数
+    _reserved: u32,
+}
+
+bitflags! {
+    /// 文件系统状态
+    pub struct SuperBlockState: u32 {
+        /// 文件系统已被修改且尚未同步，打开时仍然设置说明上次没有正常卸载
+        const DIRTY = 1 << 0;
+    }
+}
+
+bitflags! {
+    /// 兼容特性，不认识这些特性的实现仍然可以正常读写
+    pub struct CompatFeatures: u32 {
+        /// 文件系统带有日志
+        const HAS_JOURNAL = 1 << 0;
+
+        /// 文件系统中有子卷，它们的根目录是子卷目录中的目录条目，不认识的实现只是看不到子卷
+        const SUBVOLUMES = 1 << 1;
+    }
+}
+
+bitflags! {
+    /// 只读兼容特性，不认识这些特性的实现只能以只读方式打开
+    pub struct RoCompatFeatures: u32 {
+        /// 文件中可能有空洞，块ID为 0 表示空洞
+        const SPARSE_FILES = 1 << 0;
+
+        /// 超级块带有校验和，不认识的实现修改超级块后校验和会失效
+        const SUPERBLOCK_CHECKSUM = 1 << 1;
+
+        /// 超级块中维护空闲块数和空闲索引节点数，不认识的实现分配和释放时不会更新它们
+        const FREE_COUNTERS = 1 << 2;
+
+        /// 索引节点和目录内容带有校验和，不认识的实现修改它们后校验和会失效
+        const METADATA_CHECKSUM = 1 << 3;
+
+        /// 用户配额文件中记录着每个用户占用的空间，不认识的实现分配和释放时不会更新它
+        const USER_QUOTA = 1 << 4;
+
+        /// 块组描述符中维护每个块组的空闲块数和空闲索引节点数，见 [`GroupDescriptor`]，
+        /// 不认识的实现分配和释放时不会更新它们
+        const GROUP_DESCRIPTORS = 1 << 5;
+    }
+}
+
+bitflags! {
+    /// 不兼容特性，不认识这些特性的实现不能打开
+    pub struct IncompatFeatures: u32 {
+        /// 索引节点可能使用区段树映射数据块
+        const EXTENTS = 1 << 0;
+
+        /// 索引节点可能将文件内容直接存放在索引节点中
+        const INLINE_DATA = 1 << 1;
+
+        /// 文件系统被划分为多个块组
+        const BLOCK_GROUPS = 1 << 2;
+
+        /// 块大小不是 512 字节，不认识块大小的实现会按错误的块大小读取镜像
+        const LARGE_BLOCKS = 1 << 3;
+
+        /// 块ID可能超过 32 位，所有索引节点都使用区段树映射数据块，
+        /// 日志和区段中的块ID都按 48 位记录
+        const ADDRESS_48BIT = 1 << 4;
+
+        /// 文件的尾部可能被打包到与其它文件共享的尾部块中
+        const TAIL_PACKING = 1 << 5;
+
+        /// 删除目录条目时在目录中留下空位，不认识的实现会把空位当作目录条目
+        const DIR_FREE_SLOTS = 1 << 6;
+    }
 }
 
 impl SuperBlock {
-    /// 初始化超级块
+    /// 按块组布局初始化超级块
     ///
     /// # Arguments
     ///
-    /// * `total_blocks`: 总块数
-    /// * `inode_bitmap_blocks`: 索引节点位图块数
-    /// * `inode_area_blocks`: 索引节点区域块数
-    /// * `data_bitmap_blocks`: 数据位图块数
-    /// * `data_area_blocks`: 数据区域块数
-    pub fn initialize(
-        &mut self,
-        total_blocks: u32,
-        inode_bitmap_blocks: u32,
-        inode_area_blocks: u32,
-        data_bitmap_blocks: u32,
-        data_area_blocks: u32,
-    ) {
+    /// * `geometry`: 块组布局
+    pub fn initialize(&mut self, geometry: &Geometry) {
         *self = Self {
             magic: EFS_MAGIC,
-            total_blocks,
-            inode_bitmap_blocks,
-            inode_area_blocks,
-            data_bitmap_blocks,
-            data_area_blocks,
+            inode_bitmap_blocks: geometry.inode_bitmap_blocks,
+            inode_area_blocks: geometry.inode_area_blocks,
+            data_bitmap_blocks: geometry.data_bitmap_blocks,
+            data_area_blocks: geometry.data_area_blocks(0),
+            journal_blocks: geometry.journal_blocks,
+            total_blocks: geometry.total_blocks,
+            journal_uuid: [0u8; 16],
+            uuid: rand::random(),
+            label: [0u8; LABEL_LENGTH_LIMIT],
+            feature_compat: CompatFeatures::HAS_JOURNAL.bits(),
+            feature_ro_compat: RoCompatFeatures::all().bits(),
+            feature_incompat: Self::incompat_features_for(geometry).bits(),
+            checksum: 0,
+            free_data_blocks: geometry.total_data_blocks(),
+            free_inodes: geometry.total_inodes(),
+            state: 0,
+            mount_count: 0,
+            last_mount_time: 0,
+            group_count: geometry.group_count,
+            blocks_per_group: geometry.blocks_per_group,
+            inodes_per_group: geometry.inodes_per_group,
+            log_block_size: (BLOCK_SZ / MIN_BLOCK_SZ).trailing_zeros(),
+            reserved_percent: DEFAULT_RESERVED_PERCENT,
+            user_quota_inode: 0,
+            subvolume_dir: 0,
+            _reserved: 0,
+        };
+        self.update_checksum();
+    }
+
+    /// 按块组布局和块大小选择需要开启的不兼容特性
+    /// 只在需要时开启 [`IncompatFeatures::LARGE_BLOCKS`] 和 [`IncompatFeatures::ADDRESS_48BIT`]，
+    /// 使旧的实现仍然可以打开不需要它们的镜像
+    ///
+    /// # Arguments
+    ///
+    /// * `geometry`: 块组布局
+    ///
+    /// returns: IncompatFeatures 不兼容特性
+    fn incompat_features_for(geometry: &Geometry) -> IncompatFeatures {
+        let mut features = IncompatFeatures::all();
+        if BLOCK_SZ == MIN_BLOCK_SZ {
+            features -= IncompatFeatures::LARGE_BLOCKS;
         }
+        if geometry.total_blocks - 1 <= MAX_BLOCK_MAP_ID {
+            features -= IncompatFeatures::ADDRESS_48BIT;
+        }
+        features
     }
 
     /// 使用魔数检查超级块是否有效
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+
+    /// 获取磁盘格式版本，即魔数的最低字节
+    pub fn version(&self) -> u32 {
+        self.magic & 0xff
+    }
+
+    /// 计算超级块的 CRC32，校验和字段本身视为零
+    pub fn compute_checksum(&self) -> u32 {
+        let bytes = bytes_of(self);
+        let offset = core::mem::offset_of!(SuperBlock, checksum);
+        let crc = crc32(&bytes[..offset]);
+        crc32_update(crc, &bytes[offset + 4..])
+    }
+
+    /// 在修改超级块之后重新计算校验和
+    pub fn update_checksum(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    /// 全面检查超级块：魔数、校验和，各区域的块数是否与总块数一致且互不重叠，以及计数是否在范围内
+    ///
+    /// returns: Result<(), SuperBlockError> 第一个发现的问题
+    pub fn validate(&self) -> Result<(), SuperBlockError> {
+        if !self.is_valid() {
+            if self.magic & !0xff == EFS_MAGIC_BASE {
+                return Err(SuperBlockError::UnsupportedVersion(self.version()));
+            }
+            return Err(SuperBlockError::BadMagic(self.magic));
+        }
+        if self
+            .ro_compat_features()
+            .contains(RoCompatFeatures::SUPERBLOCK_CHECKSUM)
+        {
+            let computed = self.compute_checksum();
+            if computed != self.checksum {
+                return Err(SuperBlockError::ChecksumMismatch {
+                    stored: self.checksum,
+                    computed,
+                });
+            }
+        }
+
+        // 块大小在编译时确定，只能打开块大小相同的镜像
+        if self.block_size() != BLOCK_SZ {
+            return Err(SuperBlockError::BlockSizeMismatch {
+                stored: self.block_size(),
+                supported: BLOCK_SZ,
+            });
+        }
+
+        if !self.addressable(self.total_blocks) {
+            return Err(SuperBlockError::AddressOverflow(self.total_blocks));
+        }
+
+        let geometry = self.geometry();
+        if geometry.group_count == 0 {
+            return Err(SuperBlockError::EmptyRegion("block groups"));
+        }
+        if self.inode_bitmap_blocks == 0 {
+            return Err(SuperBlockError::EmptyRegion("inode bitmap"));
+        }
+        if self.data_bitmap_blocks == 0 {
+            return Err(SuperBlockError::EmptyRegion("data bitmap"));
+        }
+
+        // 超级块之后依次是各个块组和内部日志区域，最后一个块组不能超过完整块组的大小，
+        // 并且至少要有一个数据块
+        let last_group = geometry.group_count - 1;
+        let full_groups_end = geometry.group_start(last_group) + geometry.blocks_per_group as u64;
+        let groups_end = self.total_blocks.saturating_sub(self.journal_blocks as u64);
+        if self.data_area_blocks + geometry.group_metadata_blocks() != geometry.blocks_per_group
+            || groups_end > full_groups_end
+            || groups_end <= geometry.data_area_start(last_group)
+        {
+            return Err(SuperBlockError::SizeMismatch {
+                regions: full_groups_end + self.journal_blocks as u64,
+                total: self.total_blocks,
+            });
+        }
+
+        // 每个块组的索引节点都必须落在该块组的索引节点区域内，否则会写到数据位图上
+        let inode_area_bytes = self.inode_area_blocks as u64 * BLOCK_SZ as u64;
+        if geometry.inodes_per_group as u64 * core::mem::size_of::<DiskInode>() as u64
+            > inode_area_bytes
+            || geometry.inodes_per_group as u64 > self.inode_bitmap_blocks as u64 * BLOCK_BITS
+        {
+            return Err(SuperBlockError::RegionOverlap("inode area"));
+        }
+
+        // 数据位图必须覆盖一个完整块组的数据区域，否则超出的数据块无法分配和释放
+        if self.data_area_blocks as u64 > self.data_bitmap_blocks as u64 * BLOCK_BITS {
+            return Err(SuperBlockError::BitmapTooSmall("data bitmap"));
+        }
+
+        // 空闲计数不能超过总数，用户配额文件必须是一个存在的索引节点
+        if self
+            .ro_compat_features()
+            .contains(RoCompatFeatures::FREE_COUNTERS)
+        {
+            if self.free_data_blocks > geometry.total_data_blocks() {
+                return Err(SuperBlockError::FieldOutOfRange("free data blocks"));
+            }
+            if self.free_inodes > geometry.total_inodes() {
+                return Err(SuperBlockError::FieldOutOfRange("free inodes"));
+            }
+        }
+        if self.user_quota_inode as u64 >= geometry.total_inodes() {
+            return Err(SuperBlockError::FieldOutOfRange("user quota inode"));
+        }
+        if self.subvolume_dir as u64 >= geometry.total_inodes() {
+            return Err(SuperBlockError::FieldOutOfRange("subvolume directory"));
+        }
+        Ok(())
+    }
+
+    /// 设置兼容特性之外的特性，用于格式化时关闭可选的特性
+    ///
+    /// # Arguments
+    ///
+    /// * `ro_compat`: 只读兼容特性
+    /// * `incompat`: 不兼容特性
+    pub fn set_features(&mut self, ro_compat: RoCompatFeatures, incompat: IncompatFeatures) {
+        self.feature_ro_compat = ro_compat.bits();
+        self.feature_incompat = incompat.bits();
+    }
+
+    /// 开启的特性能否寻址给定的总块数
+    /// 直接索引和间接索引只能记录 32 位的块ID，更大的镜像需要开启 [`IncompatFeatures::ADDRESS_48BIT`]
+    ///
+    /// # Arguments
+    ///
+    /// * `total_blocks`: 总块数
+    ///
+    /// returns: bool 能否寻址
+    pub fn addressable(&self, total_blocks: u64) -> bool {
+        total_blocks - 1 <= MAX_BLOCK_MAP_ID
+            || self
+                .incompat_features()
+                .contains(IncompatFeatures::ADDRESS_48BIT)
+    }
+
+    /// 按扩大后的块组布局更新总块数、块组数和日志区域块数
+    /// 每个块组的大小和索引节点数保持不变，空闲计数增加新增的数据块数和索引节点数
+    ///
+    /// # Arguments
+    ///
+    /// * `geometry`: 由 [`Geometry::grow`] 得到的块组布局
+    /// * `reserved_blocks`: 新增的数据块中保留给备份超级块和块组描述符的块数
+    pub fn grow(&mut self, geometry: &Geometry, reserved_blocks: u64) {
+        let old = self.geometry();
+        assert_eq!(old.blocks_per_group, geometry.blocks_per_group);
+        self.total_blocks = geometry.total_blocks;
+        self.group_count = geometry.group_count;
+        self.journal_blocks = geometry.journal_blocks;
+        self.free_data_blocks +=
+            geometry.total_data_blocks() - old.total_data_blocks() - reserved_blocks;
+        self.free_inodes += geometry.total_inodes() - old.total_inodes();
+    }
+
+    /// 获取创建文件系统时使用的块大小
+    ///
+    /// returns: usize 块大小，单位为字节
+    pub fn block_size(&self) -> usize {
+        MIN_BLOCK_SZ.checked_shl(self.log_block_size).unwrap_or(0)
+    }
+
+    /// 获取块组布局
+    /// 旧镜像没有块组，整个文件系统被视为一个块组，索引节点数由索引节点位图的大小决定
+    pub fn geometry(&self) -> Geometry {
+        if self.group_count == 0 {
+            Geometry {
+                total_blocks: self.total_blocks,
+                group_count: 1,
+                blocks_per_group: self.inode_bitmap_blocks
+                    + self.inode_area_blocks
+                    + self.data_bitmap_blocks
+                    + self.data_area_blocks,
+                inodes_per_group: (self.inode_bitmap_blocks as u64 * BLOCK_BITS) as u32,
+                inode_bitmap_blocks: self.inode_bitmap_blocks,
+                inode_area_blocks: self.inode_area_blocks,
+                data_bitmap_blocks: self.data_bitmap_blocks,
+                journal_blocks: self.journal_blocks,
+            }
+        } else {
+            Geometry {
+                total_blocks: self.total_blocks,
+                group_count: self.group_count,
+                blocks_per_group: self.blocks_per_group,
+                inodes_per_group: self.inodes_per_group,
+                inode_bitmap_blocks: self.inode_bitmap_blocks,
+                inode_area_blocks: self.inode_area_blocks,
+                data_bitmap_blocks: self.data_bitmap_blocks,
+                journal_blocks: self.journal_blocks,
+            }
+        }
+    }
+
+    /// 是否使用外部日志设备
+    pub fn has_external_journal(&self) -> bool {
+        self.journal_uuid != [0u8; 16]
+    }
+
+    /// 获取兼容特性，忽略不认识的特性
+    pub fn compat_features(&self) -> CompatFeatures {
+        CompatFeatures::from_bits_truncate(self.feature_compat)
+    }
+
+    /// 获取只读兼容特性，忽略不认识的特性
+    pub fn ro_compat_features(&self) -> RoCompatFeatures {
+        RoCompatFeatures::from_bits_truncate(self.feature_ro_compat)
+    }
+
+    /// 获取不兼容特性，忽略不认识的特性
+    pub fn incompat_features(&self) -> IncompatFeatures {
+        IncompatFeatures::from_bits_truncate(self.feature_incompat)
+    }
+
+    /// 获取文件系统状态
+    pub fn state(&self) -> SuperBlockState {
+        SuperBlockState::from_bits_truncate(self.state)
+    }
+
+    /// 设置文件系统状态
+    ///
+    /// # Arguments
+    ///
+    /// * `state`: 文件系统状态
+    pub fn set_state(&mut self, state: SuperBlockState) {
+        self.state = state.bits();
+    }
+
+    /// 开启兼容特性
+    ///
+    /// # Arguments
+    ///
+    /// * `features`: 兼容特性
+    pub fn enable_compat_features(&mut self, features: CompatFeatures) {
+        self.feature_compat |= features.bits();
+    }
+
+    /// 开启只读兼容特性
+    ///
+    /// # Arguments
+    ///
+    /// * `features`: 只读兼容特性
+    pub fn enable_ro_compat_features(&mut self, features: RoCompatFeatures) {
+        self.feature_ro_compat |= features.bits();
+    }
+
+    /// 获取不认识的只读兼容特性的比特，不为零时只能以只读方式打开
+    pub fn unknown_ro_compat(&self) -> u32 {
+        self.feature_ro_compat & !RoCompatFeatures::all().bits()
+    }
+
+    /// 获取不认识的不兼容特性的比特，不为零时不能打开
+    pub fn unknown_incompat(&self) -> u32 {
+        self.feature_incompat & !IncompatFeatures::all().bits()
+    }
+
+    /// 获取备份超级块的块ID，按块ID从小到大排列
+    /// 备份超级块位于 [`BACKUP_SUPER_BLOCK_INTERVAL`] 的整数倍处，只有落在数据区域中的才会被使用
+    pub fn backup_block_ids(&self) -> Vec<u64> {
+        self.geometry().backup_block_ids()
+    }
+
+    /// 获取块组描述符块的块ID，按块ID从小到大排列，
+    /// 没有设置 [`RoCompatFeatures::GROUP_DESCRIPTORS`] 时为空
+    pub fn group_descriptor_block_ids(&self) -> Vec<u64> {
+        if !self
+            .ro_compat_features()
+            .contains(RoCompatFeatures::GROUP_DESCRIPTORS)
+        {
+            return Vec::new();
+        }
+        self.geometry().group_descriptor_block_ids()
+    }
+
+    /// 获取卷标，不包括填充的零
+    pub fn label(&self) -> &[u8] {
+        let len = self
+            .label
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(LABEL_LENGTH_LIMIT);
+        &self.label[..len]
+    }
+
+    /// 设置卷标
+    ///
+    /// # Arguments
+    ///
+    /// * `label`: 卷标，不能超过 [`LABEL_LENGTH_LIMIT`] 字节
+    pub fn set_label(&mut self, label: &[u8]) {
+        assert!(label.len() <= LABEL_LENGTH_LIMIT);
+        self.label = [0u8; LABEL_LENGTH_LIMIT];
+        self.label[..label.len()].copy_from_slice(label);
+    }
 }
 
-/// 磁盘索引节点的类型
-#[derive(PartialEq)]
-pub enum DiskInodeType {
-    /// 文件
-    File,
+/// 块组布局
+/// 超级块之后依次是各个块组和内部日志区域，
+/// 每个块组依次包含索引节点位图、索引节点区域、数据位图和数据区域，
+/// 除最后一个块组的数据区域可能更小以外，所有块组的大小都相同
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// 总块数
+    pub total_blocks: u64,
 
-    /// 目录
-    Directory,
+    /// 块组数
+    pub group_count: u32,
+
+    /// 每个完整块组的块数
+    pub blocks_per_group: u32,
+
+    /// 每个块组的索引节点数
+    pub inodes_per_group: u32,
+
+    /// 每个块组的索引节点位图块数
+    pub inode_bitmap_blocks: u32,
+
+    /// 每个块组的索引节点区域块数
+    pub inode_area_blocks: u32,
+
+    /// 每个块组的数据位图块数
+    pub data_bitmap_blocks: u32,
+
+    /// 日志区域块数，包括不足一个块组而并入日志区域的块
+    pub journal_blocks: u32,
 }
 
-/// 间接索引块
-type IndirectBlock = [u32; BLOCK_SZ / 4];
+impl Geometry {
+    /// 为给定大小的设备计算块组布局
+    /// 每个完整块组的块数是一个位图块的比特数，索引节点平均分配到各个块组中，
+    /// 最后剩下的放不下一个块组的块并入日志区域
+    ///
+    /// # Arguments
+    ///
+    /// * `total_blocks`: 总块数
+    /// * `inodes`: 至少需要的索引节点数
+    /// * `journal_blocks`: 日志区域块数
+    ///
+    /// returns: Result<Geometry, SuperBlockError> 块组布局，块数超出 48 位时返回 [`SuperBlockError::AddressOverflow`]，
+    /// 一个块组放不下分到它的索引节点时返回 [`SuperBlockError::RegionOverlap`]，
+    /// 放不下一个块组时返回 [`SuperBlockError::EmptyRegion`]
+    pub fn new(
+        total_blocks: u64,
+        inodes: u64,
+        journal_blocks: u32,
+    ) -> Result<Self, SuperBlockError> {
+        if total_blocks == 0 || total_blocks - 1 > MAX_BLOCK_ID {
+            return Err(SuperBlockError::AddressOverflow(total_blocks));
+        }
+        let available = total_blocks
+            .checked_sub(1 + journal_blocks as u64)
+            .filter(|&available| available > 0)
+            .ok_or(SuperBlockError::EmptyRegion("block groups"))?;
+        let blocks_per_group = available.min(BLOCKS_PER_GROUP) as u32;
+        let mut group_count = available.div_ceil(blocks_per_group as u64) as u32;
+
+        // 索引节点区域按块对齐，多出的空间也用来存放索引节点
+        let inodes_per_block = (BLOCK_SZ / core::mem::size_of::<DiskInode>()) as u64;
+        let inode_area_blocks = inodes
+            .div_ceil(group_count as u64)
+            .div_ceil(inodes_per_block) as u32;
+        let inodes_per_group = inode_area_blocks as u64 * inodes_per_block;
+        let inode_bitmap_blocks = inodes_per_group.div_ceil(BLOCK_BITS) as u32;
+        if inodes_per_group > u32::MAX as u64
+            || inode_bitmap_blocks as u64 + inode_area_blocks as u64 >= blocks_per_group as u64
+        {
+            return Err(SuperBlockError::RegionOverlap("inode area"));
+        }
+
+        // 数据位图的每一位对应数据区域中的一个块
+        let data_total_blocks = blocks_per_group - inode_bitmap_blocks - inode_area_blocks;
+        let data_bitmap_blocks = (data_total_blocks as u64).div_ceil(BLOCK_BITS + 1) as u32;
+
+        // 最后一个块组至少要有一个数据块
+        let metadata_blocks = inode_bitmap_blocks + inode_area_blocks + data_bitmap_blocks;
+        let last_group_blocks = available - (group_count as u64 - 1) * blocks_per_group as u64;
+        let mut journal_blocks = journal_blocks;
+        if last_group_blocks <= metadata_blocks as u64 {
+            group_count -= 1;
+            journal_blocks += last_group_blocks as u32;
+        }
+        if group_count == 0 {
+            return Err(SuperBlockError::EmptyRegion("block groups"));
+        }
+
+        Ok(Self {
+            total_blocks,
+            group_count,
+            blocks_per_group,
+            inodes_per_group: inodes_per_group as u32,
+            inode_bitmap_blocks,
+            inode_area_blocks,
+            data_bitmap_blocks,
+            journal_blocks,
+        })
+    }
+
+    /// 保持每个块组的大小和索引节点数不变，将块组布局扩展到更大的设备
+    /// 最后一个块组先被补足到完整块组的大小，之后追加新的块组，
+    /// 和 [`Geometry::new`] 一样，最后放不下一个块组的剩余块并入日志区域
+    ///
+    /// # Arguments
+    ///
+    /// * `total_blocks`: 新的总块数，不小于当前的总块数
+    /// * `journal_blocks`: 日志区域块数，不超过当前的日志区域块数
+    ///
+    /// returns: Geometry 块组布局
+    pub fn grow(&self, total_blocks: u64, journal_blocks: u32) -> Self {
+        assert!(total_blocks >= self.total_blocks && journal_blocks <= self.journal_blocks);
+        let available = total_blocks - 1 - journal_blocks as u64;
+        let blocks_per_group = self.blocks_per_group as u64;
+        let mut group_count = available.div_ceil(blocks_per_group) as u32;
+        let last_group_blocks = available - (group_count as u64 - 1) * blocks_per_group;
+        let mut journal_blocks = journal_blocks;
+        if last_group_blocks <= self.group_metadata_blocks() as u64 {
+            group_count -= 1;
+            journal_blocks += last_group_blocks as u32;
+        }
+        Self {
+            total_blocks,
+            group_count,
+            journal_blocks,
+            ..*self
+        }
+    }
+
+    /// 每个块组中索引节点位图、索引节点区域和数据位图的总块数
+    pub fn group_metadata_blocks(&self) -> u32 {
+        self.inode_bitmap_blocks + self.inode_area_blocks + self.data_bitmap_blocks
+    }
+
+    /// 块组的起始块ID，也是该块组索引节点位图的起始块ID
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    ///
+    /// returns: u64 块ID
+    pub fn group_start(&self, group: u32) -> u64 {
+        1 + group as u64 * self.blocks_per_group as u64
+    }
+
+    /// 块组中索引节点区域的起始块ID
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    ///
+    /// returns: u64 块ID
+    pub fn inode_area_start(&self, group: u32) -> u64 {
+        self.group_start(group) + self.inode_bitmap_blocks as u64
+    }
+
+    /// 块组中数据位图的起始块ID
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    ///
+    /// returns: u64 块ID
+    pub fn data_bitmap_start(&self, group: u32) -> u64 {
+        self.inode_area_start(group) + self.inode_area_blocks as u64
+    }
+
+    /// 块组中数据区域的起始块ID
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    ///
+    /// returns: u64 块ID
+    pub fn data_area_start(&self, group: u32) -> u64 {
+        self.data_bitmap_start(group) + self.data_bitmap_blocks as u64
+    }
+
+    /// 块组中数据区域的块数
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    ///
+    /// returns: u32 块数
+    pub fn data_area_blocks(&self, group: u32) -> u32 {
+        let group_end = (self.group_start(group) + self.blocks_per_group as u64)
+            .min(self.total_blocks - self.journal_blocks as u64);
+        (group_end - self.data_area_start(group)) as u32
+    }
+
+    /// 所有块组的数据块总数
+    pub fn total_data_blocks(&self) -> u64 {
+        (0..self.group_count)
+            .map(|group| self.data_area_blocks(group) as u64)
+            .sum()
+    }
+
+    /// 所有块组的索引节点总数
+    pub fn total_inodes(&self) -> u64 {
+        self.group_count as u64 * self.inodes_per_group as u64
+    }
+
+    /// 索引节点所在的块组
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: u32 块组序号
+    pub fn group_of_inode(&self, inode_id: u32) -> u32 {
+        inode_id / self.inodes_per_group
+    }
+
+    /// 数据块所在的块组及其在该块组数据区域中的序号，不是数据块时返回 None
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    ///
+    /// returns: Option<(u32, u32)> (块组序号, 数据区域中的序号)
+    pub fn data_block_position(&self, block_id: u64) -> Option<(u32, u32)> {
+        if block_id == 0 {
+            return None;
+        }
+        let group = ((block_id - 1) / self.blocks_per_group as u64) as u32;
+        if group >= self.group_count {
+            return None;
+        }
+        let data_area_start = self.data_area_start(group);
+        if block_id < data_area_start
+            || block_id >= data_area_start + self.data_area_blocks(group) as u64
+        {
+            return None;
+        }
+        Some((group, (block_id - data_area_start) as u32))
+    }
+
+    /// 获取备份超级块的块ID，按块ID从小到大排列
+    /// 备份超级块位于 [`BACKUP_SUPER_BLOCK_INTERVAL`] 的整数倍处，只有落在数据区域中的才会被使用
+    pub fn backup_block_ids(&self) -> Vec<u64> {
+        let groups_end = self.total_blocks - self.journal_blocks as u64;
+        (1..)
+            .map(|i| i * BACKUP_SUPER_BLOCK_INTERVAL)
+            .take_while(|&block_id| block_id < groups_end)
+            .filter(|&block_id| self.data_block_position(block_id).is_some())
+            .collect()
+    }
+
+    /// 块组描述符所在的块ID和块内偏移
+    /// 每 [`GROUP_DESCS_PER_BLOCK`] 个块组的描述符放在一个描述符块中，
+    /// 描述符块是其中第一个块组数据区域的第一个块
+    ///
+    /// # Arguments
+    ///
+    /// * `group`: 块组序号
+    ///
+    /// returns: (u64, usize) (块ID, 块内偏移)
+    pub fn group_descriptor_position(&self, group: u32) -> (u64, usize) {
+        let index = group as usize % GROUP_DESCS_PER_BLOCK;
+        (
+            self.data_area_start(group - index as u32),
+            index * core::mem::size_of::<GroupDescriptor>(),
+        )
+    }
+
+    /// 获取所有块组描述符块的块ID，按块ID从小到大排列
+    /// 它们和备份超级块一样在数据位图中被标记为已分配，见 [`Geometry::group_descriptor_position`]
+    pub fn group_descriptor_block_ids(&self) -> Vec<u64> {
+        (0..self.group_count)
+            .step_by(GROUP_DESCS_PER_BLOCK)
+            .map(|group| self.data_area_start(group))
+            .collect()
+    }
+}
+
+/// 一个描述符块中的块组描述符数
+pub const GROUP_DESCS_PER_BLOCK: usize = BLOCK_SZ / core::mem::size_of::<GroupDescriptor>();
+
+/// 块组描述符，记录一个块组中空闲的数据块数和索引节点数，
+/// 只在设置了 [`RoCompatFeatures::GROUP_DESCRIPTORS`] 时有效
+/// 分配器据此跳过已满的块组、挑选最空的块组，而不必扫描位图；
+/// 它和超级块中的空闲计数一样，在上次没有正常卸载时按位图重新统计
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GroupDescriptor {
+    /// 块组中空闲的数据块数
+    pub free_data_blocks: u32,
+
+    /// 块组中空闲的索引节点数
+    pub free_inodes: u32,
+
+    /// 保留，使描述符的大小是 8 的倍数
+    _reserved: u64,
+}
+
+impl GroupDescriptor {
+    /// 创建一个块组描述符
+    ///
+    /// # Arguments
+    ///
+    /// * `free_data_blocks`: 空闲的数据块数
+    /// * `free_inodes`: 空闲的索引节点数
+    ///
+    /// returns: GroupDescriptor 块组描述符
+    pub fn new(free_data_blocks: u32, free_inodes: u32) -> Self {
+        Self {
+            free_data_blocks,
+            free_inodes,
+            _reserved: 0,
+        }
+    }
+}
+
+/// 外部日志设备的超级块，位于外部日志设备的第一个块
+#[repr(C)]
+pub struct JournalDeviceSuperBlock {
+    /// 魔数
+    magic: u32,
+
+    /// 日志设备的 UUID
+    pub uuid: [u8; 16],
+
+    /// 日志区域块数，日志区域紧随超级块
+    pub journal_blocks: u32,
+
+    /// 保留，使结构体占满一个块
+    _reserved: [u8; BLOCK_SZ - 24],
+}
+
+impl JournalDeviceSuperBlock {
+    /// 创建一个外部日志设备超级块
+    ///
+    /// # Arguments
+    ///
+    /// * `uuid`: 日志设备的 UUID
+    /// * `journal_blocks`: 日志区域块数
+    ///
+    /// returns: JournalDeviceSuperBlock 外部日志设备超级块
+    pub fn new(uuid: [u8; 16], journal_blocks: u32) -> Self {
+        Self {
+            magic: JOURNAL_DEVICE_MAGIC,
+            uuid,
+            journal_blocks,
+            _reserved: [0u8; BLOCK_SZ - 24],
+        }
+    }
+
+    /// 使用魔数检查超级块是否有效
+    pub fn is_valid(&self) -> bool {
+        self.magic == JOURNAL_DEVICE_MAGIC
+    }
+
+    /// 序列化为不可变字节
+    pub fn as_bytes(&self) -> &[u8] {
+        bytes_of(self)
+    }
+
+    /// 序列化为可变字节
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        bytes_of_mut(self)
+    }
+}
+
+/// 日志头，位于日志区域的第一个块
+/// 记录了已提交但尚未写回原位置的事务
+#[repr(C)]
+pub struct JournalHeader {
+    /// 魔数
+    magic: u32,
+
+    /// 事务序号
+    pub sequence: u32,
+
+    /// 事务中的块数，为零表示没有待写回的事务
+    pub count: u32,
+
+    /// 日志头连同事务中各个块的内容的 CRC32，计算时该字段视为零，用于发现不完整的事务
+    checksum: u32,
+
+    /// 事务中各个块的原位置块ID的低 32 位，日志区域中紧随日志头依次存放这些块的内容
+    block_ids_lo: [u32; JOURNAL_MAX_BLOCKS],
+
+    /// 事务中各个块的原位置块ID的高 16 位
+    block_ids_hi: [u16; JOURNAL_MAX_BLOCKS],
+
+    /// 填充，使日志头占满一个块
+    _padding: [u8; BLOCK_SZ - 16 - 6 * JOURNAL_MAX_BLOCKS],
+}
+
+impl JournalHeader {
+    /// 创建一个日志头
+    ///
+    /// # Arguments
+    ///
+    /// * `sequence`: 事务序号
+    /// * `block_ids`: 事务中各个块的原位置块ID
+    ///
+    /// returns: JournalHeader 日志头
+    pub fn new(sequence: u32, block_ids: &[u64]) -> Self {
+        assert!(block_ids.len() <= JOURNAL_MAX_BLOCKS);
+        let mut block_ids_lo = [0u32; JOURNAL_MAX_BLOCKS];
+        let mut block_ids_hi = [0u16; JOURNAL_MAX_BLOCKS];
+        for (i, &block_id) in block_ids.iter().enumerate() {
+            assert!(block_id <= MAX_BLOCK_ID, "Block ID exceeds 48 bits!");
+            block_ids_lo[i] = block_id as u32;
+            block_ids_hi[i] = (block_id >> 32) as u16;
+        }
+        Self {
+            magic: JOURNAL_MAGIC,
+            sequence,
+            count: block_ids.len() as u32,
+            checksum: 0,
+            block_ids_lo,
+            block_ids_hi,
+            _padding: [0u8; BLOCK_SZ - 16 - 6 * JOURNAL_MAX_BLOCKS],
+        }
+    }
+
+    /// 获取事务中各个块的原位置块ID
+    pub fn block_ids(&self) -> Vec<u64> {
+        (0..(self.count as usize).min(JOURNAL_MAX_BLOCKS))
+            .map(|i| (self.block_ids_hi[i] as u64) << 32 | self.block_ids_lo[i] as u64)
+            .collect()
+    }
+
+    /// 使用魔数检查日志头是否有效
+    pub fn is_valid(&self) -> bool {
+        self.magic == JOURNAL_MAGIC
+    }
+
+    /// 计算日志头连同事务中各个块的内容的 CRC32
+    ///
+    /// # Arguments
+    ///
+    /// * `contents`: 事务中各个块的内容，顺序与块ID相同
+    ///
+    /// returns: u32 校验和
+    pub fn compute_checksum<'a>(&self, contents: impl IntoIterator<Item = &'a DataBlock>) -> u32 {
+        let bytes = bytes_of(self);
+        let offset = core::mem::offset_of!(JournalHeader, checksum);
+        let crc = crc32_update(crc32(&bytes[..offset]), &bytes[offset + 4..]);
+        contents
+            .into_iter()
+            .fold(crc, |crc, data| crc32_update(crc, data))
+    }
+
+    /// 在写入日志头之前按事务中各个块的内容计算校验和
+    ///
+    /// # Arguments
+    ///
+    /// * `contents`: 事务中各个块的内容，顺序与块ID相同
+    pub fn update_checksum<'a>(&mut self, contents: impl IntoIterator<Item = &'a DataBlock>) {
+        self.checksum = self.compute_checksum(contents);
+    }
+
+    /// 检查事务是否完整，即日志头和日志区域中各个块的内容是否与校验和一致
+    ///
+    /// # Arguments
+    ///
+    /// * `contents`: 从日志区域读出的各个块的内容
+    ///
+    /// returns: bool 校验和是否匹配
+    pub fn verify_checksum<'a>(&self, contents: impl IntoIterator<Item = &'a DataBlock>) -> bool {
+        self.checksum == self.compute_checksum(contents)
+    }
+
+    /// 序列化为不可变字节
+    pub fn as_bytes(&self) -> &[u8] {
+        bytes_of(self)
+    }
+
+    /// 序列化为可变字节
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        bytes_of_mut(self)
+    }
+}
+
+/// 磁盘索引节点的类型
+#[repr(u8)]
+#[derive(PartialEq)]
+pub enum DiskInodeType {
+    /// 文件
+    File,
+
+    /// 目录
+    Directory,
+}
+
+bitflags! {
+    /// 索引节点的标志
+    pub struct InodeFlags: u8 {
+        /// 使用区段树而不是间接索引树映射数据块
+        const EXTENTS = 1 << 0;
+
+        /// 文件内容直接存放在索引节点中，不占用数据块
+        const INLINE_DATA = 1 << 1;
+
+        /// 目录上设置了配额，见 [`Quota`]
+        const QUOTA = 1 << 2;
+
+        /// 文件最后一个不完整的块被打包到共享的尾部块中，见 [`Tail`]
+        const TAIL = 1 << 3;
+    }
+}
+
+/// 区段：一段逻辑上连续、在磁盘上也连续的数据块
+/// 在区段树的索引节点中，`start` 是子节点的块ID，`len` 不使用
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// 起始内部 ID
+    pub logical: u32,
+
+    /// 起始块ID，最多 48 位
+    pub start: u64,
+
+    /// 块数
+    pub len: u32,
+}
+
+/// 间接索引块
+type IndirectBlock = [u32; BLOCK_SZ / 4];
+
+/// 数据块
+type DataBlock = [u8; BLOCK_SZ];
+
+/// 磁盘索引节点
+#[repr(C)]
+pub struct DiskInode {
+    /// 文件大小
+    pub size: u64,
+
+    /// 直接索引节点
+    pub direct: [u32; INODE_DIRECT_COUNT],
+
+    /// 一级间接索引节点
+    pub indirect1: u32,
+
+    /// 二级间接索引节点
+    pub indirect2: u32,
+
+    /// 三级间接索引节点
+    pub indirect3: u32,
+
+    /// 世代号，索引节点每次被重新初始化时加一
+    generation: u32,
+
+    /// 索引节点类型，见 [`DiskInodeType`]
+    type_: u8,
+
+    /// 索引节点的标志，见 [`InodeFlags`]
+    flags: u8,
+
+    /// 填充，使时间戳按 8 字节对齐
+    _padding: [u8; 2],
+
+    /// 最后访问时间，自 UNIX 纪元以来的纳秒数
+    pub atime: u64,
+
+    /// 最后修改数据的时间
+    pub mtime: u64,
+
+    /// 最后改变数据或元数据的时间
+    pub ctime: u64,
+
+    /// 权限位，不包括文件类型
+    pub mode: u32,
+
+    /// 属主的用户ID
+    pub uid: u32,
+
+    /// 属组的组ID
+    pub gid: u32,
+
+    /// 索引节点的 CRC32，以索引节点ID为种子，计算时该字段视为零，
+    /// 只在设置了 [`RoCompatFeatures::METADATA_CHECKSUM`] 时检查
+    checksum: u32,
+
+    /// 目录内容的 CRC32，只对目录有效
+    dir_checksum: u32,
+
+    /// 为该索引节点记账的配额目录的索引节点ID，没有设置配额的根目录为零
+    quota_root: u32,
+
+    /// 目录配额，只在设置了 [`InodeFlags::QUOTA`] 时有效
+    quota: Quota,
+
+    /// 创建时间，为零时表示未知
+    pub btime: u64,
+
+    /// 打包的尾部所在的尾部块，只在设置了 [`InodeFlags::TAIL`] 时有效
+    tail_block: u64,
+
+    /// 打包的尾部在尾部块中的偏移
+    tail_offset: u16,
+
+    /// 打包的尾部的字节数
+    tail_len: u16,
+
+    /// 目录中的目录条目数，包括 `.` 和 `..`，不包括空位，为零时表示未知
+    dir_entries: u32,
+
+    /// 目录中可能是空位的第一个位置，在它之前没有空位
+    dir_free_slot: u32,
+
+    /// 保留，使磁盘索引节点占满 256 字节
+    _reserved: [u8; 20],
+}
+
+/// 目录配额，限制目录及其下所有文件和子目录占用的空间，以及其下的索引节点数
+/// 使用量按占用的块计算，包括间接索引块，不包括空洞；
+/// 子目录上另外设置了配额时，子目录下的内容只计入子目录的配额
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quota {
+    /// 最多占用的字节数，为零时不限制
+    pub max_bytes: u64,
+
+    /// 最多包含的索引节点数，不包括目录本身，为零时不限制
+    pub max_inodes: u64,
+
+    /// 已经占用的字节数
+    pub used_bytes: u64,
+
+    /// 已经包含的索引节点数
+    pub used_inodes: u64,
+}
+
+impl Quota {
+    /// 再占用给定的字节数和索引节点数后是否仍在限制之内
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes`: 增加的字节数
+    /// * `inodes`: 增加的索引节点数
+    ///
+    /// returns: bool 是否在限制之内
+    pub fn allows(&self, bytes: u64, inodes: u64) -> bool {
+        (self.max_bytes == 0 || self.used_bytes + bytes <= self.max_bytes)
+            && (self.max_inodes == 0 || self.used_inodes + inodes <= self.max_inodes)
+    }
+}
+
+/// 用户配额文件中一条记录的大小
+pub const USER_QUOTA_SZ: usize = 40;
+
+/// 用户配额文件中的一条记录，限制一个用户拥有的文件和目录占用的空间，以及它拥有的索引节点数
+/// 使用量的计算方式与 [`Quota`] 相同，记录按添加的顺序排列，不会被删除
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UserQuota {
+    /// 用户ID
+    pub uid: u32,
+
+    /// 填充，使配额按 8 字节对齐
+    _padding: u32,
+
+    /// 该用户的配额
+    pub quota: Quota,
+}
+
+impl UserQuota {
+    /// 创建一条不限制也没有占用的记录
+    ///
+    /// # Arguments
+    ///
+    /// * `uid`: 用户ID
+    ///
+    /// returns: UserQuota 记录
+    pub fn new(uid: u32) -> Self {
+        Self {
+            uid,
+            ..Self::default()
+        }
+    }
+
+    /// 序列化为不可变字节
+    pub fn as_bytes(&self) -> &[u8] {
+        bytes_of(self)
+    }
+
+    /// 序列化为可变字节
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        bytes_of_mut(self)
+    }
+}
+
+/// 占用和释放空间时需要记账的配额
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaTarget {
+    /// 配额目录的索引节点ID
+    pub quota_root: u32,
+
+    /// 属主的用户ID
+    pub uid: u32,
+}
+
+/// 打包到尾部块中的文件尾部，即文件最后一个不完整的块中的数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tail {
+    /// 尾部块的块ID
+    pub block_id: u64,
+
+    /// 在尾部块中的偏移，是 [`TAIL_FRAGMENT_SZ`] 的整数倍
+    pub offset: usize,
+
+    /// 字节数，等于文件大小除以块大小的余数
+    pub len: usize,
+}
+
+impl Tail {
+    /// 尾部占用的片段数
+    pub fn fragments(&self) -> usize {
+        self.len.div_ceil(TAIL_FRAGMENT_SZ)
+    }
+
+    /// 尾部占用的片段在尾部块头部的片段使用情况中对应的位
+    ///
+    /// returns: Option<u64> 位掩码，为空、偏移没有对齐、覆盖了头部或者超出尾部块时为 None
+    pub fn fragment_mask(&self) -> Option<u64> {
+        let first = self.offset / TAIL_FRAGMENT_SZ;
+        let fragments = self.fragments();
+        (fragments > 0
+            && self.offset.is_multiple_of(TAIL_FRAGMENT_SZ)
+            && first > 0
+            && first + fragments <= TAIL_FRAGMENTS)
+            .then(|| TailBlockHeader::mask(first, fragments))
+    }
+}
+
+/// 尾部块的头部，也是尾部块的子分配图
+/// 尾部块被划分为 [`TAIL_FRAGMENTS`] 个片段，每个打包的尾部占用连续的若干个片段
+#[repr(C)]
+pub struct TailBlockHeader {
+    /// 魔数
+    magic: u32,
+
+    /// 填充，使位图按 8 字节对齐
+    _padding: u32,
+
+    /// 片段的使用情况，第 i 位为 1 表示第 i 个片段已被占用，第 0 个片段总是被头部占用
+    used: u64,
+}
+
+impl TailBlockHeader {
+    /// 初始化一个空的尾部块头部
+    pub fn initialize(&mut self) {
+        self.magic = TAIL_BLOCK_MAGIC;
+        self._padding = 0;
+        self.used = 1;
+    }
+
+    /// 魔数是否正确
+    pub fn is_valid(&self) -> bool {
+        self.magic == TAIL_BLOCK_MAGIC
+    }
+
+    /// 分配连续的若干个片段
+    ///
+    /// # Arguments
+    ///
+    /// * `fragments`: 片段数
+    ///
+    /// returns: Option<usize> 第一个片段的序号，没有足够长的连续空闲片段时为 None
+    pub fn alloc(&mut self, fragments: usize) -> Option<usize> {
+        let mask = Self::mask(0, fragments);
+        let first =
+            (1..=TAIL_FRAGMENTS - fragments).find(|&first| self.used & (mask << first) == 0)?;
+        self.used |= mask << first;
+        Some(first)
+    }
+
+    /// 释放连续的若干个片段
+    ///
+    /// # Arguments
+    ///
+    /// * `first`: 第一个片段的序号
+    /// * `fragments`: 片段数
+    ///
+    /// returns: bool 是否释放成功，片段为空、覆盖了头部、超出尾部块或者没有全部被占用时为 false，
+    /// 此时不做任何修改
+    pub fn dealloc(&mut self, first: usize, fragments: usize) -> bool {
+        if fragments == 0 || first == 0 || first + fragments > TAIL_FRAGMENTS {
+            return false;
+        }
+        let mask = Self::mask(first, fragments);
+        if self.used & mask != mask {
+            return false;
+        }
+        self.used &= !mask;
+        true
+    }
+
+    /// 是否除了头部之外没有被占用的片段
+    pub fn is_empty(&self) -> bool {
+        self.used == 1
+    }
+
+    /// 空闲的片段数
+    pub fn free_fragments(&self) -> usize {
+        TAIL_FRAGMENTS - self.used.count_ones() as usize
+    }
+
+    /// 获取片段的使用情况，第 i 位为 1 表示第 i 个片段已被占用
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// 重新设置片段的使用情况，用于按文件尾部重建子分配图
+    ///
+    /// # Arguments
+    ///
+    /// * `used`: 片段的使用情况，头部占用的第 0 个片段总是被标记为已占用
+    pub fn set_used(&mut self, used: u64) {
+        self.used = used | 1;
+    }
+
+    /// 获取从给定片段开始的连续若干个片段对应的位
+    ///
+    /// # Arguments
+    ///
+    /// * `first`: 第一个片段的序号
+    /// * `fragments`: 片段数
+    ///
+    /// returns: u64 位掩码
+    fn mask(first: usize, fragments: usize) -> u64 {
+        assert!(fragments > 0 && first + fragments <= TAIL_FRAGMENTS);
+        (u64::MAX >> (64 - fragments)) << first
+    }
+}
+
+impl DiskInode {
+    /// 初始化一个磁盘索引节点，以及直接索引节点
+    /// 间接索引节点只有在需要时才分配
+    /// 世代号会在原有基础上加一，以区分同一索引节点ID的不同文件
+    ///
+    /// # Arguments
+    ///
+    /// * `type_`: 索引节点类型
+    pub fn initialize(&mut self, type_: DiskInodeType) {
+        self.size = 0;
+        self.direct.iter_mut().for_each(|v| *v = 0);
+        self.indirect1 = 0;
+        self.indirect2 = 0;
+        self.indirect3 = 0;
+        self.generation = self.generation.wrapping_add(1);
+        self.type_ = type_ as u8;
+        self.flags = 0;
+        self.atime = 0;
+        self.mtime = 0;
+        self.ctime = 0;
+        self.btime = 0;
+        self.mode = if self.is_dir() {
+            DEFAULT_DIR_MODE
+        } else {
+            DEFAULT_FILE_MODE
+        };
+        self.uid = 0;
+        self.gid = 0;
+        self.dir_checksum = crc32(&[]);
+        self.quota_root = 0;
+        self.quota = Quota::default();
+        self.tail_block = 0;
+        self.tail_offset = 0;
+        self.tail_len = 0;
+        self.dir_entries = 0;
+        self.dir_free_slot = 0;
+    }
+
+    /// 计算索引节点的 CRC32，以索引节点ID为种子，校验和字段本身视为零
+    /// 种子使得写到错误位置的索引节点也能被发现
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: u32 校验和
+    pub fn compute_checksum(&self, inode_id: u32) -> u32 {
+        let bytes = bytes_of(self);
+        let offset = core::mem::offset_of!(DiskInode, checksum);
+        let crc = crc32(&inode_id.to_le_bytes());
+        let crc = crc32_update(crc, &bytes[..offset]);
+        crc32_update(crc, &bytes[offset + 4..])
+    }
+
+    /// 在修改索引节点之后重新计算校验和
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    pub fn update_checksum(&mut self, inode_id: u32) {
+        self.checksum = self.compute_checksum(inode_id);
+    }
+
+    /// 检查索引节点的校验和
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: bool 校验和是否匹配
+    pub fn verify_checksum(&self, inode_id: u32) -> bool {
+        self.checksum == self.compute_checksum(inode_id)
+    }
+
+    /// 计算目录内容的 CRC32
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: u32 校验和
+    pub fn compute_dir_checksum(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let mut crc = crc32(&[]);
+        let mut buf = [0u8; BLOCK_SZ];
+        let mut offset = 0usize;
+        loop {
+            let len = self.read_at(offset, &mut buf, block_device);
+            if len == 0 {
+                break;
+            }
+            crc = crc32_update(crc, &buf[..len]);
+            offset += len;
+        }
+        crc
+    }
+
+    /// 在修改目录内容之后重新计算目录内容的校验和
+    /// 之后还需要调用 [`DiskInode::update_checksum`] 更新索引节点自身的校验和
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    pub fn update_dir_checksum(&mut self, block_device: &Arc<dyn BlockDevice>) {
+        self.dir_checksum = self.compute_dir_checksum(block_device);
+    }
+
+    /// 检查目录内容的校验和，不是目录时总是通过
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: bool 校验和是否匹配
+    pub fn verify_dir_checksum(&self, block_device: &Arc<dyn BlockDevice>) -> bool {
+        !self.is_dir() || self.dir_checksum == self.compute_dir_checksum(block_device)
+    }
+
+    /// 目录中的位置数，包括空位
+    pub fn dir_slots(&self) -> usize {
+        self.size as usize / DIRENT_SZ
+    }
+
+    /// 获取目录中的目录条目数，包括 `.` 和 `..`，不包括空位
+    /// 旧镜像中的目录没有记录条目数，也没有空位，条目数就是位置数
+    pub fn dir_entries(&self) -> usize {
+        if self.dir_entries == 0 {
+            self.dir_slots()
+        } else {
+            self.dir_entries as usize
+        }
+    }
+
+    /// 设置目录中的目录条目数
+    ///
+    /// # Arguments
+    ///
+    /// * `dir_entries`: 目录条目数，包括 `.` 和 `..`
+    pub fn set_dir_entries(&mut self, dir_entries: usize) {
+        self.dir_entries = dir_entries as u32;
+    }
+
+    /// 获取目录中可能是空位的第一个位置
+    pub fn dir_free_slot(&self) -> usize {
+        self.dir_free_slot as usize
+    }
+
+    /// 记录目录中可能是空位的第一个位置
+    ///
+    /// # Arguments
+    ///
+    /// * `slot`: 位置的序号，在它之前不能有空位
+    pub fn set_dir_free_slot(&mut self, slot: usize) {
+        self.dir_free_slot = slot as u32;
+    }
+
+    /// 查找目录中的第一个空位，没有空位时返回追加的位置
+    /// 条目数等于位置数时不读取目录内容，否则从记录的位置开始查找
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: usize 空位的序号
+    pub fn find_free_slot(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        let slots = self.dir_slots();
+        if self.dir_entries() == slots {
+            return slots;
+        }
+        let mut dirent = DirEntry::empty();
+        (self.dir_free_slot as usize..slots)
+            .find(|&slot| {
+                self.read_at(slot * DIRENT_SZ, dirent.as_bytes_mut(), block_device);
+                dirent.is_free()
+            })
+            .unwrap_or(slots)
+    }
+
+    /// 这个磁盘索引节点是否是一个目录
+    pub fn is_dir(&self) -> bool {
+        self.type_ == DiskInodeType::Directory as u8
+    }
+
+    /// 释放索引节点时清零整个磁盘索引节点，只保留世代号，
+    /// 使同一索引节点ID再次分配时世代号仍然在原有基础上加一
+    pub fn zero(&mut self) {
+        let generation = self.generation;
+        bytes_of_mut(self).fill(0);
+        self.generation = generation;
+    }
+
+    /// 获取索引节点的世代号
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// 获取索引节点的标志
+    pub fn flags(&self) -> InodeFlags {
+        InodeFlags::from_bits_truncate(self.flags)
+    }
+
+    /// 是否使用区段树映射数据块
+    pub fn uses_extents(&self) -> bool {
+        self.flags().contains(InodeFlags::EXTENTS)
+    }
+
+    /// 当前映射格式能够寻址的最大文件大小
+    /// 间接索引受三级间接索引的范围限制，区段树受 32 位的内部 ID 限制
+    pub fn max_size(&self) -> u64 {
+        if self.uses_extents() {
+            u32::MAX as u64 * BLOCK_SZ as u64
+        } else {
+            INDIRECT3_BOUND as u64 * BLOCK_SZ as u64
+        }
+    }
+
+    /// 改为使用区段树映射数据块，只能在没有数据时切换
+    pub fn enable_extents(&mut self) {
+        assert_eq!(
+            self.size, 0,
+            "Mapping format can only change on empty inodes!"
+        );
+        self.flags &= !InodeFlags::INLINE_DATA.bits();
+        self.flags |= InodeFlags::EXTENTS.bits();
+        self.set_map_words([0u32; INODE_MAP_WORDS]);
+    }
+
+    /// 获取目录配额，没有设置配额时为 None
+    pub fn quota(&self) -> Option<Quota> {
+        self.flags()
+            .contains(InodeFlags::QUOTA)
+            .then_some(self.quota)
+    }
+
+    /// 设置或者移除目录配额
+    ///
+    /// # Arguments
+    ///
+    /// * `quota`: 目录配额，为 None 时移除
+    pub fn set_quota(&mut self, quota: Option<Quota>) {
+        match quota {
+            Some(quota) => {
+                self.flags |= InodeFlags::QUOTA.bits();
+                self.quota = quota;
+            }
+            None => {
+                self.flags &= !InodeFlags::QUOTA.bits();
+                self.quota = Quota::default();
+            }
+        }
+    }
+
+    /// 获取为该索引节点记账的配额目录的索引节点ID
+    pub fn quota_root(&self) -> u32 {
+        self.quota_root
+    }
+
+    /// 设置为该索引节点记账的配额目录
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_root`: 配额目录的索引节点ID
+    pub fn set_quota_root(&mut self, quota_root: u32) {
+        self.quota_root = quota_root;
+    }
+
+    /// 获取为该索引节点的数据块记账的配额目录和用户
+    /// 设置了配额的目录自己的数据块计入自己的配额
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 该索引节点的ID
+    ///
+    /// returns: QuotaTarget 配额目录的索引节点ID和属主的用户ID
+    pub fn quota_target(&self, inode_id: u32) -> QuotaTarget {
+        QuotaTarget {
+            quota_root: if self.quota().is_some() {
+                inode_id
+            } else {
+                self.quota_root
+            },
+            uid: self.uid,
+        }
+    }
+
+    /// 文件内容是否直接存放在索引节点中
+    pub fn has_inline_data(&self) -> bool {
+        self.flags().contains(InodeFlags::INLINE_DATA)
+    }
+
+    /// 改为将文件内容直接存放在索引节点中，只能在没有数据时切换
+    pub fn enable_inline_data(&mut self) {
+        assert_eq!(
+            self.size, 0,
+            "Mapping format can only change on empty inodes!"
+        );
+        self.flags |= InodeFlags::INLINE_DATA.bits();
+        self.set_map_words([0u32; INODE_MAP_WORDS]);
+    }
+
+    /// 将内联数据迁出到数据块，之后按原有的映射格式使用数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 新分配的数据块，文件为空时为 None
+    /// * `block_device`: 块设备
+    pub fn spill_inline_data(
+        &mut self,
+        block_id: Option<u64>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        assert!(self.has_inline_data());
+        assert_eq!(block_id.is_some(), self.size > 0);
+        let data = self.inline_bytes();
+        self.flags &= !InodeFlags::INLINE_DATA.bits();
+        self.set_map_words([0u32; INODE_MAP_WORDS]);
+        if let Some(block_id) = block_id {
+            let unused = self.fill_holes(0, 1, vec![block_id], block_device);
+            assert!(unused.is_empty());
+            let cache = get_block_cache(block_id, block_device.clone());
+            cache.lock().modify(0, |data_block: &mut DataBlock| {
+                data_block[..INLINE_DATA_CAPACITY].copy_from_slice(&data);
+            });
+        }
+    }
+
+    /// 获取内联数据
+    ///
+    /// returns: [u8; 112] 用于映射数据块的字中的字节
+    fn inline_bytes(&self) -> [u8; INLINE_DATA_CAPACITY] {
+        let mut data = [0u8; INLINE_DATA_CAPACITY];
+        for (bytes, word) in data.chunks_exact_mut(4).zip(self.map_words()) {
+            bytes.copy_from_slice(&word.to_ne_bytes());
+        }
+        data
+    }
+
+    /// 设置内联数据
+    ///
+    /// # Arguments
+    ///
+    /// * `data`: 用于映射数据块的字中的字节
+    fn set_inline_bytes(&mut self, data: &[u8; INLINE_DATA_CAPACITY]) {
+        let mut words = [0u32; INODE_MAP_WORDS];
+        for (word, bytes) in words.iter_mut().zip(data.chunks_exact(4)) {
+            *word = u32::from_ne_bytes(bytes.try_into().unwrap());
+        }
+        self.set_map_words(words);
+    }
+
+    /// 获取打包到尾部块中的文件尾部
+    pub fn tail(&self) -> Option<Tail> {
+        self.flags().contains(InodeFlags::TAIL).then_some(Tail {
+            block_id: self.tail_block,
+            offset: self.tail_offset as usize,
+            len: self.tail_len as usize,
+        })
+    }
+
+    /// 设置或清除打包到尾部块中的文件尾部
+    /// 设置时文件最后一个不完整的块不能再由映射的数据块存放
+    ///
+    /// # Arguments
+    ///
+    /// * `tail`: 文件尾部，为 None 时清除
+    pub fn set_tail(&mut self, tail: Option<Tail>) {
+        match tail {
+            Some(tail) => {
+                assert_eq!(tail.len, self.size as usize % BLOCK_SZ);
+                self.flags |= InodeFlags::TAIL.bits();
+                self.tail_block = tail.block_id;
+                self.tail_offset = tail.offset as u16;
+                self.tail_len = tail.len as u16;
+            }
+            None => {
+                self.flags &= !InodeFlags::TAIL.bits();
+                self.tail_block = 0;
+                self.tail_offset = 0;
+                self.tail_len = 0;
+            }
+        }
+    }
+
+    /// 获取一个内部 ID 对应的数据所在的块和在块中的偏移
+    /// 文件尾部被打包时，最后一个块的数据在尾部块中
+    ///
+    /// # Arguments
+    ///
+    /// * `inner_id`: 内部 ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Option<(u64, usize)> 块ID和偏移，空洞为 None
+    fn data_location(
+        &self,
+        inner_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Option<(u64, usize)> {
+        if let Some(tail) = self.tail() {
+            if inner_id as u64 == self.size / BLOCK_SZ as u64 {
+                return Some((tail.block_id, tail.offset));
+            }
+        }
+        let block_id = self.get_block_id(inner_id, block_device);
+        (block_id != 0).then_some((block_id, 0))
+    }
+
+    /// 返回与当前数据大小对应的块数
+    pub fn data_blocks(&self) -> u32 {
+        Self::_data_blocks(self.size)
+    }
+
+    /// 返回需要的块数
+    ///
+    /// # Arguments
+    ///
+    /// * `size`: 字节数
+    ///
+    /// returns: u32 块数
+    fn _data_blocks(size: u64) -> u32 {
+        u32::try_from(size.div_ceil(BLOCK_SZ as u64)).expect("File too large!")
+    }
+
+    /// 返回需要的块数，包括间接索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `size`: 字节数
+    ///
+    /// returns: u32 块数
+    pub fn total_blocks(size: u64) -> u32 {
+        let data_blocks = Self::_data_blocks(size) as usize;
+        let mut total = data_blocks;
+        for level in 1..=3 {
+            let (start, bound) = Self::level_range(level);
+            if data_blocks <= start {
+                break;
+            }
+            // 该层索引树中每一级的间接索引块
+            let blocks = data_blocks.min(bound) - start;
+            for height in 1..=level {
+                total += blocks.div_ceil(INODE_INDIRECT1_COUNT.pow(height as u32));
+            }
+        }
+        total as u32
+    }
+
+    /// 获取在新的数据大小下需要分配的数据块数
+    ///
+    /// # Arguments
+    ///
+    /// * `new_size`: 新的数据大小
+    ///
+    /// returns: u32 新增块数
+    pub fn blocks_num_needed(&self, new_size: u64) -> u32 {
+        assert!(new_size >= self.size);
+        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+    }
+
+    /// 获取一层间接索引树覆盖的内部 ID 范围
+    ///
+    /// # Arguments
+    ///
+    /// * `level`: 间接索引的层数，1 到 3
+    ///
+    /// returns: (usize, usize) 起始内部 ID 和结束内部 ID（不包含）
+    fn level_range(level: usize) -> (usize, usize) {
+        match level {
+            1 => (DIRECT_BOUND, INDIRECT1_BOUND),
+            2 => (INDIRECT1_BOUND, INDIRECT2_BOUND),
+            _ => (INDIRECT2_BOUND, INDIRECT3_BOUND),
+        }
+    }
+
+    /// 将内部 ID 定位到间接索引树中
+    ///
+    /// # Arguments
+    ///
+    /// * `inner_id`: 内部 ID
+    ///
+    /// returns: Option<(usize, usize)> 间接索引的层数和在该层索引树中的序号，直接索引时为 None
+    fn locate(inner_id: usize) -> Option<(usize, usize)> {
+        assert!(inner_id < INDIRECT3_BOUND, "File too large!");
+        if inner_id < DIRECT_BOUND {
+            return None;
+        }
+        (1..=3).find_map(|level| {
+            let (start, bound) = Self::level_range(level);
+            (inner_id < bound).then(|| (level, inner_id - start))
+        })
+    }
+
+    /// 序号在索引树第 `depth` 级（根为第 0 级）的间接索引块中对应的条目下标
+    ///
+    /// # Arguments
+    ///
+    /// * `index`: 在索引树中的序号
+    /// * `level`: 间接索引的层数
+    /// * `depth`: 级数
+    ///
+    /// returns: usize 条目下标
+    fn slot_at(index: usize, level: usize, depth: usize) -> usize {
+        index / INODE_INDIRECT1_COUNT.pow((level - 1 - depth) as u32) % INODE_INDIRECT1_COUNT
+    }
+
+    /// 获取一层间接索引树的根
+    ///
+    /// # Arguments
+    ///
+    /// * `level`: 间接索引的层数
+    ///
+    /// returns: &mut u32 根的块 ID
+    fn indirect_root(&mut self, level: usize) -> &mut u32 {
+        match level {
+            1 => &mut self.indirect1,
+            2 => &mut self.indirect2,
+            _ => &mut self.indirect3,
+        }
+    }
+
+    /// 读取间接索引块中的一个条目
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 间接索引块的块 ID
+    /// * `slot`: 条目下标
+    /// * `block_device`: 块设备
+    ///
+    /// returns: u32 条目的值
+    fn read_entry(block_id: u32, slot: usize, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        let cache = get_block_cache(block_id as u64, block_device.clone());
+        let entry = cache
+            .lock()
+            .read(0, |indirect: &IndirectBlock| indirect[slot]);
+        entry
+    }
+
+    /// 写入间接索引块中的一个条目
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 间接索引块的块 ID
+    /// * `slot`: 条目下标
+    /// * `value`: 条目的值
+    /// * `block_device`: 块设备
+    fn write_entry(block_id: u32, slot: usize, value: u32, block_device: &Arc<dyn BlockDevice>) {
+        let cache = get_block_cache(block_id as u64, block_device.clone());
+        cache.lock().modify(0, |indirect: &mut IndirectBlock| {
+            indirect[slot] = value;
+        });
+    }
+
+    /// 获取给定内部 ID 的块 ID
+    /// 块 ID 为 0 表示空洞，0 号块是超级块，不会被分配为数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `inner_id`: 内部 ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: u64 块 ID
+    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u64 {
+        if self.has_inline_data() {
+            return 0;
+        }
+        if self.uses_extents() {
+            return self.extent_block_id(inner_id, block_device);
+        }
+        let inner_id = inner_id as usize;
+        let Some((level, index)) = Self::locate(inner_id) else {
+            return self.direct[inner_id] as u64;
+        };
+        let mut block_id = match level {
+            1 => self.indirect1,
+            2 => self.indirect2,
+            _ => self.indirect3,
+        };
+        for depth in 0..level {
+            if block_id == 0 {
+                return 0;
+            }
+            block_id = Self::read_entry(block_id, Self::slot_at(index, level, depth), block_device);
+        }
+        block_id as u64
+    }
+
+    /// 获取为给定内部 ID 分配数据块时的目标：它前面一个块的块 ID，
+    /// 前面一个块是空洞时使用文件当前的最后一个数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `inner_id`: 内部 ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Option<u64> 块 ID，都是空洞时返回 None
+    pub fn goal_block(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> Option<u64> {
+        let last = self.data_blocks().checked_sub(1);
+        [inner_id.checked_sub(1), last]
+            .into_iter()
+            .flatten()
+            .map(|inner_id| self.get_block_id(inner_id, block_device))
+            .find(|&block_id| block_id != 0)
+    }
+
+    /// 将块ID转换为直接索引和间接索引中记录的 32 位块ID
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    ///
+    /// returns: u32 32 位块ID
+    fn block_map_id(block_id: u64) -> u32 {
+        u32::try_from(block_id).expect("Block-mapped inodes cannot address blocks beyond 32 bits!")
+    }
+
+    /// 设置给定内部 ID 的块 ID，所需的间接索引块必须已经存在
+    ///
+    /// # Arguments
+    ///
+    /// * `inner_id`: 内部 ID
+    /// * `block_id`: 块 ID
+    /// * `block_device`: 块设备
+    fn set_block_id(&mut self, inner_id: u32, block_id: u32, block_device: &Arc<dyn BlockDevice>) {
+        let inner_id = inner_id as usize;
+        let Some((level, index)) = Self::locate(inner_id) else {
+            self.direct[inner_id] = block_id;
+            return;
+        };
+        let mut node = *self.indirect_root(level);
+        for depth in 0..level - 1 {
+            node = Self::read_entry(node, Self::slot_at(index, level, depth), block_device);
+        }
+        Self::write_entry(
+            node,
+            Self::slot_at(index, level, level - 1),
+            block_id,
+            block_device,
+        );
+    }
+
+    /// 获取当前磁盘索引节点占用的所有块ID，包括数据块和间接索引块
+    /// 数据块在前，间接索引块在后，不包括与其它文件共享的尾部块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 块ID
+    pub fn block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u64> {
+        if self.has_inline_data() {
+            return Vec::new();
+        }
+        if self.uses_extents() {
+            let (extents, nodes) = self.extent_tree(block_device);
+            let mut v: Vec<u64> = extents
+                .iter()
+                .flat_map(|extent| extent.start..extent.start + extent.len as u64)
+                .collect();
+            v.extend(nodes);
+            return v;
+        }
+        let data_blocks = (self.data_blocks() as usize).min(INODE_DIRECT_COUNT);
+        let mut v: Vec<u32> = self.direct[..data_blocks]
+            .iter()
+            .copied()
+            .filter(|&block_id| block_id != 0)
+            .collect();
+        let mut index_blocks = Vec::new();
+        for (height, root) in [
+            (1, self.indirect1),
+            (2, self.indirect2),
+            (3, self.indirect3),
+        ] {
+            if root != 0 {
+                Self::collect_tree(root, height, &mut v, &mut index_blocks, block_device);
+            }
+        }
+        v.extend(index_blocks);
+        v.into_iter().map(u64::from).collect()
+    }
+
+    /// 获取所有间接索引块或者区段树的节点块，不包括数据块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 块ID
+    pub fn index_block_ids(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<u64> {
+        if self.has_inline_data() {
+            return Vec::new();
+        }
+        if self.uses_extents() {
+            return self.extent_tree(block_device).1;
+        }
+        let mut data_blocks = Vec::new();
+        let mut index_blocks = Vec::new();
+        for (height, root) in [
+            (1, self.indirect1),
+            (2, self.indirect2),
+            (3, self.indirect3),
+        ] {
+            if root != 0 {
+                Self::collect_tree(
+                    root,
+                    height,
+                    &mut data_blocks,
+                    &mut index_blocks,
+                    block_device,
+                );
+            }
+        }
+        index_blocks.into_iter().map(u64::from).collect()
+    }
+
+    /// 获取给定范围内的数据所在的块，包括打包的尾部所在的尾部块，跳过空洞
+    ///
+    /// # Arguments
+    ///
+    /// * `start_block`: 起始内部 ID
+    /// * `end_block`: 结束内部 ID（不包含）
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 块ID
+    pub fn range_block_ids(
+        &self,
+        start_block: u32,
+        end_block: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        if self.has_inline_data() {
+            return Vec::new();
+        }
+        let end_block = end_block.min(self.data_blocks());
+        (start_block..end_block)
+            .filter_map(|inner_id| self.data_location(inner_id, block_device))
+            .map(|(block_id, _)| block_id)
+            .collect()
+    }
+
+    /// 收集一棵间接索引子树中的数据块和间接索引块
+    ///
+    /// # Arguments
+    ///
+    /// * `node`: 子树根的块 ID
+    /// * `height`: 子树的高度，为 1 时条目指向数据块
+    /// * `data_blocks`: 收集到的数据块
+    /// * `index_blocks`: 收集到的间接索引块
+    /// * `block_device`: 块设备
+    fn collect_tree(
+        node: u32,
+        height: usize,
+        data_blocks: &mut Vec<u32>,
+        index_blocks: &mut Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        index_blocks.push(node);
+        let cache = get_block_cache(node as u64, block_device.clone());
+        let entries: Vec<u32> = cache.lock().read(0, |indirect: &IndirectBlock| {
+            indirect
+                .iter()
+                .copied()
+                .filter(|&entry| entry != 0)
+                .collect()
+        });
+        drop(cache);
+        if height == 1 {
+            data_blocks.extend(entries);
+        } else {
+            for entry in entries {
+                Self::collect_tree(entry, height - 1, data_blocks, index_blocks, block_device);
+            }
+        }
+    }
+
+    /// 获取填充给定范围内的空洞所需的块数，包括缺少的间接索引块
+    ///
+    /// # Arguments
+    ///
+    /// * `start_block`: 起始内部 ID
+    /// * `end_block`: 结束内部 ID（不包含）
+    /// * `block_device`: 块设备
+    ///
+    /// returns: u32 块数
+    pub fn holes_num(
+        &self,
+        start_block: u32,
+        end_block: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> u32 {
+        assert!(!self.has_inline_data());
+        if self.uses_extents() {
+            // 最坏情况下每个空洞都成为一个新的区段
+            let (extents, nodes) = self.extent_tree(block_device);
+            let holes = Self::extent_holes(&extents, start_block, end_block).len();
+            let nodes_needed = Self::extent_nodes_needed(extents.len() + holes);
+            return (holes + nodes_needed.saturating_sub(nodes.len())) as u32;
+        }
+        let mut total = 0;
+        // 需要分配的间接索引块：(层数, 级数, 在该级中的序号)
+        let mut missing = BTreeSet::new();
+        for inner_id in start_block..end_block {
+            if self.get_block_id(inner_id, block_device) != 0 {
+                continue;
+            }
+            total += 1;
+            let Some((level, index)) = Self::locate(inner_id as usize) else {
+                continue;
+            };
+            let mut node = match level {
+                1 => self.indirect1,
+                2 => self.indirect2,
+                _ => self.indirect3,
+            };
+            for depth in 0..level {
+                if node == 0 {
+                    for depth in depth..level {
+                        let prefix = index / INODE_INDIRECT1_COUNT.pow((level - depth) as u32);
+                        missing.insert((level, depth, prefix));
+                    }
+                    break;
+                }
+                node = Self::read_entry(node, Self::slot_at(index, level, depth), block_device);
+            }
+        }
+        total + missing.len() as u32
+    }
+
+    /// 用新分配的块填充给定范围内的空洞，并返回没有用到的块
+    /// 缺少的间接索引块会先于它下面的数据块从 `new_blocks` 中取出，
+    /// 新分配的块必须已经清零
+    /// 使用区段树时，`new_blocks` 中靠前的块依次填充空洞，其余的块和原有的区段树节点一起用于重建区段树，
+    /// 合并后不再需要的块会被返回
+    ///
+    /// # Arguments
+    ///
+    /// * `start_block`: 起始内部 ID
+    /// * `end_block`: 结束内部 ID（不包含）
+    /// * `new_blocks`: 新分配的块，数量由 [`DiskInode::holes_num`] 给出
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 没有用到的块
+    pub fn fill_holes(
+        &mut self,
+        start_block: u32,
+        end_block: u32,
+        new_blocks: Vec<u64>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        assert!(!self.has_inline_data());
+        assert!(
+            self.tail().is_none() || (end_block as u64) <= self.size / BLOCK_SZ as u64,
+            "Unpack the tail before filling it!"
+        );
+        let mut new_blocks = new_blocks.into_iter();
+        if self.uses_extents() {
+            let (mut extents, mut pool) = self.extent_tree(block_device);
+            for inner_id in Self::extent_holes(&extents, start_block, end_block) {
+                extents.push(Extent {
+                    logical: inner_id,
+                    start: new_blocks.next().unwrap(),
+                    len: 1,
+                });
+            }
+            pool.extend(new_blocks);
+            return self.store_extents(&Self::merge_extents(extents), pool, block_device);
+        }
+        for inner_id in start_block..end_block {
+            if self.get_block_id(inner_id, block_device) != 0 {
+                continue;
+            }
+            let Some((level, index)) = Self::locate(inner_id as usize) else {
+                self.direct[inner_id as usize] = Self::block_map_id(new_blocks.next().unwrap());
+                continue;
+            };
+            let root = self.indirect_root(level);
+            if *root == 0 {
+                *root = Self::block_map_id(new_blocks.next().unwrap());
+            }
+            let mut node = *root;
+            for depth in 0..level - 1 {
+                let slot = Self::slot_at(index, level, depth);
+                let mut child = Self::read_entry(node, slot, block_device);
+                if child == 0 {
+                    child = Self::block_map_id(new_blocks.next().unwrap());
+                    Self::write_entry(node, slot, child, block_device);
+                }
+                node = child;
+            }
+            Self::write_entry(
+                node,
+                Self::slot_at(index, level, level - 1),
+                Self::block_map_id(new_blocks.next().unwrap()),
+                block_device,
+            );
+        }
+        new_blocks.collect()
+    }
+
+    /// 扩容当前磁盘索引节点的大小，并返回没有用到的块
+    /// 新增的范围全部分配数据块，不留空洞
+    ///
+    /// # Arguments
+    ///
+    /// * `new_size`: 新的大小
+    /// * `new_blocks`: 新分配的块
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 没有用到的块
+    pub fn increase_size(
+        &mut self,
+        new_size: u64,
+        new_blocks: Vec<u64>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        let current_blocks = self.data_blocks();
+        self.size = new_size;
+        let total_blocks = self.data_blocks();
+        self.fill_holes(current_blocks, total_blocks, new_blocks, block_device)
+    }
+
+    /// 缩小当前磁盘索引节点的大小，并返回应该被释放的块
+    /// 我们将在稍后将块内容清零
+    /// 保留下来的间接索引块中超出新大小的条目会被清零，之后扩大文件时它们是空洞
+    ///
+    /// # Arguments
+    ///
+    /// * `new_size`: 新的大小
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 待释放的块
+    pub fn decrease_size(
+        &mut self,
+        new_size: u64,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        assert!(new_size <= self.size);
+        assert!(
+            self.tail().is_none() || new_size == self.size,
+            "Unpack the tail before truncating!"
+        );
+        if self.has_inline_data() {
+            // 截掉的部分清零，之后扩大文件时读出零
+            self.zero_range(new_size as usize, self.size as usize, block_device);
+            self.size = new_size;
+            return Vec::new();
+        }
+        let old_blocks = self.data_blocks() as usize;
+        let new_blocks = Self::_data_blocks(new_size) as usize;
+        let v = self.release_blocks(new_blocks, old_blocks, block_device);
+        self.size = new_size;
+        v
+    }
+
+    /// 在给定范围内打洞，文件大小保持不变，并返回应该被释放的块
+    /// 完全落在范围内的数据块从索引中移除，范围两端不完整的块中对应的部分被清零，
+    /// 打洞后不再索引任何块的间接索引块也会被释放
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `len`: 长度
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 待释放的块
+    pub fn punch_hole(
+        &mut self,
+        offset: usize,
+        len: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        let size = self.size as usize;
+        let end = (offset + len).min(size);
+        if offset >= end {
+            return Vec::new();
+        }
+        if self.has_inline_data() {
+            self.zero_range(offset, end, block_device);
+            return Vec::new();
+        }
 
-/// 数据块
-type DataBlock = [u8; BLOCK_SZ];
+        // 完全落在范围内的块，文件末尾之后的部分不会被读到，因此最后一个块可以整块释放
+        let first = offset.div_ceil(BLOCK_SZ);
+        let last = if end == size {
+            end.div_ceil(BLOCK_SZ)
+        } else {
+            end / BLOCK_SZ
+        };
+        if first >= last {
+            self.zero_range(offset, end, block_device);
+            return Vec::new();
+        }
+        self.zero_range(offset, first * BLOCK_SZ, block_device);
+        self.zero_range(last * BLOCK_SZ, end, block_device);
+        // 打包的尾部整个落在范围内时成为空洞，调用者负责释放它占用的片段
+        if self.tail().is_some() && last * BLOCK_SZ >= size {
+            self.set_tail(None);
+        }
+        self.release_blocks(first, last, block_device)
+    }
 
-/// 磁盘索引节点
-#[repr(C)]
-pub struct DiskInode {
-    /// 文件大小
-    pub size: u32,
+    /// 从索引中移除给定范围内的数据块，并释放因此不再索引任何块的间接索引块
+    ///
+    /// # Arguments
+    ///
+    /// * `first`: 起始内部 ID
+    /// * `last`: 结束内部 ID（不包含）
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 待释放的块
+    fn release_blocks(
+        &mut self,
+        first: usize,
+        last: usize,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        if self.uses_extents() {
+            return self.release_extents(first as u32, last as u32, block_device);
+        }
+        let mut v: Vec<u64> = Vec::new();
 
-    /// 直接索引节点
-    pub direct: [u32; INODE_DIRECT_COUNT],
+        // 数据块，跳过空洞
+        for inner_id in first..last {
+            let block_id = self.get_block_id(inner_id as u32, block_device);
+            if block_id != 0 {
+                v.push(block_id);
+                self.set_block_id(inner_id as u32, 0, block_device);
+            }
+        }
 
-    /// 一级间接索引节点
-    pub indirect1: u32,
+        // 间接索引块
+        for level in 1..=3 {
+            let (start, bound) = Self::level_range(level);
+            let (lo, hi) = (first.max(start), last.min(bound));
+            let root = *self.indirect_root(level);
+            if lo >= hi || root == 0 {
+                continue;
+            }
+            if Self::prune_tree(root, level, lo - start, hi - start, &mut v, block_device) {
+                v.push(root as u64);
+                *self.indirect_root(level) = 0;
+            }
+        }
+        v
+    }
 
-    /// 二级间接索引节点
-    pub indirect2: u32,
+    /// 释放一棵间接索引子树中与给定范围相交且不再索引任何块的间接索引块
+    ///
+    /// # Arguments
+    ///
+    /// * `node`: 子树根的块 ID
+    /// * `height`: 子树的高度，为 1 时条目指向数据块
+    /// * `lo`: 范围在子树中的起始序号
+    /// * `hi`: 范围在子树中的结束序号（不包含）
+    /// * `v`: 待释放的块
+    /// * `block_device`: 块设备
+    ///
+    /// returns: bool 子树的根是否也不再索引任何块
+    fn prune_tree(
+        node: u32,
+        height: usize,
+        lo: usize,
+        hi: usize,
+        v: &mut Vec<u64>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> bool {
+        if height > 1 {
+            let span = INODE_INDIRECT1_COUNT.pow((height - 1) as u32);
+            for slot in lo / span..=(hi - 1) / span {
+                let child = Self::read_entry(node, slot, block_device);
+                if child == 0 {
+                    continue;
+                }
+                let child_lo = lo.max(slot * span) - slot * span;
+                let child_hi = hi.min((slot + 1) * span) - slot * span;
+                if Self::prune_tree(child, height - 1, child_lo, child_hi, v, block_device) {
+                    v.push(child as u64);
+                    Self::write_entry(node, slot, 0, block_device);
+                }
+            }
+        }
+        Self::is_empty_index(node, block_device)
+    }
 
-    /// 索引节点类型
-    type_: DiskInodeType,
-}
+    /// 获取索引节点中用于映射数据块的字
+    ///
+    /// returns: [u32; 28] 直接索引节点和三个间接索引节点
+    fn map_words(&self) -> [u32; INODE_MAP_WORDS] {
+        let mut words = [0u32; INODE_MAP_WORDS];
+        words[..INODE_DIRECT_COUNT].copy_from_slice(&self.direct);
+        words[INODE_DIRECT_COUNT..].copy_from_slice(&[
+            self.indirect1,
+            self.indirect2,
+            self.indirect3,
+        ]);
+        words
+    }
 
-impl DiskInode {
-    /// 初始化一个磁盘索引节点，以及直接索引节点
-    /// 间接索引节点只有在需要时才分配
+    /// 设置索引节点中用于映射数据块的字
     ///
     /// # Arguments
     ///
-    /// * `type_`: 索引节点类型
-    pub fn initialize(&mut self, type_: DiskInodeType) {
-        self.size = 0;
-        self.direct.iter_mut().for_each(|v| *v = 0);
-        self.indirect1 = 0;
-        self.indirect2 = 0;
-        self.type_ = type_;
+    /// * `words`: 直接索引节点和三个间接索引节点
+    fn set_map_words(&mut self, words: [u32; INODE_MAP_WORDS]) {
+        self.direct.copy_from_slice(&words[..INODE_DIRECT_COUNT]);
+        self.indirect1 = words[INODE_DIRECT_COUNT];
+        self.indirect2 = words[INODE_DIRECT_COUNT + 1];
+        self.indirect3 = words[INODE_DIRECT_COUNT + 2];
     }
 
-    /// 这个磁盘索引节点是否是一个目录
-    pub fn is_dir(&self) -> bool {
-        self.type_ == DiskInodeType::Directory
+    /// 解析一个区段树节点
+    ///
+    /// # Arguments
+    ///
+    /// * `words`: 节点的字
+    ///
+    /// returns: (usize, Vec<Extent, Global>) 节点深度和条目，深度为 0 的节点是叶子
+    fn parse_extent_node(words: &[u32]) -> (usize, Vec<Extent>) {
+        let entries = words[0] as usize;
+        let depth = words[1] as usize;
+        let extents = words[EXTENT_HEADER_WORDS..]
+            .chunks_exact(EXTENT_WORDS)
+            .take(entries)
+            .map(|entry| Extent {
+                logical: entry[0],
+                start: ((entry[2] >> 16) as u64) << 32 | entry[1] as u64,
+                len: entry[2] & MAX_EXTENT_LEN,
+            })
+            .collect();
+        (depth, extents)
     }
 
-    /// 返回与当前数据大小对应的块数
-    pub fn data_blocks(&self) -> u32 {
-        Self::_data_blocks(self.size)
+    /// 编码一个区段树节点
+    ///
+    /// # Arguments
+    ///
+    /// * `words`: 节点的字
+    /// * `depth`: 节点深度
+    /// * `extents`: 条目
+    fn encode_extent_node(words: &mut [u32], depth: usize, extents: &[Extent]) {
+        words.fill(0);
+        words[0] = extents.len() as u32;
+        words[1] = depth as u32;
+        for (entry, extent) in words[EXTENT_HEADER_WORDS..]
+            .chunks_exact_mut(EXTENT_WORDS)
+            .zip(extents)
+        {
+            assert!(extent.len <= MAX_EXTENT_LEN && extent.start <= MAX_BLOCK_ID);
+            entry.copy_from_slice(&[
+                extent.logical,
+                extent.start as u32,
+                ((extent.start >> 32) as u32) << 16 | extent.len,
+            ]);
+        }
     }
 
-    /// 返回需要的块数
+    /// 读取一个区段树节点块
     ///
     /// # Arguments
     ///
-    /// * `size`: 字节数
+    /// * `block_id`: 节点的块ID
+    /// * `block_device`: 块设备
     ///
-    /// returns: u32 块数
-    fn _data_blocks(size: u32) -> u32 {
-        (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
+    /// returns: (usize, Vec<Extent, Global>) 节点深度和条目
+    fn read_extent_node(
+        block_id: u64,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> (usize, Vec<Extent>) {
+        let cache = get_block_cache(block_id, block_device.clone());
+        let node = cache
+            .lock()
+            .read(0, |words: &IndirectBlock| Self::parse_extent_node(words));
+        node
     }
 
-    /// 返回需要的块数，包括间接索引节点
+    /// 获取使用区段树时给定内部 ID 的块 ID
     ///
     /// # Arguments
     ///
-    /// * `size`: 字节数
+    /// * `inner_id`: 内部 ID
+    /// * `block_device`: 块设备
     ///
-    /// returns: u32 块数
-    pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
-        let mut total = data_blocks as usize;
-        // indirect1
-        if data_blocks > INODE_DIRECT_COUNT {
-            total += 1;
-        }
-        // indirect2
-        if data_blocks > INDIRECT1_BOUND {
-            total += 1;
-            // sub indirect1
-            total +=
-                (data_blocks - INDIRECT1_BOUND + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT;
+    /// returns: u64 块 ID，空洞为 0
+    fn extent_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u64 {
+        let (mut depth, mut extents) = Self::parse_extent_node(&self.map_words());
+        loop {
+            let Some(extent) = extents
+                .iter()
+                .rev()
+                .find(|extent| extent.logical <= inner_id)
+            else {
+                return 0;
+            };
+            if depth == 0 {
+                return if inner_id - extent.logical < extent.len {
+                    extent.start + (inner_id - extent.logical) as u64
+                } else {
+                    0
+                };
+            }
+            (depth, extents) = Self::read_extent_node(extent.start, block_device);
         }
-        total as u32
     }
 
-    /// 获取在新的数据大小下需要分配的数据块数
+    /// 获取所有区段，按内部 ID 排序
     ///
     /// # Arguments
     ///
-    /// * `new_size`: 新的数据大小
+    /// * `block_device`: 块设备
     ///
-    /// returns: u32 新增块数
-    pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
-        assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+    /// returns: Vec<Extent, Global> 区段，不使用区段树时为空
+    pub fn extents(&self, block_device: &Arc<dyn BlockDevice>) -> Vec<Extent> {
+        if !self.uses_extents() {
+            return Vec::new();
+        }
+        self.extent_tree(block_device).0
     }
 
-    /// 获取给定内部 ID 的块 ID
+    /// 读取整棵区段树
     ///
     /// # Arguments
     ///
-    /// * `inner_id`: 内部 ID
     /// * `block_device`: 块设备
     ///
-    /// returns: u32 块 ID
-    pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
-        let cache;
-        let inner_id = inner_id as usize;
-        if inner_id < INODE_DIRECT_COUNT {
-            self.direct[inner_id]
-        } else if inner_id < INDIRECT1_BOUND {
-            cache = get_block_cache(self.indirect1 as usize, block_device.clone());
-            cache.lock().read(0, |indirect_block: &IndirectBlock| {
-                indirect_block[inner_id - INODE_DIRECT_COUNT]
-            })
-        } else {
-            let last = inner_id - INDIRECT1_BOUND;
-            let indirect2_cache = get_block_cache(self.indirect2 as usize, block_device.clone());
-            let indirect1 = indirect2_cache.lock().read(0, |indirect2: &IndirectBlock| {
-                indirect2[last / INODE_INDIRECT1_COUNT]
-            });
-            cache = get_block_cache(indirect1 as usize, block_device.clone());
-            cache.lock().read(0, |indirect1: &IndirectBlock| {
-                indirect1[last % INODE_INDIRECT1_COUNT]
-            })
-        }
+    /// returns: (Vec<Extent, Global>, Vec<u64, Global>) 区段和区段树节点块
+    fn extent_tree(&self, block_device: &Arc<dyn BlockDevice>) -> (Vec<Extent>, Vec<u64>) {
+        let mut extents = Vec::new();
+        let mut nodes = Vec::new();
+        let (depth, entries) = Self::parse_extent_node(&self.map_words());
+        Self::collect_extents(depth, entries, &mut extents, &mut nodes, block_device);
+        (extents, nodes)
     }
 
-    /// 扩容当前磁盘索引节点的大小
+    /// 收集一棵区段子树中的区段和节点块
     ///
     /// # Arguments
     ///
-    /// * `new_size`: 新的大小
-    /// * `new_blocks`: 新分配的块
+    /// * `depth`: 子树根的深度
+    /// * `entries`: 子树根的条目
+    /// * `extents`: 收集到的区段
+    /// * `nodes`: 收集到的节点块
     /// * `block_device`: 块设备
-    pub fn increase_size(
-        &mut self,
-        new_size: u32,
-        new_blocks: Vec<u32>,
+    fn collect_extents(
+        depth: usize,
+        entries: Vec<Extent>,
+        extents: &mut Vec<Extent>,
+        nodes: &mut Vec<u64>,
         block_device: &Arc<dyn BlockDevice>,
     ) {
-        let mut current_blocks = self.data_blocks();
-        self.size = new_size;
-        let mut total_blocks = self.data_blocks();
-        let mut new_blocks = new_blocks.into_iter();
+        if depth == 0 {
+            extents.extend(entries);
+            return;
+        }
+        for entry in entries {
+            nodes.push(entry.start);
+            let (depth, entries) = Self::read_extent_node(entry.start, block_device);
+            Self::collect_extents(depth, entries, extents, nodes, block_device);
+        }
+    }
 
-        // 填充直接索引节点
-        while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
-            self.direct[current_blocks as usize] = new_blocks.next().unwrap();
-            current_blocks += 1;
+    /// 容纳给定数量的区段需要的区段树节点块数
+    ///
+    /// # Arguments
+    ///
+    /// * `count`: 区段数
+    ///
+    /// returns: usize 节点块数
+    fn extent_nodes_needed(count: usize) -> usize {
+        let mut entries = count;
+        let mut nodes = 0;
+        while entries > EXTENT_ROOT_CAPACITY {
+            entries = entries.div_ceil(EXTENT_NODE_CAPACITY);
+            nodes += entries;
         }
+        nodes
+    }
 
-        // 分配一级间接索引节点
-        if total_blocks > INODE_DIRECT_COUNT as u32 {
-            if current_blocks == INODE_DIRECT_COUNT as u32 {
-                self.indirect1 = new_blocks.next().unwrap();
-            }
-            current_blocks -= INODE_DIRECT_COUNT as u32;
-            total_blocks -= INODE_DIRECT_COUNT as u32;
-        } else {
-            return;
+    /// 用给定的区段重建区段树，并返回没有用到的块
+    ///
+    /// # Arguments
+    ///
+    /// * `extents`: 按内部 ID 排序的区段
+    /// * `pool`: 可用作节点的块，数量不少于 [`DiskInode::extent_nodes_needed`]
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 没有用到的块
+    fn store_extents(
+        &mut self,
+        extents: &[Extent],
+        pool: Vec<u64>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        let mut pool = pool.into_iter();
+        let mut entries = extents.to_vec();
+        let mut depth = 0;
+        while entries.len() > EXTENT_ROOT_CAPACITY {
+            entries = entries
+                .chunks(EXTENT_NODE_CAPACITY)
+                .map(|chunk| {
+                    let block_id = pool.next().unwrap();
+                    let cache = get_block_cache(block_id, block_device.clone());
+                    cache.lock().modify(0, |words: &mut IndirectBlock| {
+                        Self::encode_extent_node(words, depth, chunk);
+                    });
+                    Extent {
+                        logical: chunk[0].logical,
+                        start: block_id,
+                        len: 0,
+                    }
+                })
+                .collect();
+            depth += 1;
         }
+        let mut words = [0u32; INODE_MAP_WORDS];
+        Self::encode_extent_node(&mut words, depth, &entries);
+        self.set_map_words(words);
+        pool.collect()
+    }
 
-        // 填充一级间接索引节点
-        let indirect1_cache = get_block_cache(self.indirect1 as usize, block_device.clone());
-        indirect1_cache
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < total_blocks.min(INODE_INDIRECT1_COUNT as u32) {
-                    indirect1[current_blocks as usize] = new_blocks.next().unwrap();
-                    current_blocks += 1;
+    /// 排序并合并逻辑上和磁盘上都相邻的区段，合并后的区段不超过 [`MAX_EXTENT_LEN`] 个块
+    ///
+    /// # Arguments
+    ///
+    /// * `extents`: 区段
+    ///
+    /// returns: Vec<Extent, Global> 合并后的区段
+    fn merge_extents(mut extents: Vec<Extent>) -> Vec<Extent> {
+        extents.sort_by_key(|extent| extent.logical);
+        let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+        for extent in extents {
+            match merged.last_mut() {
+                Some(last)
+                    if last.logical + last.len == extent.logical
+                        && last.start + last.len as u64 == extent.start
+                        && last.len + extent.len <= MAX_EXTENT_LEN =>
+                {
+                    last.len += extent.len;
                 }
-            });
+                _ => merged.push(extent),
+            }
+        }
+        merged
+    }
 
-        // 分配二级间接索引节点
-        if total_blocks > INODE_INDIRECT1_COUNT as u32 {
-            if current_blocks == INODE_INDIRECT1_COUNT as u32 {
-                self.indirect2 = new_blocks.next().unwrap();
+    /// 获取给定范围内没有被区段覆盖的内部 ID
+    ///
+    /// # Arguments
+    ///
+    /// * `extents`: 按内部 ID 排序的区段
+    /// * `start_block`: 起始内部 ID
+    /// * `end_block`: 结束内部 ID（不包含）
+    ///
+    /// returns: Vec<u32, Global> 空洞的内部 ID
+    fn extent_holes(extents: &[Extent], start_block: u32, end_block: u32) -> Vec<u32> {
+        let mut holes = Vec::new();
+        let mut next = start_block;
+        for extent in extents {
+            if extent.logical >= end_block {
+                break;
             }
-            current_blocks -= INODE_INDIRECT1_COUNT as u32;
-            total_blocks -= INODE_INDIRECT1_COUNT as u32;
-        } else {
-            return;
+            if extent.logical + extent.len <= next {
+                continue;
+            }
+            holes.extend(next..extent.logical.max(next));
+            next = extent.logical + extent.len;
         }
+        holes.extend(next..end_block);
+        holes
+    }
 
-        // 填充二级间接索引节点从 (a0, b0) -> (a1, b1)
-        let mut a0 = current_blocks as usize / INODE_INDIRECT1_COUNT;
-        let mut b0 = current_blocks as usize % INODE_INDIRECT1_COUNT;
-        let a1 = total_blocks as usize / INODE_INDIRECT1_COUNT;
-        let b1 = total_blocks as usize % INODE_INDIRECT1_COUNT;
+    /// 使用区段树时，从映射中移除给定范围内的数据块，并返回应该被释放的块
+    ///
+    /// # Arguments
+    ///
+    /// * `first`: 起始内部 ID
+    /// * `last`: 结束内部 ID（不包含）
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<u64, Global> 待释放的块
+    fn release_extents(
+        &mut self,
+        first: u32,
+        last: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<u64> {
+        let (extents, mut pool) = self.extent_tree(block_device);
+        let mut kept = Vec::with_capacity(extents.len() + 1);
+        let mut v = Vec::new();
+        for extent in extents {
+            let end = extent.logical + extent.len;
+            if end <= first || extent.logical >= last {
+                kept.push(extent);
+                continue;
+            }
+            if extent.logical < first {
+                kept.push(Extent {
+                    len: first - extent.logical,
+                    ..extent
+                });
+            }
+            let lo = extent.logical.max(first) - extent.logical;
+            let hi = end.min(last) - extent.logical;
+            v.extend(extent.start + lo as u64..extent.start + hi as u64);
+            if end > last {
+                kept.push(Extent {
+                    logical: last,
+                    start: extent.start + (last - extent.logical) as u64,
+                    len: end - last,
+                });
+            }
+        }
 
-        // 分配低等级的一级间接索引节点
-        let indirect2_cache = get_block_cache(self.indirect2 as usize, block_device.clone());
-        indirect2_cache
-            .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                while (a0 < a1) || (a0 == a1 && b0 < b1) {
-                    if b0 == 0 {
-                        indirect2[a0] = new_blocks.next().unwrap();
-                    }
+        // 从一个区段中间移除时区段数会加一，可能需要更多的节点，从被移除的数据块中借用
+        let nodes_needed = Self::extent_nodes_needed(kept.len());
+        if nodes_needed > pool.len() + v.len() {
+            // 被移除的块太少，保留这个区段并将范围内的数据清零
+            self.zero_range(
+                first as usize * BLOCK_SZ,
+                last as usize * BLOCK_SZ,
+                block_device,
+            );
+            return Vec::new();
+        }
+        while pool.len() < nodes_needed {
+            pool.push(v.pop().unwrap());
+        }
+        v.extend(self.store_extents(&kept, pool, block_device));
+        v
+    }
 
-                    // 填充当前
-                    let cache = get_block_cache(indirect2[a0] as usize, block_device.clone());
-                    cache.lock().modify(0, |indirect1: &mut IndirectBlock| {
-                        indirect1[b0] = new_blocks.next().unwrap();
-                    });
+    /// 将给定范围内的数据清零，空洞保持不变
+    ///
+    /// # Arguments
+    ///
+    /// * `start`: 起始偏移
+    /// * `end`: 结束偏移（不包含）
+    /// * `block_device`: 块设备
+    fn zero_range(&mut self, mut start: usize, end: usize, block_device: &Arc<dyn BlockDevice>) {
+        if self.has_inline_data() {
+            let mut data = self.inline_bytes();
+            data[start..end].fill(0);
+            self.set_inline_bytes(&data);
+            return;
+        }
+        while start < end {
+            let end_current_block = ((start / BLOCK_SZ + 1) * BLOCK_SZ).min(end);
+            let location = self.data_location((start / BLOCK_SZ) as u32, block_device);
+            if let Some((block_id, offset)) = location {
+                let block_start = offset + start % BLOCK_SZ;
+                let len = end_current_block - start;
+                let cache = get_block_cache(block_id, block_device.clone());
+                cache.lock().modify(0, |data_block: &mut DataBlock| {
+                    data_block[block_start..block_start + len].fill(0);
+                });
+            }
+            start = end_current_block;
+        }
+    }
 
-                    // 移动到下一个
-                    b0 += 1;
-                    if b0 == INODE_INDIRECT1_COUNT {
-                        b0 = 0;
-                        a0 += 1;
-                    }
-                }
-            });
+    /// 间接索引块是否不再索引任何块
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 间接索引块的块 ID
+    /// * `block_device`: 块设备
+    ///
+    /// returns: bool 是否为空
+    fn is_empty_index(block_id: u32, block_device: &Arc<dyn BlockDevice>) -> bool {
+        let cache = get_block_cache(block_id as u64, block_device.clone());
+        let empty = cache.lock().read(0, |indirect: &IndirectBlock| {
+            indirect.iter().all(|&entry| entry == 0)
+        });
+        empty
     }
 
     /// 将大小清空为零，并返回应该被释放的块
@@ -320,82 +2964,56 @@ impl DiskInode {
     ///
     /// * `block_device`: 块设备
     ///
-    /// returns: Vec<u32, Global> 待释放的块
-    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
-        let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
-        self.size = 0;
-        let mut current_blocks = 0usize;
-        // 直接
-        while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
-            v.push(self.direct[current_blocks]);
-            self.direct[current_blocks] = 0;
-            current_blocks += 1;
-        }
-        // 一级间接索引块
-        if data_blocks > INODE_DIRECT_COUNT {
-            v.push(self.indirect1);
-            data_blocks -= INODE_DIRECT_COUNT;
-            current_blocks = 0;
-        } else {
-            return v;
+    /// returns: Vec<u64, Global> 待释放的块
+    pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u64> {
+        self.decrease_size(0, block_device)
+    }
+
+    /// 找到一段块的数据位置，并将其中不在缓存里的块成批加载到块缓存
+    ///
+    /// # Arguments
+    ///
+    /// * `inner_ids`: 内部 ID 的范围
+    /// * `block_device`: 块设备
+    ///
+    /// returns: Vec<Option<(u64, usize)>, Global> 每个块的块ID和偏移，空洞为 None
+    fn prefetch_range(
+        &self,
+        inner_ids: Range<usize>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> Vec<Option<(u64, usize)>> {
+        let locations: Vec<Option<(u64, usize)>> = inner_ids
+            .map(|inner_id| self.data_location(inner_id as u32, block_device))
+            .collect();
+        let block_ids: Vec<u64> = locations
+            .iter()
+            .flatten()
+            .map(|&(block_id, _)| block_id)
+            .collect();
+        if !block_ids.is_empty() {
+            block_cache_prefetch(block_device, &block_ids);
         }
-        // 一级间接索引
-        let indirect1_cache = get_block_cache(self.indirect1 as usize, block_device.clone());
-        indirect1_cache
-            .lock()
-            .modify(0, |indirect1: &mut IndirectBlock| {
-                while current_blocks < data_blocks.min(INODE_INDIRECT1_COUNT) {
-                    v.push(indirect1[current_blocks]);
-                    //indirect1[current_blocks] = 0;
-                    current_blocks += 1;
-                }
-            });
-        self.indirect1 = 0;
-        // 二级间接索引块
-        if data_blocks > INODE_INDIRECT1_COUNT {
-            v.push(self.indirect2);
-            data_blocks -= INODE_INDIRECT1_COUNT;
-        } else {
-            return v;
+        locations
+    }
+
+    /// 预读从给定偏移开始的若干个块，超出文件大小的部分和空洞被忽略
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `blocks`: 块数
+    /// * `block_device`: 块设备
+    pub fn read_ahead(&self, offset: usize, blocks: usize, block_device: &Arc<dyn BlockDevice>) {
+        if self.has_inline_data() || offset >= self.size as usize {
+            return;
         }
-        // 二级间接索引
-        assert!(data_blocks <= INODE_INDIRECT2_COUNT);
-        let a1 = data_blocks / INODE_INDIRECT1_COUNT;
-        let b1 = data_blocks % INODE_INDIRECT1_COUNT;
-        let indirect2_cache = get_block_cache(self.indirect2 as usize, block_device.clone());
-        indirect2_cache
-            .lock()
-            .modify(0, |indirect2: &mut IndirectBlock| {
-                // 完整的一级间接索引块
-                for entry in indirect2.iter_mut().take(a1) {
-                    v.push(*entry);
-                    let cache = get_block_cache(*entry as usize, block_device.clone());
-                    cache.lock().modify(0, |indirect1: &mut IndirectBlock| {
-                        for entry in indirect1.iter() {
-                            v.push(*entry);
-                        }
-                    });
-                }
-                // 最后一个一级间接索引块
-                if b1 > 0 {
-                    v.push(indirect2[a1]);
-                    let block_cache = get_block_cache(indirect2[a1] as usize, block_device.clone());
-                    block_cache
-                        .lock()
-                        .modify(0, |indirect1: &mut IndirectBlock| {
-                            for entry in indirect1.iter().take(b1) {
-                                v.push(*entry);
-                            }
-                        });
-                    //indirect2[a1] = 0;
-                }
-            });
-        self.indirect2 = 0;
-        v
+        let first = offset / BLOCK_SZ;
+        let end = (self.size as usize).div_ceil(BLOCK_SZ).min(first + blocks);
+        self.prefetch_range(first..end, block_device);
     }
 
     /// 从当前磁盘索引节点中读取数据
+    /// 每次先找到接下来 [`READ_AHEAD_BLOCKS`] 个块的数据位置，将它们成批加载到块缓存再逐块复制
     ///
     /// # Arguments
     ///
@@ -415,24 +3033,45 @@ impl DiskInode {
         if start >= end {
             return 0;
         }
+        if self.has_inline_data() {
+            buf[..end - start].copy_from_slice(&self.inline_bytes()[start..end]);
+            return end - start;
+        }
         let mut start_block = start / BLOCK_SZ;
+        let end_block = end.div_ceil(BLOCK_SZ);
         let mut read_size = 0usize;
+        let mut window = Vec::new().into_iter();
         loop {
             // 计算当前块的结尾
             let mut end_current_block = (start / BLOCK_SZ + 1) * BLOCK_SZ;
             end_current_block = end_current_block.min(end);
 
+            // 进入下一个窗口时成批加载窗口中的块
+            let location = match window.next() {
+                Some(location) => location,
+                None => {
+                    let window_end = end_block.min(start_block + READ_AHEAD_BLOCKS);
+                    window = self
+                        .prefetch_range(start_block..window_end, block_device)
+                        .into_iter();
+                    window.next().unwrap()
+                }
+            };
+
             // 读取并更新读取大小
             let block_read_size = end_current_block - start;
             let dst = &mut buf[read_size..read_size + block_read_size];
-            let cache = get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                block_device.clone(),
-            );
-            cache.lock().read(0, |data_block: &DataBlock| {
-                let src = &data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_read_size];
-                dst.copy_from_slice(src);
-            });
+            match location {
+                // 空洞读出零
+                None => dst.fill(0),
+                Some((block_id, offset)) => {
+                    let cache = get_block_cache(block_id, block_device.clone());
+                    cache.lock().read(0, |data_block: &DataBlock| {
+                        let start = offset + start % BLOCK_SZ;
+                        dst.copy_from_slice(&data_block[start..start + block_read_size]);
+                    });
+                }
+            }
             read_size += block_read_size;
 
             // 移动到下一个块
@@ -446,7 +3085,7 @@ impl DiskInode {
     }
 
     /// 写入数据到当前磁盘索引节点
-    /// 大小必须在调用前调整
+    /// 大小必须在调用前调整，写入范围内的空洞必须已经填充
     ///
     /// # Arguments
     ///
@@ -464,6 +3103,15 @@ impl DiskInode {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
         assert!(start <= end);
+        if start == end {
+            return 0;
+        }
+        if self.has_inline_data() {
+            let mut data = self.inline_bytes();
+            data[start..end].copy_from_slice(&buf[..end - start]);
+            self.set_inline_bytes(&data);
+            return end - start;
+        }
         let mut start_block = start / BLOCK_SZ;
         let mut write_size = 0usize;
         loop {
@@ -473,10 +3121,9 @@ impl DiskInode {
 
             // 写入并更新写入大小
             let block_write_size = end_current_block - start;
-            let cache = get_block_cache(
-                self.get_block_id(start_block as u32, block_device) as usize,
-                block_device.clone(),
-            );
+            let block_id = self.get_block_id(start_block as u32, block_device);
+            assert_ne!(block_id, 0, "Writing to a hole!");
+            let cache = get_block_cache(block_id, block_device.clone());
             cache.lock().modify(0, |data_block: &mut DataBlock| {
                 let src = &buf[write_size..write_size + block_write_size];
                 let dst = &mut data_block[start % BLOCK_SZ..start % BLOCK_SZ + block_write_size];
@@ -496,10 +3143,40 @@ impl DiskInode {
     }
 }
 
+/// 目录条目记录的索引节点类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DirEntryType {
+    /// 未知，旧版本写入的目录条目
+    Unknown = 0,
+
+    /// 文件
+    File = 1,
+
+    /// 目录
+    Directory = 2,
+
+    /// 符号链接
+    Symlink = 3,
+}
+
+impl From<&DiskInodeType> for DirEntryType {
+    fn from(type_: &DiskInodeType) -> Self {
+        match type_ {
+            DiskInodeType::File => Self::File,
+            DiskInodeType::Directory => Self::Directory,
+        }
+    }
+}
+
 /// 一个目录条目
 #[repr(C)]
 pub struct DirEntry {
     name: [u8; NAME_LENGTH_LIMIT + 1],
+
+    /// 索引节点类型，旧版本中这里总是名称末尾的零字节
+    type_: u8,
+
     inode_number: u32,
 }
 
@@ -511,41 +3188,76 @@ impl DirEntry {
     pub fn empty() -> Self {
         Self {
             name: [0u8; NAME_LENGTH_LIMIT + 1],
+            type_: DirEntryType::Unknown as u8,
             inode_number: 0,
         }
     }
 
-    /// 根据名称和索引节点号创建一个目录条目
+    /// 是否是删除目录条目后留下的空位，合法的目录条目的名称不为空
+    pub fn is_free(&self) -> bool {
+        self.name[0] == 0
+    }
+
+    /// 根据名称、索引节点号和索引节点类型创建一个目录条目
     ///
     /// # Arguments
     ///
-    /// * `name`: 文件名
+    /// * `name`: 检查过的文件名
     /// * `inode_number`: 索引节点号
+    /// * `type_`: 索引节点类型
     ///
     /// returns: DirEntry 目录条目
-    pub fn new(name: &str, inode_number: u32) -> Self {
+    pub fn new(name: FileName<'_>, inode_number: u32, type_: DirEntryType) -> Self {
         let mut bytes = [0u8; NAME_LENGTH_LIMIT + 1];
         bytes[..name.len()].copy_from_slice(name.as_bytes());
         Self {
             name: bytes,
+            type_: type_ as u8,
             inode_number,
         }
     }
 
     /// 序列化为不可变字节
     pub fn as_bytes(&self) -> &[u8] {
-        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SZ) }
+        bytes_of(self)
     }
 
     /// 序列化为可变字节
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, DIRENT_SZ) }
+        bytes_of_mut(self)
     }
 
     /// 获取条目的名称
-    pub fn name(&self) -> &str {
-        let len = (0usize..).find(|i| self.name[*i] == 0).unwrap();
-        core::str::from_utf8(&self.name[..len]).unwrap()
+    /// 名称不是合法的 UTF-8 时返回错误，此时可以使用 [`DirEntry::name_bytes`] 或 [`DirEntry::name_lossy`]
+    ///
+    /// returns: Result<&str, Utf8Error> 条目的名称
+    pub fn name(&self) -> Result<&str, Utf8Error> {
+        core::str::from_utf8(self.name_bytes())
+    }
+
+    /// 获取条目名称的原始字节
+    pub fn name_bytes(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+
+    /// 获取用于显示的条目名称，不合法的 UTF-8 序列被替换为 `U+FFFD`
+    pub fn name_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.name_bytes())
+    }
+
+    /// 获取条目记录的索引节点类型，无需读取磁盘索引节点
+    pub fn entry_type(&self) -> DirEntryType {
+        match self.type_ {
+            1 => DirEntryType::File,
+            2 => DirEntryType::Directory,
+            3 => DirEntryType::Symlink,
+            _ => DirEntryType::Unknown,
+        }
     }
 
     /// 获取条目的索引节点号
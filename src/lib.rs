@@ -0,0 +1,44 @@
+pub mod bitmap;
+pub mod block_cache;
+pub mod block_device;
+pub mod builder;
+pub mod checksum;
+pub mod clock;
+pub mod efs;
+pub mod error;
+pub mod eviction;
+pub mod file;
+pub mod fsck;
+pub mod journal;
+pub mod layout;
+pub mod migrate;
+pub mod mount;
+pub mod name;
+pub mod permission;
+pub mod pod;
+pub mod vfs;
+#[cfg(feature = "writeback")]
+pub mod writeback;
+
+/// 一个块占用的字节数
+/// 默认为 512 字节，可以通过 `block-1k`、`block-2k` 或 `block-4k` 特性选择更大的块，
+/// 同时启用多个特性时使用最大的块；块大小记录在超级块中，只能打开块大小相同的镜像
+#[cfg(not(any(feature = "block-1k", feature = "block-2k", feature = "block-4k")))]
+pub const BLOCK_SZ: usize = 512;
+
+/// 一个块占用的字节数
+#[cfg(all(
+    feature = "block-1k",
+    not(any(feature = "block-2k", feature = "block-4k"))
+))]
+pub const BLOCK_SZ: usize = 1024;
+
+/// 一个块占用的字节数
+#[cfg(all(feature = "block-2k", not(feature = "block-4k")))]
+pub const BLOCK_SZ: usize = 2048;
+
+/// 一个块占用的字节数
+#[cfg(feature = "block-4k")]
+pub const BLOCK_SZ: usize = 4096;
+
+pub fn nop() {}
@@ -1,38 +1,187 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::sync::{Arc, Mutex};
+#[cfg(feature = "writeback")]
+use std::time::{Duration, Instant};
 
-use crate::block_device::BlockDevice;
-use crate::efs::EasyFileSystem;
-
-mod bitmap;
-mod block_cache;
-mod block_device;
-mod efs;
-mod layout;
-mod vfs;
-
-/// 一个块占用的字节数
-const BLOCK_SZ: usize = 512;
+use file_system::bitmap::Bitmap;
+use file_system::block_cache::{
+    block_cache_prefetch, block_cache_release, block_cache_sync_all, get_block_cache,
+    pin_block_cache, set_block_cache_capacity, set_block_cache_policy, unpin_block_cache,
+    DEFAULT_BLOCK_CACHE_SIZE,
+};
+use file_system::block_device::BlockDevice;
+use file_system::builder::FilesystemBuilder;
+use file_system::efs::{compact, EasyFileSystem, WriteMode};
+use file_system::error::MigrateError;
+use file_system::error::{CacheError, FsError};
+use file_system::eviction::{ArcPolicy, ClockPolicy, EvictionPolicy, FifoPolicy, LruPolicy};
+use file_system::file::{FileHandle, OpenFlags};
+use file_system::fsck::{self, Problem};
+use file_system::layout::{
+    DirEntryType, GroupDescriptor, QuotaTarget, EFS_VERSION, INLINE_DATA_CAPACITY,
+};
+use file_system::migrate::migrate;
+use file_system::mount::MountTable;
+use file_system::vfs::{glob_match, Walk};
+#[cfg(feature = "writeback")]
+use file_system::writeback::{start_writeback, stop_writeback, writeback_running, WritebackConfig};
+use file_system::BLOCK_SZ;
 
 #[derive(Debug)]
 /// 块文件
 struct BlockFile(Mutex<File>);
 
 impl BlockDevice for BlockFile {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+        file.seek(SeekFrom::Start(block_id * BLOCK_SZ as u64))
             .expect("Error when seeking!");
         assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
     }
 
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
         let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
+        file.seek(SeekFrom::Start(block_id * BLOCK_SZ as u64))
             .expect("Error when seeking!");
         assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
     }
+
+    fn flush(&self) {
+        let file = self.0.lock().unwrap();
+        file.sync_data().expect("Error when flushing!");
+    }
+
+    fn num_blocks(&self) -> Option<u64> {
+        let file = self.0.lock().unwrap();
+        Some(file.metadata().ok()?.len() / BLOCK_SZ as u64)
+    }
+}
+
+#[derive(Debug)]
+/// 稀疏的内存块设备，全零的块不占用空间，内容相同的块共享同一份数据
+struct SparseDevice {
+    /// 总块数
+    total_blocks: u64,
+
+    /// 块ID到块内容的映射
+    blocks: Mutex<HashMap<u64, Arc<[u8]>>>,
+
+    /// 所有不同的块内容
+    contents: Mutex<HashSet<Arc<[u8]>>>,
+}
+
+impl SparseDevice {
+    fn new(total_blocks: u64) -> Self {
+        Self {
+            total_blocks,
+            blocks: Mutex::new(HashMap::new()),
+            contents: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl BlockDevice for SparseDevice {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        assert!(block_id < self.total_blocks, "Out of device range!");
+        match self.blocks.lock().unwrap().get(&block_id) {
+            Some(block) => buf.copy_from_slice(block),
+            None => buf.fill(0),
+        }
+    }
+
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
+        assert!(block_id < self.total_blocks, "Out of device range!");
+        let mut blocks = self.blocks.lock().unwrap();
+        let mut contents = self.contents.lock().unwrap();
+        if buf.iter().all(|&byte| byte == 0) {
+            blocks.remove(&block_id);
+            return;
+        }
+        let block = match contents.get(buf) {
+            Some(block) => block.clone(),
+            None => {
+                let block: Arc<[u8]> = buf.into();
+                contents.insert(block.clone());
+                block
+            }
+        };
+        blocks.insert(block_id, block);
+    }
+
+    fn num_blocks(&self) -> Option<u64> {
+        Some(self.total_blocks)
+    }
+}
+
+#[derive(Debug)]
+/// 记录每次读写的内存块设备，用来观察块缓存如何访问块设备
+struct CountingDevice {
+    /// 保存块内容的设备
+    inner: SparseDevice,
+
+    /// 每次读取的起始块ID和块数
+    reads: Mutex<Vec<(u64, usize)>>,
+
+    /// 每次写入的起始块ID和块数
+    writes: Mutex<Vec<(u64, usize)>>,
+}
+
+impl CountingDevice {
+    fn new(total_blocks: u64) -> Self {
+        Self {
+            inner: SparseDevice::new(total_blocks),
+            reads: Mutex::new(Vec::new()),
+            writes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 取出记录的读取
+    fn take_reads(&self) -> Vec<(u64, usize)> {
+        std::mem::take(&mut self.reads.lock().unwrap())
+    }
+
+    /// 取出记录的写入
+    fn take_writes(&self) -> Vec<(u64, usize)> {
+        std::mem::take(&mut self.writes.lock().unwrap())
+    }
+}
+
+impl BlockDevice for CountingDevice {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        self.reads.lock().unwrap().push((block_id, 1));
+        self.inner.read_block(block_id, buf);
+    }
+
+    fn read_blocks(&self, block_id: u64, buf: &mut [u8]) {
+        self.reads
+            .lock()
+            .unwrap()
+            .push((block_id, buf.len() / BLOCK_SZ));
+        for (i, chunk) in buf.chunks_mut(BLOCK_SZ).enumerate() {
+            self.inner.read_block(block_id + i as u64, chunk);
+        }
+    }
+
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
+        self.writes.lock().unwrap().push((block_id, 1));
+        self.inner.write_block(block_id, buf);
+    }
+
+    fn write_blocks(&self, block_id: u64, buf: &[u8]) {
+        self.writes
+            .lock()
+            .unwrap()
+            .push((block_id, buf.len() / BLOCK_SZ));
+        for (i, chunk) in buf.chunks(BLOCK_SZ).enumerate() {
+            self.inner.write_block(block_id + i as u64, chunk);
+        }
+    }
+
+    fn num_blocks(&self) -> Option<u64> {
+        self.inner.num_blocks()
+    }
 }
 
 fn efs_test() -> std::io::Result<()> {
@@ -41,35 +190,390 @@ fn efs_test() -> std::io::Result<()> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open("target/fs.img")?;
-        f.set_len(8192 * 512).unwrap();
+        f.set_len(8192 * BLOCK_SZ as u64).unwrap();
         f
     })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
+    FilesystemBuilder::new(8192)
+        .bytes_per_inode(1024)
+        .format(block_file.clone())?;
+    let efs = EasyFileSystem::open(block_file.clone())?;
+    efs.lock().set_label("efs-test").unwrap();
+    assert_eq!(efs.lock().label(), "efs-test");
     let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
+    root_inode.create("filea")?;
+    root_inode.create("fileb")?;
+    for name in root_inode.ls().unwrap() {
         println!("{}", name);
     }
+    let dira = root_inode.create_dir("dira").unwrap();
+    assert!(root_inode
+        .read_dir()
+        .any(|dirent| dirent.is_ok_and(|dirent| {
+            dirent.name() == Ok("dira") && dirent.entry_type() == DirEntryType::Directory
+        })));
+    dira.create("filec")?;
+    assert!(root_inode.find_path("dira/../filea").is_ok());
+    assert!(root_inode.find_path("/dira/./filec").is_ok());
+    dira.create_dir("dirb")?;
+    assert_eq!(dira.remove_dir("dirb"), Ok(()));
+    assert_eq!(
+        root_inode.remove_dir("dira"),
+        Err(FsError::DirectoryNotEmpty)
+    );
     let filea = root_inode.find("filea").unwrap();
+    assert!(Arc::ptr_eq(&filea, &root_inode.find("filea").unwrap()));
     let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
+    filea.write_at(0, greet_str.as_bytes())?;
     //let mut buffer = [0u8; 512];
     let mut buffer = [0u8; 233];
     let len = filea.read_at(0, &mut buffer);
     assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
+    let fileb = root_inode.find("fileb").unwrap();
+    fileb.write_append(b"Hello, ")?;
+    fileb.write_append(b"world!")?;
+    assert_eq!(fileb.read_all(), greet_str.as_bytes());
+    let filed = root_inode.copy("fileb", &dira, "filed").unwrap();
+    assert_eq!(filed.read_all(), greet_str.as_bytes());
+    let mut handle = FileHandle::new(filed);
+    handle.seek(SeekFrom::End(-6))?;
+    let mut word = String::new();
+    handle.read_to_string(&mut word)?;
+    assert_eq!(word, "world!");
+    let mut log = root_inode.open(
+        "log",
+        OpenFlags::CREATE | OpenFlags::WRONLY | OpenFlags::APPEND,
+    )?;
+    log.write_all(b"one")?;
+    log.seek(SeekFrom::Start(0))?;
+    log.write_all(b"two")?;
+    assert_eq!(log.inode().read_all(), b"onetwo");
+    assert!(log.read(&mut buffer).is_err());
+    assert_eq!(
+        log.inode().write_at(2 << 30, b"x"),
+        Err(FsError::FileTooLarge)
+    );
+    assert_eq!(log.inode().read_all(), b"onetwo");
+    assert_eq!(
+        root_inode
+            .open("log", OpenFlags::CREATE | OpenFlags::EXCL)
+            .err(),
+        Some(FsError::AlreadyExists)
+    );
+    // 释放元数据块时返回的错误带有块ID
+    let error = efs.lock().dealloc_data(0).unwrap_err();
+    assert_eq!(error.kind(), &FsError::Corrupted);
+    assert_eq!(
+        error.to_string(),
+        "dealloc_data (block 0): filesystem corrupted"
+    );
+    // 重复释放尾部和超出预留的释放返回错误，不做任何修改
+    let first = efs.lock().alloc_tail(100, 0)?.unwrap();
+    let second = efs.lock().alloc_tail(100, 0)?.unwrap();
+    assert_eq!(first.block_id, second.block_id);
+    efs.lock().dealloc_tail(first)?;
+    let error = efs.lock().dealloc_tail(first).unwrap_err();
+    assert_eq!(error.kind(), &FsError::Corrupted);
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "dealloc_tail (block {}): filesystem corrupted",
+            first.block_id
+        )
+    );
+    efs.lock().dealloc_tail(second)?;
+    let target = QuotaTarget {
+        quota_root: 0,
+        uid: 0,
+    };
+    let error = efs.lock().release_reservation(target, 1).unwrap_err();
+    assert_eq!(error.kind(), &FsError::Corrupted);
+    assert_eq!(efs.lock().delayed_reserved_blocks(), 0);
+    // 删除后仍被打开的文件不能影响重新使用同一个索引节点ID的文件
+    let unlinked = root_inode.create("unlinked")?;
+    root_inode.unlink("unlinked")?;
+    let reused = root_inode.create("reused")?;
+    reused.write_at(0, greet_str.as_bytes())?;
+    unlinked.write_at(0, b"stale")?;
+    unlinked.clear()?;
+    assert_eq!(reused.read_all(), greet_str.as_bytes());
+    drop(unlinked);
+    drop(reused);
+    root_inode.unlink("reused")?;
+    // 内联数据和间接索引共用同一块空间，检查时不能把内容当作块ID
+    efs.lock().set_inline_data(true);
+    let inline = root_inode.create("inline")?;
+    inline.write_at(0, &[0xffu8; INLINE_DATA_CAPACITY])?;
+    efs.lock().set_inline_data(false);
+    assert!(fsck::check(&efs).is_clean());
+    // 事务提交后修改全部生效，回滚后全部没有生效
+    EasyFileSystem::transaction(&efs, |txn| {
+        txn.root_inode().create("committed")?.write_at(0, b"kept")?;
+        root_inode.unlink("inline")
+    })?;
+    assert_eq!(
+        EasyFileSystem::transaction(&efs, |txn| {
+            txn.root_inode().create("rolled-back")?;
+            root_inode.unlink("committed")?;
+            Err::<(), _>(FsError::InvalidArgument)
+        }),
+        Err(FsError::InvalidArgument)
+    );
+    assert!(root_inode.find("rolled-back").is_err());
+    assert_eq!(root_inode.find("committed")?.read_all(), b"kept");
+    assert!(root_inode.find("inline").is_err());
+    drop(inline);
+    assert!(fsck::check(&efs).is_clean());
+    // 事务中删除了还有索引节点对象的文件，回滚后目录条目恢复，释放最后一个对象时不能释放它
+    let held = root_inode.create("held")?;
+    held.write_at(0, &[1u8; 4 * BLOCK_SZ])?;
+    assert_eq!(
+        EasyFileSystem::transaction(&efs, |_| {
+            root_inode.unlink("held")?;
+            Err::<(), _>(FsError::InvalidArgument)
+        }),
+        Err(FsError::InvalidArgument)
+    );
+    drop(held);
+    assert_eq!(root_inode.find("held")?.read_all(), [1u8; 4 * BLOCK_SZ]);
+    assert!(fsck::check(&efs).is_clean());
+    root_inode.unlink("held")?;
+    // 同步单个文件后，即使没有同步整个文件系统，镜像的副本也是一致的；
+    // 放大块缓存，位图和间接索引块不会因为被替换出去而碰巧写回
+    let holes = root_inode.create("holes")?;
+    holes.write_at(0, &vec![5u8; 300 * BLOCK_SZ])?;
+    efs.lock().sync();
+    set_block_cache_capacity(1024);
+    efs.lock().set_write_mode(WriteMode::Writeback);
+    holes.punch_hole(100 * BLOCK_SZ, 100 * BLOCK_SZ)?;
+    let synced = root_inode.create("synced")?;
+    synced.write_at(0, &vec![7u8; 150 * BLOCK_SZ])?;
+    root_inode.sync()?;
+    synced.sync()?;
+    efs.lock().set_write_mode(WriteMode::Sync);
+    std::fs::copy("target/fs.img", "target/fs-copy.img")?;
+    let copy_file = Arc::new(BlockFile(Mutex::new(
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("target/fs-copy.img")?,
+    )));
+    let copy = EasyFileSystem::open(copy_file)?;
+    assert!(fsck::check(&copy).is_clean());
+    assert_eq!(
+        EasyFileSystem::root_inode(&copy)
+            .find("synced")?
+            .read_all()
+            .len(),
+        150 * BLOCK_SZ
+    );
+    drop(copy);
+    set_block_cache_capacity(DEFAULT_BLOCK_CACHE_SIZE);
+
+    // 固定的块不会被替换出去，写回所有块缓存时跳过它和依赖它的块，撤销固定之后才写回；
+    // 固定的块数不能达到缓存容量
+    let pinned_device: Arc<dyn BlockDevice> = Arc::new(SparseDevice::new(16));
+    let on_device = |block_id| {
+        let mut buf = [0u8; BLOCK_SZ];
+        pinned_device.read_block(block_id, &mut buf);
+        buf[0]
+    };
+    set_block_cache_capacity(4);
+    pin_block_cache(1, pinned_device.clone())
+        .unwrap()
+        .lock()
+        .modify(0, |block: &mut [u8; BLOCK_SZ]| block.fill(1));
+    for block_id in 3..10 {
+        get_block_cache(block_id, pinned_device.clone());
+    }
+    assert_eq!(on_device(1), 0);
+    assert!(pin_block_cache(2, pinned_device.clone()).is_ok());
+    assert!(pin_block_cache(3, pinned_device.clone()).is_ok());
+    assert_eq!(
+        pin_block_cache(4, pinned_device.clone()).err(),
+        Some(CacheError::Exhausted)
+    );
+    assert!(pin_block_cache(1, pinned_device.clone()).is_ok());
+    assert!(unpin_block_cache(1, &pinned_device));
+    assert!(unpin_block_cache(2, &pinned_device));
+    assert!(unpin_block_cache(3, &pinned_device));
+    {
+        let dependent = get_block_cache(2, pinned_device.clone());
+        let mut dependent = dependent.lock();
+        dependent.modify(0, |block: &mut [u8; BLOCK_SZ]| block.fill(2));
+        dependent.depend_on(1);
+    }
+    block_cache_sync_all();
+    assert_eq!((on_device(1), on_device(2)), (0, 0));
+    assert!(unpin_block_cache(1, &pinned_device));
+    assert!(!unpin_block_cache(1, &pinned_device));
+    block_cache_sync_all();
+    assert_eq!((on_device(1), on_device(2)), (1, 2));
+    block_cache_release(&pinned_device);
+    set_block_cache_capacity(DEFAULT_BLOCK_CACHE_SIZE);
+
+    // 替换策略：容量为 3 时依次访问 1、2、3，再命中 1，加入 4 时 FIFO 替换出 1，其它策略替换出 2
+    let counting = Arc::new(CountingDevice::new(64));
+    let device: Arc<dyn BlockDevice> = counting.clone();
+    set_block_cache_capacity(3);
+    for block_id in 10..13 {
+        get_block_cache(block_id, device.clone());
+    }
+    block_cache_release(&device);
+    let policies: [(Box<dyn EvictionPolicy>, u64); 4] = [
+        (Box::new(FifoPolicy::default()), 1),
+        (Box::new(LruPolicy::default()), 2),
+        (Box::new(ClockPolicy::default()), 2),
+        (Box::new(ArcPolicy::new(3)), 2),
+    ];
+    for (policy, evicted) in policies {
+        set_block_cache_policy(policy);
+        for block_id in [1, 2, 3, 1, 4] {
+            get_block_cache(block_id, device.clone());
+        }
+        counting.take_reads();
+        get_block_cache(3 - evicted, device.clone());
+        assert!(counting.take_reads().is_empty());
+        get_block_cache(evicted, device.clone());
+        assert_eq!(counting.take_reads(), [(evicted, 1)]);
+        block_cache_release(&device);
+    }
+    set_block_cache_policy(Box::<FifoPolicy>::default());
+    set_block_cache_capacity(DEFAULT_BLOCK_CACHE_SIZE);
+
+    // 后台写回线程写回变脏太久的块，脏块太多时从最早变脏的块开始写回，停止之后不再写回
+    #[cfg(feature = "writeback")]
+    {
+        let wait_writes = |count: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while counting.writes.lock().unwrap().len() < count && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            counting.take_writes()
+        };
+        let fill = |block_id: u64| {
+            get_block_cache(block_id, device.clone())
+                .lock()
+                .modify(0, |block: &mut [u8; BLOCK_SZ]| block.fill(block_id as u8));
+        };
+        block_cache_sync_all();
+        counting.take_writes();
+        let config = WritebackConfig {
+            interval: Duration::from_millis(5),
+            max_age: Duration::from_millis(20),
+            dirty_percent: 100,
+        };
+        assert!(start_writeback(config));
+        assert!(!start_writeback(config));
+        assert!(writeback_running());
+        fill(1);
+        assert_eq!(wait_writes(1), [(1, 1)]);
+        assert!(stop_writeback());
+        assert!(!writeback_running());
+        assert!(!stop_writeback());
+
+        // 容量的四分之一是 4 个块，8 个脏块中最早变脏的 4 个被写回
+        let config = WritebackConfig {
+            max_age: Duration::from_secs(3600),
+            dirty_percent: 25,
+            ..config
+        };
+        for block_id in 2..10 {
+            fill(block_id);
+        }
+        assert!(start_writeback(config));
+        let mut written = wait_writes(4);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(stop_writeback());
+        written.extend(counting.take_writes());
+        written.sort();
+        assert_eq!(written, [(2, 1), (3, 1), (4, 1), (5, 1)]);
+
+        // 停止之后剩下的脏块只有显式同步时才写回
+        fill(10);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(counting.take_writes().is_empty());
+        block_cache_release(&device);
+        assert_eq!(counting.take_writes().len(), 5);
+    }
+
+    // 预读：不在缓存中的连续块通过一次读取加载，已经缓存的块不再读取，最多加载缓存容量一半的块
+    counting.take_reads();
+    assert_eq!(block_cache_prefetch(&device, &[1, 2, 3, 7, 8]), 5);
+    assert_eq!(counting.take_reads(), [(1, 3), (7, 2)]);
+    get_block_cache(2, device.clone());
+    assert!(counting.take_reads().is_empty());
+    assert_eq!(block_cache_prefetch(&device, &[2, 3, 4]), 1);
+    assert_eq!(counting.take_reads(), [(4, 1)]);
+    let block_ids: Vec<u64> = (20..40).collect();
+    assert_eq!(
+        block_cache_prefetch(&device, &block_ids),
+        DEFAULT_BLOCK_CACHE_SIZE / 2
+    );
+    assert_eq!(counting.take_reads(), [(20, DEFAULT_BLOCK_CACHE_SIZE / 2)]);
+    block_cache_release(&device);
+
+    // 顺序读取时成批预读之后的块，逐块读取一个文件时读取块设备的次数少于块数
+    let counting = Arc::new(CountingDevice::new(8192));
+    let device: Arc<dyn BlockDevice> = counting.clone();
+    let read_ahead_efs = FilesystemBuilder::new(8192).format(device.clone())?;
+    let data: Vec<u8> = (0..32 * BLOCK_SZ).map(|i| (i / BLOCK_SZ) as u8).collect();
+    EasyFileSystem::root_inode(&read_ahead_efs)
+        .create("sequential")?
+        .write_at(0, &data)?;
+    drop(read_ahead_efs);
+    block_cache_release(&device);
+    let read_ahead_efs = EasyFileSystem::open(device.clone())?;
+    let sequential = EasyFileSystem::root_inode(&read_ahead_efs).find("sequential")?;
+    counting.take_reads();
+    let mut buf = [0u8; BLOCK_SZ];
+    for (i, expected) in data.chunks(BLOCK_SZ).enumerate() {
+        assert_eq!(sequential.read_at(i * BLOCK_SZ, &mut buf), BLOCK_SZ);
+        assert_eq!(&buf[..], expected);
+    }
+    let reads = counting.take_reads();
+    assert!(reads.iter().any(|&(_, blocks)| blocks > 1));
+    assert!(reads.len() < 32);
+    drop(sequential);
+    drop(read_ahead_efs);
+    block_cache_release(&device);
+
+    // 写回所有块缓存时按块ID排列脏块，连续的块合并成一次写入，依赖其它块的块在之后一轮写回
+    let counting = Arc::new(CountingDevice::new(64));
+    let device: Arc<dyn BlockDevice> = counting.clone();
+    for block_id in [30, 22, 20, 25, 21] {
+        get_block_cache(block_id, device.clone())
+            .lock()
+            .modify(0, |block: &mut [u8; BLOCK_SZ]| block.fill(block_id as u8));
+    }
+    block_cache_sync_all();
+    assert_eq!(counting.take_writes(), [(20, 3), (25, 1), (30, 1)]);
+    for block_id in [30, 22, 20, 25, 21] {
+        let cache = get_block_cache(block_id, device.clone());
+        let mut cache = cache.lock();
+        cache.modify(0, |block: &mut [u8; BLOCK_SZ]| block.fill(!block_id as u8));
+        if block_id == 21 {
+            cache.depend_on(30);
+        }
+    }
+    block_cache_sync_all();
+    assert_eq!(
+        counting.take_writes(),
+        [(20, 1), (22, 1), (25, 1), (30, 1), (21, 1)]
+    );
+    block_cache_release(&device);
+    assert!(counting.take_writes().is_empty());
 
     let mut random_str_test = |len: usize| {
-        filea.clear();
+        filea.clear().unwrap();
         assert_eq!(filea.read_at(0, &mut buffer), 0,);
         let mut str = String::new();
         // 随机数字
         for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
         }
-        filea.write_at(0, str.as_bytes());
+        filea.write_at(0, str.as_bytes()).unwrap();
         let mut read_buffer = [0u8; 127];
         let mut offset = 0usize;
         let mut read_str = String::new();
@@ -82,6 +586,7 @@ fn efs_test() -> std::io::Result<()> {
             read_str.push_str(core::str::from_utf8(&read_buffer[..len]).unwrap());
         }
         assert_eq!(str, read_str);
+        assert_eq!(str.as_bytes(), filea.read_all());
     };
 
     random_str_test(4 * BLOCK_SZ);
@@ -93,11 +598,457 @@ fn efs_test() -> std::io::Result<()> {
     random_str_test(1000 * BLOCK_SZ);
     random_str_test(2000 * BLOCK_SZ);
 
+    // 块组描述符中的空闲计数随分配和释放一起更新，之和与超级块中的计数一致；
+    // 被改坏的描述符能被检查出来，并在修复时按位图重新统计
+    let descriptors = efs.lock().read_group_descriptors().unwrap();
+    assert_eq!(
+        descriptors.len(),
+        efs.lock().geometry().group_count as usize
+    );
+    assert_eq!(
+        descriptors
+            .iter()
+            .map(|descriptor| descriptor.free_data_blocks as u64)
+            .sum::<u64>(),
+        efs.lock().free_data_blocks()
+    );
+    assert_eq!(
+        descriptors
+            .iter()
+            .map(|descriptor| descriptor.free_inodes as u64)
+            .sum::<u64>(),
+        efs.lock().free_inodes()
+    );
+    assert!(fsck::check(&efs).is_clean());
+    let (block_id, block_offset) = efs.lock().geometry().group_descriptor_position(0);
+    get_block_cache(block_id, block_file.clone())
+        .lock()
+        .modify(block_offset, |descriptor: &mut GroupDescriptor| {
+            descriptor.free_inodes += 1
+        });
+    assert!(matches!(
+        fsck::check(&efs).problems[..],
+        [Problem::GroupSummaryMismatch { group: 0, .. }]
+    ));
+    assert!(fsck::repair(&efs)?.remaining.is_empty());
+    assert_eq!(efs.lock().read_group_descriptors(), Some(descriptors));
+    efs.lock().sync();
+
+    // 手工构造一个版本 1 的镜像：8192 个块，1 个索引节点位图块，1024 个索引节点区域块，
+    // 2 个数据位图块，之后是数据区域；索引节点中有 28 个直接索引，一级和二级间接索引在第 116 和 120 字节
+    let mut image = vec![0u8; 8192 * BLOCK_SZ];
+    let put_u32 = |image: &mut [u8], offset: usize, value: u32| {
+        image[offset..offset + 4].copy_from_slice(&value.to_ne_bytes())
+    };
+    for (i, value) in [0x3b800001, 8192, 1, 1024, 2, 7164].into_iter().enumerate() {
+        put_u32(&mut image, i * 4, value);
+    }
+    let data_area_start = 1028u32;
+    let mut next_block = data_area_start;
+    let mut alloc = |image: &mut [u8]| {
+        let bit = (next_block - data_area_start) as usize;
+        image[1026 * BLOCK_SZ + bit / 8] |= 1 << (bit % 8);
+        next_block += 1;
+        next_block - 1
+    };
+    let inode_offset = |inode_id: usize| 2 * BLOCK_SZ + inode_id * 128;
+    // 根目录中有文件 big 和目录 sub，sub 中有带空洞的文件 sparse，最后一块也是空洞
+    let dirents: [(usize, &[(&str, u32)]); 2] =
+        [(0, &[("big", 1), ("sub", 2)]), (2, &[("sparse", 3)])];
+    for (inode_id, entries) in dirents {
+        let block_id = alloc(&mut image) as usize;
+        for (i, (name, child)) in entries.iter().enumerate() {
+            let offset = block_id * BLOCK_SZ + i * 32;
+            image[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+            put_u32(&mut image, offset + 28, *child);
+        }
+        put_u32(
+            &mut image,
+            inode_offset(inode_id),
+            (entries.len() * 32) as u32,
+        );
+        put_u32(&mut image, inode_offset(inode_id) + 4, block_id as u32);
+        image[inode_offset(inode_id) + 124] = 1;
+    }
+    let block_content = |i: usize| (i % 251) as u8 + 1;
+    let big_size = 200 * BLOCK_SZ - 100;
+    put_u32(&mut image, inode_offset(1), big_size as u32);
+    let indirect1 = alloc(&mut image) as usize;
+    let indirect2 = alloc(&mut image) as usize;
+    let indirect2_child = alloc(&mut image) as usize;
+    put_u32(&mut image, inode_offset(1) + 116, indirect1 as u32);
+    put_u32(&mut image, inode_offset(1) + 120, indirect2 as u32);
+    put_u32(&mut image, indirect2 * BLOCK_SZ, indirect2_child as u32);
+    for i in 0..200 {
+        let block_id = alloc(&mut image);
+        let offset = match i {
+            0..28 => inode_offset(1) + 4 + i * 4,
+            28..156 => indirect1 * BLOCK_SZ + (i - 28) * 4,
+            _ => indirect2_child * BLOCK_SZ + (i - 156) * 4,
+        };
+        put_u32(&mut image, offset, block_id);
+        image[block_id as usize * BLOCK_SZ..][..BLOCK_SZ].fill(block_content(i));
+    }
+    let sparse_size = 10 * BLOCK_SZ + 7;
+    put_u32(&mut image, inode_offset(3), sparse_size as u32);
+    for i in (0..10).filter(|&i| i != 3) {
+        let block_id = alloc(&mut image);
+        put_u32(&mut image, inode_offset(3) + 4 + i * 4, block_id);
+        image[block_id as usize * BLOCK_SZ..][..BLOCK_SZ].fill(block_content(i));
+    }
+    image[BLOCK_SZ] = 0b1111;
+
+    // 目录条目构成环的镜像被拒绝，镜像没有被修改
+    let mut cyclic = image.clone();
+    put_u32(
+        &mut cyclic,
+        (data_area_start as usize + 1) * BLOCK_SZ + 28,
+        0,
+    );
+    std::fs::write("target/v1.img", &cyclic)?;
+    let open_image = || -> std::io::Result<Arc<BlockFile>> {
+        Ok(Arc::new(BlockFile(Mutex::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("target/v1.img")?,
+        ))))
+    };
+    assert!(matches!(
+        migrate(open_image()?),
+        Err(MigrateError::Corrupted(_))
+    ));
+    assert_eq!(std::fs::read("target/v1.img")?, cyclic);
+
+    // 原地迁移之后内容和空洞都被保留，检查不出问题，再次迁移时什么也不做
+    std::fs::write("target/v1.img", &image)?;
+    let v1_file = open_image()?;
+    assert_eq!(migrate(v1_file.clone()), Ok(1));
+    assert_eq!(migrate(v1_file.clone()), Ok(EFS_VERSION));
+    let migrated = EasyFileSystem::open(v1_file)?;
+    assert!(fsck::check(&migrated).is_clean());
+    let migrated_root = EasyFileSystem::root_inode(&migrated);
+    let big = migrated_root.find("big")?.read_all();
+    assert_eq!(big.len(), big_size);
+    assert!(big
+        .chunks(BLOCK_SZ)
+        .enumerate()
+        .all(|(i, block)| block.iter().all(|&byte| byte == block_content(i))));
+    let sparse = migrated_root.find_path("sub/sparse")?;
+    let data = sparse.read_all();
+    assert_eq!(data.len(), sparse_size);
+    assert!(data.chunks(BLOCK_SZ).enumerate().all(|(i, block)| {
+        let expected = if i == 3 || i == 10 {
+            0
+        } else {
+            block_content(i)
+        };
+        block.iter().all(|&byte| byte == expected)
+    }));
+    assert_eq!(sparse.allocated_blocks(), 9);
+
+    // 紧凑重写后权限位、属主、属组和时间戳都保持不变，源镜像没有被修改
+    sparse.chmod(0o640)?;
+    sparse.chown(1000, 100)?;
+    migrated_root.find("sub")?.chmod(0o700)?;
+    let expected: Vec<_> = ["sub/sparse", "sub", "big", ""]
+        .into_iter()
+        .map(|path| -> std::io::Result<_> {
+            let statx = migrated_root.find_path(path)?.statx();
+            Ok((
+                statx.metadata.mode,
+                statx.metadata.uid,
+                statx.metadata.gid,
+                statx.metadata.atime,
+                statx.metadata.mtime,
+                statx.metadata.ctime,
+                statx.btime,
+            ))
+        })
+        .collect::<std::io::Result<_>>()?;
+    drop((sparse, migrated_root));
+    drop(migrated);
+    let source = std::fs::read("target/v1.img")?;
+    let compact_file = Arc::new(BlockFile(Mutex::new({
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("target/compact.img")?;
+        f.set_len(8192 * BLOCK_SZ as u64)?;
+        f
+    })));
+    compact(open_image()?, compact_file.clone())?;
+    assert_eq!(std::fs::read("target/v1.img")?, source);
+    let compacted = EasyFileSystem::open(compact_file)?;
+    assert!(fsck::check(&compacted).is_clean());
+    let compacted_root = EasyFileSystem::root_inode(&compacted);
+    for (path, expected) in ["sub/sparse", "sub", "big", ""].into_iter().zip(expected) {
+        let statx = compacted_root.find_path(path)?.statx();
+        assert_eq!(
+            (
+                statx.metadata.mode,
+                statx.metadata.uid,
+                statx.metadata.gid,
+                statx.metadata.atime,
+                statx.metadata.mtime,
+                statx.metadata.ctime,
+                statx.btime
+            ),
+            expected
+        );
+    }
+    drop(compacted_root);
+    drop(compacted);
+
+    // 块数超出 32 位寻址范围时格式化开启 48 位寻址，重新打开后可以正常读写
+    let large_blocks = (1 << 32) + 8192;
+    let large = Arc::new(SparseDevice::new(large_blocks));
+    FilesystemBuilder::new(large_blocks)
+        .inodes(1024)
+        .format(large.clone())?;
+    let large_efs = EasyFileSystem::open(large.clone())?;
+    assert!(large_efs.lock().address_48bit());
+    assert_eq!(large_efs.lock().geometry().total_blocks, large_blocks);
+    let large_root = EasyFileSystem::root_inode(&large_efs);
+    let large_file = large_root.create("large")?;
+    large_file.write_at(0, &[0x5a; BLOCK_SZ * 3])?;
+    drop((large_file, large_root));
+    drop(large_efs);
+    // 检查所有块组的位图太慢，这里只检查数据能否读回
+    let large_efs = EasyFileSystem::open(large)?;
+    let mut buf = [0u8; BLOCK_SZ * 3];
+    let large_file = EasyFileSystem::root_inode(&large_efs).find("large")?;
+    assert_eq!(large_file.read_at(0, &mut buf), BLOCK_SZ * 3);
+    assert!(buf.iter().all(|&byte| byte == 0x5a));
+    drop(large_file);
+    drop(large_efs);
+
+    // 子树遍历：默认先返回目录再按目录条目的顺序返回其中的内容，可以后序遍历、按名称排序和限制深度
+    let tree_device: Arc<dyn BlockDevice> = Arc::new(SparseDevice::new(8192));
+    let tree_efs = FilesystemBuilder::new(8192).format(tree_device.clone())?;
+    let tree_root = EasyFileSystem::root_inode(&tree_efs);
+    let walk_dir = tree_root.create_dir("walk")?;
+    walk_dir.create("b")?;
+    let a = walk_dir.create_dir("a")?;
+    a.create("c")?;
+    a.create_dir("d")?.create("e")?;
+    let paths = |walk: Walk| -> std::io::Result<Vec<String>> {
+        Ok(walk
+            .map(|entry| entry.map(|(path, _, _)| path))
+            .collect::<Result<_, _>>()?)
+    };
+    assert_eq!(
+        paths(walk_dir.walk("/walk"))?,
+        [
+            "/walk",
+            "/walk/b",
+            "/walk/a",
+            "/walk/a/c",
+            "/walk/a/d",
+            "/walk/a/d/e"
+        ]
+    );
+    assert_eq!(
+        paths(walk_dir.walk("/walk").sort_by_name(true))?,
+        [
+            "/walk",
+            "/walk/a",
+            "/walk/a/c",
+            "/walk/a/d",
+            "/walk/a/d/e",
+            "/walk/b"
+        ]
+    );
+    assert_eq!(
+        paths(
+            walk_dir
+                .walk("/walk")
+                .sort_by_name(true)
+                .contents_first(true)
+        )?,
+        [
+            "/walk/a/c",
+            "/walk/a/d/e",
+            "/walk/a/d",
+            "/walk/a",
+            "/walk/b",
+            "/walk"
+        ]
+    );
+    assert_eq!(
+        paths(walk_dir.walk("/walk").sort_by_name(true).max_depth(1))?,
+        ["/walk", "/walk/a", "/walk/b"]
+    );
+    assert_eq!(paths(walk_dir.walk("/walk").max_depth(0))?, ["/walk"]);
+    assert_eq!(paths(tree_root.walk("/").max_depth(1))?, ["/", "/walk"]);
+    for entry in walk_dir.walk("/walk") {
+        let (path, inode, metadata) = entry?;
+        assert_eq!(metadata.inode_id, inode.metadata().inode_id);
+        assert_eq!(
+            metadata.is_dir,
+            ["/walk", "/walk/a", "/walk/a/d"].contains(&path.as_str())
+        );
+    }
+
+    // 模式匹配：`*` 可以匹配空串并在失配时回溯，`?` 恰好匹配一个字符，包括非 ASCII 字符
+    for (pattern, name, matched) in [
+        ("", "", true),
+        ("", "a", false),
+        ("*", "", true),
+        ("?", "", false),
+        ("?", "中", true),
+        ("**a", "a", true),
+        ("*ab", "aab", true),
+        ("a*b*c", "abbbcbc", true),
+        ("a*b*c", "abbbcb", false),
+        ("*.txt", ".txt", true),
+        ("*.txt", "a.txt.bak", false),
+        ("data?", "data1", true),
+        ("data?", "data", false),
+        ("data?", "data12", false),
+    ] {
+        assert_eq!(glob_match(pattern, name), matched, "{pattern} {name}");
+    }
+    a.create("x.txt")?;
+    walk_dir.create("b.txt")?;
+    walk_dir.create("c.txt.bak")?;
+    walk_dir.create("data1")?;
+    walk_dir.create("data12")?;
+    let mut found = walk_dir.find_matching("/walk", "*.txt")?;
+    found.sort();
+    assert_eq!(found, ["/walk/a/x.txt", "/walk/b.txt"]);
+    assert_eq!(walk_dir.find_matching("/walk", "data?")?, ["/walk/data1"]);
+    // 子树的根自身不参与匹配
+    assert!(walk_dir.find_matching("/walk", "walk")?.is_empty());
+    assert_eq!(tree_root.find_matching("", "?")?.len(), 5);
+
+    // 在线扩大：新的空间可以立即使用，重新打开之后布局保持扩大后的样子，数据完整且一致
+    let grow_device: Arc<dyn BlockDevice> = Arc::new(SparseDevice::new(40000));
+    let grow_efs = FilesystemBuilder::new(8192).format(grow_device.clone())?;
+    let grow_root = EasyFileSystem::root_inode(&grow_efs);
+    grow_root
+        .create("before")?
+        .write_at(0, &vec![0x11; 100 * BLOCK_SZ])?;
+    let free_before = grow_efs.lock().free_data_blocks();
+    assert_eq!(grow_efs.lock().grow(8192), Err(FsError::InvalidArgument));
+    assert!(matches!(
+        grow_efs.lock().grow(50000),
+        Err(FsError::SuperBlock(_))
+    ));
+    grow_efs.lock().grow(40000)?;
+    assert_eq!(grow_efs.lock().geometry().total_blocks, 40000);
+    assert!(grow_efs.lock().free_data_blocks() > free_before + 30000);
+    assert!(fsck::check(&grow_efs).is_clean());
+    // 比扩大之前的整个文件系统还大的文件
+    grow_root
+        .create("after")?
+        .write_at(0, &vec![0x22; 9000 * BLOCK_SZ])?;
+    drop(grow_root);
+    drop(grow_efs);
+    let grow_efs = EasyFileSystem::open(grow_device)?;
+    assert_eq!(grow_efs.lock().geometry().total_blocks, 40000);
+    assert!(fsck::check(&grow_efs).is_clean());
+    let grow_root = EasyFileSystem::root_inode(&grow_efs);
+    assert_eq!(
+        grow_root.find("before")?.read_all(),
+        vec![0x11; 100 * BLOCK_SZ]
+    );
+    let after = grow_root.find("after")?.read_all();
+    assert_eq!(after.len(), 9000 * BLOCK_SZ);
+    assert!(after.iter().all(|&byte| byte == 0x22));
+    drop(grow_root);
+    drop(grow_efs);
+
+    // 挂载表：经过挂载点时进入被挂载的文件系统，`..` 按走过的路径回到挂载点所在的目录，
+    // 同一个目录上叠加挂载时只有最后挂载的可见，卸载之后被覆盖的内容重新可见
+    let new_fs = || -> std::io::Result<_> {
+        let device: Arc<dyn BlockDevice> = Arc::new(SparseDevice::new(8192));
+        Ok(FilesystemBuilder::new(8192).format(device)?)
+    };
+    let assets = new_fs()?;
+    let assets_root = EasyFileSystem::root_inode(&assets);
+    assets_root.create("logo")?.write_at(0, b"logo")?;
+    assets_root.create_dir("sub")?;
+    let overlay = new_fs()?;
+    EasyFileSystem::root_inode(&overlay).create("overlay")?;
+    let mnt = tree_root.create_dir("mnt")?;
+    mnt.create("hidden")?;
+    let mut mounts = MountTable::new(tree_efs.clone());
+    assert_eq!(
+        mounts.mount("/", assets.clone()),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(
+        mounts.mount("/walk/b", assets.clone()),
+        Err(FsError::NotADirectory)
+    );
+    mounts.mount("/mnt", assets.clone())?;
+    assert_eq!(
+        mounts.mount("/walk", assets.clone()),
+        Err(FsError::InvalidArgument)
+    );
+    assert!(Arc::ptr_eq(mounts.find_path("/mnt")?.fs(), &assets));
+    assert_eq!(mounts.find_path("mnt/logo")?.read_all(), b"logo");
+    assert_eq!(
+        mounts.find_path("/mnt/hidden").err(),
+        Some(FsError::NotFound)
+    );
+    let b = mounts.find_path("/mnt/sub/../../walk/b")?;
+    assert!(Arc::ptr_eq(b.fs(), &tree_efs));
+    assert_eq!(
+        b.metadata().inode_id,
+        walk_dir.find("b")?.metadata().inode_id
+    );
+
+    // 叠加挂载和嵌套挂载
+    mounts.mount("/mnt", overlay.clone())?;
+    mounts.find_path("/mnt/overlay")?;
+    assert_eq!(mounts.find_path("/mnt/logo").err(), Some(FsError::NotFound));
+    assert!(Arc::ptr_eq(&mounts.unmount("/mnt")?, &overlay));
+    mounts.mount("/mnt/sub", overlay.clone())?;
+    mounts.find_path("/mnt/sub/overlay")?;
+    let mut paths: Vec<String> = mounts.mounts().into_iter().map(|(path, _)| path).collect();
+    paths.sort();
+    assert_eq!(paths, ["/mnt", "/mnt/sub"]);
+    assert_eq!(mounts.unmount("/mnt").err(), Some(FsError::InvalidArgument));
+    assert!(Arc::ptr_eq(&mounts.unmount("/mnt/sub")?, &overlay));
+    assert!(Arc::ptr_eq(&mounts.unmount("/mnt")?, &assets));
+    assert_eq!(mounts.unmount("/mnt").err(), Some(FsError::NotFound));
+    mounts.find_path("/mnt/hidden")?;
+    assert!(mounts.mounts().is_empty());
+
+    // 位图迭代器：按顺序返回已分配或者空闲的比特，跨越位图块时每个位图块只读取一次，
+    // 不返回超出可分配范围的比特
+    let counting = Arc::new(CountingDevice::new(8));
+    let device: Arc<dyn BlockDevice> = counting.clone();
+    let bits = BLOCK_SZ * 8 + 10;
+    let bitmap = Bitmap::with_bits(1, 2, bits);
+    let allocated = [0, 63, 64, BLOCK_SZ * 8 - 1, BLOCK_SZ * 8, bits - 1];
+    for bit in allocated {
+        bitmap.reserve(&device, bit);
+    }
+    block_cache_release(&device);
+    counting.take_reads();
+    assert_eq!(
+        bitmap.iter_allocated(&device).collect::<Vec<_>>(),
+        allocated
+    );
+    assert_eq!(counting.take_reads(), [(1, 1), (2, 1)]);
+    let free: Vec<usize> = bitmap.iter_free(&device).collect();
+    assert_eq!(free.len(), bits - allocated.len());
+    assert!(free.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(free.iter().all(|bit| !allocated.contains(bit)));
+    assert_eq!(free.last(), Some(&(bits - 2)));
+    let empty = Bitmap::with_bits(3, 1, 0);
+    assert_eq!(empty.iter_allocated(&device).next(), None);
+    assert_eq!(empty.iter_free(&device).next(), None);
+    block_cache_release(&device);
+
     Ok(())
 }
 
 fn main() {
     efs_test().unwrap()
 }
-
-pub fn nop() {}
@@ -0,0 +1,924 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use crate::block_cache::{block_cache_release, get_block_cache};
+use crate::block_device::BlockDevice;
+use crate::builder::FilesystemBuilder;
+use crate::efs::{EasyFileSystem, JOURNAL_BLOCKS};
+use crate::error::MigrateError;
+use crate::layout::{
+    DirEntry, DiskInode, Geometry, DIRENT_SZ, EFS_MAGIC_BASE, EFS_VERSION, INLINE_DATA_CAPACITY,
+};
+use crate::name::FileName;
+use crate::pod::{bytes_of, bytes_of_mut, from_bytes};
+use crate::vfs::Inode;
+use crate::BLOCK_SZ;
+
+/// 数据块
+type DataBlock = [u8; BLOCK_SZ];
+
+/// 旧版本镜像的块大小
+const LEGACY_BLOCK_SZ: usize = 512;
+
+/// 旧版本镜像中磁盘索引节点的大小
+const LEGACY_INODE_SZ: usize = 128;
+
+/// 版本 3 的磁盘索引节点中有效字段的字节数，与当前版本的前缀相同
+const V3_INODE_FIELDS_SZ: usize = 126;
+
+/// 版本 1 的磁盘索引节点中直接索引的数量，之后是一级和二级间接索引
+const V1_DIRECT_COUNT: usize = 28;
+
+/// 版本 2 的磁盘索引节点中直接索引的数量，之后是一级、二级和三级间接索引
+const V2_DIRECT_COUNT: usize = 26;
+
+/// 旧版本日志头的魔数
+const LEGACY_JOURNAL_MAGIC: u32 = 0x6a726e6c;
+
+/// 旧版本日志头中最多记录的块数
+const LEGACY_JOURNAL_MAX_BLOCKS: usize = (LEGACY_BLOCK_SZ - 12) / 4;
+
+/// 目录树的最大深度，更深的目录树多半是损坏的镜像
+const MAX_DEPTH: usize = 1024;
+
+/// 复制文件内容时每次读写的块数
+const COPY_CHUNK_BLOCKS: usize = 64;
+
+/// 旧版本镜像的超级块中迁移需要的字段
+struct LegacySuperBlock {
+    /// 磁盘格式版本
+    version: u32,
+
+    /// 总块数
+    total_blocks: u64,
+
+    /// 索引节点位图块数
+    inode_bitmap_blocks: u32,
+
+    /// 索引节点区域块数
+    inode_area_blocks: u32,
+
+    /// 数据位图块数
+    data_bitmap_blocks: u32,
+
+    /// 数据区域块数
+    data_area_blocks: u32,
+
+    /// 日志区域块数，日志区域位于设备末尾，版本 1 没有日志
+    journal_blocks: u32,
+}
+
+impl LegacySuperBlock {
+    /// 从超级块所在的块中解析出迁移需要的字段，并检查各区域的大小
+    ///
+    /// # Arguments
+    ///
+    /// * `version`: 磁盘格式版本
+    /// * `block`: 超级块所在的块
+    /// * `device_blocks`: 块设备的总块数，未知时为 None
+    ///
+    /// returns: Result<LegacySuperBlock, MigrateError> 旧版本的超级块
+    fn parse(
+        version: u32,
+        block: &DataBlock,
+        device_blocks: Option<u64>,
+    ) -> Result<Self, MigrateError> {
+        let super_block = match version {
+            // 版本 1 的区域块数紧随魔数，总块数是 32 位的
+            1 => Self {
+                version,
+                total_blocks: read_u32(block, 4) as u64,
+                inode_bitmap_blocks: read_u32(block, 8),
+                inode_area_blocks: read_u32(block, 12),
+                data_bitmap_blocks: read_u32(block, 16),
+                data_area_blocks: read_u32(block, 20),
+                journal_blocks: 0,
+            },
+            2 => Self {
+                version,
+                total_blocks: read_u32(block, 4) as u64,
+                inode_bitmap_blocks: read_u32(block, 8),
+                inode_area_blocks: read_u32(block, 12),
+                data_bitmap_blocks: read_u32(block, 16),
+                data_area_blocks: read_u32(block, 20),
+                journal_blocks: read_u32(block, 24),
+            },
+            3 => Self {
+                version,
+                total_blocks: u64::from_ne_bytes(block[24..32].try_into().unwrap()),
+                inode_bitmap_blocks: read_u32(block, 4),
+                inode_area_blocks: read_u32(block, 8),
+                data_bitmap_blocks: read_u32(block, 12),
+                data_area_blocks: read_u32(block, 16),
+                journal_blocks: read_u32(block, 20),
+            },
+            _ => return Err(MigrateError::Unsupported("unknown format version")),
+        };
+        let journal_uuid = match version {
+            2 => &block[28..44],
+            3 => &block[32..48],
+            _ => &[][..],
+        };
+        if journal_uuid.iter().any(|&byte| byte != 0) {
+            return Err(MigrateError::Unsupported("external journal"));
+        }
+
+        let regions = 1
+            + super_block.inode_bitmap_blocks as u64
+            + super_block.inode_area_blocks as u64
+            + super_block.data_bitmap_blocks as u64
+            + super_block.data_area_blocks as u64
+            + super_block.journal_blocks as u64;
+        if regions != super_block.total_blocks {
+            return Err(MigrateError::Corrupted(
+                "region sizes do not add up to the total block count",
+            ));
+        }
+        if super_block.inode_bitmap_blocks == 0
+            || super_block.inode_area_blocks == 0
+            || super_block.data_bitmap_blocks == 0
+        {
+            return Err(MigrateError::Corrupted("empty metadata region"));
+        }
+        if super_block.journal_blocks == 1 {
+            return Err(MigrateError::Corrupted("journal is too small"));
+        }
+        if device_blocks.is_some_and(|device_blocks| super_block.total_blocks > device_blocks) {
+            return Err(MigrateError::Corrupted("image is larger than the device"));
+        }
+        Ok(super_block)
+    }
+
+    /// 索引节点区域的起始块ID
+    fn inode_area_start(&self) -> u64 {
+        1 + self.inode_bitmap_blocks as u64
+    }
+
+    /// 数据区域的起始块ID
+    fn data_area_start(&self) -> u64 {
+        self.inode_area_start() + self.inode_area_blocks as u64 + self.data_bitmap_blocks as u64
+    }
+
+    /// 日志区域的起始块ID，没有日志时为总块数
+    fn journal_start(&self) -> u64 {
+        self.total_blocks - self.journal_blocks as u64
+    }
+
+    /// 索引节点的数量，受索引节点位图和索引节点区域中较小的一个限制
+    fn total_inodes(&self) -> u64 {
+        (self.inode_bitmap_blocks as u64 * BLOCK_SZ as u64 * 8)
+            .min(self.inode_area_blocks as u64 * (BLOCK_SZ / LEGACY_INODE_SZ) as u64)
+    }
+}
+
+#[derive(Debug)]
+/// 旧版本镜像的只读视图
+/// 日志中已经提交但还没有写回原位置的块在这里读出日志中的内容，
+/// 超出旧镜像范围的块读出零，损坏的块指针不会读到块设备以外
+struct LegacyDevice {
+    /// 镜像所在的块设备
+    inner: Arc<dyn BlockDevice>,
+
+    /// 旧镜像的总块数
+    total_blocks: u64,
+
+    /// 重放日志得到的块，按原位置的块ID索引
+    replayed: BTreeMap<u64, DataBlock>,
+}
+
+impl LegacyDevice {
+    /// 创建旧版本镜像的只读视图，并在内存中重放日志
+    /// 旧版本的日志头有效且块数不为零就表示事务已经提交，日志头之后依次存放各个块的新内容
+    ///
+    /// # Arguments
+    ///
+    /// * `inner`: 镜像所在的块设备
+    /// * `super_block`: 旧版本的超级块
+    ///
+    /// returns: Result<LegacyDevice, MigrateError> 只读视图，日志记录的块超出范围时返回 [`MigrateError::Corrupted`]
+    fn new(
+        inner: Arc<dyn BlockDevice>,
+        super_block: &LegacySuperBlock,
+    ) -> Result<Self, MigrateError> {
+        let mut replayed = BTreeMap::new();
+        if super_block.journal_blocks > 0 {
+            let journal_start = super_block.journal_start();
+            let mut header = [0u8; BLOCK_SZ];
+            inner.read_block(journal_start, &mut header);
+            let count = read_u32(&header, 8) as usize;
+            if read_u32(&header, 0) == LEGACY_JOURNAL_MAGIC && count > 0 {
+                if count > LEGACY_JOURNAL_MAX_BLOCKS || count >= super_block.journal_blocks as usize
+                {
+                    return Err(MigrateError::Corrupted("journal header is too large"));
+                }
+                for i in 0..count {
+                    let block_id = read_u32(&header, 12 + i * 4) as u64;
+                    if block_id >= journal_start {
+                        return Err(MigrateError::Corrupted(
+                            "journal records a block outside the filesystem",
+                        ));
+                    }
+                    let mut data = [0u8; BLOCK_SZ];
+                    inner.read_block(journal_start + 1 + i as u64, &mut data);
+                    replayed.insert(block_id, data);
+                }
+            }
+        }
+        Ok(Self {
+            inner,
+            total_blocks: super_block.total_blocks,
+            replayed,
+        })
+    }
+}
+
+impl BlockDevice for LegacyDevice {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        if let Some(data) = self.replayed.get(&block_id) {
+            buf.copy_from_slice(data);
+        } else if block_id < self.total_blocks {
+            self.inner.read_block(block_id, buf);
+        } else {
+            buf.fill(0);
+        }
+    }
+
+    fn write_block(&self, _block_id: u64, _buf: &[u8]) {
+        unreachable!("The legacy image is read only!");
+    }
+
+    fn num_blocks(&self) -> Option<u64> {
+        Some(self.total_blocks)
+    }
+}
+
+#[derive(Debug)]
+/// 迁移时新文件系统使用的块设备
+/// 写入旧镜像仍在使用的块时只保存在内存中，直到 [`commit`](Self::commit) 才写回，
+/// 其余的块直接写入块设备；读取时先查内存中的块
+struct MigrationDevice {
+    /// 镜像所在的块设备
+    inner: Arc<dyn BlockDevice>,
+
+    /// 提交之前不能覆盖的块
+    protected: BTreeSet<u64>,
+
+    /// 尚未写回的块，按块ID索引
+    pending: Mutex<BTreeMap<u64, Box<DataBlock>>>,
+}
+
+impl MigrationDevice {
+    /// 写回所有保存在内存中的块
+    /// 先写回其它块，然后是新的备份超级块，最后是 0 号块上的主超级块，每一步之后刷新块设备；
+    /// 主超级块写入之前旧版本的超级块仍然有效，当前版本打开镜像时也不会找到完整的备份超级块
+    ///
+    /// # Arguments
+    ///
+    /// * `backup_block_ids`: 新的备份超级块的块ID
+    fn commit(&self, backup_block_ids: &[u64]) {
+        let mut pending = core::mem::take(&mut *self.pending.lock());
+        let primary = pending.remove(&0);
+        let backups: Vec<_> = backup_block_ids
+            .iter()
+            .filter_map(|block_id| Some((*block_id, pending.remove(block_id)?)))
+            .collect();
+        for (block_id, data) in pending {
+            self.inner.write_block(block_id, data.as_slice());
+        }
+        self.inner.flush();
+        for (block_id, data) in backups {
+            self.inner.write_block(block_id, data.as_slice());
+        }
+        self.inner.flush();
+        if let Some(data) = primary {
+            self.inner.write_block(0, data.as_slice());
+            self.inner.flush();
+        }
+    }
+}
+
+impl BlockDevice for MigrationDevice {
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) {
+        match self.pending.lock().get(&block_id) {
+            Some(data) => buf.copy_from_slice(data.as_slice()),
+            None => self.inner.read_block(block_id, buf),
+        }
+    }
+
+    fn write_block(&self, block_id: u64, buf: &[u8]) {
+        if self.protected.contains(&block_id) {
+            self.pending
+                .lock()
+                .insert(block_id, Box::new(buf.try_into().unwrap()));
+        } else {
+            self.inner.write_block(block_id, buf);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+
+    fn num_blocks(&self) -> Option<u64> {
+        self.inner.num_blocks()
+    }
+}
+
+/// 文件的内容
+enum Contents {
+    /// 每个数据块在旧镜像中的块ID，空洞为零
+    Blocks(Vec<u64>),
+
+    /// 保存在索引节点中的内容
+    Inline(Vec<u8>),
+}
+
+/// 从旧版本镜像中读出的文件或目录
+enum Node {
+    /// 文件的大小和内容，数据块的内容在写入新镜像时才读出
+    File {
+        /// 文件大小
+        size: u64,
+
+        /// 文件的内容
+        contents: Contents,
+    },
+
+    /// 目录中的条目名称和对应的文件或目录
+    Dir(Vec<(String, Node)>),
+}
+
+/// 旧版本的索引节点
+type LegacyInode = [u8; LEGACY_INODE_SZ];
+
+/// 将旧版本的镜像原地迁移为当前的磁盘格式
+///
+/// 先在内存中重放旧版本的日志，读出整个目录树的结构和每个文件的块映射，
+/// 确认当前格式放得下之后在同一个块设备上格式化新的文件系统，逐个文件复制内容并保留空洞。
+/// 旧镜像仍在使用的块（元数据、日志和文件引用的块）在迁移期间只在内存中修改，
+/// 新的数据只写入旧镜像没有使用的块，因此迁移失败时镜像保持原样，仍是原来的版本；
+/// 全部写入之后才写回内存中的块，主超级块最后写回，只有在这一步中途崩溃会使镜像不完整。
+///
+/// 文件名中不合法的 UTF-8 序列被替换为 `U+FFFD`。旧版本的格式没有记录时间戳、权限和所有者，
+/// 迁移后的文件和目录取新建时的默认值
+///
+/// # Arguments
+///
+/// * `block_device`: 旧版本镜像所在的块设备
+///
+/// returns: Result<u32, MigrateError> 镜像原来的版本，已经是当前版本时不做任何修改
+pub fn migrate(block_device: Arc<dyn BlockDevice>) -> Result<u32, MigrateError> {
+    //region 读取旧版本的超级块
+    // 之前通过块缓存写入的内容先写回，之后直接读取块设备
+    block_cache_release(&block_device);
+    let mut block = [0u8; BLOCK_SZ];
+    block_device.read_block(0, &mut block);
+    let magic = read_u32(&block, 0);
+    if magic & !0xff != EFS_MAGIC_BASE {
+        return Err(MigrateError::BadMagic(magic));
+    }
+    let version = magic & 0xff;
+    if version == EFS_VERSION {
+        return Ok(version);
+    }
+    if version > EFS_VERSION {
+        return Err(MigrateError::NewerVersion(version));
+    }
+    if BLOCK_SZ != LEGACY_BLOCK_SZ {
+        return Err(MigrateError::Unsupported("block size is not 512 bytes"));
+    }
+    let super_block = LegacySuperBlock::parse(version, &block, block_device.num_blocks())?;
+    //endregion
+
+    //region 读出整个目录树的结构，并检查当前格式是否放得下
+    let legacy: Arc<dyn BlockDevice> =
+        Arc::new(LegacyDevice::new(block_device.clone(), &super_block)?);
+    let mut reader = Reader {
+        block_device: legacy.clone(),
+        super_block: &super_block,
+        visited: BTreeSet::new(),
+        used: BTreeSet::new(),
+    };
+    let root = reader.read_node(0, 0);
+    let used = reader.used;
+    let result = root.and_then(|root| {
+        check_names(&root)?;
+        let (inodes, data_blocks) = measure(&root);
+        let geometry = Geometry::new(
+            super_block.total_blocks,
+            super_block.total_inodes(),
+            JOURNAL_BLOCKS,
+        )
+        .map_err(|_| MigrateError::Unsupported("layout does not fit the current format"))?;
+        let available = geometry.total_data_blocks()
+            - geometry.backup_block_ids().len() as u64
+            - geometry.group_descriptor_block_ids().len() as u64;
+        if data_blocks > available {
+            return Err(MigrateError::NoSpace {
+                needed: data_blocks,
+                available,
+            });
+        }
+        if inodes > geometry.total_inodes() {
+            return Err(MigrateError::Unsupported("too many inodes"));
+        }
+        let mut protected: BTreeSet<u64> = (0..super_block.data_area_start()).collect();
+        protected.extend(super_block.journal_start()..super_block.total_blocks);
+        protected.extend(used);
+        protected.extend(geometry.backup_block_ids());
+        Ok((root, geometry, protected))
+    });
+    //endregion
+
+    //region 格式化新的文件系统并写入目录树，然后写回旧镜像仍在使用的块
+    let result = result.and_then(|(root, geometry, protected)| {
+        let migration = Arc::new(MigrationDevice {
+            inner: block_device.clone(),
+            protected,
+            pending: Mutex::new(BTreeMap::new()),
+        });
+        let device: Arc<dyn BlockDevice> = migration.clone();
+        let written = write_tree(&legacy, &device, &super_block, root);
+        block_cache_release(&device);
+        written?;
+        migration.commit(&geometry.backup_block_ids());
+        Ok(version)
+    });
+    block_cache_release(&legacy);
+    //endregion
+
+    result
+}
+
+/// 在新的文件系统中重建目录树，返回前同步整个文件系统
+///
+/// # Arguments
+///
+/// * `legacy`: 旧版本镜像的只读视图
+/// * `device`: 新文件系统使用的块设备
+/// * `super_block`: 旧版本的超级块
+/// * `root`: 根目录
+///
+/// returns: Result<(), MigrateError> 写入时出错返回 [`MigrateError::Write`]
+fn write_tree(
+    legacy: &Arc<dyn BlockDevice>,
+    device: &Arc<dyn BlockDevice>,
+    super_block: &LegacySuperBlock,
+    root: Node,
+) -> Result<(), MigrateError> {
+    let efs = FilesystemBuilder::new(super_block.total_blocks)
+        .inodes(super_block.total_inodes())
+        .format(device.clone())?;
+    // 容量检查包括了保留的数据块，写入时也可以使用它们
+    efs.lock().set_use_reserved(true);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    if let Node::Dir(entries) = root {
+        write_entries(legacy, &root_inode, entries)?;
+    }
+    drop(root_inode);
+    EasyFileSystem::sync_all(&efs)?;
+    Ok(())
+}
+
+/// 读取一个块的内容
+///
+/// # Arguments
+///
+/// * `block_device`: 块设备
+/// * `block_id`: 块ID
+///
+/// returns: [u8; BLOCK_SZ] 块的内容
+fn read_block(block_device: &Arc<dyn BlockDevice>, block_id: u64) -> DataBlock {
+    let cache = get_block_cache(block_id, block_device.clone());
+    let data = cache.lock().read(0, |data_block: &DataBlock| *data_block);
+    data
+}
+
+/// 读取给定偏移处的 u32
+///
+/// # Arguments
+///
+/// * `bytes`: 字节
+/// * `offset`: 偏移
+///
+/// returns: u32 读取的值
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// 用版本 3 的索引节点调用回调函数
+/// 版本 3 的索引节点与当前版本的前 126 字节相同，补零后就可以用当前的实现读取
+///
+/// # Arguments
+///
+/// * `raw`: 索引节点的字节
+/// * `f`: 回调函数
+///
+/// returns: T 回调函数的返回值
+fn with_v3_inode<T>(raw: &LegacyInode, f: impl FnOnce(&DiskInode) -> T) -> T {
+    let mut words = [0u64; core::mem::size_of::<DiskInode>() / 8];
+    bytes_of_mut(&mut words)[..V3_INODE_FIELDS_SZ].copy_from_slice(&raw[..V3_INODE_FIELDS_SZ]);
+    f(from_bytes(bytes_of(&words)))
+}
+
+/// 读取旧版本镜像中目录树的结构
+/// 记录访问过的索引节点和被引用的块，每个索引节点和块都只能被引用一次，
+/// 损坏的镜像中的环和交叉引用因此都会被发现
+struct Reader<'a> {
+    /// 旧版本镜像的只读视图
+    block_device: Arc<dyn BlockDevice>,
+
+    /// 旧版本的超级块
+    super_block: &'a LegacySuperBlock,
+
+    /// 访问过的索引节点
+    visited: BTreeSet<u32>,
+
+    /// 被引用的数据块和间接索引块
+    used: BTreeSet<u64>,
+}
+
+impl Reader<'_> {
+    /// 读取一个索引节点及其下的目录结构，文件只记录大小和块映射
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    /// * `depth`: 目录的深度，根目录为 0
+    ///
+    /// returns: Result<Node, MigrateError> 文件或目录
+    fn read_node(&mut self, inode_id: u32, depth: usize) -> Result<Node, MigrateError> {
+        if depth > MAX_DEPTH {
+            return Err(MigrateError::Unsupported(
+                "directory tree is nested too deeply",
+            ));
+        }
+        let raw = self.read_inode(inode_id)?;
+        let (is_dir, size) = if self.super_block.version == 3 {
+            with_v3_inode(&raw, |disk_inode| (disk_inode.is_dir(), disk_inode.size))
+        } else {
+            (raw[124] == 1, read_u32(&raw, 0) as u64)
+        };
+        if size > self.super_block.total_blocks * BLOCK_SZ as u64 {
+            return Err(MigrateError::Corrupted("file is larger than the image"));
+        }
+        let contents = self.read_contents(&raw, size)?;
+        if !is_dir {
+            return Ok(Node::File { size, contents });
+        }
+
+        if size % DIRENT_SZ as u64 != 0 {
+            return Err(MigrateError::Corrupted(
+                "directory size is not a multiple of the entry size",
+            ));
+        }
+        let mut entries = Vec::new();
+        let mut block = [0u8; BLOCK_SZ];
+        for start in (0..size as usize).step_by(BLOCK_SZ) {
+            let len = BLOCK_SZ.min(size as usize - start);
+            match &contents {
+                Contents::Inline(data) => block[..len].copy_from_slice(&data[start..start + len]),
+                // 空洞读出零，其中没有目录条目
+                Contents::Blocks(block_ids) => match block_ids[start / BLOCK_SZ] {
+                    0 => block.fill(0),
+                    block_id => block = read_block(&self.block_device, block_id),
+                },
+            }
+            for chunk in block[..len].chunks_exact(DIRENT_SZ) {
+                // 版本 1 的文件名以零结尾，最长 27 字节，也能按当前的目录条目读出
+                let mut dirent = DirEntry::empty();
+                dirent.as_bytes_mut().copy_from_slice(chunk);
+                let name = dirent.name_bytes();
+                if name.is_empty() || name == b"." || name == b".." {
+                    continue;
+                }
+                entries.push((
+                    dirent.name_lossy().into_owned(),
+                    self.read_node(dirent.inode_number(), depth + 1)?,
+                ));
+            }
+        }
+        Ok(Node::Dir(entries))
+    }
+
+    /// 读取一个索引节点，检查它在索引节点位图中已分配，并且之前没有被访问过
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: Result<[u8; 128], MigrateError> 索引节点的字节
+    fn read_inode(&mut self, inode_id: u32) -> Result<LegacyInode, MigrateError> {
+        if inode_id as u64 >= self.super_block.total_inodes() {
+            return Err(MigrateError::Corrupted("inode number is out of range"));
+        }
+        let bits_per_block = (BLOCK_SZ * 8) as u32;
+        let bitmap_block = read_block(&self.block_device, 1 + (inode_id / bits_per_block) as u64);
+        let bit = (inode_id % bits_per_block) as usize;
+        let word = u64::from_ne_bytes(bitmap_block[bit / 64 * 8..][..8].try_into().unwrap());
+        if word & (1 << (bit % 64)) == 0 {
+            return Err(MigrateError::Corrupted(
+                "directory entry refers to a free inode",
+            ));
+        }
+        if !self.visited.insert(inode_id) {
+            return Err(MigrateError::Corrupted(
+                "inode is referenced more than once",
+            ));
+        }
+        // 旧版本的索引节点区域紧随索引节点位图
+        let inodes_per_block = (BLOCK_SZ / LEGACY_INODE_SZ) as u32;
+        let block_id = self.super_block.inode_area_start() + (inode_id / inodes_per_block) as u64;
+        let offset = (inode_id % inodes_per_block) as usize * LEGACY_INODE_SZ;
+        let block = read_block(&self.block_device, block_id);
+        Ok(block[offset..offset + LEGACY_INODE_SZ].try_into().unwrap())
+    }
+
+    /// 读取索引节点的块映射，保存在索引节点中的内容直接读出
+    ///
+    /// # Arguments
+    ///
+    /// * `raw`: 索引节点的字节
+    /// * `size`: 文件大小
+    ///
+    /// returns: Result<Contents, MigrateError> 文件的内容
+    fn read_contents(&mut self, raw: &LegacyInode, size: u64) -> Result<Contents, MigrateError> {
+        let count = size.div_ceil(BLOCK_SZ as u64) as usize;
+        let block_ids = if self.super_block.version == 3 {
+            let block_device = self.block_device.clone();
+            if with_v3_inode(raw, DiskInode::has_inline_data) {
+                if size > INLINE_DATA_CAPACITY as u64 {
+                    return Err(MigrateError::Corrupted(
+                        "inline data is larger than the inode",
+                    ));
+                }
+                let mut data = vec![0u8; size as usize];
+                with_v3_inode(raw, |disk_inode| {
+                    disk_inode.read_at(0, &mut data, &block_device)
+                });
+                return Ok(Contents::Inline(data));
+            }
+            let (index_block_ids, block_ids) = with_v3_inode(raw, |disk_inode| {
+                let block_ids = (0..count as u32)
+                    .map(|inner_id| disk_inode.get_block_id(inner_id, &block_device))
+                    .collect::<Vec<_>>();
+                (disk_inode.index_block_ids(&block_device), block_ids)
+            });
+            for block_id in index_block_ids {
+                self.claim(block_id)?;
+            }
+            block_ids
+        } else {
+            self.map_indirect_blocks(raw, count)?
+        };
+        for &block_id in block_ids.iter().filter(|&&block_id| block_id != 0) {
+            self.claim(block_id)?;
+        }
+        Ok(Contents::Blocks(block_ids))
+    }
+
+    /// 读取版本 1 或版本 2 的索引节点中前 `count` 个数据块的块ID
+    /// 两个版本的文件大小都是 32 位的，版本 1 有 28 个直接索引以及一级和二级间接索引，
+    /// 版本 2 有 26 个直接索引以及一级、二级和三级间接索引，类型都在第 124 字节
+    ///
+    /// # Arguments
+    ///
+    /// * `raw`: 索引节点的字节
+    /// * `count`: 数据块数
+    ///
+    /// returns: Result<Vec<u64>, MigrateError> 数据块的块ID，空洞为零
+    fn map_indirect_blocks(
+        &mut self,
+        raw: &LegacyInode,
+        count: usize,
+    ) -> Result<Vec<u64>, MigrateError> {
+        let (direct_count, levels) = if self.super_block.version == 1 {
+            (V1_DIRECT_COUNT, 2)
+        } else {
+            (V2_DIRECT_COUNT, 3)
+        };
+        let mut block_ids: Vec<u64> = (0..direct_count.min(count))
+            .map(|i| read_u32(raw, 4 + i * 4) as u64)
+            .collect();
+        for level in 1..=levels {
+            if block_ids.len() == count {
+                break;
+            }
+            let root = read_u32(raw, 4 + (direct_count + level as usize - 1) * 4);
+            self.map_indirect(root, level, count, &mut block_ids)?;
+        }
+        if block_ids.len() < count {
+            return Err(MigrateError::Corrupted("file is larger than its block map"));
+        }
+        Ok(block_ids)
+    }
+
+    /// 读取一棵间接索引树中的数据块ID，追加到 `block_ids` 直到有 `count` 个为止
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 间接索引块ID，为零时整棵树都是空洞
+    /// * `height`: 树的高度，一级间接索引块的高度为 1
+    /// * `count`: 数据块数
+    /// * `block_ids`: 已经读出的数据块ID
+    ///
+    /// returns: Result<(), MigrateError> 块指针超出范围或者被重复引用时返回 [`MigrateError::Corrupted`]
+    fn map_indirect(
+        &mut self,
+        block_id: u32,
+        height: u32,
+        count: usize,
+        block_ids: &mut Vec<u64>,
+    ) -> Result<(), MigrateError> {
+        let per_block = BLOCK_SZ / 4;
+        if block_id == 0 {
+            let len = per_block.pow(height).min(count - block_ids.len());
+            block_ids.resize(block_ids.len() + len, 0);
+            return Ok(());
+        }
+        self.claim(block_id as u64)?;
+        let block = read_block(&self.block_device, block_id as u64);
+        for i in 0..per_block {
+            if block_ids.len() == count {
+                break;
+            }
+            let entry = read_u32(&block, i * 4);
+            if height == 1 {
+                block_ids.push(entry as u64);
+            } else {
+                self.map_indirect(entry, height - 1, count, block_ids)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 记录一个被引用的块，检查它在数据区域中，并且之前没有被引用过
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id`: 块ID
+    ///
+    /// returns: Result<(), MigrateError> 块指针超出数据区域或者被重复引用时返回 [`MigrateError::Corrupted`]
+    fn claim(&mut self, block_id: u64) -> Result<(), MigrateError> {
+        let data_area_start = self.super_block.data_area_start();
+        let data_area_end = data_area_start + self.super_block.data_area_blocks as u64;
+        if !(data_area_start..data_area_end).contains(&block_id) {
+            return Err(MigrateError::Corrupted(
+                "block pointer is outside the data area",
+            ));
+        }
+        if !self.used.insert(block_id) {
+            return Err(MigrateError::Corrupted(
+                "block is referenced more than once",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 统计目录树需要的索引节点数和数据块数（包括间接索引块），文件中的空洞不占用数据块
+///
+/// # Arguments
+///
+/// * `node`: 文件或目录
+///
+/// returns: (u64, u64) (索引节点数, 数据块数)
+fn measure(node: &Node) -> (u64, u64) {
+    match node {
+        Node::File {
+            contents: Contents::Inline(_),
+            ..
+        } => (1, 0),
+        Node::File {
+            size,
+            contents: Contents::Blocks(block_ids),
+        } => {
+            let data_blocks = block_ids.iter().filter(|&&block_id| block_id != 0).count();
+            let index_blocks = DiskInode::total_blocks(*size) as usize - block_ids.len();
+            // 最后一块是空洞时，写入时会暂时为它分配一个数据块
+            let last_hole = usize::from(block_ids.last() == Some(&0));
+            (1, (data_blocks + index_blocks + last_hole) as u64)
+        }
+        Node::Dir(entries) => {
+            // 目录中还有 `.` 和 `..` 两个条目
+            let size = ((entries.len() + 2) * DIRENT_SZ) as u64;
+            entries.iter().fold(
+                (1, DiskInode::total_blocks(size) as u64),
+                |(inodes, data_blocks), (_, child)| {
+                    let (child_inodes, child_data_blocks) = measure(child);
+                    (inodes + child_inodes, data_blocks + child_data_blocks)
+                },
+            )
+        }
+    }
+}
+
+/// 检查目录树中的文件名在当前格式中都是合法的，并且同一目录下没有重名的条目，
+/// 不合法的文件名有损转换后可能重名，版本 1 的文件名也可能比当前格式允许的更长
+///
+/// # Arguments
+///
+/// * `node`: 文件或目录
+///
+/// returns: Result<(), MigrateError> 有不合法或者重名的条目时返回 [`MigrateError::Unsupported`]
+fn check_names(node: &Node) -> Result<(), MigrateError> {
+    let Node::Dir(entries) = node else {
+        return Ok(());
+    };
+    let mut names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    names.sort_unstable();
+    if names.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(MigrateError::Unsupported(
+            "file names collide after lossy conversion",
+        ));
+    }
+    if names.iter().any(|name| FileName::new(name).is_err()) {
+        return Err(MigrateError::Unsupported(
+            "file name is not valid in the current format",
+        ));
+    }
+    entries.iter().try_for_each(|(_, child)| check_names(child))
+}
+
+/// 将目录条目写入目录，文件内容从旧版本镜像中分块读出，空洞不写入
+///
+/// # Arguments
+///
+/// * `legacy`: 旧版本镜像的只读视图
+/// * `dir`: 目录
+/// * `entries`: 条目名称和对应的文件或目录
+///
+/// returns: Result<(), MigrateError> 写入时出错返回 [`MigrateError::Write`]
+fn write_entries(
+    legacy: &Arc<dyn BlockDevice>,
+    dir: &Inode,
+    entries: Vec<(String, Node)>,
+) -> Result<(), MigrateError> {
+    for (name, node) in entries {
+        match node {
+            Node::File {
+                contents: Contents::Inline(data),
+                ..
+            } => {
+                dir.create(&name)?.write_at(0, &data)?;
+            }
+            Node::File {
+                size,
+                contents: Contents::Blocks(block_ids),
+            } => {
+                let file = dir.create(&name)?;
+                write_blocks(legacy, &file, size as usize, &block_ids)?;
+            }
+            Node::Dir(entries) => {
+                let child = dir.create_dir(&name)?;
+                write_entries(legacy, &child, entries)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 将文件的数据块从旧版本镜像复制到新文件，连续的数据块成批写入，空洞跳过
+///
+/// # Arguments
+///
+/// * `legacy`: 旧版本镜像的只读视图
+/// * `file`: 新文件
+/// * `size`: 文件大小
+/// * `block_ids`: 每个数据块在旧镜像中的块ID，空洞为零
+///
+/// returns: Result<(), MigrateError> 写入时出错返回 [`MigrateError::Write`]
+fn write_blocks(
+    legacy: &Arc<dyn BlockDevice>,
+    file: &Inode,
+    size: usize,
+    block_ids: &[u64],
+) -> Result<(), MigrateError> {
+    let mut buf = vec![0u8; COPY_CHUNK_BLOCKS * BLOCK_SZ];
+    let mut index = 0;
+    while index < block_ids.len() {
+        if block_ids[index] == 0 {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < block_ids.len() && block_ids[index] != 0 && index - start < COPY_CHUNK_BLOCKS
+        {
+            buf[(index - start) * BLOCK_SZ..][..BLOCK_SZ]
+                .copy_from_slice(&read_block(legacy, block_ids[index]));
+            index += 1;
+        }
+        let offset = start * BLOCK_SZ;
+        let end = (index * BLOCK_SZ).min(size);
+        file.write_at(offset, &buf[..end - offset])?;
+    }
+    // 最后一块是空洞时，先写入最后一个字节确定文件大小，再把最后一块打成空洞
+    if block_ids.last() == Some(&0) {
+        let last_start = (block_ids.len() - 1) * BLOCK_SZ;
+        file.write_at(size - 1, &[0])?;
+        file.punch_hole(last_start, size - last_start)?;
+    }
+    Ok(())
+}
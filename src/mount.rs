@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use crate::efs::EasyFileSystem;
+use crate::error::{FsError, FsResult};
+use crate::vfs::Inode;
+
+/// 挂载点的键：（文件系统标识，被覆盖的目录的索引节点ID）
+type MountKey = (usize, u32);
+
+/// 文件系统的标识，取文件系统对象的地址
+///
+/// # Arguments
+///
+/// * `efs`: 简易文件系统
+///
+/// returns: usize 文件系统标识
+fn fs_id(efs: &Arc<Mutex<EasyFileSystem>>) -> usize {
+    Arc::as_ptr(efs) as usize
+}
+
+/// 索引节点作为挂载点时的键
+///
+/// # Arguments
+///
+/// * `inode`: 索引节点
+///
+/// returns: MountKey 挂载点的键
+fn mount_key(inode: &Inode) -> MountKey {
+    (fs_id(inode.fs()), inode.inode_id())
+}
+
+/// 一次挂载
+struct Mount {
+    /// 挂载时给出的路径，只用于列出挂载点
+    path: String,
+
+    /// 挂载的文件系统
+    fs: Arc<Mutex<EasyFileSystem>>,
+}
+
+/// 挂载表，把多个文件系统组合成一个命名空间
+/// 根文件系统之外的文件系统挂载在某个目录上，按路径查找时经过这个目录就进入被挂载的文件系统的根目录，
+/// 被覆盖的目录中原有的内容在卸载之前不可见；同一个目录上可以叠加挂载，只有最后挂载的可见
+///
+/// 路径中的 `..` 按已经走过的路径解析，因此可以从被挂载的文件系统的根目录回到挂载点所在的目录
+pub struct MountTable {
+    /// 根文件系统
+    root: Arc<Mutex<EasyFileSystem>>,
+
+    /// 挂载点到挂载的文件系统
+    mounts: BTreeMap<MountKey, Mount>,
+}
+
+impl MountTable {
+    /// 以给定的文件系统为根创建挂载表
+    ///
+    /// # Arguments
+    ///
+    /// * `root`: 根文件系统
+    ///
+    /// returns: MountTable 挂载表
+    pub fn new(root: Arc<Mutex<EasyFileSystem>>) -> Self {
+        Self {
+            root,
+            mounts: BTreeMap::new(),
+        }
+    }
+
+    /// 获取根文件系统
+    pub fn root_fs(&self) -> &Arc<Mutex<EasyFileSystem>> {
+        &self.root
+    }
+
+    /// 获取命名空间的根目录，即根文件系统的根目录
+    pub fn root_inode(&self) -> Arc<Inode> {
+        EasyFileSystem::root_inode(&self.root)
+    }
+
+    /// 将一个文件系统挂载到给定路径的目录上
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 挂载点的路径，从命名空间的根目录开始解析，可以位于其它被挂载的文件系统中
+    /// * `fs`: 被挂载的文件系统
+    ///
+    /// returns: Result<(), FsError> 挂载点不是目录时返回 [`FsError::NotADirectory`]，
+    /// 路径指向命名空间的根目录，或者该文件系统已经在挂载表中时返回 [`FsError::InvalidArgument`]
+    pub fn mount(&mut self, path: &str, fs: Arc<Mutex<EasyFileSystem>>) -> FsResult<()> {
+        let id = fs_id(&fs);
+        if id == fs_id(&self.root) || self.mounts.values().any(|mount| fs_id(&mount.fs) == id) {
+            return Err(FsError::InvalidArgument);
+        }
+        let dir = self.resolve(path, true)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        if fs_id(dir.fs()) == fs_id(&self.root) && dir.inode_id() == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        self.mounts.insert(
+            mount_key(&dir),
+            Mount {
+                path: path.to_string(),
+                fs,
+            },
+        );
+        Ok(())
+    }
+
+    /// 卸载给定路径上最后挂载的文件系统
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 挂载点的路径，最后一个路径分量必须是文件名
+    ///
+    /// returns: Result<Arc<Mutex<EasyFileSystem, Spin>>, FsError> 被卸载的文件系统，
+    /// 路径上没有挂载文件系统时返回 [`FsError::NotFound`]，
+    /// 被卸载的文件系统中还挂载着其它文件系统时返回 [`FsError::InvalidArgument`]
+    pub fn unmount(&mut self, path: &str) -> FsResult<Arc<Mutex<EasyFileSystem>>> {
+        let dir = self.resolve(path, false)?;
+        let mut key = mount_key(&dir);
+        if !self.mounts.contains_key(&key) {
+            return Err(FsError::NotFound);
+        }
+        // 找到叠加在同一个目录上的最后一次挂载
+        loop {
+            let next = (fs_id(&self.mounts[&key].fs), 0);
+            if !self.mounts.contains_key(&next) {
+                break;
+            }
+            key = next;
+        }
+        let id = fs_id(&self.mounts[&key].fs);
+        if self.mounts.keys().any(|&(fs, _)| fs == id) {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(self.mounts.remove(&key).unwrap().fs)
+    }
+
+    /// 列出所有挂载点的路径和挂载在上面的文件系统，按挂载点排列
+    pub fn mounts(&self) -> Vec<(String, Arc<Mutex<EasyFileSystem>>)> {
+        self.mounts
+            .values()
+            .map(|mount| (mount.path.clone(), mount.fs.clone()))
+            .collect()
+    }
+
+    /// 按路径查找索引节点，经过挂载点时进入被挂载的文件系统
+    /// 路径总是从命名空间的根目录开始解析，开头的 `/` 可以省略
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 路径
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点，错误与 [`Inode::find_path`] 相同
+    pub fn find_path(&self, path: &str) -> FsResult<Arc<Inode>> {
+        self.resolve(path, true)
+    }
+
+    /// 如果索引节点是挂载点，则换成挂载在上面的文件系统的根目录，直到不再是挂载点
+    ///
+    /// # Arguments
+    ///
+    /// * `inode`: 索引节点
+    ///
+    /// returns: Arc<Inode> 可见的索引节点
+    fn cross(&self, mut inode: Arc<Inode>) -> Arc<Inode> {
+        while let Some(mount) = self.mounts.get(&mount_key(&inode)) {
+            inode = EasyFileSystem::root_inode(&mount.fs);
+        }
+        inode
+    }
+
+    /// 按路径查找索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 路径
+    /// * `cross_last`: 最后一个路径分量是挂载点时是否进入被挂载的文件系统
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点
+    fn resolve(&self, path: &str, cross_last: bool) -> FsResult<Arc<Inode>> {
+        let max_depth = self.root.lock().max_path_depth();
+        let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+        if names.len() > max_depth {
+            return Err(FsError::LoopDetected);
+        }
+
+        // 从根目录到当前索引节点经过的目录
+        let root = self.root_inode();
+        root.verify()?;
+        let mut stack = vec![root];
+        for (index, &name) in names.iter().enumerate() {
+            match name {
+                "." => {}
+                ".." => {
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                }
+                _ => {
+                    let current = stack.last().unwrap();
+                    if !current.is_dir() {
+                        return Err(FsError::NotADirectory);
+                    }
+                    let mut inode = current.find(name)?;
+                    inode.verify()?;
+                    if cross_last || index + 1 < names.len() {
+                        inode = self.cross(inode);
+                        inode.verify()?;
+                    }
+                    stack.push(inode);
+                }
+            }
+        }
+        Ok(stack.pop().unwrap())
+    }
+}
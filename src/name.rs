@@ -0,0 +1,82 @@
+use core::fmt;
+use std::borrow::Cow;
+use std::str::Utf8Error;
+
+use crate::error::{FsError, FsResult};
+use crate::layout::NAME_LENGTH_LIMIT;
+
+/// 经过检查的文件名
+/// 文件名不能为空，不能超过 [`NAME_LENGTH_LIMIT`] 字节，不能包含 `/` 和零字节，
+/// 除此之外可以是任意的字节串，不要求是合法的 UTF-8
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileName<'a>(&'a [u8]);
+
+impl<'a> FileName<'a> {
+    /// 指向目录自身的文件名
+    pub const DOT: FileName<'static> = FileName(b".");
+
+    /// 指向上一级目录的文件名
+    pub const DOT_DOT: FileName<'static> = FileName(b"..");
+
+    /// 检查一个字符串能否作为文件名
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名
+    ///
+    /// returns: Result<FileName, FsError> 文件名
+    pub fn new(name: &'a str) -> FsResult<Self> {
+        Self::from_bytes(name.as_bytes())
+    }
+
+    /// 检查一个字节串能否作为文件名
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名的原始字节
+    ///
+    /// returns: Result<FileName, FsError> 文件名，为空或者包含 `/` 和零字节时返回
+    /// [`FsError::InvalidArgument`]，过长时返回 [`FsError::NameTooLong`]
+    pub fn from_bytes(name: &'a [u8]) -> FsResult<Self> {
+        if name.is_empty() || name.iter().any(|&byte| byte == b'/' || byte == 0) {
+            return Err(FsError::InvalidArgument);
+        }
+        if name.len() > NAME_LENGTH_LIMIT {
+            return Err(FsError::NameTooLong);
+        }
+        Ok(Self(name))
+    }
+
+    /// 获取文件名的原始字节
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// 获取文件名的字符串形式
+    ///
+    /// returns: Result<&str, Utf8Error> 文件名，不是合法的 UTF-8 时返回错误
+    pub fn to_str(&self) -> Result<&'a str, Utf8Error> {
+        core::str::from_utf8(self.0)
+    }
+
+    /// 获取用于显示的文件名，不合法的 UTF-8 序列被替换为 `U+FFFD`
+    pub fn to_string_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(self.0)
+    }
+
+    /// 文件名的字节数
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// 文件名是否为空，检查过的文件名总是非空
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for FileName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt::Debug;
+
+use bitflags::bitflags;
+
+use crate::vfs::Metadata;
+
+bitflags! {
+    /// 请求的访问权限，与权限位中每一组的三个比特对应
+    pub struct Access: u32 {
+        /// 读
+        const READ = 0o4;
+
+        /// 写
+        const WRITE = 0o2;
+
+        /// 执行或者搜索目录
+        const EXECUTE = 0o1;
+    }
+}
+
+/// 权限检查的特征
+/// 由内核提供调用者的身份并决定是否允许访问，文件系统只在打开、读和写时调用它
+pub trait PermissionCheck: Debug + Send + Sync {
+    /// 检查是否允许以给定的权限访问一个索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `metadata`: 索引节点的元数据
+    /// * `access`: 请求的访问权限
+    ///
+    /// returns: bool 是否允许
+    fn check(&self, metadata: &Metadata, access: Access) -> bool;
+}
+
+/// 按 UNIX 规则检查权限：属主使用属主位，同组使用组位，其他人使用其他位
+/// 用户ID为 0 时总是允许
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixPermissions {
+    /// 调用者的用户ID
+    pub uid: u32,
+
+    /// 调用者的组ID
+    pub gid: u32,
+}
+
+impl PermissionCheck for UnixPermissions {
+    fn check(&self, metadata: &Metadata, access: Access) -> bool {
+        if self.uid == 0 {
+            return true;
+        }
+        let shift = if self.uid == metadata.uid {
+            6
+        } else if self.gid == metadata.gid {
+            3
+        } else {
+            0
+        };
+        let granted = Access::from_bits_truncate(metadata.mode >> shift);
+        granted.contains(access)
+    }
+}
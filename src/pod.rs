@@ -0,0 +1,119 @@
+use core::mem::{align_of, size_of};
+
+use crate::layout::{
+    DirEntry, DiskInode, GroupDescriptor, JournalDeviceSuperBlock, JournalHeader, Quota,
+    SuperBlock, TailBlockHeader, UserQuota, DIRENT_SZ, TAIL_FRAGMENT_SZ, USER_QUOTA_SZ,
+};
+use crate::BLOCK_SZ;
+
+mod sealed {
+    /// 密封特征，阻止本 crate 之外的类型实现 [`super::Pod`]
+    pub trait Sealed {}
+}
+
+/// 可以直接按字节解释的磁盘布局类型
+/// 块缓存只允许以这些类型访问块中的数据，以其它类型访问会在编译时报错
+/// 该特征是密封的，只有本 crate 中的磁盘布局类型实现了它
+///
+/// # Safety
+///
+/// 实现者必须是基本整数类型、由它们组成的数组，或者只包含这些类型的 `#[repr(C)]` 结构体，
+/// 不能有隐式的填充字节，并且任意的位模式都是合法的值
+pub unsafe trait Pod: sealed::Sealed + Sized {}
+
+/// 为磁盘布局类型实现 [`Pod`]
+macro_rules! impl_pod {
+    ($($type_:ty),* $(,)?) => {
+        $(
+            impl sealed::Sealed for $type_ {}
+            unsafe impl Pod for $type_ {}
+        )*
+    };
+}
+
+impl_pod!(
+    u8,
+    u16,
+    u32,
+    u64,
+    SuperBlock,
+    JournalDeviceSuperBlock,
+    JournalHeader,
+    DiskInode,
+    Quota,
+    UserQuota,
+    TailBlockHeader,
+    DirEntry,
+    GroupDescriptor,
+);
+
+impl<T: Pod, const N: usize> sealed::Sealed for [T; N] {}
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+// 磁盘布局类型的大小必须与磁盘格式一致，多出的字节说明出现了填充
+const _: () = assert!(size_of::<SuperBlock>() == 176);
+const _: () = assert!(size_of::<JournalDeviceSuperBlock>() == BLOCK_SZ);
+const _: () = assert!(size_of::<JournalHeader>() == BLOCK_SZ);
+const _: () = assert!(size_of::<DiskInode>() == 256);
+const _: () = assert!(size_of::<DirEntry>() == DIRENT_SZ);
+const _: () = assert!(size_of::<TailBlockHeader>() <= TAIL_FRAGMENT_SZ);
+const _: () = assert!(size_of::<UserQuota>() == USER_QUOTA_SZ);
+const _: () = assert!(size_of::<GroupDescriptor>() == 16);
+
+/// 将值视为不可变字节
+///
+/// # Arguments
+///
+/// * `value`: 值
+///
+/// returns: &[u8] 值的字节
+pub fn bytes_of<T: Pod>(value: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) }
+}
+
+/// 将值视为可变字节
+///
+/// # Arguments
+///
+/// * `value`: 值
+///
+/// returns: &mut [u8] 值的字节
+pub fn bytes_of_mut<T: Pod>(value: &mut T) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(value as *mut T as *mut u8, size_of::<T>()) }
+}
+
+/// 将字节解释为不可变的值
+/// 字节的长度必须等于值的大小，地址必须满足值的对齐要求，否则会 panic
+///
+/// # Arguments
+///
+/// * `bytes`: 字节
+///
+/// returns: &T 值
+pub fn from_bytes<T: Pod>(bytes: &[u8]) -> &T {
+    assert_eq!(bytes.len(), size_of::<T>(), "Size mismatch!");
+    assert_eq!(
+        bytes.as_ptr() as usize % align_of::<T>(),
+        0,
+        "Misaligned access!"
+    );
+    unsafe { &*(bytes.as_ptr() as *const T) }
+}
+
+/// 将字节解释为可变的值
+/// 字节的长度必须等于值的大小，地址必须满足值的对齐要求，否则会 panic
+///
+/// # Arguments
+///
+/// * `bytes`: 字节
+///
+/// returns: &mut T 值
+pub fn from_bytes_mut<T: Pod>(bytes: &mut [u8]) -> &mut T {
+    assert_eq!(bytes.len(), size_of::<T>(), "Size mismatch!");
+    assert_eq!(
+        bytes.as_ptr() as usize % align_of::<T>(),
+        0,
+        "Misaligned access!"
+    );
+    unsafe { &mut *(bytes.as_mut_ptr() as *mut T) }
+}
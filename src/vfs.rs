@@ -1,17 +1,129 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use spin::{Mutex, MutexGuard};
+use spin::Mutex;
 
-use crate::block_cache::{block_cache_sync_all, get_block_cache};
+use crate::block_cache::{block_cache_sync, get_block_cache};
 use crate::block_device::BlockDevice;
-use crate::efs::EasyFileSystem;
-use crate::layout::{DirEntry, DiskInode, DiskInodeType, DIRENT_SZ};
-use crate::nop;
+use crate::efs::{AllocGoal, EasyFileSystem, NameEncoding};
+use crate::error::{ErrorContext, FsError, FsResult, ResultExt};
+use crate::file::{FileHandle, OpenFlags};
+use crate::layout::{
+    DirEntry, DirEntryType, DiskInode, DiskInodeType, Extent, InodeFlags, Quota, QuotaTarget, Tail,
+    DIRENT_SZ, INLINE_DATA_CAPACITY, READ_AHEAD_BLOCKS, TAIL_PACK_LIMIT,
+};
+use crate::name::FileName;
+use crate::permission::Access;
+use crate::{nop, BLOCK_SZ};
+
+/// 9P 协议中目录的 QID 类型
+pub const QTDIR: u8 = 0x80;
+
+/// 9P 协议中普通文件的 QID 类型
+pub const QTFILE: u8 = 0x00;
+
+/// 一个文件延迟分配的数据达到这么多块时立即落盘
+const DELAYED_FLUSH_BLOCKS: usize = 256;
+
+/// 索引节点的稳定标识，格式与 9P 协议的 QID 一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Qid {
+    /// 类型
+    pub type_: u8,
+
+    /// 版本，取索引节点的世代号
+    pub version: u32,
+
+    /// 路径，高 32 位为世代号，低 32 位为索引节点ID
+    pub path: u64,
+}
+
+impl Qid {
+    /// 按 9P 协议的线上格式（小端序）序列化为 13 字节
+    pub fn to_bytes(&self) -> [u8; 13] {
+        let mut bytes = [0u8; 13];
+        bytes[0] = self.type_;
+        bytes[1..5].copy_from_slice(&self.version.to_le_bytes());
+        bytes[5..13].copy_from_slice(&self.path.to_le_bytes());
+        bytes
+    }
+}
+
+/// 索引节点的元数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// 索引节点ID
+    pub inode_id: u32,
+
+    /// 是否是一个目录
+    pub is_dir: bool,
+
+    /// 数据的字节数
+    pub size: usize,
+
+    /// 世代号
+    pub generation: u32,
+
+    /// 最后访问时间，自 UNIX 纪元以来的纳秒数
+    pub atime: u64,
+
+    /// 最后修改数据的时间
+    pub mtime: u64,
+
+    /// 最后改变数据或元数据的时间
+    pub ctime: u64,
+
+    /// 权限位，不包括文件类型
+    pub mode: u32,
+
+    /// 属主的用户ID
+    pub uid: u32,
+
+    /// 属组的组ID
+    pub gid: u32,
+}
+
+/// 索引节点的扩展元数据，一次读取磁盘索引节点得到，类似于 `statx`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Statx {
+    /// 基本元数据
+    pub metadata: Metadata,
+
+    /// 创建时间，自 UNIX 纪元以来的纳秒数，为零时表示未知
+    pub btime: u64,
+
+    /// 索引节点的标志
+    pub flags: InodeFlags,
+
+    /// 实际占用的块数，包括间接索引块，不包括空洞
+    pub blocks: usize,
+
+    /// 块大小，即分配和 I/O 的单位
+    pub block_size: usize,
+}
+
+/// 子树的空间占用
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiskUsage {
+    /// 数据的字节数之和
+    pub size: usize,
+
+    /// 实际占用的块数之和，包括间接索引块，不包括空洞
+    pub blocks: usize,
+}
 
 /// 简易文件系统之上的虚拟文件系统层
+/// 读写磁盘索引节点及其数据时只持有索引节点自己的锁，
+/// 只有分配和释放索引节点、数据块时才持有文件系统锁
+/// 加锁顺序为：父目录的索引节点锁、子索引节点锁、文件系统锁、块缓存锁，
+/// 持有块缓存锁期间不会再去获取文件系统锁
 pub struct Inode {
+    /// 索引节点ID
+    inode_id: u32,
+
     /// 块ID
-    block_id: usize,
+    block_id: u64,
 
     /// 块内偏移
     block_offset: usize,
@@ -21,6 +133,12 @@ pub struct Inode {
 
     /// 块设备
     block_device: Arc<dyn BlockDevice>,
+
+    /// 索引节点锁，保护磁盘索引节点及其数据
+    lock: Mutex<()>,
+
+    /// 上一次读取结束的位置，下一次读取从这里开始时视为顺序读取并预读之后的块
+    read_end: AtomicUsize,
 }
 
 impl Inode {
@@ -28,6 +146,7 @@ impl Inode {
     ///
     /// # Arguments
     ///
+    /// * `inode_id`: 索引节点ID
     /// * `block_id`: 块ID
     /// * `block_offset`: 块内偏移
     /// * `fs`: 文件系统
@@ -35,17 +154,410 @@ impl Inode {
     ///
     /// returns: Inode 索引节点
     pub fn new(
-        block_id: u32,
+        inode_id: u32,
+        block_id: u64,
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
     ) -> Self {
         Self {
-            block_id: block_id as usize,
+            inode_id,
+            block_id,
             block_offset,
             fs,
             block_device,
+            lock: Mutex::new(()),
+            read_end: AtomicUsize::new(0),
+        }
+    }
+
+    /// 获取索引节点ID
+    pub fn inode_id(&self) -> u32 {
+        self.inode_id
+    }
+
+    /// 获取索引节点所在的文件系统
+    pub fn fs(&self) -> &Arc<Mutex<EasyFileSystem>> {
+        &self.fs
+    }
+
+    /// 当前索引节点是否是一个目录
+    pub fn is_dir(&self) -> bool {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+
+    /// 获取当前索引节点中数据的字节数
+    pub fn size(&self) -> usize {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.size as usize)
+    }
+
+    /// 获取索引节点的 QID 标识
+    pub fn qid(&self) -> Qid {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| {
+            let generation = disk_inode.generation();
+            Qid {
+                type_: if disk_inode.is_dir() { QTDIR } else { QTFILE },
+                version: generation,
+                path: ((generation as u64) << 32) | self.inode_id as u64,
+            }
+        })
+    }
+
+    /// 获取索引节点的元数据
+    pub fn metadata(&self) -> Metadata {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| self.metadata_of(disk_inode))
+    }
+
+    /// 从磁盘索引节点中取出元数据
+    ///
+    /// # Arguments
+    ///
+    /// * `disk_inode`: 当前索引节点对应的磁盘索引节点
+    ///
+    /// returns: Metadata 元数据
+    fn metadata_of(&self, disk_inode: &DiskInode) -> Metadata {
+        Metadata {
+            inode_id: self.inode_id,
+            is_dir: disk_inode.is_dir(),
+            size: disk_inode.size as usize,
+            generation: disk_inode.generation(),
+            atime: disk_inode.atime,
+            mtime: disk_inode.mtime,
+            ctime: disk_inode.ctime,
+            mode: disk_inode.mode,
+            uid: disk_inode.uid,
+            gid: disk_inode.gid,
+        }
+    }
+
+    /// 获取索引节点的扩展元数据，包括创建时间、标志和实际占用的块数
+    /// 只读取一次磁盘索引节点，适合实现 `stat` 一类的调用
+    pub fn statx(&self) -> Statx {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| Statx {
+            metadata: self.metadata_of(disk_inode),
+            btime: disk_inode.btime,
+            flags: disk_inode.flags(),
+            blocks: disk_inode.block_ids(&self.block_device).len(),
+            block_size: BLOCK_SZ,
+        })
+    }
+
+    /// 修改权限位
+    ///
+    /// # Arguments
+    ///
+    /// * `mode`: 权限位，不能超过 `0o7777`
+    ///
+    /// returns: Result<(), FsError>
+    pub fn chmod(&self, mode: u32) -> FsResult<()> {
+        if mode > 0o7777 {
+            return Err(FsError::InvalidArgument);
+        }
+        self.ensure_writable()?;
+        let _guard = self.lock.lock();
+        let now = self.now();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.mode = mode;
+            disk_inode.ctime = now;
+        });
+        Ok(())
+    }
+
+    /// 修改属主和属组
+    ///
+    /// # Arguments
+    ///
+    /// * `uid`: 属主的用户ID
+    /// * `gid`: 属组的组ID
+    ///
+    /// returns: Result<(), FsError> 强制执行用户配额且新的属主超出配额时返回 [`FsError::QuotaExceeded`]
+    pub fn chown(&self, uid: u32, gid: u32) -> FsResult<()> {
+        self.ensure_writable()?;
+        let _guard = self.lock.lock();
+        // 延迟分配的数据先落盘，它们预留的数据块记在原来的属主名下
+        self.write_back_delayed()?;
+        let now = self.now();
+        // 索引节点及其占用的空间转移到新的属主的用户配额，打包的尾部按一个块计算
+        let (old_uid, blocks) = self.read_disk_inode(|disk_inode| {
+            (
+                disk_inode.uid,
+                disk_inode.block_ids(&self.block_device).len()
+                    + disk_inode.tail().is_some() as usize,
+            )
+        });
+        if !self
+            .fs
+            .lock()
+            .transfer_user_quota(old_uid, uid, (blocks * BLOCK_SZ) as u64, 1)
+        {
+            return Err(FsError::QuotaExceeded);
+        }
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.uid = uid;
+            disk_inode.gid = gid;
+            disk_inode.ctime = now;
+        });
+        Ok(())
+    }
+
+    /// 恢复复制来的权限位、属主、属组和各个时间戳，重写镜像时用来保留文件和目录的元数据
+    /// 时间戳最后设置，不会被修改权限位和属主时更新的改变时间覆盖
+    ///
+    /// # Arguments
+    ///
+    /// * `statx`: 源索引节点的扩展元数据
+    ///
+    /// returns: Result<(), FsError> 错误与 [`Inode::chmod`] 和 [`Inode::chown`] 相同
+    pub(crate) fn restore_metadata(&self, statx: &Statx) -> FsResult<()> {
+        let metadata = &statx.metadata;
+        self.chmod(metadata.mode)?;
+        self.chown(metadata.uid, metadata.gid)?;
+        let _guard = self.lock.lock();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.atime = metadata.atime;
+            disk_inode.mtime = metadata.mtime;
+            disk_inode.ctime = metadata.ctime;
+            disk_inode.btime = statx.btime;
+        });
+        Ok(())
+    }
+
+    /// 用文件系统的权限检查确认是否允许以给定的权限访问当前索引节点，
+    /// 没有设置权限检查时总是允许
+    /// 调用者不能持有当前索引节点的锁
+    ///
+    /// # Arguments
+    ///
+    /// * `access`: 请求的访问权限
+    ///
+    /// returns: Result<(), FsError> 不允许时返回 [`FsError::PermissionDenied`]
+    pub fn check_access(&self, access: Access) -> FsResult<()> {
+        let Some(permission_check) = self.fs.lock().permission_check() else {
+            return Ok(());
+        };
+        if permission_check.check(&self.metadata(), access) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied)
+        }
+    }
+
+    /// 获取当前索引节点实际占用的块数，包括间接索引块，不包括空洞
+    pub fn allocated_blocks(&self) -> usize {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.block_ids(&self.block_device).len())
+    }
+
+    /// 获取当前文件的区段，不使用区段树时为空
+    pub fn extents(&self) -> Vec<Extent> {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.extents(&self.block_device))
+    }
+
+    /// 让当前文件改用区段树映射数据块，适合大的顺序写入的文件
+    /// 只能在文件为空时切换，否则返回 [`FsError::InvalidArgument`]
+    ///
+    /// returns: Result<(), FsError>
+    pub fn enable_extents(&self) -> FsResult<()> {
+        self.ensure_writable()?;
+        let _guard = self.lock.lock();
+        let now = self.now();
+        self.modify_disk_inode(|disk_inode| {
+            if disk_inode.size != 0 {
+                return Err(FsError::InvalidArgument);
+            }
+            disk_inode.enable_extents();
+            disk_inode.ctime = now;
+            Ok(())
+        })
+    }
+
+    /// 获取当前目录的配额和使用量，没有设置配额时为 None
+    pub fn quota(&self) -> Option<Quota> {
+        let _guard = self.lock.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.quota())
+    }
+
+    /// 在当前目录上设置配额，限制目录及其下所有内容占用的字节数和索引节点数
+    /// 第一次设置时统计目录下已有内容的使用量，并将它们从原来所属的配额中转出；
+    /// 已经设置过配额时只修改限制，使用量超出新的限制时不删除任何内容，只拒绝新的分配
+    /// 调用期间目录下的内容不应被修改
+    ///
+    /// # Arguments
+    ///
+    /// * `max_bytes`: 最多占用的字节数，为零时不限制
+    /// * `max_inodes`: 最多包含的索引节点数，不包括目录本身，为零时不限制
+    ///
+    /// returns: Result<(), FsError>
+    pub fn set_quota(&self, max_bytes: u64, max_inodes: u64) -> FsResult<()> {
+        self.ensure_writable()?;
+        if !self.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        if let Some(quota) = self.quota() {
+            let _guard = self.lock.lock();
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.set_quota(Some(Quota {
+                    max_bytes,
+                    max_inodes,
+                    ..quota
+                }))
+            });
+            self.fs.lock().sync_on_write();
+            return Ok(());
+        }
+
+        let mut visited = BTreeSet::from([self.inode_id]);
+        let (blocks, inodes) = self.assign_quota_root(self.inode_id, &mut visited)?;
+        let used_bytes = ((blocks + self.allocated_blocks()) * BLOCK_SZ) as u64;
+        let _guard = self.lock.lock();
+        let old_quota_root = self.read_disk_inode(|disk_inode| disk_inode.quota_root());
+        // 根目录没有上一级配额
+        if old_quota_root != self.inode_id {
+            self.fs
+                .lock()
+                .charge_quota(old_quota_root, -(used_bytes as i64), -(inodes as i64));
+        }
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.set_quota(Some(Quota {
+                max_bytes,
+                max_inodes,
+                used_bytes,
+                used_inodes: inodes,
+            }))
+        });
+        self.fs.lock().sync_on_write();
+        Ok(())
+    }
+
+    /// 移除当前目录上的配额，目录下的内容重新计入上一级配额
+    /// 上一级配额不会因此拒绝移除，但使用量超出限制后会拒绝新的分配
+    /// 调用期间目录下的内容不应被修改
+    ///
+    /// returns: Result<(), FsError> 没有设置配额时返回 [`FsError::NotFound`]
+    pub fn remove_quota(&self) -> FsResult<()> {
+        self.ensure_writable()?;
+        let quota = self.quota().ok_or(FsError::NotFound)?;
+        let quota_root = self.read_disk_inode(|disk_inode| disk_inode.quota_root());
+        let mut visited = BTreeSet::from([self.inode_id]);
+        self.assign_quota_root(quota_root, &mut visited)?;
+        let _guard = self.lock.lock();
+        self.modify_disk_inode(|disk_inode| disk_inode.set_quota(None));
+        self.fs.lock().modify_quota(quota_root, |parent_quota| {
+            parent_quota.used_bytes += quota.used_bytes;
+            parent_quota.used_inodes += quota.used_inodes;
+        });
+        self.fs.lock().sync_on_write();
+        Ok(())
+    }
+
+    /// 将当前目录下的内容记账到给定的配额目录，返回它们占用的块数和索引节点数
+    /// 另外设置了配额的子目录只修改它自身的记账，不统计也不进入其中的内容
+    /// 调用者不能持有当前目录的索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `quota_root`: 配额目录的索引节点ID
+    /// * `visited`: 已经访问过的索引节点ID，避免通过硬链接重复统计
+    ///
+    /// returns: Result<(usize, u64), FsError> 占用的块数和索引节点数
+    fn assign_quota_root(
+        &self,
+        quota_root: u32,
+        visited: &mut BTreeSet<u32>,
+    ) -> FsResult<(usize, u64)> {
+        let inode_ids: Vec<u32> = self
+            .read_dir()
+            .filter(|dirent| {
+                dirent.as_ref().map_or(true, |dirent| {
+                    dirent.name_bytes() != b"." && dirent.name_bytes() != b".."
+                })
+            })
+            .map(|dirent| dirent.map(|dirent| dirent.inode_number()))
+            .collect::<FsResult<_>>()?;
+        let mut blocks = 0;
+        let mut inodes = 0;
+        for inode_id in inode_ids {
+            if !visited.insert(inode_id) {
+                continue;
+            }
+            let child = self.inode_by_id(inode_id);
+            let (is_dir, has_quota, child_blocks) = {
+                let _guard = child.lock.lock();
+                child.modify_disk_inode(|disk_inode| {
+                    disk_inode.set_quota_root(quota_root);
+                    (
+                        disk_inode.is_dir(),
+                        disk_inode.quota().is_some(),
+                        disk_inode.block_ids(&self.block_device).len()
+                            + disk_inode.tail().is_some() as usize,
+                    )
+                })
+            };
+            inodes += 1;
+            if has_quota {
+                continue;
+            }
+            blocks += child_blocks;
+            if is_dir {
+                let (child_blocks, child_inodes) = child.assign_quota_root(quota_root, visited)?;
+                blocks += child_blocks;
+                inodes += child_inodes;
+            }
+        }
+        Ok((blocks, inodes))
+    }
+
+    /// 从文件系统的时钟获取当前时间
+    /// 调用者不能持有块缓存锁
+    fn now(&self) -> u64 {
+        self.fs.lock().now()
+    }
+
+    /// 文件系统以只读方式打开时返回 [`FsError::ReadOnly`]
+    /// 调用者不能持有块缓存锁
+    fn ensure_writable(&self) -> FsResult<()> {
+        if self.fs.lock().read_only() {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 检查写入的范围是否超出了当前映射格式能够寻址的最大文件大小
+    /// 调用者需持有索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `len`: 长度
+    ///
+    /// returns: Result<(), FsError> 超出时返回 [`FsError::FileTooLarge`]
+    fn check_size(&self, offset: usize, len: usize) -> FsResult<()> {
+        let max_size = self.read_disk_inode(|disk_inode| disk_inode.max_size());
+        match (offset as u64).checked_add(len as u64) {
+            Some(end) if end <= max_size => Ok(()),
+            _ => Err(FsError::FileTooLarge),
+        }
+    }
+
+    /// 更新访问时间，文件系统以只读方式打开或者设置了 noatime 时不更新
+    /// 调用者需持有索引节点锁
+    fn touch_atime(&self) {
+        let skip = {
+            let fs = self.fs.lock();
+            fs.read_only() || fs.noatime()
+        };
+        if skip {
+            return;
         }
+        let now = self.now();
+        self.modify_disk_inode(|disk_inode| disk_inode.atime = now);
     }
 
     /// 在磁盘索引节点上调用一个函数来读取它
@@ -71,205 +583,2038 @@ impl Inode {
     /// returns: V 回调函数的返回值
     fn modify_disk_inode<V>(&self, f: impl FnOnce(&mut DiskInode) -> V) -> V {
         let cache = get_block_cache(self.block_id, self.block_device.clone());
-        let ret = cache.lock().modify(self.block_offset, f);
+        let ret = cache
+            .lock()
+            .modify(self.block_offset, |disk_inode: &mut DiskInode| {
+                let ret = f(disk_inode);
+                disk_inode.update_checksum(self.inode_id);
+                ret
+            });
         nop();
         ret
     }
 
-    /// 在磁盘索引节点下通过名称查找索引节点
+    /// 检查当前索引节点的校验和，目录还会检查目录内容的校验和
+    /// 文件系统没有开启元数据校验和时总是通过
+    /// 调用者不能持有当前索引节点的锁
+    ///
+    /// returns: Result<(), FsError> 校验和不匹配时返回 [`FsError::Corrupted`]
+    pub fn verify(&self) -> FsResult<()> {
+        if !self.fs.lock().metadata_checksum() {
+            return Ok(());
+        }
+        let _guard = self.lock.lock();
+        let valid = self.read_disk_inode(|disk_inode| {
+            disk_inode.verify_checksum(self.inode_id)
+                && disk_inode.verify_dir_checksum(&self.block_device)
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err(FsError::Corrupted.context(ErrorContext::new("verify").inode(self.inode_id)))
+        }
+    }
+
+    /// 按ID获取同一文件系统中的另一个索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `inode_id`: 索引节点ID
+    ///
+    /// returns: Arc<Inode> 索引节点
+    fn inode_by_id(&self, inode_id: u32) -> Arc<Inode> {
+        EasyFileSystem::get_inode(&self.fs, inode_id)
+    }
+
+    /// 在磁盘索引节点下通过名称查找目录条目
+    /// 名称按原始字节比较
     ///
     /// # Arguments
     ///
     /// * `name`: 文件名
     /// * `disk_inode`: 磁盘索引节点
     ///
-    /// returns: Option<u32> 索引节点ID
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+    /// returns: Option<(usize, u32)> 目录条目的序号和索引节点ID
+    fn find_dirent(&self, name: &[u8], disk_inode: &DiskInode) -> Option<(usize, u32)> {
         // 断言是一个目录
         assert!(disk_inode.is_dir());
-        let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+        // 空位的名称为空，不能按空名称查找
+        if name.is_empty() {
+            return None;
+        }
+        // 找遍所有目录条目之后不再读取剩下的空位
+        let mut remaining = disk_inode.dir_entries();
         let mut dirent = DirEntry::empty();
-        for i in 0..file_count {
+        for i in 0..disk_inode.dir_slots() {
+            if remaining == 0 {
+                break;
+            }
             assert_eq!(
                 disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device),
                 DIRENT_SZ,
             );
-            if dirent.name() == name {
-                return Some(dirent.inode_number() as u32);
+            if dirent.is_free() {
+                continue;
+            }
+            if dirent.name_bytes() == name {
+                return Some((i, dirent.inode_number()));
             }
+            remaining -= 1;
         }
         None
     }
 
-    /// 在当前索引节点下按名称查找索引节点
+    /// 在磁盘索引节点下通过名称查找索引节点
     ///
     /// # Arguments
     ///
     /// * `name`: 文件名
+    /// * `disk_inode`: 磁盘索引节点
     ///
-    /// returns: Option<Arc<Inode>> 索引节点
-    pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
-        })
+    /// returns: Option<u32> 索引节点ID
+    fn find_inode_id(&self, name: &[u8], disk_inode: &DiskInode) -> Option<u32> {
+        self.find_dirent(name, disk_inode)
+            .map(|(_, inode_id)| inode_id)
     }
 
-    /// 扩容磁盘索引节点
+    /// 从目录中删除一个目录条目
+    /// 开启了目录空位时在原处留下空位，删除的是最后一个位置时连同它前面的空位一起缩容；
+    /// 否则用最后一个目录条目填补空位
     ///
     /// # Arguments
     ///
-    /// * `new_size`: 新的大小
-    /// * `disk_inode`: 磁盘索引节点
-    /// * `fs`: 文件系统
-    fn increase_size(
+    /// * `index`: 目录条目的序号
+    /// * `disk_inode`: 目录的磁盘索引节点
+    /// * `free_slots`: 是否开启了目录空位，见 [`EasyFileSystem::dir_free_slots`]
+    ///
+    /// returns: Vec<u64, Global> 缩容后待释放的块
+    fn remove_dirent(
         &self,
-        new_size: u32,
+        index: usize,
         disk_inode: &mut DiskInode,
-        fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size {
-            return;
-        }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        free_slots: bool,
+    ) -> Vec<u64> {
+        let entries = disk_inode.dir_entries();
+        let slots = disk_inode.dir_slots();
+        let last = slots - 1;
+        let mut new_slots = last;
+        let mut dirent = DirEntry::empty();
+        if free_slots {
+            disk_inode.write_at(index * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+            if index == last {
+                // 连同最后一个目录条目之后的空位一起缩容
+                while new_slots > 0 {
+                    disk_inode.read_at(
+                        (new_slots - 1) * DIRENT_SZ,
+                        dirent.as_bytes_mut(),
+                        &self.block_device,
+                    );
+                    if !dirent.is_free() {
+                        break;
+                    }
+                    new_slots -= 1;
+                }
+            } else {
+                new_slots = slots;
+            }
+            disk_inode.set_dir_free_slot(disk_inode.dir_free_slot().min(index).min(new_slots));
+        } else if index != last {
+            disk_inode.read_at(last * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+            disk_inode.write_at(index * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        let blocks_dealloc = if new_slots < slots {
+            disk_inode.decrease_size((new_slots * DIRENT_SZ) as u64, &self.block_device)
+        } else {
+            Vec::new()
+        };
+        disk_inode.set_dir_entries(entries - 1);
+        disk_inode.update_dir_checksum(&self.block_device);
+        blocks_dealloc
     }
 
-    /// 在当前索引节点下按名称创建索引节点
+    /// 在当前索引节点下按名称查找索引节点
     ///
     /// # Arguments
     ///
     /// * `name`: 文件名
     ///
-    /// returns: Option<Arc<Inode>> 索引节点
-    pub fn create(&self, name: &str) -> Option<Arc<Inode>> {
-        let mut fs = self.fs.lock();
-        let op = |root_inode: &DiskInode| {
-            // 断言根索引节点是一个目录
-            assert!(root_inode.is_dir());
-
-            // 当前文件是否已经创建
-            self.find_inode_id(name, root_inode)
-        };
-
-        // 如果文件已经存在，返回 None
-        if self.read_disk_inode(op).is_some() {
-            return None;
-        }
-
-        // 创建一个新文件
-        // 在间接块中分配一个索引节点
-        let new_inode_id = fs.alloc_inode();
-
-        // 初始化索引节点
-        let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        let cache = get_block_cache(new_inode_block_id as usize, self.block_device.clone());
-        cache
-            .lock()
-            .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
-            });
-        self.modify_disk_inode(|root_inode| {
-            // 在目录条目中添加文件
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // 扩容
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // 写入目录条目
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
-
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
-        block_cache_sync_all();
+    /// returns: Result<Arc<Inode>, FsError> 索引节点，当前索引节点不是目录时返回
+    /// [`FsError::NotADirectory`]，找不到时返回 [`FsError::NotFound`]
+    pub fn find(&self, name: &str) -> FsResult<Arc<Inode>> {
+        self.find_bytes(name.as_bytes())
+    }
 
-        // 返回索引节点
-        Some(Arc::new(Self::new(
-            block_id,
-            block_offset,
-            self.fs.clone(),
-            self.block_device.clone(),
-        )))
+    /// 在当前索引节点下按原始字节名称查找索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名的原始字节
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点，见 [`Inode::find`]
+    pub fn find_bytes(&self, name: &[u8]) -> FsResult<Arc<Inode>> {
+        let inode_id = self.lookup_id_bytes(name)?;
+        Ok(self.inode_by_id(inode_id))
+    }
 
-        // 由编译器自动释放简易文件系统锁
+    /// 在当前索引节点下按名称查找索引节点ID，不创建索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名
+    ///
+    /// returns: Result<u32, FsError> 索引节点ID，见 [`Inode::find`]
+    pub fn lookup_id(&self, name: &str) -> FsResult<u32> {
+        self.lookup_id_bytes(name.as_bytes())
     }
 
-    /// 列出当前索引节点下的索引节点
-    pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
+    /// 在当前索引节点下按原始字节名称查找索引节点ID
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名的原始字节
+    ///
+    /// returns: Result<u32, FsError> 索引节点ID，见 [`Inode::find`]
+    fn lookup_id_bytes(&self, name: &[u8]) -> FsResult<u32> {
+        let _guard = self.lock.lock();
         self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device),
-                    DIRENT_SZ,
-                );
-                v.push(String::from(dirent.name()));
+            if !disk_inode.is_dir() {
+                return Err(FsError::NotADirectory);
             }
-            v
+            self.find_inode_id(name, disk_inode)
+                .ok_or(FsError::NotFound)
         })
     }
 
-    /// 从当前索引节点中读取数据
+    /// 当前索引节点下是否存在给定名称的目录条目
     ///
     /// # Arguments
     ///
-    /// * `offset`: 偏移
-    /// * `buf`: 缓冲区
+    /// * `name`: 文件名
     ///
-    /// returns: usize 读取的字节数
-    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+    /// returns: bool 是否存在
+    pub fn exists(&self, name: &str) -> bool {
+        self.lookup_id(name).is_ok()
     }
 
-    /// 将数据写入到当前索引节点
+    /// 为写入做准备：写入范围超出文件末尾时扩大文件，并为范围内的空洞分配数据块
+    /// 文件末尾和写入范围之间的部分成为空洞，不分配数据块
+    /// 内联数据放不下写入范围时，先将它迁出到数据块；写入范围到达打包的尾部时，先将尾部迁回数据块
+    /// 调用者需持有索引节点锁，只在分配数据块期间持有文件系统锁
+    /// 可以分配的数据块不够或者超出目录配额或用户配额时不做任何修改，
+    /// 见 [`EasyFileSystem::available_data_blocks`] 和 [`Inode::set_quota`]
     ///
     /// # Arguments
     ///
     /// * `offset`: 偏移
-    /// * `buf`: 缓冲区
+    /// * `len`: 写入的字节数
     ///
-    /// returns: usize 写入的字节数
-    pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
+    /// returns: Result<(), FsError> 可以分配的数据块不够时返回 [`FsError::NoSpace`]，
+    /// 超出配额时返回 [`FsError::QuotaExceeded`]
+    fn prepare_write(&self, offset: usize, len: usize) -> FsResult<()> {
+        let quota_target =
+            self.read_disk_inode(|disk_inode| disk_inode.quota_target(self.inode_id));
+        let tail_start = self.read_disk_inode(|disk_inode| {
+            disk_inode
+                .tail()
+                .map(|_| disk_inode.size as usize / BLOCK_SZ * BLOCK_SZ)
         });
-        block_cache_sync_all();
-        size
-    }
-
-    /// 清空当前索引节点中的数据
-    pub fn clear(&self) {
-        let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
+        if tail_start.is_some_and(|tail_start| offset + len > tail_start) {
+            self.unpack_tail()?;
+        }
+        let inline_size = self
+            .read_disk_inode(|disk_inode| disk_inode.has_inline_data().then_some(disk_inode.size));
+        if let Some(size) = inline_size {
+            if offset + len <= INLINE_DATA_CAPACITY {
+                self.modify_disk_inode(|disk_inode| {
+                    disk_inode.size = disk_inode.size.max((offset + len) as u64);
+                });
+                return Ok(());
             }
+            let block_id = if size > 0 {
+                let mut fs = self.fs.lock();
+                let block_ids = fs.alloc_charged(
+                    AllocGoal::Inode(self.inode_id),
+                    1,
+                    quota_target,
+                    BLOCK_SZ as i64,
+                )?;
+                Some(block_ids[0])
+            } else {
+                None
+            };
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.spill_inline_data(block_id, &self.block_device);
+            });
+            self.order_allocated(block_id.as_slice());
+        }
+        let start_block = (offset / BLOCK_SZ) as u32;
+        let end_block = if len == 0 {
+            start_block
+        } else {
+            (offset + len).div_ceil(BLOCK_SZ) as u32
+        };
+        let blocks_needed = self.read_disk_inode(|disk_inode| {
+            disk_inode.holes_num(start_block, end_block, &self.block_device)
+        });
+        let goal = self.alloc_goal(start_block);
+        let v = self.fs.lock().alloc_charged(
+            goal,
+            blocks_needed as usize,
+            quota_target,
+            (blocks_needed as usize * BLOCK_SZ) as i64,
+        )?;
+        let unused = self.modify_disk_inode(|disk_inode| {
+            disk_inode.size = disk_inode.size.max((offset + len) as u64);
+            disk_inode.fill_holes(start_block, end_block, v.clone(), &self.block_device)
         });
-        block_cache_sync_all();
+        self.order_allocated(&v);
+        if !unused.is_empty() {
+            let mut fs = self.fs.lock();
+            fs.charge_usage(quota_target, -((unused.len() * BLOCK_SZ) as i64), 0);
+            fs.dealloc_data_blocks(&unused)?;
+        }
+        Ok(())
+    }
+
+    /// 获取为给定内部 ID 分配数据块时的目标，使文件的数据块保持连续
+    /// 见 [`DiskInode::goal_block`]，文件还没有数据块时靠近索引节点分配
+    ///
+    /// # Arguments
+    ///
+    /// * `inner_id`: 内部 ID
+    ///
+    /// returns: AllocGoal 分配目标
+    fn alloc_goal(&self, inner_id: u32) -> AllocGoal {
+        self.read_disk_inode(|disk_inode| disk_inode.goal_block(inner_id, &self.block_device))
+            .map_or(AllocGoal::Inode(self.inode_id), AllocGoal::After)
+    }
+
+    /// 记录数据块指针的块：索引节点所在的块和所有间接索引块或者区段树的节点块
+    /// 调用者需持有索引节点锁
+    ///
+    /// returns: Vec<u64, Global> 块ID
+    fn pointer_blocks(&self) -> Vec<u64> {
+        let mut block_ids =
+            self.read_disk_inode(|disk_inode| disk_inode.index_block_ids(&self.block_device));
+        block_ids.push(self.block_id);
+        block_ids
+    }
+
+    /// 开启了软更新时记录当前索引节点的指针块在新分配的块之后写回，
+    /// 见 [`EasyFileSystem::set_soft_updates`]
+    /// 调用者需持有索引节点锁，不能持有文件系统锁
+    ///
+    /// # Arguments
+    ///
+    /// * `block_ids`: 新分配给当前索引节点的块ID
+    fn order_allocated(&self, block_ids: &[u64]) {
+        if block_ids.is_empty() || !self.fs.lock().soft_updates() {
+            return;
+        }
+        let pointer_blocks = self.pointer_blocks();
+        self.fs.lock().order_pointers(&pointer_blocks, block_ids);
+    }
+
+    /// 这次写入是否延迟分配数据块
+    /// 只延迟没有打包尾部的普通文件，空的内联文件写入超出内联容量时先在这里迁出
+    /// 调用者需持有索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `len`: 写入的字节数
+    ///
+    /// returns: bool 是否延迟分配
+    fn use_delayed_allocation(&self, offset: usize, len: usize) -> bool {
+        {
+            // 数据日志模式下写入返回前数据必须已经提交，事务中的数据必须和事务一起提交，都不能留在内存中
+            let fs = self.fs.lock();
+            if len == 0 || !fs.delayed_allocation() || fs.data_journaling() || fs.in_transaction() {
+                return false;
+            }
+        }
+        let (eligible, inline) = self.read_disk_inode(|disk_inode| {
+            let inline = disk_inode.has_inline_data();
+            let eligible = !disk_inode.is_dir()
+                && disk_inode.tail().is_none()
+                && (!inline || (disk_inode.size == 0 && offset + len > INLINE_DATA_CAPACITY));
+            (eligible, inline)
+        });
+        if eligible && inline {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.spill_inline_data(None, &self.block_device);
+            });
+        }
+        eligible
+    }
+
+    /// 将数据写入当前索引节点，落在空洞中的块只预留数据块，数据先留在内存中
+    /// 调用者需持有索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `buf`: 缓冲区
+    ///
+    /// returns: Result<usize, FsError> 写入的字节数，错误同 [`Inode::prepare_write`]，失败时不做任何修改
+    fn write_delayed(&self, offset: usize, buf: &[u8]) -> FsResult<usize> {
+        let end = offset + buf.len();
+        let start_block = (offset / BLOCK_SZ) as u32;
+        let end_block = end.div_ceil(BLOCK_SZ) as u32;
+        let (quota_target, holes, blocks_needed) = self.read_disk_inode(|disk_inode| {
+            let holes: Vec<u32> = (start_block..end_block)
+                .filter(|&inner_id| disk_inode.get_block_id(inner_id, &self.block_device) == 0)
+                .collect();
+            (
+                disk_inode.quota_target(self.inode_id),
+                holes,
+                disk_inode.holes_num(start_block, end_block, &self.block_device) as usize,
+            )
+        });
+        let stale = self
+            .fs
+            .lock()
+            .delayed(self.inode_id)
+            .is_some_and(|delayed| delayed.quota_target != quota_target);
+        if stale {
+            // 预留之后改变了配额目录，先按原来的配额落盘
+            self.write_back_delayed()?;
+        }
+        {
+            let mut fs = self.fs.lock();
+            // 已经延迟的块在之前的写入中预留过
+            let pending = fs.delayed(self.inode_id).map_or(0, |delayed| {
+                holes
+                    .iter()
+                    .filter(|inner_id| delayed.blocks.contains_key(inner_id))
+                    .count()
+            });
+            let count = blocks_needed - pending;
+            fs.reserve_data(quota_target, count)?;
+            let delayed = fs.delayed_mut(self.inode_id, quota_target);
+            delayed.reserved += count;
+            for &inner_id in &holes {
+                let block_start = inner_id as usize * BLOCK_SZ;
+                let from = offset.max(block_start);
+                let to = end.min(block_start + BLOCK_SZ);
+                let data = delayed
+                    .blocks
+                    .entry(inner_id)
+                    .or_insert_with(|| Box::new([0u8; BLOCK_SZ]));
+                data[from - block_start..to - block_start]
+                    .copy_from_slice(&buf[from - offset..to - offset]);
+            }
+        }
+
+        // 已经有数据块的部分直接写入
+        let now = self.now();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.size = disk_inode.size.max(end as u64);
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+            for inner_id in start_block..end_block {
+                if holes.binary_search(&inner_id).is_ok() {
+                    continue;
+                }
+                let from = offset.max(inner_id as usize * BLOCK_SZ);
+                let to = end.min((inner_id as usize + 1) * BLOCK_SZ);
+                disk_inode.write_at(from, &buf[from - offset..to - offset], &self.block_device);
+            }
+        });
+
+        let pending = self
+            .fs
+            .lock()
+            .delayed(self.inode_id)
+            .map_or(0, |delayed| delayed.blocks.len());
+        if pending >= DELAYED_FLUSH_BLOCKS {
+            // 写入已经完成，分配不到数据块时数据仍然留在内存中，之后再落盘
+            self.write_back_delayed().ok();
+        }
+        Ok(buf.len())
+    }
+
+    /// 用延迟分配的数据覆盖从磁盘读出的数据，磁盘上对应的位置都是空洞
+    /// 调用者需持有索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `buf`: 已经从磁盘读出的数据
+    fn read_delayed(&self, offset: usize, buf: &mut [u8]) {
+        let fs = self.fs.lock();
+        let Some(delayed) = fs.delayed(self.inode_id) else {
+            return;
+        };
+        let end = offset + buf.len();
+        let range = (offset / BLOCK_SZ) as u32..end.div_ceil(BLOCK_SZ) as u32;
+        for (&inner_id, data) in delayed.blocks.range(range) {
+            let block_start = inner_id as usize * BLOCK_SZ;
+            let from = offset.max(block_start);
+            let to = end.min(block_start + BLOCK_SZ);
+            buf[from - offset..to - offset]
+                .copy_from_slice(&data[from - block_start..to - block_start]);
+        }
+    }
+
+    /// 为延迟分配的数据分配数据块，并将数据写入块缓存
+    /// 每一段连续的数据一起分配，得到连续的数据块，预留的数据块随之转为真正分配的数据块
+    /// 调用者需持有索引节点锁
+    ///
+    /// returns: Result<(), FsError> 错误同 [`Inode::prepare_write`]，没能落盘的数据放回内存中
+    fn write_back_delayed(&self) -> FsResult<()> {
+        let Some(mut delayed) = self.fs.lock().take_delayed(self.inode_id) else {
+            return Ok(());
+        };
+        let mut result = Ok(());
+        while let Some(&first) = delayed.blocks.keys().next() {
+            let mut end = first + 1;
+            while delayed.blocks.contains_key(&end) {
+                end += 1;
+            }
+            let (quota_target, blocks_needed) = self.read_disk_inode(|disk_inode| {
+                (
+                    disk_inode.quota_target(self.inode_id),
+                    disk_inode.holes_num(first, end, &self.block_device) as usize,
+                )
+            });
+            let goal = self.alloc_goal(first);
+            let v = {
+                let mut fs = self.fs.lock();
+                let released = blocks_needed.min(delayed.reserved);
+                if let Err(err) = fs.release_reservation(delayed.quota_target, released) {
+                    fs.restore_delayed(self.inode_id, delayed);
+                    return Err(err);
+                }
+                match fs.alloc_charged(
+                    goal,
+                    blocks_needed,
+                    quota_target,
+                    (blocks_needed * BLOCK_SZ) as i64,
+                ) {
+                    Ok(v) => {
+                        delayed.reserved -= released;
+                        v
+                    }
+                    Err(err) => {
+                        // 分配失败时不做任何修改，刚释放的数据块一定可以重新预留
+                        fs.reserve_data(delayed.quota_target, released)
+                            .expect("Failed to restore a reservation!");
+                        fs.restore_delayed(self.inode_id, delayed);
+                        return Err(err);
+                    }
+                }
+            };
+            let unused = self.modify_disk_inode(|disk_inode| {
+                let unused = disk_inode.fill_holes(first, end, v.clone(), &self.block_device);
+                for inner_id in first..end {
+                    let data = delayed.blocks.remove(&inner_id).unwrap();
+                    let block_id = disk_inode.get_block_id(inner_id, &self.block_device);
+                    let cache = get_block_cache(block_id, self.block_device.clone());
+                    cache
+                        .lock()
+                        .modify(0, |data_block: &mut [u8; BLOCK_SZ]| *data_block = *data);
+                }
+                unused
+            });
+            self.order_allocated(&v);
+            if !unused.is_empty() {
+                let mut fs = self.fs.lock();
+                fs.charge_usage(quota_target, -((unused.len() * BLOCK_SZ) as i64), 0);
+                // 数据已经写入，出错时仍然继续，释放剩余的预留
+                result = result.and(fs.dealloc_data_blocks(&unused));
+            }
+        }
+        {
+            let mut fs = self.fs.lock();
+            result = result.and(fs.release_reservation(delayed.quota_target, delayed.reserved));
+            fs.sync_on_write();
+        }
+        result.and(self.pack_tail())
+    }
+
+    /// 将打包的尾部迁回一个独占的数据块，并释放它占用的片段
+    /// 调用者需持有索引节点锁，可以分配的数据块不够或者超出目录配额或用户配额时不做任何修改
+    ///
+    /// returns: Result<(), FsError> 没有打包的尾部时总是成功，错误同 [`Inode::prepare_write`]
+    fn unpack_tail(&self) -> FsResult<()> {
+        let (tail, inner_id, quota_target) = self.read_disk_inode(|disk_inode| {
+            (
+                disk_inode.tail(),
+                (disk_inode.size / BLOCK_SZ as u64) as u32,
+                disk_inode.quota_target(self.inode_id),
+            )
+        });
+        let Some(tail) = tail else {
+            return Ok(());
+        };
+        let blocks_needed = self.read_disk_inode(|disk_inode| {
+            disk_inode.holes_num(inner_id, inner_id + 1, &self.block_device)
+        });
+        // 尾部在配额中已经按一个块计算
+        let goal = self.alloc_goal(inner_id);
+        let v = self.fs.lock().alloc_charged(
+            goal,
+            blocks_needed as usize,
+            quota_target,
+            ((blocks_needed as usize - 1) * BLOCK_SZ) as i64,
+        )?;
+        let unused = self.modify_disk_inode(|disk_inode| {
+            disk_inode.set_tail(None);
+            let unused =
+                disk_inode.fill_holes(inner_id, inner_id + 1, v.clone(), &self.block_device);
+            let block_id = disk_inode.get_block_id(inner_id, &self.block_device);
+            let mut data = [0u8; BLOCK_SZ];
+            let cache = get_block_cache(tail.block_id, self.block_device.clone());
+            cache.lock().read(0, |tail_block: &[u8; BLOCK_SZ]| {
+                data[..tail.len].copy_from_slice(&tail_block[tail.offset..tail.offset + tail.len]);
+            });
+            let cache = get_block_cache(block_id, self.block_device.clone());
+            cache.lock().modify(0, |data_block: &mut [u8; BLOCK_SZ]| {
+                data_block[..tail.len].copy_from_slice(&data[..tail.len]);
+            });
+            unused
+        });
+        self.order_allocated(&v);
+        let pointer_blocks = self.pointer_blocks();
+        let mut fs = self.fs.lock();
+        fs.commit(&pointer_blocks);
+        fs.charge_usage(quota_target, -((unused.len() * BLOCK_SZ) as i64), 0);
+        let result = fs.dealloc_data_blocks(&unused);
+        let result = result.and(fs.dealloc_tail(tail));
+        fs.order_tail_free(tail, self.block_id);
+        result
+    }
+
+    /// 将不超过 [`TAIL_PACK_LIMIT`] 字节的文件尾部打包到共享的尾部块中，并释放它原来的数据块
+    /// 只在文件系统开启了尾部打包时处理没有使用内联数据的文件，
+    /// 尾部是空洞或者分配不到尾部块中的片段时不做任何修改
+    /// 调用者需持有索引节点锁
+    ///
+    /// returns: Result<(), FsError> 分配尾部块出错时返回错误，此时不做任何修改，
+    /// 释放原来的数据块出错时返回错误，此时尾部已经打包
+    fn pack_tail(&self) -> FsResult<()> {
+        {
+            // 还有延迟分配的数据时等到它们落盘之后再打包
+            let fs = self.fs.lock();
+            if !fs.tail_packing() || fs.delayed(self.inode_id).is_some() {
+                return Ok(());
+            }
+        }
+        let candidate = self.read_disk_inode(|disk_inode| {
+            let len = disk_inode.size as usize % BLOCK_SZ;
+            if disk_inode.is_dir()
+                || disk_inode.has_inline_data()
+                || disk_inode.tail().is_some()
+                || len == 0
+                || len > TAIL_PACK_LIMIT
+            {
+                return None;
+            }
+            let inner_id = (disk_inode.size / BLOCK_SZ as u64) as u32;
+            let block_id = disk_inode.get_block_id(inner_id, &self.block_device);
+            (block_id != 0).then_some((
+                inner_id,
+                block_id,
+                len,
+                disk_inode.quota_target(self.inode_id),
+            ))
+        });
+        let Some((inner_id, block_id, len, quota_target)) = candidate else {
+            return Ok(());
+        };
+        let Some(tail) = self.fs.lock().alloc_tail(len, self.inode_id)? else {
+            return Ok(());
+        };
+        let mut data = [0u8; BLOCK_SZ];
+        let cache = get_block_cache(block_id, self.block_device.clone());
+        cache.lock().read(0, |data_block: &[u8; BLOCK_SZ]| {
+            data.copy_from_slice(data_block);
+        });
+        let cache = get_block_cache(tail.block_id, self.block_device.clone());
+        cache.lock().modify(0, |tail_block: &mut [u8; BLOCK_SZ]| {
+            tail_block[tail.offset..tail.offset + len].copy_from_slice(&data[..len]);
+        });
+        let blocks_dealloc = self.modify_disk_inode(|disk_inode| {
+            let size = disk_inode.size;
+            let blocks_dealloc =
+                disk_inode.decrease_size(inner_id as u64 * BLOCK_SZ as u64, &self.block_device);
+            disk_inode.size = size;
+            disk_inode.set_tail(Some(tail));
+            blocks_dealloc
+        });
+        self.order_allocated(&[tail.block_id]);
+
+        // 索引节点和保留下来的间接索引块的更新持久化之后再释放原来的数据块
+        let pointer_blocks = self.pointer_blocks();
+        let mut fs = self.fs.lock();
+        fs.commit(&pointer_blocks);
+        // 尾部在配额中按一个块计算，只有一同释放的间接索引块减少使用量
+        fs.charge_usage(
+            quota_target,
+            -(((blocks_dealloc.len() - 1) * BLOCK_SZ) as i64),
+            0,
+        );
+        let result = fs.dealloc_data_blocks(&blocks_dealloc);
+        fs.order_frees(&blocks_dealloc, &pointer_blocks);
+        result
+    }
+
+    /// 按路径查找索引节点
+    /// 路径以 `/` 分隔，以 `/` 开头时从当前目录所在的命名空间的根目录开始查找，否则从当前索引节点开始查找，
+    /// 命名空间的根目录见 [`Inode::namespace_root`]
+    /// `.` 和 `..` 通过目录条目解析
+    /// 路径分量数超过文件系统允许的最大深度，或者沿路径回到了一个祖先目录时，
+    /// 返回 [`FsError::LoopDetected`]
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 路径
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点
+    pub fn find_path(&self, path: &str) -> FsResult<Arc<Inode>> {
+        let max_depth = self.fs.lock().max_path_depth();
+        let inode_id = if path.starts_with('/') {
+            self.namespace_root()?
+        } else {
+            self.inode_id
+        };
+        let mut inode = self.inode_by_id(inode_id);
+        inode.verify()?;
+
+        // 从起点到当前索引节点经过的目录
+        let mut ancestors = vec![inode.inode_id];
+        for (depth, name) in path.split('/').filter(|name| !name.is_empty()).enumerate() {
+            if depth >= max_depth {
+                return Err(FsError::LoopDetected);
+            }
+            if !inode.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            inode = inode.find(name)?;
+            inode.verify()?;
+            match name {
+                "." => {}
+                ".." => {
+                    ancestors.pop();
+                    if ancestors.is_empty() {
+                        ancestors.push(inode.inode_id);
+                    }
+                }
+                _ => {
+                    if ancestors.contains(&inode.inode_id) {
+                        return Err(FsError::LoopDetected);
+                    }
+                    ancestors.push(inode.inode_id);
+                }
+            }
+        }
+        Ok(inode)
+    }
+
+    /// 获取当前目录所在的命名空间的根目录，即沿 `..` 向上找到的第一个父目录是它自己的目录
+    /// 文件系统的根目录和各个子卷的根目录都是这样的目录，当前索引节点不是目录时返回文件系统的根目录
+    ///
+    /// returns: Result<u32, FsError> 根目录的索引节点ID，沿 `..` 回到了走过的目录时返回 [`FsError::LoopDetected`]
+    pub fn namespace_root(&self) -> FsResult<u32> {
+        if !self.is_dir() {
+            return Ok(0);
+        }
+        let mut inode_id = self.inode_id;
+        let mut visited = BTreeSet::new();
+        loop {
+            let parent = self.inode_by_id(inode_id).lookup_id("..")?;
+            if parent == inode_id {
+                return Ok(inode_id);
+            }
+            if !visited.insert(inode_id) {
+                return Err(FsError::LoopDetected);
+            }
+            inode_id = parent;
+        }
+    }
+
+    /// 将当前目录的 `..` 目录条目指向给定的目录，用于让子卷的根目录成为自己的父目录
+    ///
+    /// # Arguments
+    ///
+    /// * `parent_inode_id`: 父目录的索引节点ID
+    pub(crate) fn set_parent(&self, parent_inode_id: u32) {
+        let _guard = self.lock.lock();
+        self.modify_disk_inode(|disk_inode| {
+            assert!(disk_inode.is_dir());
+            let dot_dot =
+                DirEntry::new(FileName::DOT_DOT, parent_inode_id, DirEntryType::Directory);
+            disk_inode.write_at(DIRENT_SZ, dot_dot.as_bytes(), &self.block_device);
+            disk_inode.update_dir_checksum(&self.block_device);
+        });
+        self.fs.lock().sync_on_write();
+    }
+
+    /// 按打开标志打开当前目录下的一个文件
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名
+    /// * `flags`: 打开标志
+    ///
+    /// returns: Result<FileHandle, FsError> 文件句柄
+    pub fn open(&self, name: &str, flags: OpenFlags) -> FsResult<FileHandle> {
+        self.verify()?;
+        let inode = match self.find(name) {
+            Ok(inode) => {
+                inode.verify()?;
+                if flags.contains(OpenFlags::CREATE | OpenFlags::EXCL) {
+                    return Err(FsError::AlreadyExists);
+                }
+                if flags.writable() && inode.is_dir() {
+                    return Err(FsError::IsADirectory);
+                }
+                let mut access = Access::empty();
+                if flags.readable() {
+                    access |= Access::READ;
+                }
+                if flags.writable() {
+                    access |= Access::WRITE;
+                }
+                inode.check_access(access)?;
+                if flags.writable() {
+                    inode.ensure_writable()?;
+                }
+                if flags.writable() && flags.contains(OpenFlags::TRUNC) {
+                    inode.clear()?;
+                }
+                inode
+            }
+            Err(FsError::NotFound) => {
+                if !flags.contains(OpenFlags::CREATE) {
+                    return Err(FsError::NotFound);
+                }
+                let name = FileName::new(name)?;
+                self.check_access(Access::WRITE | Access::EXECUTE)?;
+                self.ensure_writable()?;
+                self.create_inode(name, DiskInodeType::File)?
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(FileHandle::with_flags(inode, flags))
+    }
+
+    /// 在当前索引节点下按名称创建文件
+    /// 文件名不合法时的错误见 [`FileName::new`]
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点，文件已存在时返回 [`FsError::AlreadyExists`]，
+    /// 没有空闲的索引节点或者数据块时返回 [`FsError::NoSpace`]，超出配额时返回 [`FsError::QuotaExceeded`]
+    pub fn create(&self, name: &str) -> FsResult<Arc<Inode>> {
+        self.create_inode(FileName::new(name)?, DiskInodeType::File)
+    }
+
+    /// 在当前索引节点下按原始字节名称创建文件
+    /// 文件名编码为 [`NameEncoding::Utf8`] 时，不是合法 UTF-8 的文件名返回 [`FsError::InvalidArgument`]
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名的原始字节
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点
+    pub fn create_bytes(&self, name: &[u8]) -> FsResult<Arc<Inode>> {
+        self.create_inode(FileName::from_bytes(name)?, DiskInodeType::File)
+    }
+
+    /// 在当前索引节点下按名称创建目录
+    /// 新目录会自动包含 `.` 和 `..` 目录条目
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 目录名
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点
+    pub fn create_dir(&self, name: &str) -> FsResult<Arc<Inode>> {
+        self.create_inode(FileName::new(name)?, DiskInodeType::Directory)
+    }
+
+    /// 在当前索引节点下按名称和类型创建索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 检查过的文件名
+    /// * `type_`: 索引节点类型
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 索引节点，文件已存在时返回 [`FsError::AlreadyExists`]，
+    /// 没有空闲的索引节点或者可以分配的数据块不够时返回 [`FsError::NoSpace`]，
+    /// 超出配额时返回 [`FsError::QuotaExceeded`]，失败时不做任何修改
+    fn create_inode(&self, name: FileName<'_>, type_: DiskInodeType) -> FsResult<Arc<Inode>> {
+        // 只读时不能创建
+        self.ensure_writable()?;
+        if self.fs.lock().name_encoding() == NameEncoding::Utf8 && name.to_str().is_err() {
+            return Err(FsError::InvalidArgument);
+        }
+        let _guard = self.lock.lock();
+        // 已经被删除、等待释放的目录中不能再创建
+        if self.fs.lock().is_orphan(self.inode_id) {
+            return Err(FsError::NotFound);
+        }
+        let op = |root_inode: &DiskInode| {
+            // 断言根索引节点是一个目录
+            assert!(root_inode.is_dir());
+
+            // 当前文件是否已经创建
+            self.find_inode_id(name.as_bytes(), root_inode)
+        };
+
+        // 如果文件已经存在，返回错误
+        if self.read_disk_inode(op).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        // 扩容目录和新目录需要的数据块都不能占用保留的数据块，在分配索引节点之前检查
+        // 优先复用目录中的空位，没有空位时追加到末尾
+        let (slot, entries) = self.read_disk_inode(|root_inode| {
+            (
+                root_inode.find_free_slot(&self.block_device),
+                root_inode.dir_entries(),
+            )
+        });
+        let dirent_blocks = self.read_disk_inode(|root_inode| {
+            root_inode.holes_num(
+                (slot * DIRENT_SZ / BLOCK_SZ) as u32,
+                ((slot + 1) * DIRENT_SZ).div_ceil(BLOCK_SZ) as u32,
+                &self.block_device,
+            )
+        });
+        let inode_blocks = (type_ == DiskInodeType::Directory) as u64;
+        let blocks_needed = dirent_blocks as u64 + inode_blocks;
+        // 新索引节点及其数据块和目录条目一样，计入当前目录所属的配额，
+        // 但在用户配额中，目录条目计入当前目录的属主，新索引节点计入它自己的属主
+        let quota_target =
+            self.read_disk_inode(|root_inode| root_inode.quota_target(self.inode_id));
+        // 新索引节点的属主是 uid 0
+        let new_quota_target = QuotaTarget {
+            uid: 0,
+            ..quota_target
+        };
+        {
+            let fs = self.fs.lock();
+            let user_allowed = if quota_target.uid == new_quota_target.uid {
+                fs.user_quota_allows(quota_target.uid, blocks_needed * BLOCK_SZ as u64, 1)
+            } else {
+                fs.user_quota_allows(quota_target.uid, dirent_blocks as u64 * BLOCK_SZ as u64, 0)
+                    && fs.user_quota_allows(new_quota_target.uid, inode_blocks * BLOCK_SZ as u64, 1)
+            };
+            if fs.available_data_blocks() < blocks_needed {
+                return Err(FsError::NoSpace);
+            }
+            if !fs.quota_allows(quota_target.quota_root, blocks_needed * BLOCK_SZ as u64, 1)
+                || !user_allowed
+            {
+                return Err(FsError::QuotaExceeded);
+            }
+        }
+
+        // 创建一个新文件
+        let entry_type = DirEntryType::from(&type_);
+        let now = self.now();
+        let new_inode_id = {
+            let mut fs = self.fs.lock();
+
+            // 优先在父目录所在的块组中分配一个索引节点
+            let new_inode_id = fs.alloc_inode_near(self.inode_id)?;
+
+            // 初始化索引节点
+            if type_ == DiskInodeType::Directory {
+                if let Err(err) = fs.initialize_dir(new_inode_id, self.inode_id) {
+                    fs.dealloc_inode(new_inode_id).ok();
+                    return Err(err);
+                }
+            } else {
+                let (new_inode_block_id, new_inode_block_offset) =
+                    fs.get_disk_inode_pos(new_inode_id);
+                let address_48bit = fs.address_48bit();
+                let cache = get_block_cache(new_inode_block_id, self.block_device.clone());
+                cache
+                    .lock()
+                    .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+                        new_inode.initialize(type_);
+                        new_inode.atime = now;
+                        new_inode.mtime = now;
+                        new_inode.ctime = now;
+                        new_inode.btime = now;
+                        // 直接索引和间接索引只能记录 32 位的块ID
+                        if address_48bit {
+                            new_inode.enable_extents();
+                        }
+                        if fs.inline_data() {
+                            new_inode.enable_inline_data();
+                        }
+                        new_inode.update_checksum(new_inode_id);
+                    });
+            }
+
+            // 记账到当前目录所属的配额
+            let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
+            let cache = get_block_cache(new_inode_block_id, self.block_device.clone());
+            let new_blocks =
+                cache
+                    .lock()
+                    .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+                        new_inode.set_quota_root(quota_target.quota_root);
+                        new_inode.update_checksum(new_inode_id);
+                        new_inode.block_ids(&self.block_device).len()
+                    });
+            fs.charge_usage(new_quota_target, (new_blocks * BLOCK_SZ) as i64, 1);
+            new_inode_id
+
+            // 由编译器自动释放简易文件系统锁
+        };
+
+        // 在目录条目中添加文件
+        // 扩容，需要的数据块已经在前面检查过，仍然失败时释放新索引节点
+        if let Err(err) = self.prepare_write(slot * DIRENT_SZ, DIRENT_SZ) {
+            let mut fs = self.fs.lock();
+            let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
+            let cache = get_block_cache(new_inode_block_id, self.block_device.clone());
+            let blocks_dealloc = cache
+                .lock()
+                .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
+                    new_inode.clear_size(&self.block_device)
+                });
+            fs.charge_usage(
+                new_quota_target,
+                -((blocks_dealloc.len() * BLOCK_SZ) as i64),
+                -1,
+            );
+            fs.dealloc_data_blocks(&blocks_dealloc).ok();
+            fs.dealloc_inode(new_inode_id).ok();
+            return Err(err);
+        }
+        // 写入目录条目
+        self.modify_disk_inode(|root_inode| {
+            root_inode.mtime = now;
+            root_inode.ctime = now;
+            let dirent = DirEntry::new(name, new_inode_id, entry_type);
+            root_inode.write_at(slot * DIRENT_SZ, dirent.as_bytes(), &self.block_device);
+            root_inode.set_dir_entries(entries + 1);
+            // 在此之前的位置都已经被占用
+            root_inode.set_dir_free_slot(slot + 1);
+            root_inode.update_dir_checksum(&self.block_device);
+        });
+        // 目录条目在它指向的索引节点初始化并在位图中分配之后写回
+        let dirent_block = self.read_disk_inode(|root_inode| {
+            root_inode.get_block_id((slot * DIRENT_SZ / BLOCK_SZ) as u32, &self.block_device)
+        });
+        {
+            let fs = self.fs.lock();
+            let (new_inode_block_id, _) = fs.get_disk_inode_pos(new_inode_id);
+            fs.order_after(dirent_block, new_inode_block_id);
+            fs.order_after(dirent_block, fs.inode_bitmap_block(new_inode_id));
+            fs.sync_on_write();
+        }
+
+        // 返回索引节点
+        Ok(self.inode_by_id(new_inode_id))
+    }
+
+    /// 在当前目录下按名称查找一个可删除的目录条目
+    /// 调用者需持有当前目录的索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名
+    ///
+    /// returns: Result<(usize, Arc<Inode>), FsError> 目录条目的序号和它指向的索引节点
+    fn find_removable(&self, name: &[u8]) -> FsResult<(usize, Arc<Inode>)> {
+        if name == b"." || name == b".." {
+            return Err(FsError::InvalidArgument);
+        }
+        self.ensure_writable()?;
+        if !self.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return Err(FsError::NotADirectory);
+        }
+        let (index, inode_id) = self
+            .read_disk_inode(|disk_inode| self.find_dirent(name, disk_inode))
+            .ok_or(FsError::NotFound)?;
+        Ok((index, self.inode_by_id(inode_id)))
+    }
+
+    /// 清空当前索引节点的数据，准备释放它
+    /// 调用者需持有索引节点锁
+    ///
+    /// returns: (QuotaTarget, Vec<u64, Global>, QuotaTarget, Option<Tail>) 数据计入的配额、
+    /// 待释放的数据块、索引节点计入的配额和待释放的尾部
+    fn detach_data(&self) -> (QuotaTarget, Vec<u64>, QuotaTarget, Option<Tail>) {
+        self.modify_disk_inode(|disk_inode| {
+            let tail = disk_inode.tail();
+            disk_inode.set_tail(None);
+            (
+                disk_inode.quota_target(self.inode_id),
+                disk_inode.clear_size(&self.block_device),
+                QuotaTarget {
+                    quota_root: disk_inode.quota_root(),
+                    uid: disk_inode.uid,
+                },
+                tail,
+            )
+        })
+    }
+
+    /// 释放一个推迟释放的索引节点及其数据块，它已经没有目录条目指向，也没有其它索引节点对象
+    ///
+    /// # Arguments
+    ///
+    /// * `dirent_block`: 曾经指向它的目录条目所在的块
+    ///
+    /// returns: Result<(), FsError> 位图与索引节点不一致时返回第一个错误，其余的数据块照常释放
+    fn release(&self, dirent_block: u64) -> FsResult<()> {
+        let (quota_target, blocks_dealloc, inode_quota_target, tail) = self.detach_data();
+        let blocks = blocks_dealloc.len() + tail.is_some() as usize;
+
+        // 索引节点的更新持久化之后再释放数据块
+        let mut fs = self.fs.lock();
+        fs.commit(&[self.block_id]);
+        fs.charge_usage(quota_target, -((blocks * BLOCK_SZ) as i64), 0);
+        fs.charge_usage(inode_quota_target, 0, -1);
+        let mut result = fs.dealloc_data_blocks(&blocks_dealloc);
+        fs.order_frees(&blocks_dealloc, &[self.block_id]);
+        if let Some(tail) = tail {
+            result = result.and(fs.dealloc_tail(tail));
+            fs.order_tail_free(tail, self.block_id);
+        }
+        result = result.and(fs.dealloc_inode(self.inode_id));
+        fs.order_after(fs.inode_bitmap_block(self.inode_id), dirent_block);
+        fs.sync_on_write();
+        result
+    }
+
+    /// 删除当前目录下的一个目录条目
+    /// 如果给出了它指向的索引节点，则一并释放该索引节点及其数据块；
+    /// 该索引节点还有其它索引节点对象时推迟到最后一个对象被释放时再释放，
+    /// 以免它们继续操作被重新分配给其它文件的索引节点
+    /// 调用者需持有当前目录和该索引节点的索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `index`: 目录条目的序号
+    /// * `child`: 需要释放的索引节点
+    ///
+    /// returns: Result<(), FsError> 位图与目录条目不一致时返回第一个错误，目录条目仍会被删除，
+    /// 其余的数据块和索引节点照常释放
+    fn remove_entry(&self, index: usize, child: Option<&Arc<Inode>>) -> FsResult<()> {
+        let now = self.now();
+        let free_slots = self.fs.lock().dir_free_slots();
+        let dirent_block = self.read_disk_inode(|disk_inode| {
+            disk_inode.get_block_id((index * DIRENT_SZ / BLOCK_SZ) as u32, &self.block_device)
+        });
+        let (quota_target, mut blocks_dealloc) = self.modify_disk_inode(|disk_inode| {
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+            (
+                disk_inode.quota_target(self.inode_id),
+                self.remove_dirent(index, disk_inode, free_slots),
+            )
+        });
+        let mut quota_charges = vec![(quota_target, blocks_dealloc.len(), 0)];
+        let mut pointer_blocks = self.pointer_blocks();
+        let mut tail_dealloc = None;
+        let child = child.filter(|child| {
+            // 索引节点对象只能在持有文件系统锁时从索引节点表中取得，持有文件系统锁时的引用计数是准确的
+            let mut fs = self.fs.lock();
+            if Arc::strong_count(child) > 1 {
+                fs.add_orphan(child.inode_id, dirent_block);
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(child) = child {
+            let (child_quota_target, child_blocks, child_inode_quota_target, child_tail) =
+                child.detach_data();
+            quota_charges.push((
+                child_quota_target,
+                child_blocks.len() + child_tail.is_some() as usize,
+                0,
+            ));
+            quota_charges.push((child_inode_quota_target, 0, 1));
+            blocks_dealloc.extend(child_blocks);
+            tail_dealloc = child_tail;
+            if child.block_id != self.block_id {
+                pointer_blocks.push(child.block_id);
+            }
+        }
+
+        // 索引节点和保留下来的间接索引块的更新持久化之后再释放数据块
+        let mut fs = self.fs.lock();
+        fs.commit(&pointer_blocks);
+        for (quota_target, blocks, inodes) in quota_charges {
+            fs.charge_usage(quota_target, -((blocks * BLOCK_SZ) as i64), -inodes);
+        }
+        let mut result = fs.dealloc_data_blocks(&blocks_dealloc);
+        fs.order_frees(&blocks_dealloc, &pointer_blocks);
+        if let Some(tail) = tail_dealloc {
+            result = result.and(fs.dealloc_tail(tail));
+        }
+        if let Some(child) = child {
+            if let Some(tail) = tail_dealloc {
+                fs.order_tail_free(tail, child.block_id);
+            }
+            result = result.and(fs.dealloc_inode(child.inode_id));
+            // 索引节点在指向它的目录条目被删除之后才释放
+            fs.order_after(fs.inode_bitmap_block(child.inode_id), dirent_block);
+        }
+        fs.sync_on_write();
+        result
+    }
+
+    /// 重新统计当前目录的条目数，然后清除给定的目录条目，用于修复损坏的目录
+    /// 被清除的目录条目指向的索引节点不会被释放
+    ///
+    /// # Arguments
+    ///
+    /// * `indices`: 需要清除的目录条目的序号
+    pub(crate) fn repair_entries(&self, indices: &[usize]) {
+        let _guard = self.lock.lock();
+        let free_slots = self.fs.lock().dir_free_slots();
+        self.modify_disk_inode(|disk_inode| {
+            let mut dirent = DirEntry::empty();
+            let entries = (0..disk_inode.dir_slots())
+                .filter(|&slot| {
+                    disk_inode.read_at(slot * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                    !free_slots || !dirent.is_free()
+                })
+                .count();
+            disk_inode.set_dir_entries(entries);
+            disk_inode.set_dir_free_slot(0);
+            disk_inode.update_dir_checksum(&self.block_device);
+        });
+        self.fs.lock().commit(&[self.block_id]);
+
+        // 从后往前删除，前面的目录条目的序号不受影响
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        // 位图中的错误留给之后重建位图时修复
+        for index in indices.into_iter().rev() {
+            self.remove_entry(index, None).ok();
+        }
+    }
+
+    /// 删除当前目录下的一个空目录
+    /// 只包含 `.` 和 `..` 的目录才能被删除
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 目录名
+    ///
+    /// returns: Result<(), FsError> 删除结果
+    pub fn remove_dir(&self, name: &str) -> FsResult<()> {
+        self.remove_empty_dir(name.as_bytes())
+    }
+
+    /// 按原始字节名称删除当前目录下的一个空目录
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 目录名的原始字节
+    ///
+    /// returns: Result<(), FsError> 删除结果
+    fn remove_empty_dir(&self, name: &[u8]) -> FsResult<()> {
+        let _guard = self.lock.lock();
+        let (index, child) = self.find_removable(name)?;
+        // 指向当前目录自身的目录条目不能删除，否则会重复获取同一把索引节点锁
+        if child.inode_id == self.inode_id {
+            return Err(FsError::InvalidArgument);
+        }
+        let _child_guard = child.lock.lock();
+
+        // 检查是否为空目录
+        child.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            // 只剩下 `.` 和 `..`
+            if disk_inode.dir_entries() > 2 {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+            Ok(())
+        })?;
+
+        self.remove_entry(index, Some(&child))
+    }
+
+    /// 删除当前目录下的一个文件
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 文件名
+    ///
+    /// returns: Result<(), FsError> 删除结果
+    pub fn unlink(&self, name: &str) -> FsResult<()> {
+        let _guard = self.lock.lock();
+        let (index, child) = self.find_removable(name.as_bytes())?;
+        if child.inode_id == self.inode_id {
+            return Err(FsError::IsADirectory);
+        }
+        let _child_guard = child.lock.lock();
+        if child.read_disk_inode(|disk_inode| disk_inode.is_dir()) {
+            return Err(FsError::IsADirectory);
+        }
+        self.remove_entry(index, Some(&child))
+    }
+
+    /// 递归删除当前目录下的一个目录及其中的所有内容
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 目录名
+    ///
+    /// returns: Result<(), FsError> 删除结果
+    pub fn remove_dir_all(&self, name: &str) -> FsResult<()> {
+        let child = {
+            let _guard = self.lock.lock();
+            self.find_removable(name.as_bytes())?.1
+        };
+        if !child.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let mut visited = BTreeSet::new();
+        visited.insert(child.inode_id);
+        child.remove_children(&mut visited)?;
+        // 不再持有索引节点对象，目录可以被立即释放
+        drop(child);
+        self.remove_dir(name)
+    }
+
+    /// 深度优先地删除当前目录下除 `.` 和 `..` 以外的所有目录条目
+    /// 已经释放过的索引节点只删除目录条目，避免通过硬链接重复释放
+    ///
+    /// # Arguments
+    ///
+    /// * `visited`: 已经访问过的索引节点ID
+    ///
+    /// returns: Result<(), FsError> 删除结果
+    fn remove_children(&self, visited: &mut BTreeSet<u32>) -> FsResult<()> {
+        let names: Vec<Vec<u8>> = self
+            .ls_bytes()?
+            .into_iter()
+            .filter(|name| name != b"." && name != b"..")
+            .collect();
+        for name in names {
+            let guard = self.lock.lock();
+            let (index, child) = self.find_removable(&name)?;
+            if !visited.insert(child.inode_id) {
+                self.remove_entry(index, None)?;
+                continue;
+            }
+            if child.is_dir() {
+                drop(guard);
+                child
+                    .remove_children(visited)
+                    .context(ErrorContext::new("remove_dir_all").inode(child.inode_id))?;
+                drop(child);
+                self.remove_empty_dir(&name)?;
+            } else {
+                let _child_guard = child.lock.lock();
+                self.remove_entry(index, Some(&child))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 列出当前索引节点下的索引节点
+    /// 文件名编码为 [`NameEncoding::Raw`] 时，文件名有损地转换为 UTF-8
+    ///
+    /// returns: Result<Vec<String, Global>, FsError> 文件名，目录已损坏或者文件名编码为
+    /// [`NameEncoding::Utf8`] 但出现了不合法的 UTF-8 时返回 [`FsError::Corrupted`]
+    pub fn ls(&self) -> FsResult<Vec<String>> {
+        let name_encoding = self.fs.lock().name_encoding();
+        self.read_dir()
+            .map(|dirent| {
+                let dirent = dirent?;
+                match name_encoding {
+                    NameEncoding::Utf8 => dirent.name().map(String::from).map_err(|_| {
+                        FsError::Corrupted.context(ErrorContext::new("ls").inode(self.inode_id))
+                    }),
+                    NameEncoding::Raw => Ok(dirent.name_lossy().into_owned()),
+                }
+            })
+            .collect()
+    }
+
+    /// 以原始字节列出当前索引节点下的索引节点
+    ///
+    /// returns: Result<Vec<Vec<u8, Global>, Global>, FsError> 文件名的原始字节，
+    /// 目录已损坏时返回 [`FsError::Corrupted`]
+    pub fn ls_bytes(&self) -> FsResult<Vec<Vec<u8>>> {
+        self.read_dir()
+            .map(|dirent| dirent.map(|dirent| dirent.name_bytes().to_vec()))
+            .collect()
+    }
+
+    /// 从头开始逐个读取当前目录下的目录条目
+    pub fn read_dir(&self) -> ReadDir<'_> {
+        self.read_dir_from(0)
+    }
+
+    /// 从给定的游标处继续逐个读取当前目录下的目录条目
+    ///
+    /// # Arguments
+    ///
+    /// * `cookie`: 游标，由 [`ReadDir::cookie`] 返回
+    ///
+    /// returns: ReadDir 目录条目迭代器
+    pub fn read_dir_from(&self, cookie: usize) -> ReadDir<'_> {
+        ReadDir {
+            inode: self,
+            offset: cookie,
+            verified: false,
+            failed: false,
+        }
+    }
+
+    /// 深度优先地遍历以当前索引节点为根的子树
+    /// 默认先返回目录再返回其中的内容，最大深度为文件系统允许的最大路径深度
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 当前索引节点的路径，子树中的路径以它为前缀
+    ///
+    /// returns: Walk 子树迭代器
+    pub fn walk(self: &Arc<Self>, path: &str) -> Walk {
+        let max_depth = self.fs.lock().max_path_depth();
+        Walk {
+            stack: vec![(String::from(path), self.clone(), 0, false)],
+            max_depth,
+            contents_first: false,
+            sort_by_name: false,
+        }
+    }
+
+    /// 在以当前索引节点为根的子树中查找名称与模式匹配的索引节点
+    /// 模式支持 `*` 匹配任意个字符，`?` 匹配一个字符，只与路径的最后一个分量比较
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 当前索引节点的路径，返回的路径以它为前缀
+    /// * `pattern`: 模式
+    ///
+    /// returns: Result<Vec<String, Global>, FsError> 匹配的完整路径，子树中有目录已损坏时返回
+    /// [`FsError::Corrupted`]
+    pub fn find_matching(self: &Arc<Self>, path: &str, pattern: &str) -> FsResult<Vec<String>> {
+        let mut matches = Vec::new();
+        // 第一项是当前索引节点自身，不参与匹配
+        for (i, entry) in self.walk(path).enumerate() {
+            let (path, _, _) = entry?;
+            let name = path.rsplit('/').next().unwrap_or(&path);
+            if i > 0 && glob_match(pattern, name) {
+                matches.push(path);
+            }
+        }
+        Ok(matches)
+    }
+
+    /// 统计以当前索引节点为根的子树的空间占用
+    /// 通过硬链接多次出现的索引节点只统计一次
+    ///
+    /// returns: Result<DiskUsage, FsError> 空间占用，子树中有目录已损坏时返回 [`FsError::Corrupted`]
+    pub fn disk_usage(self: &Arc<Self>) -> FsResult<DiskUsage> {
+        let mut visited = BTreeSet::new();
+        let mut usage = DiskUsage::default();
+        for entry in self.walk("") {
+            let (_, inode, metadata) = entry?;
+            if visited.insert(metadata.inode_id) {
+                usage.size += metadata.size;
+                usage.blocks += inode.allocated_blocks();
+            }
+        }
+        Ok(usage)
+    }
+
+    /// 从当前索引节点中读取数据
+    /// 从上一次读取结束的位置继续读取时，再预读之后的 [`READ_AHEAD_BLOCKS`] 个块
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `buf`: 缓冲区
+    ///
+    /// returns: usize 读取的字节数
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let _guard = self.lock.lock();
+        self.touch_atime();
+        let sequential = self.read_end.load(Ordering::Relaxed) == offset;
+        let len = self.read_disk_inode(|disk_inode| {
+            let len = disk_inode.read_at(offset, buf, &self.block_device);
+            if sequential && len > 0 {
+                disk_inode.read_ahead(offset + len, READ_AHEAD_BLOCKS, &self.block_device);
+            }
+            len
+        });
+        self.read_end.store(offset + len, Ordering::Relaxed);
+        self.read_delayed(offset, &mut buf[..len]);
+        len
+    }
+
+    /// 读取当前索引节点中的全部数据
+    pub fn read_all(&self) -> Vec<u8> {
+        let _guard = self.lock.lock();
+        self.touch_atime();
+        let mut buf = self.read_disk_inode(|disk_inode| {
+            let mut buf = vec![0u8; disk_inode.size as usize];
+            let mut offset = 0usize;
+            while offset < buf.len() {
+                let len = disk_inode.read_at(offset, &mut buf[offset..], &self.block_device);
+                if len == 0 {
+                    break;
+                }
+                offset += len;
+            }
+            buf.truncate(offset);
+            buf
+        });
+        self.read_delayed(0, &mut buf);
+        buf
+    }
+
+    /// 将数据写入到当前索引节点
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `buf`: 缓冲区
+    ///
+    /// returns: Result<usize, FsError> 写入的字节数，文件系统只读时返回 [`FsError::ReadOnly`]，
+    /// 可以分配的数据块不够时返回 [`FsError::NoSpace`]，超出配额时返回 [`FsError::QuotaExceeded`]，
+    /// 超出最大文件大小时返回 [`FsError::FileTooLarge`]，失败时不做任何修改
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> FsResult<usize> {
+        self.ensure_writable()?;
+        let _guard = self.lock.lock();
+        self.check_size(offset, buf.len())?;
+        if self.use_delayed_allocation(offset, buf.len()) {
+            match self.write_delayed(offset, buf) {
+                // 预留时按最坏情况估计索引块，落盘之后按实际需要的块数再试一次
+                Err(FsError::NoSpace) => {}
+                result => return result,
+            }
+        }
+        self.write_back_delayed()?;
+        self.prepare_write(offset, buf.len())?;
+        let written = self.write_prepared(offset, buf);
+        let packed = self.pack_tail();
+        self.commit_data(offset, written);
+        packed.map(|_| written)
+    }
+
+    /// 将数据写入到已经为写入做好准备的范围，并更新修改时间
+    /// 调用者需持有索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `buf`: 缓冲区
+    ///
+    /// returns: usize 写入的字节数
+    fn write_prepared(&self, offset: usize, buf: &[u8]) -> usize {
+        let now = self.now();
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+            disk_inode.write_at(offset, buf, &self.block_device)
+        })
+    }
+
+    /// 数据日志模式下通过日志提交一次写入涉及的数据块、索引块、它们所在的数据位图块、块组描述符块和磁盘索引节点
+    /// 磁盘索引节点放在最后，拆成多个事务时它在最后一个事务中提交
+    /// 调用者需持有索引节点锁
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `len`: 写入的字节数
+    fn commit_data(&self, offset: usize, len: usize) {
+        if len == 0 || !self.fs.lock().data_journaling() {
+            return;
+        }
+        let start_block = (offset / BLOCK_SZ) as u32;
+        let end_block = (offset + len).div_ceil(BLOCK_SZ) as u32;
+        let mut block_ids = self.read_disk_inode(|disk_inode| {
+            let mut block_ids =
+                disk_inode.range_block_ids(start_block, end_block, &self.block_device);
+            block_ids.extend(disk_inode.index_block_ids(&self.block_device));
+            block_ids
+        });
+        let mut fs = self.fs.lock();
+        let bitmap_blocks: BTreeSet<u64> = block_ids
+            .iter()
+            .filter_map(|&block_id| fs.data_bitmap_block(block_id))
+            .collect();
+        let mut seen = BTreeSet::new();
+        block_ids.retain(|&block_id| seen.insert(block_id));
+        block_ids.extend(bitmap_blocks);
+        block_ids.extend(fs.group_descriptor_block_ids());
+        block_ids.push(self.block_id);
+        fs.commit(&block_ids);
+    }
+
+    /// 将当前目录下的一个文件复制到目标目录，见 [`copy`]
+    ///
+    /// # Arguments
+    ///
+    /// * `name`: 源文件名
+    /// * `target_dir`: 目标目录
+    /// * `new_name`: 新文件名
+    ///
+    /// returns: Result<Arc<Inode>, FsError> 新文件的索引节点
+    pub fn copy(&self, name: &str, target_dir: &Inode, new_name: &str) -> FsResult<Arc<Inode>> {
+        let src = self.find(name)?;
+        copy(&src, target_dir, new_name, CopyOptions::default())
+    }
+
+    /// 将数据追加到当前索引节点的末尾
+    /// 读取文件大小和写入在同一次持有索引节点锁期间完成，并发追加不会互相覆盖
+    ///
+    /// # Arguments
+    ///
+    /// * `buf`: 缓冲区
+    ///
+    /// returns: Result<usize, FsError> 写入的字节数，错误见 [`Inode::write_at`]
+    pub fn write_append(&self, buf: &[u8]) -> FsResult<usize> {
+        self.ensure_writable()?;
+        let _guard = self.lock.lock();
+        let offset = self.read_disk_inode(|disk_inode| disk_inode.size as usize);
+        self.check_size(offset, buf.len())?;
+        if self.use_delayed_allocation(offset, buf.len()) {
+            match self.write_delayed(offset, buf) {
+                // 预留时按最坏情况估计索引块，落盘之后按实际需要的块数再试一次
+                Err(FsError::NoSpace) => {}
+                result => return result,
+            }
+        }
+        self.write_back_delayed()?;
+        self.prepare_write(offset, buf.len())?;
+        let written = self.write_prepared(offset, buf);
+        let packed = self.pack_tail();
+        self.commit_data(offset, written);
+        packed.map(|_| written)
+    }
+
+    /// 将当前索引节点的数据块、间接索引块和磁盘索引节点同步到块设备
+    /// 先为延迟分配的数据分配数据块，然后同步数据块，
+    /// 再通过日志提交记录这些块和索引节点分配情况的位图块、块组描述符块、超级块，最后提交间接索引块和磁盘索引节点所在的块
+    ///
+    /// returns: Result<(), FsError> 延迟分配的数据分配不到数据块时返回错误，见 [`Inode::flush_delayed`]
+    pub fn sync(&self) -> FsResult<()> {
+        let _guard = self.lock.lock();
+        self.write_back_delayed()?;
+        let block_ids = self.read_disk_inode(|disk_inode| {
+            let mut block_ids = disk_inode.block_ids(&self.block_device);
+            block_ids.extend(disk_inode.tail().map(|tail| tail.block_id));
+            block_ids
+        });
+        let pointer_blocks = self.pointer_blocks();
+        let data_blocks: Vec<u64> = block_ids
+            .iter()
+            .copied()
+            .filter(|block_id| !pointer_blocks.contains(block_id))
+            .collect();
+        block_cache_sync(&self.block_device, &data_blocks);
+        let mut fs = self.fs.lock();
+        if !fs.read_only() {
+            // 位图和空闲计数先于指向这些块的指针持久化，崩溃后不会出现占用了却没有标记的块
+            let bitmap_blocks: BTreeSet<u64> = block_ids
+                .iter()
+                .filter_map(|&block_id| fs.data_bitmap_block(block_id))
+                .chain([fs.inode_bitmap_block(self.inode_id)])
+                .collect();
+            let mut metadata_blocks: Vec<u64> = bitmap_blocks.into_iter().collect();
+            metadata_blocks.extend(fs.group_descriptor_block_ids());
+            metadata_blocks.push(0);
+            metadata_blocks.extend(pointer_blocks);
+            fs.commit(&metadata_blocks);
+        }
+        drop(fs);
+        self.block_device.flush();
+        Ok(())
+    }
+
+    /// 为当前文件延迟分配的数据分配数据块，并将数据写入块缓存
+    /// 见 [`EasyFileSystem::set_delayed_allocation`]
+    ///
+    /// returns: Result<(), FsError> 可以分配的数据块不够时返回 [`FsError::NoSpace`]，
+    /// 超出配额时返回 [`FsError::QuotaExceeded`]，没能落盘的数据仍然留在内存中
+    pub fn flush_delayed(&self) -> FsResult<()> {
+        let _guard = self.lock.lock();
+        self.write_back_delayed()
+    }
+
+    /// 在当前索引节点的给定范围内打洞，文件大小保持不变
+    /// 完全落在范围内的数据块被释放，范围两端不完整的块中对应的部分被清零
+    ///
+    /// # Arguments
+    ///
+    /// * `offset`: 偏移
+    /// * `len`: 长度
+    ///
+    /// returns: Result<usize, FsError> 释放的块数，包括间接索引块，文件系统只读时返回 [`FsError::ReadOnly`]
+    pub fn punch_hole(&self, offset: usize, len: usize) -> FsResult<usize> {
+        self.ensure_writable()?;
+        let _guard = self.lock.lock();
+        self.write_back_delayed()?;
+        let now = self.now();
+        let (quota_target, blocks_dealloc, tail_dealloc) = self.modify_disk_inode(|disk_inode| {
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+            let tail = disk_inode.tail();
+            let blocks_dealloc = disk_inode.punch_hole(offset, len, &self.block_device);
+            (
+                disk_inode.quota_target(self.inode_id),
+                blocks_dealloc,
+                tail.filter(|_| disk_inode.tail().is_none()),
+            )
+        });
+        let count = blocks_dealloc.len();
+        // 索引节点和保留下来的间接索引块的更新持久化之后再释放数据块
+        let pointer_blocks = self.pointer_blocks();
+        let mut fs = self.fs.lock();
+        fs.commit(&pointer_blocks);
+        // 打包的尾部在配额中按一个块计算
+        fs.charge_usage(
+            quota_target,
+            -(((count + tail_dealloc.is_some() as usize) * BLOCK_SZ) as i64),
+            0,
+        );
+        let mut result = fs.dealloc_data_blocks(&blocks_dealloc);
+        fs.order_frees(&blocks_dealloc, &pointer_blocks);
+        if let Some(tail) = tail_dealloc {
+            result = result.and(fs.dealloc_tail(tail));
+            fs.order_tail_free(tail, self.block_id);
+        }
+        fs.sync_on_write();
+        result.map(|_| count)
+    }
+
+    /// 清空当前索引节点中的数据
+    /// 只有在索引节点的更新持久化之后才会释放数据块，
+    /// 避免崩溃后出现两个文件指向同一个数据块的情况
+    /// 文件系统只读时返回 [`FsError::ReadOnly`]，不做任何修改
+    pub fn clear(&self) -> FsResult<()> {
+        self.ensure_writable()?;
+        let _guard = self.lock.lock();
+        let now = self.now();
+        let (quota_target, data_blocks_dealloc, tail_dealloc) =
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.mtime = now;
+                disk_inode.ctime = now;
+                let allocated = disk_inode.block_ids(&self.block_device).len();
+                let tail = disk_inode.tail();
+                disk_inode.set_tail(None);
+                let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
+                // 空洞不占用数据块，占用的块全部被释放
+                assert_eq!(data_blocks_dealloc.len(), allocated);
+                (
+                    disk_inode.quota_target(self.inode_id),
+                    data_blocks_dealloc,
+                    tail,
+                )
+            });
+        let mut fs = self.fs.lock();
+        fs.commit(&[self.block_id]);
+        let discarded = fs.discard_delayed(self.inode_id);
+        fs.charge_usage(
+            quota_target,
+            -(((data_blocks_dealloc.len() + tail_dealloc.is_some() as usize) * BLOCK_SZ) as i64),
+            0,
+        );
+        let mut result = discarded.and(fs.dealloc_data_blocks(&data_blocks_dealloc));
+        fs.order_frees(&data_blocks_dealloc, &[self.block_id]);
+        if let Some(tail) = tail_dealloc {
+            result = result.and(fs.dealloc_tail(tail));
+            fs.order_tail_free(tail, self.block_id);
+        }
+        fs.sync_on_write();
+        result
+    }
+}
+
+impl Drop for Inode {
+    /// 索引节点表保证每个索引节点ID只有一个索引节点对象，它被释放后就没有办法再落盘延迟分配的数据，
+    /// 所以在释放时落盘；这些数据已经预留了数据块，不会因为空间不足而失败
+    fn drop(&mut self) {
+        let orphan = self.fs.lock().take_orphan(self.inode_id);
+        if let Some(dirent_block) = orphan {
+            let _ = self.release(dirent_block);
+        } else if self.fs.lock().delayed(self.inode_id).is_some() {
+            let _ = self.flush_delayed();
+        }
+    }
+}
+
+/// 目录条目迭代器
+/// 每一步只读取一个目录条目，且只在读取期间持有目录的索引节点锁
+/// 第一步先检查目录的校验和，不匹配时只返回一个 [`FsError::Corrupted`]，
+/// 避免把写了一半的目录块中的垃圾数据当作目录条目返回
+/// 删除目录条目后留下的空位被跳过，不会作为目录条目返回
+pub struct ReadDir<'a> {
+    /// 目录的索引节点
+    inode: &'a Inode,
+
+    /// 下一个目录条目在目录中的偏移
+    offset: usize,
+
+    /// 是否已经检查过目录的校验和
+    verified: bool,
+
+    /// 是否已经返回过错误，之后不再返回目录条目
+    failed: bool,
+}
+
+impl ReadDir<'_> {
+    /// 获取当前位置的游标，可用于 [`Inode::read_dir_from`] 恢复迭代
+    pub fn cookie(&self) -> usize {
+        self.offset
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = FsResult<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if !self.verified {
+            self.verified = true;
+            if let Err(err) = self.inode.verify() {
+                self.failed = true;
+                return Some(Err(err));
+            }
+        }
+        let _guard = self.inode.lock.lock();
+        self.inode.read_disk_inode(|disk_inode| {
+            // 断言是一个目录
+            assert!(disk_inode.is_dir());
+            // 跳过删除目录条目后留下的空位
+            let mut dirent = DirEntry::empty();
+            loop {
+                if self.offset + DIRENT_SZ > disk_inode.size as usize {
+                    return None;
+                }
+                assert_eq!(
+                    disk_inode.read_at(
+                        self.offset,
+                        dirent.as_bytes_mut(),
+                        &self.inode.block_device
+                    ),
+                    DIRENT_SZ,
+                );
+                self.offset += DIRENT_SZ;
+                if !dirent.is_free() {
+                    return Some(Ok(dirent));
+                }
+            }
+        })
+    }
+}
+
+/// 复制文件的选项
+#[derive(Default)]
+pub struct CopyOptions<'a> {
+    /// 进度回调，参数为已复制的字节数和总字节数
+    pub progress: Option<&'a mut dyn FnMut(usize, usize)>,
+}
+
+/// 将一个文件复制到目标目录
+/// 数据经由块缓存逐块复制，最多只占用一个块大小的缓冲区
+/// 全零的块不写入目标文件，以保留源文件中的空洞
+/// 文件系统不支持共享数据块，因此不会尝试引用复制
+///
+/// # Arguments
+///
+/// * `src`: 源文件
+/// * `dst_dir`: 目标目录
+/// * `name`: 新文件名
+/// * `options`: 复制选项
+///
+/// returns: Result<Arc<Inode>, FsError> 新文件的索引节点
+pub fn copy(
+    src: &Inode,
+    dst_dir: &Inode,
+    name: &str,
+    mut options: CopyOptions,
+) -> FsResult<Arc<Inode>> {
+    if src.is_dir() {
+        return Err(FsError::IsADirectory);
+    }
+    let file_name = FileName::new(name)?;
+    dst_dir.ensure_writable()?;
+    let dst = dst_dir.create_inode(file_name, DiskInodeType::File)?;
+    let total = src.size();
+    let mut buf = [0u8; BLOCK_SZ];
+    let mut offset = 0usize;
+    loop {
+        let len = src.read_at(offset, &mut buf);
+        if len == 0 {
+            break;
+        }
+        // 最后一块总是写入，以确定文件大小
+        if offset + len == total || buf[..len].iter().any(|&byte| byte != 0) {
+            if let Err(error) = dst.write_at(offset, &buf[..len]) {
+                // 复制失败时不留下不完整的文件
+                dst_dir.unlink(name).ok();
+                return Err(error);
+            }
+        }
+        offset += len;
+        if let Some(progress) = options.progress.as_mut() {
+            progress(offset, total);
+        }
+    }
+    Ok(dst)
+}
+
+/// 子树迭代器，由 [`Inode::walk`] 创建
+/// 每一项为 (路径, 索引节点, 元数据)，`.` 和 `..` 不会被返回
+/// 遇到已损坏的目录时先返回一个错误，目录本身照常返回但不展开，之后可以继续迭代
+pub struct Walk {
+    /// 待访问的 (路径, 索引节点, 深度, 是否已经展开)
+    stack: Vec<(String, Arc<Inode>, usize, bool)>,
+
+    /// 最大深度，根的深度为 0
+    max_depth: usize,
+
+    /// 是否先返回目录中的内容再返回目录本身
+    contents_first: bool,
+
+    /// 是否按名称排序目录中的内容
+    sort_by_name: bool,
+}
+
+impl Walk {
+    /// 设置最大深度，超过该深度的目录不再展开
+    ///
+    /// # Arguments
+    ///
+    /// * `max_depth`: 最大深度，根的深度为 0
+    ///
+    /// returns: Walk 子树迭代器
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// 设置是否先返回目录中的内容再返回目录本身
+    ///
+    /// # Arguments
+    ///
+    /// * `contents_first`: 是否后序遍历
+    ///
+    /// returns: Walk 子树迭代器
+    pub fn contents_first(mut self, contents_first: bool) -> Self {
+        self.contents_first = contents_first;
+        self
+    }
+
+    /// 设置是否按名称排序目录中的内容，否则按目录条目的顺序
+    ///
+    /// # Arguments
+    ///
+    /// * `sort_by_name`: 是否排序
+    ///
+    /// returns: Walk 子树迭代器
+    pub fn sort_by_name(mut self, sort_by_name: bool) -> Self {
+        self.sort_by_name = sort_by_name;
+        self
+    }
+
+    /// 将目录中的内容压入栈中，使它们按顺序出栈
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: 目录的路径
+    /// * `dir`: 目录
+    /// * `depth`: 目录的深度
+    ///
+    /// returns: Result<(), FsError> 目录已损坏时返回 [`FsError::Corrupted`]，不压入任何内容
+    fn push_children(&mut self, path: &str, dir: &Inode, depth: usize) -> FsResult<()> {
+        let mut children: Vec<DirEntry> = dir
+            .read_dir()
+            .filter(|dirent| {
+                dirent.as_ref().map_or(true, |dirent| {
+                    dirent.name_bytes() != b"." && dirent.name_bytes() != b".."
+                })
+            })
+            .collect::<FsResult<_>>()?;
+        if self.sort_by_name {
+            children.sort_by(|a, b| a.name_bytes().cmp(b.name_bytes()));
+        }
+        for dirent in children.into_iter().rev() {
+            let child_path = format!("{}/{}", path.trim_end_matches('/'), dirent.name_lossy());
+            let child = EasyFileSystem::get_inode(&dir.fs, dirent.inode_number());
+            self.stack.push((child_path, child, depth + 1, false));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = FsResult<(String, Arc<Inode>, Metadata)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (path, inode, depth, expanded) = self.stack.pop()?;
+            let metadata = inode.metadata();
+            if !expanded && metadata.is_dir && depth < self.max_depth {
+                if self.contents_first {
+                    self.stack.push((path.clone(), inode.clone(), depth, true));
+                    if let Err(err) = self.push_children(&path, &inode, depth) {
+                        return Some(Err(err));
+                    }
+                    continue;
+                }
+                if let Err(err) = self.push_children(&path, &inode, depth) {
+                    // 目录自身在错误之后返回
+                    self.stack.push((path, inode, depth, true));
+                    return Some(Err(err));
+                }
+            }
+            return Some(Ok((path, inode, metadata)));
+        }
+    }
+}
+
+/// 名称是否与模式匹配
+/// 模式支持 `*` 匹配任意个字符，`?` 匹配一个字符，其余字符按原样匹配
+///
+/// # Arguments
+///
+/// * `pattern`: 模式
+/// * `name`: 名称
+///
+/// returns: bool 是否匹配
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+
+    // 最近一个 `*` 的位置，以及它当时对应的名称位置
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // 让 `*` 多匹配一个字符后重试
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
     }
+    pattern[p..].iter().all(|&c| c == '*')
 }
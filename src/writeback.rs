@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use spin::Mutex;
+
+use crate::block_cache::block_cache_writeback;
+
+/// 后台写回线程的参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WritebackConfig {
+    /// 两次检查之间的间隔
+    pub interval: Duration,
+
+    /// 脏块在缓存中最多停留的时长，超过后被写回
+    pub max_age: Duration,
+
+    /// 脏块最多占块缓存容量的百分比，超过后从最早变脏的块开始写回
+    pub dirty_percent: usize,
+}
+
+impl Default for WritebackConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_age: Duration::from_secs(5),
+            dirty_percent: 50,
+        }
+    }
+}
+
+/// 正在运行的写回线程
+struct Worker {
+    /// 通知线程退出
+    stop: Arc<AtomicBool>,
+
+    /// 线程句柄
+    handle: JoinHandle<()>,
+}
+
+/// 全局唯一的写回线程，块缓存在所有文件系统之间共享，一个线程就能覆盖所有块设备
+static WORKER: Mutex<Option<Worker>> = Mutex::new(None);
+
+/// 启动后台写回线程
+/// 线程每隔一段时间写回变脏太久的块缓存，脏块太多时再写回最早变脏的块缓存，
+/// 每个块都在它依赖的块之后写回；写回模式下的修改因此不必等到被替换出去或者显式同步才持久化
+///
+/// # Arguments
+///
+/// * `config`: 写回线程的参数
+///
+/// returns: bool 是否启动了线程，已经在运行时返回 false
+pub fn start_writeback(config: WritebackConfig) -> bool {
+    let mut worker = WORKER.lock();
+    if worker.is_some() {
+        return false;
+    }
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = stop.clone();
+    let handle = thread::spawn(move || {
+        while !flag.load(Ordering::Acquire) {
+            thread::park_timeout(config.interval);
+            if flag.load(Ordering::Acquire) {
+                break;
+            }
+            block_cache_writeback(config.max_age, config.dirty_percent);
+        }
+    });
+    *worker = Some(Worker { stop, handle });
+    true
+}
+
+/// 停止后台写回线程，等待正在进行的写回完成后返回
+/// 停止时不会写回剩余的脏块，需要持久化时调用 [`EasyFileSystem::sync`](crate::efs::EasyFileSystem::sync)
+///
+/// returns: bool 是否停止了线程，没有在运行时返回 false
+pub fn stop_writeback() -> bool {
+    // 先取出线程再等待它退出，等待时不持有锁
+    let Some(worker) = WORKER.lock().take() else {
+        return false;
+    };
+    worker.stop.store(true, Ordering::Release);
+    worker.handle.thread().unpark();
+    worker.handle.join().expect("writeback thread panicked");
+    true
+}
+
+/// 后台写回线程是否正在运行
+pub fn writeback_running() -> bool {
+    WORKER.lock().is_some()
+}